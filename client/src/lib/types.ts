@@ -6,6 +6,7 @@ export type EventRecord = {
   last_seen: number;
   count: number;
   deleted_count: number;
+  purged_count?: number;
 };
 export type NsidCount = {
   nsid: string;
@@ -0,0 +1,370 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use ahash::AHashMap;
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use rclite::Arc;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::{
+    api::{
+        HitsRange,
+        extract::{JsonBody, Query},
+    },
+    config::{self, Setting},
+    db::{
+        AlertRule, ContentDigest, Db, IngestState, LabelMap, PartitionKind, QuiesceState,
+        SyncStats, TierStatus, WatchResult, is_valid_did, validate_labels,
+    },
+    error::{AppError, AppResult, ErrorCode},
+};
+
+const DEFAULT_PAUSE_TIMEOUT: Duration = Duration::from_secs(60 * 15); // 15 mins
+const MAX_PAUSE_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 6); // 6 hours
+const DEFAULT_QUIESCE_TIMEOUT: Duration = Duration::from_secs(60 * 5); // 5 mins
+const MAX_QUIESCE_TIMEOUT: Duration = Duration::from_secs(60 * 60); // 1 hour
+const DEFAULT_REHYDRATE_HOLD: Duration = Duration::from_secs(60 * 60 * 24); // 1 day
+
+fn admin_token() -> Option<SmolStr> {
+    config::env_or("ADMIN_TOKEN", None, |token| {
+        (!token.is_empty()).then(|| Some(SmolStr::new(token)))
+    })
+}
+
+fn has_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| value == token)
+}
+
+/// whether the request carries the admin token, for admin only features
+/// on public routes
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    admin_token().is_some_and(|token| has_token(headers, &token))
+}
+
+// admin routes are only mounted if ADMIN_TOKEN is set
+pub fn router() -> Option<Router<Arc<Db>>> {
+    let token = admin_token()?;
+    let router = Router::new()
+        .route("/pause_ingest", post(pause_ingest))
+        .route("/resume_ingest", post(resume_ingest))
+        .route("/quiesce", post(quiesce))
+        .route("/unquiesce", post(unquiesce))
+        .route("/tier_status", get(tier_status))
+        .route("/rehydrate", post(rehydrate))
+        .route("/digest", get(digest))
+        .route(
+            "/watchlist",
+            get(watchlist).post(watch_did).delete(unwatch_did),
+        )
+        .route("/labels", get(labels).put(set_labels).delete(remove_labels))
+        .route(
+            "/alert_rules",
+            get(alert_rules)
+                .put(put_alert_rule)
+                .delete(remove_alert_rule),
+        )
+        .route("/sync", post(sync))
+        .route("/config", get(effective_config))
+        .route_layer(middleware::from_fn(move |request: Request, next: Next| {
+            require_token(token.clone(), request, next)
+        }));
+    Some(router)
+}
+
+async fn require_token(token: SmolStr, request: Request, next: Next) -> AppResult<Response> {
+    if !has_token(request.headers(), &token) {
+        return Err(AppError::new(
+            ErrorCode::Unauthorized,
+            "needs the admin token as a bearer token",
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+#[derive(Debug, Serialize)]
+struct IngestStatus {
+    ingest: IngestState,
+}
+
+#[derive(Debug, Deserialize)]
+struct PauseQuery {
+    // seconds until ingest automatically resumes
+    timeout: Option<u64>,
+}
+
+async fn pause_ingest(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<PauseQuery>,
+) -> Json<IngestStatus> {
+    let timeout = params
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PAUSE_TIMEOUT)
+        .min(MAX_PAUSE_TIMEOUT);
+    Json(IngestStatus {
+        ingest: db.pause_ingest(timeout),
+    })
+}
+
+async fn resume_ingest(State(db): State<Arc<Db>>) -> Json<IngestStatus> {
+    Json(IngestStatus {
+        ingest: db.resume_ingest(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct Quiesced {
+    quiesce: QuiesceState,
+    // where a restored snapshot should resume jetstream from
+    cursor: Option<u64>,
+}
+
+async fn quiesce(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<PauseQuery>,
+) -> AppResult<Json<Quiesced>> {
+    let timeout = params
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_QUIESCE_TIMEOUT)
+        .min(MAX_QUIESCE_TIMEOUT);
+    tokio::task::spawn_blocking(move || {
+        let quiesce = db.quiesce(timeout)?;
+        let cursor = db.stored_cursor()?;
+        AppResult::Ok(Json(Quiesced { quiesce, cursor }))
+    })
+    .await?
+}
+
+async fn unquiesce(State(db): State<Arc<Db>>) -> AppResult<Json<Quiesced>> {
+    tokio::task::spawn_blocking(move || {
+        let quiesce = db.unquiesce()?;
+        let cursor = db.stored_cursor()?;
+        AppResult::Ok(Json(Quiesced { quiesce, cursor }))
+    })
+    .await?
+}
+
+#[derive(Debug, Deserialize)]
+struct NsidQuery {
+    nsid: SmolStr,
+}
+
+async fn tier_status(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<NsidQuery>,
+) -> AppResult<Json<TierStatus>> {
+    tokio::task::spawn_blocking(move || db.tier_status(&params.nsid))
+        .await?
+        .map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct RehydrateQuery {
+    nsid: SmolStr,
+    from: Option<u64>,
+    to: Option<u64>,
+    // seconds to keep the rehydrated blocks out of the cold tier
+    hold: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Rehydrated {
+    blocks: usize,
+}
+
+async fn rehydrate(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<RehydrateQuery>,
+) -> AppResult<Json<Rehydrated>> {
+    let range = HitsRange::new(params.from, params.to);
+    let hold = params
+        .hold
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_REHYDRATE_HOLD);
+    let blocks =
+        tokio::task::spawn_blocking(move || db.rehydrate(&params.nsid, range, hold)).await??;
+    Ok(Json(Rehydrated { blocks }))
+}
+
+#[derive(Debug, Serialize)]
+struct Watchlist {
+    dids: Vec<SmolStr>,
+}
+
+async fn watchlist(State(db): State<Arc<Db>>) -> Json<Watchlist> {
+    Json(Watchlist {
+        dids: db.watchlist(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct DidQuery {
+    did: SmolStr,
+}
+
+#[derive(Debug, Serialize)]
+struct Watched {
+    result: WatchResult,
+}
+
+async fn watch_did(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<DidQuery>,
+) -> AppResult<Response> {
+    if !is_valid_did(&params.did) {
+        return Err(AppError::new(
+            ErrorCode::InvalidDid,
+            format!("{} isnt a valid did", params.did),
+        ));
+    }
+    let result = db.watch(&params.did)?;
+    let status = match result {
+        WatchResult::Full => StatusCode::CONFLICT,
+        WatchResult::Added | WatchResult::AlreadyWatched => StatusCode::OK,
+    };
+    Ok((status, Json(Watched { result })).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct Removed {
+    removed: bool,
+}
+
+async fn unwatch_did(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<DidQuery>,
+) -> AppResult<Json<Removed>> {
+    Ok(Json(Removed {
+        removed: db.unwatch(&params.did)?,
+    }))
+}
+
+async fn labels(State(db): State<Arc<Db>>) -> Json<AHashMap<SmolStr, LabelMap>> {
+    Json((*db.all_labels()).clone())
+}
+
+#[derive(Debug, Deserialize)]
+struct LabelsQuery {
+    nsid: SmolStr,
+}
+
+// replaces every label of the nsid with the body, `{}` removes them
+async fn set_labels(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<LabelsQuery>,
+    JsonBody(labels): JsonBody<LabelMap>,
+) -> AppResult<Response> {
+    if params.nsid.is_empty() || !PartitionKind::is_hits(&params.nsid) {
+        return Err(AppError::new(ErrorCode::InvalidNsid, "invalid nsid"));
+    }
+    if let Err(err) = validate_labels(&labels) {
+        return Err(AppError::new(ErrorCode::InvalidLabels, err));
+    }
+    db.set_labels(&params.nsid, labels.clone())?;
+    Ok(Json(labels).into_response())
+}
+
+async fn remove_labels(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<LabelsQuery>,
+) -> AppResult<Json<Removed>> {
+    Ok(Json(Removed {
+        removed: db.remove_labels(&params.nsid)?,
+    }))
+}
+
+async fn alert_rules(State(db): State<Arc<Db>>) -> Json<Vec<AlertRule>> {
+    Json(db.alerts().rules())
+}
+
+// adds the rule in the body, or replaces the one with its id
+async fn put_alert_rule(
+    State(db): State<Arc<Db>>,
+    JsonBody(rule): JsonBody<AlertRule>,
+) -> AppResult<Json<AlertRule>> {
+    if let Err(err) = rule.validate() {
+        return Err(AppError::bad_request(err));
+    }
+    db.alerts().put_rule(rule.clone())?;
+    Ok(Json(rule))
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertRuleQuery {
+    id: SmolStr,
+}
+
+// the alerts of the rule go with it
+async fn remove_alert_rule(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<AlertRuleQuery>,
+) -> AppResult<Json<Removed>> {
+    Ok(Json(Removed {
+        removed: db.alerts().remove_rule(&params.id)?,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DigestQuery {
+    // only nsids starting with this
+    #[serde(default)]
+    prefix: SmolStr,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+async fn digest(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<DigestQuery>,
+) -> AppResult<Json<ContentDigest>> {
+    let range = HitsRange::new(params.from, params.to);
+    tokio::task::spawn_blocking(move || db.content_digest(&params.prefix, range))
+        .await?
+        .map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncQuery {
+    // write out everything buffered, not just full blocks
+    #[serde(default)]
+    all: bool,
+}
+
+// a sync right now instead of at the next tick, a 409 if one is running
+async fn sync(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<SyncQuery>,
+) -> AppResult<Json<SyncStats>> {
+    let stats = tokio::task::spawn_blocking(move || db.try_sync(params.all)).await?;
+    match stats {
+        Some(stats) => Ok(Json(stats?)),
+        None => Err(AppError::new(
+            ErrorCode::SyncRunning,
+            "a sync is already running",
+        )),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EffectiveConfig {
+    // by env var name, secrets only say whether they are set
+    settings: BTreeMap<SmolStr, Setting>,
+}
+
+async fn effective_config() -> Json<EffectiveConfig> {
+    Json(EffectiveConfig {
+        settings: config::settings(),
+    })
+}
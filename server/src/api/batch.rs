@@ -0,0 +1,225 @@
+use axum::{Extension, Json, extract::State, http::HeaderMap};
+use futures_util::StreamExt;
+use rclite::Arc;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    api::{
+        EpsQuery, EventsQuery, Freshness, HitsFormat, HitsQuery, NsidQuery, SinceQuery, admission,
+        eps_of,
+        extract::JsonBody,
+        heavy::HeavyQuery,
+        hits_params, json_hits, limited, nsid_info_of,
+        pool::run_query,
+        sample::{Sampler, sampled_hits},
+        since_of, write_events,
+    },
+    db::{DataSource, Db},
+    error::{AppError, AppResult, ErrorBody},
+};
+
+// a batch with more queries than this is a 400
+const MAX_BATCH_SIZE: usize = 20;
+// how many queries of a batch run at once
+const BATCH_PARALLELISM: usize = 4;
+
+/// which route a query of a batch stands in for
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum BatchKind {
+    Events,
+    /// only json hits of a single nsid, without resolution or step
+    Hits,
+    Since,
+    NsidInfo,
+    Eps,
+}
+
+/// one query of a batch
+#[derive(Debug, Deserialize, ToSchema)]
+struct BatchQuery {
+    query: BatchKind,
+    /// the query parameters of the route as an object, like
+    /// `{"nsid": "app.bsky.feed.post", "limit": 100}`
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    params: serde_json::Value,
+}
+
+/// the answer to one query of a batch, the body the route would have
+/// answered with or its error
+#[derive(Serialize, ToSchema)]
+struct BatchResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    body: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+impl From<AppResult<serde_json::Value>> for BatchResult {
+    fn from(res: AppResult<serde_json::Value>) -> Self {
+        match res {
+            Ok(body) => Self {
+                status: 200,
+                body: Some(body),
+                error: None,
+            },
+            Err(err) => Self {
+                status: err.kind().status().as_u16(),
+                body: None,
+                error: Some(err.body()),
+            },
+        }
+    }
+}
+
+/// hits as /batch answers them, the headers of /hits go into the body
+#[derive(Debug, Serialize)]
+struct BatchHits<T> {
+    hits: Vec<T>,
+    truncated: bool,
+}
+
+pub fn routes() -> OpenApiRouter<Arc<Db>> {
+    OpenApiRouter::new().routes(limited(routes!(batch)))
+}
+
+// several queries in one request, for dashboards that would otherwise send a
+// burst of small ones. a query that fails only fails its own entry. the batch
+// counts as one heavy query
+#[utoipa::path(
+    post,
+    path = "/batch",
+    tag = "batch",
+    request_body = Vec<BatchQuery>,
+    responses(
+        (status = 200, description = "the result of every query, in order", body = Vec<BatchResult>),
+        (status = "4XX", description = "see /error_codes", body = ErrorBody)
+    )
+)]
+async fn batch(
+    State(db): State<Arc<Db>>,
+    Extension(query): Extension<HeavyQuery>,
+    headers: HeaderMap,
+    JsonBody(queries): JsonBody<Vec<BatchQuery>>,
+) -> AppResult<Json<Vec<BatchResult>>> {
+    if queries.is_empty() || queries.len() > MAX_BATCH_SIZE {
+        return Err(AppError::bad_request(format!(
+            "a batch takes 1 to {MAX_BATCH_SIZE} queries"
+        )));
+    }
+    let results = futures_util::stream::iter(queries)
+        .map(|batch_query| run(db.clone(), batch_query, &headers, &query))
+        .buffered(BATCH_PARALLELISM)
+        .map(BatchResult::from)
+        .collect()
+        .await;
+    Ok(Json(results))
+}
+
+fn parse<T: DeserializeOwned>(params: serde_json::Value) -> AppResult<T> {
+    // no params is the same as an empty query string
+    let params = match params {
+        serde_json::Value::Null => serde_json::Value::Object(Default::default()),
+        params => params,
+    };
+    serde_json::from_value(params)
+        .map_err(|err| AppError::bad_request(format!("bad params: {err}")))
+}
+
+async fn run(
+    db: Arc<Db>,
+    batch_query: BatchQuery,
+    headers: &HeaderMap,
+    query: &HeavyQuery,
+) -> AppResult<serde_json::Value> {
+    let params = batch_query.params;
+    let value = match batch_query.query {
+        BatchKind::Events => {
+            let params = parse::<EventsQuery>(params)?;
+            run_query(move || events_value(&db, &params)).await??
+        }
+        BatchKind::Hits => hits_value(db, parse(params)?, headers, query.clone()).await?,
+        BatchKind::Since => serde_json::to_value(since_of(db, parse(params)?).await?)?,
+        BatchKind::NsidInfo => serde_json::to_value(nsid_info_of(db, parse(params)?).await?)?,
+        BatchKind::Eps => serde_json::to_value(eps_of(&db, parse(params)?)?)?,
+    };
+    Ok(value)
+}
+
+// the body /events would stream, as a value
+fn events_value(db: &Db, params: &EventsQuery) -> AppResult<serde_json::Value> {
+    let page = params.page()?;
+    let freshness = Freshness::of(db, DataSource::Counts);
+    let mut body = Vec::new();
+    write_events(db, params, page, freshness, |chunk| {
+        body.extend_from_slice(&chunk);
+        true
+    })?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn hits_value(
+    db: Arc<Db>,
+    mut params: HitsQuery,
+    headers: &HeaderMap,
+    query: HeavyQuery,
+) -> AppResult<serde_json::Value> {
+    params.kind = params.kind.with_deleted(params.deleted)?;
+    if params.nsid.contains(',')
+        || params.format != HitsFormat::Json
+        || params.width().is_some()
+        || params.debug
+    {
+        return Err(AppError::bad_request(
+            "a batch only has json hits of one nsid, without resolution or step",
+        ));
+    }
+    let (range, limit) = hits_params(&db, &params, headers)?;
+    if let Some(sampler) = Sampler::of(&params)? {
+        let sampled = run_query(move || {
+            sampled_hits(
+                &db,
+                &params.nsid,
+                range,
+                limit,
+                params.kind,
+                sampler,
+                &query,
+            )
+        })
+        .await??;
+        return Ok(serde_json::to_value(sampled)?);
+    }
+    let (hits, truncated) = run_query(move || {
+        json_hits(
+            &db,
+            &params.nsid,
+            range,
+            limit,
+            params.kind,
+            admission(&params),
+            &query,
+        )
+    })
+    .await??;
+    Ok(serde_json::to_value(BatchHits { hits, truncated })?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_batch_result_of_error() {
+        let err = AppError::new(crate::error::ErrorCode::NsidNotFound, "never seen");
+        let result = serde_json::to_value(BatchResult::from(Err(err))).unwrap();
+        assert_eq!(result["status"], 404);
+        assert_eq!(result["error"]["code"], "NSID_NOT_FOUND");
+        assert!(result.get("body").is_none());
+    }
+}
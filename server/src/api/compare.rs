@@ -0,0 +1,436 @@
+use std::time::Duration;
+
+use ahash::AHashMap;
+use axum::{
+    Extension, Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use rclite::Arc;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    api::{
+        extract::Query,
+        heavy::HeavyQuery,
+        limited,
+        multi_hits::{parse_nsids, unreadable},
+        pool::run_query,
+    },
+    db::{Db, NsidCounts},
+    error::{AppError, AppResult, ErrorBody, ErrorCode},
+    utils::{CLOCK, get_time},
+};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 7 days
+const MAX_WINDOW: Duration = Duration::from_secs(60 * 60 * 24 * 366); // ~1 year
+const DEFAULT_LIMIT: usize = 50;
+const CACHE_TTL: Duration = Duration::from_secs(60);
+const DAY: u64 = 60 * 60 * 24;
+const WEEK: u64 = DAY * 7;
+// the unix epoch was a thursday, weeks start on mondays
+const WEEK_OFFSET: u64 = DAY * 4;
+
+/// parses durations like `30m`, `12h`, `7d` or `2w`
+pub fn parse_window(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = s.split_at(split);
+    let amount = amount.parse::<u64>().ok().filter(|amount| *amount > 0)?;
+    let unit = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => DAY,
+        "w" => WEEK,
+        _ => return None,
+    };
+    amount.checked_mul(unit).map(Duration::from_secs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowAlign {
+    /// current window is the last `window` up to now, previous is the one
+    /// right before it
+    #[default]
+    Rolling,
+    /// windows are whole periods aligned to multiples of the window from the
+    /// unix epoch (weeks start on monday UTC). current is the last complete
+    /// period, the in progress one is not counted
+    Calendar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Windows {
+    /// [start, end) in epoch seconds
+    #[schema(value_type = Vec<u64>)]
+    pub current: (u64, u64),
+    #[schema(value_type = Vec<u64>)]
+    pub previous: (u64, u64),
+}
+
+impl Windows {
+    pub fn new(now: u64, window: u64, align: WindowAlign) -> Self {
+        let end = match align {
+            WindowAlign::Rolling => now,
+            WindowAlign::Calendar => {
+                let offset = if window % WEEK == 0 { WEEK_OFFSET } else { 0 };
+                now.saturating_sub(offset) / window * window + offset
+            }
+        };
+        let mid = end.saturating_sub(window);
+        Self {
+            current: (mid, end),
+            previous: (mid.saturating_sub(window), mid),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CompareQuery {
+    /// like `30m`, `12h`, `7d` or `2w`, 7 days if left out
+    #[param(value_type = Option<String>)]
+    window: Option<SmolStr>,
+    #[serde(default)]
+    #[param(inline)]
+    align: WindowAlign,
+    limit: Option<usize>,
+    #[param(value_type = Option<String>)]
+    prefix: Option<SmolStr>,
+    /// comma separated nsids to compare `[a_from, a_to)` against
+    /// `[b_from, b_to)` for, instead of windows. takes all four
+    #[param(value_type = Option<String>)]
+    nsid: Option<SmolStr>,
+    /// epoch seconds
+    a_from: Option<u64>,
+    a_to: Option<u64>,
+    b_from: Option<u64>,
+    b_to: Option<u64>,
+}
+
+impl CompareQuery {
+    /// the nsids and ranges to compare, None to compare windows
+    fn ranges(&self) -> AppResult<Option<(&str, (u64, u64), (u64, u64))>> {
+        let bounds = (self.a_from, self.a_to, self.b_from, self.b_to);
+        let (nsid, a, b) = match (self.nsid.as_deref(), bounds) {
+            (None, (None, None, None, None)) => return Ok(None),
+            (Some(nsid), (Some(a_from), Some(a_to), Some(b_from), Some(b_to))) => {
+                (nsid, (a_from, a_to), (b_from, b_to))
+            }
+            _ => {
+                return Err(AppError::bad_request(
+                    "nsid, a_from, a_to, b_from and b_to go together",
+                ));
+            }
+        };
+        if a.0 > a.1 || b.0 > b.1 {
+            return Err(AppError::new(
+                ErrorCode::InvalidRange,
+                "a range cant start after it ends",
+            ));
+        }
+        Ok(Some((nsid, a, b)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct NsidComparison {
+    #[schema(value_type = String)]
+    nsid: SmolStr,
+    current: u64,
+    previous: u64,
+    delta: i64,
+    /// None if there was nothing in the previous window
+    percent_change: Option<f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Comparison {
+    windows: Windows,
+    nsids: Vec<NsidComparison>,
+}
+
+/// how `a` differs from `b`, None when either range is empty
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct Delta {
+    delta: Option<i64>,
+    /// None if there was nothing in `b` either
+    percent_change: Option<f64>,
+}
+
+impl Delta {
+    fn new(a: u128, b: u128, empty: bool) -> Self {
+        if empty {
+            return Delta {
+                delta: None,
+                percent_change: None,
+            };
+        }
+        let delta = a as i64 - b as i64;
+        Delta {
+            delta: Some(delta),
+            percent_change: (b > 0).then(|| delta as f64 / b as f64 * 100.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct RangeTotals {
+    count: u128,
+    deleted_count: u128,
+}
+
+impl From<NsidCounts> for RangeTotals {
+    fn from(counts: NsidCounts) -> Self {
+        RangeTotals {
+            count: counts.count,
+            deleted_count: counts.deleted_count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum RangeComparison {
+    Compared {
+        #[schema(value_type = String)]
+        nsid: SmolStr,
+        a: RangeTotals,
+        b: RangeTotals,
+        /// of creates
+        created: Delta,
+        /// of deletes (not counting purges)
+        deleted: Delta,
+    },
+    Error {
+        #[schema(value_type = String)]
+        nsid: SmolStr,
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RangesComparison {
+    /// [from, to) in epoch seconds
+    #[schema(value_type = Vec<u64>)]
+    a: (u64, u64),
+    #[schema(value_type = Vec<u64>)]
+    b: (u64, u64),
+    /// in the order they were asked for
+    nsids: Vec<RangeComparison>,
+}
+
+/// what /compare answers with, windows of every nsid or ranges of some
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum CompareBody {
+    Windows(Comparison),
+    Ranges(RangesComparison),
+}
+
+type CacheKey = (u64, WindowAlign, Option<SmolStr>);
+
+// comparisons scan every nsid so we keep them around for a bit
+#[derive(Default)]
+pub struct CompareCache {
+    entries: Mutex<AHashMap<CacheKey, (quanta::Instant, Windows, Arc<Vec<NsidComparison>>)>>,
+}
+
+fn compare_all(
+    db: &Db,
+    windows: Windows,
+    prefix: Option<&str>,
+    query: &HeavyQuery,
+) -> AppResult<Vec<NsidComparison>> {
+    let mut nsids = Vec::new();
+    for nsid in db.get_nsids() {
+        if prefix.is_some_and(|prefix| !nsid.starts_with(prefix)) {
+            continue;
+        }
+        query.check()?;
+        let (start, end) = windows.current;
+        let current = db.count_hits(&nsid, start, end)?;
+        let (start, end) = windows.previous;
+        let previous = db.count_hits(&nsid, start, end)?;
+        if current == 0 && previous == 0 {
+            continue;
+        }
+        let delta = current as i64 - previous as i64;
+        nsids.push(NsidComparison {
+            nsid: SmolStr::new(&*nsid),
+            current,
+            previous,
+            delta,
+            percent_change: (previous > 0).then(|| delta as f64 / previous as f64 * 100.0),
+        });
+    }
+    nsids.sort_unstable_by(|a, b| {
+        b.delta
+            .unsigned_abs()
+            .cmp(&a.delta.unsigned_abs())
+            .then_with(|| a.nsid.cmp(&b.nsid))
+    });
+    Ok(nsids)
+}
+
+// every hit in both ranges is decoded, see `Db::window_counts`
+fn compare_ranges(
+    db: &Db,
+    nsids: Vec<SmolStr>,
+    a: (u64, u64),
+    b: (u64, u64),
+    query: &HeavyQuery,
+) -> AppResult<Vec<RangeComparison>> {
+    let empty = a.0 == a.1 || b.0 == b.1;
+    nsids
+        .into_iter()
+        .map(|nsid| {
+            if let Some(error) = unreadable(db, &nsid) {
+                return Ok(RangeComparison::Error { nsid, error });
+            }
+            query.check()?;
+            let a = db.window_counts(&nsid, a.0, a.1)?;
+            query.check()?;
+            let b = db.window_counts(&nsid, b.0, b.1)?;
+            Ok(RangeComparison::Compared {
+                created: Delta::new(a.count, b.count, empty),
+                deleted: Delta::new(a.deleted_count, b.deleted_count, empty),
+                nsid,
+                a: a.into(),
+                b: b.into(),
+            })
+        })
+        .collect()
+}
+
+pub fn routes() -> OpenApiRouter<Arc<Db>> {
+    OpenApiRouter::new().routes(limited(routes!(compare)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/compare",
+    tag = "counts",
+    params(CompareQuery),
+    responses(
+        (status = 200, description = "hits per nsid in this window and the one before, or in the two ranges", body = CompareBody),
+        (status = "4XX", description = "see /error_codes", body = ErrorBody)
+    )
+)]
+pub async fn compare(
+    State(db): State<Arc<Db>>,
+    Extension(cache): Extension<Arc<CompareCache>>,
+    Extension(query): Extension<HeavyQuery>,
+    Query(params): Query<CompareQuery>,
+) -> AppResult<Response> {
+    if let Some((nsid, a, b)) = params.ranges()? {
+        let nsids = parse_nsids(nsid)?;
+        let nsids = run_query(move || compare_ranges(&db, nsids, a, b, &query)).await??;
+        let body = CompareBody::Ranges(RangesComparison { a, b, nsids });
+        return Ok(Json(body).into_response());
+    }
+    let window = match params.window.as_deref() {
+        Some(window) => parse_window(window)
+            .ok_or_else(|| AppError::bad_request(format!("invalid window {window}")))?,
+        None => DEFAULT_WINDOW,
+    }
+    .min(MAX_WINDOW)
+    .as_secs();
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let key = (window, params.align, params.prefix.clone());
+
+    let cached = cache
+        .entries
+        .lock()
+        .get(&key)
+        .filter(|(at, _, _)| at.elapsed() < CACHE_TTL)
+        .map(|(_, windows, nsids)| (*windows, nsids.clone()));
+    let (windows, nsids) = match cached {
+        Some(cached) => cached,
+        None => {
+            let windows = Windows::new(get_time().as_secs(), window, params.align);
+            let prefix = params.prefix;
+            let nsids =
+                run_query(move || compare_all(&db, windows, prefix.as_deref(), &query)).await??;
+            let nsids = Arc::new(nsids);
+            let mut entries = cache.entries.lock();
+            entries.retain(|_, (at, _, _)| at.elapsed() < CACHE_TTL);
+            entries.insert(key, (CLOCK.now(), windows, nsids.clone()));
+            (windows, nsids)
+        }
+    };
+
+    let body = CompareBody::Windows(Comparison {
+        windows,
+        nsids: nsids.iter().take(limit).cloned().collect(),
+    });
+    Ok(Json(body).into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("7d"), Some(Duration::from_secs(WEEK)));
+        assert_eq!(parse_window("2w"), Some(Duration::from_secs(WEEK * 2)));
+        assert_eq!(parse_window("12h"), Some(Duration::from_secs(60 * 60 * 12)));
+        assert_eq!(parse_window("30m"), Some(Duration::from_secs(60 * 30)));
+        assert_eq!(parse_window("0d"), None);
+        assert_eq!(parse_window("d"), None);
+        assert_eq!(parse_window("7"), None);
+        assert_eq!(parse_window("7y"), None);
+    }
+
+    #[test]
+    fn test_rolling_windows() {
+        let now = 1_700_000_000;
+        let windows = Windows::new(now, WEEK, WindowAlign::Rolling);
+        assert_eq!(windows.current, (now - WEEK, now));
+        assert_eq!(windows.previous, (now - WEEK * 2, now - WEEK));
+    }
+
+    #[test]
+    fn test_calendar_weeks_start_on_monday() {
+        // 2023-11-14 22:13:20 UTC, a tuesday
+        let now = 1_700_000_000;
+        let monday = 1_699_833_600; // 2023-11-13 00:00:00 UTC
+        let windows = Windows::new(now, WEEK, WindowAlign::Calendar);
+        assert_eq!(windows.current, (monday - WEEK, monday));
+        assert_eq!(windows.previous, (monday - WEEK * 2, monday - WEEK));
+
+        // exactly on the boundary the week that just ended is current
+        let windows = Windows::new(monday, WEEK, WindowAlign::Calendar);
+        assert_eq!(windows.current, (monday - WEEK, monday));
+    }
+
+    #[test]
+    fn test_calendar_days() {
+        let now = 1_700_000_000;
+        let midnight = 1_699_920_000; // 2023-11-14 00:00:00 UTC
+        let windows = Windows::new(now, DAY, WindowAlign::Calendar);
+        assert_eq!(windows.current, (midnight - DAY, midnight));
+        assert_eq!(windows.previous, (midnight - DAY * 2, midnight - DAY));
+    }
+
+    #[test]
+    fn test_deltas_dont_divide_by_zero() {
+        let delta = Delta::new(15, 10, false);
+        assert_eq!((delta.delta, delta.percent_change), (Some(5), Some(50.0)));
+        let delta = Delta::new(0, 10, false);
+        assert_eq!(
+            (delta.delta, delta.percent_change),
+            (Some(-10), Some(-100.0))
+        );
+        // nothing to compare against
+        let delta = Delta::new(3, 0, false);
+        assert_eq!((delta.delta, delta.percent_change), (Some(3), None));
+        let delta = Delta::new(0, 0, true);
+        assert_eq!((delta.delta, delta.percent_change), (None, None));
+    }
+}
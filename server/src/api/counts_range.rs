@@ -0,0 +1,125 @@
+use axum::{Extension, Json, extract::State};
+use rclite::Arc;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    api::{
+        extract::Query,
+        heavy::HeavyQuery,
+        limited,
+        multi_hits::{parse_nsids, unreadable},
+        pool::run_query,
+    },
+    db::{Db, RangeCounts},
+    error::{AppError, AppResult, ErrorBody, ErrorCode},
+    utils::get_time,
+};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CountsRangeQuery {
+    /// one nsid or several, comma separated
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    /// epoch seconds
+    from: u64,
+    /// epoch seconds, now if left out
+    to: Option<u64>,
+}
+
+/// [from, to) in epoch seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Covered {
+    from: u64,
+    to: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum NsidRangeCounts {
+    Counts {
+        #[schema(value_type = String)]
+        nsid: SmolStr,
+        count: u128,
+        deleted_count: u128,
+        /// the part of the range there are stored hits for, None if there
+        /// are none in it. history before `from` of it is missing, hits after
+        /// `to` of it arent synced yet
+        covered: Option<Covered>,
+    },
+    Error {
+        #[schema(value_type = String)]
+        nsid: SmolStr,
+        error: String,
+    },
+}
+
+impl NsidRangeCounts {
+    fn new(nsid: SmolStr, range: RangeCounts) -> Self {
+        NsidRangeCounts::Counts {
+            nsid,
+            count: range.counts.count,
+            deleted_count: range.counts.deleted_count,
+            covered: range.covered.map(|(from, to)| Covered { from, to }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CountsRange {
+    /// epoch seconds, the range asked for is [from, to)
+    from: u64,
+    to: u64,
+    /// in the order they were asked for
+    nsids: Vec<NsidRangeCounts>,
+}
+
+pub fn routes() -> OpenApiRouter<Arc<Db>> {
+    OpenApiRouter::new().routes(limited(routes!(counts_range)))
+}
+
+// every hit in the range is decoded, see `Db::range_counts`. an nsid that
+// cant be read only fails its own entry
+#[utoipa::path(
+    get,
+    path = "/counts_range",
+    tag = "counts",
+    params(CountsRangeQuery),
+    responses(
+        (status = 200, description = "counts of every nsid within the range", body = CountsRange),
+        (status = "4XX", description = "see /error_codes", body = ErrorBody)
+    )
+)]
+pub async fn counts_range(
+    State(db): State<Arc<Db>>,
+    Extension(query): Extension<HeavyQuery>,
+    Query(params): Query<CountsRangeQuery>,
+) -> AppResult<Json<CountsRange>> {
+    let nsids = parse_nsids(&params.nsid)?;
+    let from = params.from;
+    let to = params.to.unwrap_or_else(|| get_time().as_secs());
+    if from >= to {
+        return Err(AppError::new(
+            ErrorCode::InvalidRange,
+            "from must be before to",
+        ));
+    }
+    let nsids = run_query(move || {
+        nsids
+            .into_iter()
+            .map(|nsid| {
+                if let Some(error) = unreadable(&db, &nsid) {
+                    return Ok(NsidRangeCounts::Error { nsid, error });
+                }
+                query.check()?;
+                let range = db.range_counts(&nsid, from, to)?;
+                Ok(NsidRangeCounts::new(nsid, range))
+            })
+            .collect::<AppResult<Vec<_>>>()
+    })
+    .await??;
+    Ok(Json(CountsRange { from, to, nsids }))
+}
@@ -0,0 +1,244 @@
+// the binary stream_events frames of `format=bin`, for clients getting
+// thousands of updates a second. only frames of plain counts are encoded,
+// the hello frame and ones with labels or sparklines stay json text frames.
+// varints are the ordered-varint encoding the blocks use:
+//
+//   magic       4 bytes, `LTEF`
+//   version     1 byte, currently 1
+//   flags       1 byte: 1 snapshot, 2 the rows are deltas (mode=delta),
+//               4 totals follow
+//   per_second  varint
+//   totals      varint count, deleted_count and purged_count, if flagged
+//   rows        varint count, then for every nsid a varint length and that
+//               many utf-8 bytes, and the count, deleted_count, purged_count
+//               (or their deltas) and last_seen as varints
+
+use std::io::{self, Write};
+
+use crate::{api::Events, utils::WriteVariableExt};
+
+const MAGIC: &[u8; 4] = b"LTEF";
+const VERSION: u8 = 1;
+
+const SNAPSHOT: u8 = 1;
+const DELTAS: u8 = 2;
+const TOTALS: u8 = 4;
+
+/// None if the frame has something the layout doesnt carry
+pub fn encode(events: &Events) -> Option<io::Result<Vec<u8>>> {
+    let plain = events.server.is_none()
+        && events.labels.is_empty()
+        && (events.events.is_empty() || events.deltas.is_empty())
+        && events
+            .events
+            .values()
+            .all(|count| count.sparkline.is_none() && count.labels.is_none());
+    plain.then(|| encode_plain(events))
+}
+
+fn encode_plain(events: &Events) -> io::Result<Vec<u8>> {
+    let rows = events.events.len().max(events.deltas.len());
+    let mut out = Vec::with_capacity(16 + rows * 40);
+    out.write_all(MAGIC)?;
+    let mut flags = 0;
+    if events.snapshot {
+        flags |= SNAPSHOT;
+    }
+    if !events.deltas.is_empty() {
+        flags |= DELTAS;
+    }
+    if events.totals.is_some() {
+        flags |= TOTALS;
+    }
+    out.write_all(&[VERSION, flags])?;
+    out.write_varint(events.per_second)?;
+    if let Some(totals) = &events.totals {
+        out.write_varint(totals.count)?;
+        out.write_varint(totals.deleted_count)?;
+        out.write_varint(totals.purged_count)?;
+    }
+    out.write_varint(rows)?;
+    let mut row = |nsid: &str, counts: [u128; 3], last_seen: u64| -> io::Result<()> {
+        out.write_varint(nsid.len())?;
+        out.write_all(nsid.as_bytes())?;
+        for count in counts {
+            out.write_varint(count)?;
+        }
+        out.write_varint(last_seen)?;
+        Ok(())
+    };
+    for (nsid, count) in &events.events {
+        let counts = [count.count, count.deleted_count, count.purged_count];
+        row(nsid, counts, count.last_seen)?;
+    }
+    for (nsid, delta) in &events.deltas {
+        let counts = [delta.count_delta, delta.deleted_delta, delta.purged_delta];
+        row(nsid, counts, delta.last_seen)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub struct BinFrame {
+    pub snapshot: bool,
+    pub deltas: bool,
+    pub per_second: usize,
+    pub totals: Option<[u128; 3]>,
+    pub rows: Vec<(smol_str::SmolStr, [u128; 3], u64)>,
+}
+
+// the rows sorted by nsid
+#[cfg(test)]
+pub fn decode(mut bytes: &[u8]) -> crate::error::AppResult<BinFrame> {
+    use std::io::Read;
+
+    use crate::utils::ReadVariableExt;
+
+    let mut header = [0; 6];
+    bytes.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || header[4] != VERSION {
+        return Err(anyhow::anyhow!("not a binary events frame").into());
+    }
+    let flags = header[5];
+    let per_second = bytes.read_varint()?;
+    let counts = |bytes: &mut &[u8]| -> io::Result<[u128; 3]> {
+        Ok([
+            bytes.read_varint()?,
+            bytes.read_varint()?,
+            bytes.read_varint()?,
+        ])
+    };
+    let totals = match flags & TOTALS {
+        0 => None,
+        _ => Some(counts(&mut bytes)?),
+    };
+    let mut rows = Vec::new();
+    for _ in 0..bytes.read_varint::<usize>()? {
+        let mut nsid = vec![0; bytes.read_varint::<usize>()?];
+        bytes.read_exact(&mut nsid)?;
+        let nsid = String::from_utf8(nsid).map_err(anyhow::Error::from)?;
+        rows.push((nsid.into(), counts(&mut bytes)?, bytes.read_varint()?));
+    }
+    if !bytes.is_empty() {
+        return Err(anyhow::anyhow!("trailing bytes after the last row").into());
+    }
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(BinFrame {
+        snapshot: flags & SNAPSHOT != 0,
+        deltas: flags & DELTAS != 0,
+        per_second,
+        totals,
+        rows,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use ahash::AHashMap;
+    use smol_str::SmolStr;
+
+    use super::*;
+    use crate::{
+        api::{NsidCount, NsidDelta},
+        db::{NsidCounts, Totals},
+    };
+
+    fn events() -> Events {
+        Events {
+            per_second: 1234,
+            totals: None,
+            events: AHashMap::new(),
+            server: None,
+            snapshot: false,
+            labels: AHashMap::new(),
+            deltas: AHashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let counts = |count, deleted_count| NsidCounts {
+            count,
+            deleted_count,
+            last_seen: 1_700_000_000,
+            purged_count: u64::MAX as u128 + 1,
+        };
+        let frame = Events {
+            totals: Some(Totals {
+                count: 10,
+                deleted_count: 2,
+                purged_count: 0,
+            }),
+            snapshot: true,
+            events: AHashMap::from_iter([
+                ("app.bsky.feed.like".into(), NsidCount::from(&counts(7, 1))),
+                ("app.bsky.feed.post".into(), NsidCount::from(&counts(3, 1))),
+            ]),
+            ..events()
+        };
+        let decoded = decode(&encode(&frame).unwrap().unwrap()).unwrap();
+        let purged = u64::MAX as u128 + 1;
+        assert_eq!(
+            decoded,
+            BinFrame {
+                snapshot: true,
+                deltas: false,
+                per_second: 1234,
+                totals: Some([10, 2, 0]),
+                rows: vec![
+                    (
+                        SmolStr::new("app.bsky.feed.like"),
+                        [7, 1, purged],
+                        1_700_000_000
+                    ),
+                    (
+                        SmolStr::new("app.bsky.feed.post"),
+                        [3, 1, purged],
+                        1_700_000_000
+                    ),
+                ],
+            }
+        );
+
+        let delta = NsidDelta {
+            count_delta: 5,
+            deleted_delta: 0,
+            purged_delta: 1,
+            last_seen: 1_700_000_010,
+        };
+        let frame = Events {
+            deltas: AHashMap::from_iter([("app.bsky.feed.like".into(), delta)]),
+            ..events()
+        };
+        let bytes = encode(&frame).unwrap().unwrap();
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.deltas && !decoded.snapshot);
+        assert_eq!(decoded.totals, None);
+        assert_eq!(
+            decoded.rows,
+            [(SmolStr::new("app.bsky.feed.like"), [5, 0, 1], 1_700_000_010)]
+        );
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+
+        // an empty frame still says how fast events come in
+        let decoded = decode(&encode(&events()).unwrap().unwrap()).unwrap();
+        assert_eq!((decoded.per_second, decoded.rows.len()), (1234, 0));
+    }
+
+    #[test]
+    fn test_labels_stay_json() {
+        let mut labeled = NsidCount::from(&NsidCounts::default());
+        labeled.labels = Some(Default::default());
+        let frame = Events {
+            events: AHashMap::from_iter([("app.bsky.feed.like".into(), labeled)]),
+            ..events()
+        };
+        assert!(encode(&frame).is_none());
+        let frame = Events {
+            labels: AHashMap::from_iter([("app.bsky.feed.like".into(), Default::default())]),
+            ..events()
+        };
+        assert!(encode(&frame).is_none());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{sync::OnceLock, time::Duration};
+
+use axum::{
+    extract::Request,
+    http::header::RETRY_AFTER,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config,
+    error::{AppError, AppResult, ErrorCode},
+};
+
+const DEFAULT_MAX_HEAVY_QUERIES: usize = 8;
+const DEFAULT_HEAVY_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// routes that decode blocks (raw hits and histograms) run at most
+// `MAX_HEAVY_QUERIES` at once over every instance, the ones past that are
+// turned away with a 503 instead of queueing up behind them. each one gets
+// `HEAVY_QUERY_TIMEOUT_SECS`, then it is a 503 too
+struct HeavyQueries {
+    permits: Semaphore,
+    timeout: Duration,
+}
+
+fn max_heavy_queries() -> usize {
+    config::env_or("MAX_HEAVY_QUERIES", DEFAULT_MAX_HEAVY_QUERIES, |max| {
+        max.parse::<usize>().ok().filter(|max| *max > 0)
+    })
+}
+
+fn heavy_query_timeout() -> Duration {
+    let secs = config::env_or(
+        "HEAVY_QUERY_TIMEOUT_SECS",
+        DEFAULT_HEAVY_QUERY_TIMEOUT.as_secs(),
+        |secs| secs.parse::<u64>().ok().filter(|secs| *secs > 0),
+    );
+    Duration::from_secs(secs)
+}
+
+fn heavy_queries() -> &'static HeavyQueries {
+    static HEAVY: OnceLock<HeavyQueries> = OnceLock::new();
+    HEAVY.get_or_init(|| HeavyQueries {
+        permits: Semaphore::new(max_heavy_queries()),
+        timeout: heavy_query_timeout(),
+    })
+}
+
+// reads the settings, which otherwise happens on the first heavy query
+pub fn init() {
+    heavy_queries();
+}
+
+/// given to the handlers of heavy routes. it is cancelled once the request
+/// timed out or the client went away, so blocking work that outlives the
+/// request (which cant be aborted) stops on its own
+#[derive(Debug, Clone)]
+pub struct HeavyQuery(CancellationToken);
+
+impl HeavyQuery {
+    #[inline(always)]
+    pub fn check(&self) -> AppResult<()> {
+        if self.0.is_cancelled() {
+            return Err(AppError::cancelled());
+        }
+        Ok(())
+    }
+}
+
+pub async fn limit_heavy(mut request: Request, next: Next) -> Response {
+    let heavy = heavy_queries();
+    let Ok(_permit) = heavy.permits.try_acquire() else {
+        let err = AppError::new(
+            ErrorCode::Overloaded,
+            "too many expensive queries running, try again",
+        )
+        .with_detail(serde_json::json!({ "retry_after_secs": 1 }));
+        return ([(RETRY_AFTER, "1")], err).into_response();
+    };
+    let cancel = CancellationToken::new();
+    // streamed bodies (ndjson, csv) are written after this returns, they
+    // dont look at the token
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    request.extensions_mut().insert(HeavyQuery(cancel));
+    match tokio::time::timeout(heavy.timeout, next.run(request)).await {
+        Ok(res) => res,
+        Err(_) => {
+            tracing::warn!(timeout = ?heavy.timeout, "query timed out");
+            AppError::new(ErrorCode::QueryTimeout, "query timed out")
+                .with_detail(serde_json::json!({ "timeout_ms": heavy.timeout.as_millis() as u64 }))
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use serde::{Serialize, Serializer};
+
+use crate::{config, error::AppResult};
+
+const DEFAULT_PORT: u16 = 3713;
+// owner and group, the reverse proxy usually shares a group with us
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// where `serve` listens. `BIND_ADDR` takes `host:port` or
+/// `unix:/path/to.sock`, without it we listen on every interface at `PORT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix { path: PathBuf, mode: u32 },
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("a unix socket needs a path, like unix:/run/tracker.sock".into());
+            }
+            return Ok(Self::Unix {
+                path: PathBuf::from(path),
+                mode: DEFAULT_SOCKET_MODE,
+            });
+        }
+        s.parse::<SocketAddr>()
+            .map(Self::Tcp)
+            .map_err(|err| format!("{s} isnt host:port or unix:<path> ({err})"))
+    }
+}
+
+impl Display for BindAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for BindAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| "expected permissions in octal, like 660".to_owned())
+}
+
+impl BindAddr {
+    /// reads `BIND_ADDR`, `PORT` and `UNIX_SOCKET_MODE`. unlike most settings
+    /// these dont fall back to the default when they dont parse, serving
+    /// somewhere else than asked would go unnoticed
+    pub fn from_env() -> AppResult<Self> {
+        let addr = config::try_env("BIND_ADDR", None, |addr| addr.parse().map(Some))?;
+        let path = match addr {
+            Some(Self::Unix { path, .. }) => path,
+            Some(addr) => return Ok(addr),
+            None => {
+                let port = config::try_env("PORT", DEFAULT_PORT, |port| {
+                    port.parse::<u16>().map_err(|err| err.to_string())
+                })?;
+                return Ok(Self::Tcp(SocketAddr::from(([0, 0, 0, 0], port))));
+            }
+        };
+        let mode = config::try_env(
+            "UNIX_SOCKET_MODE",
+            format!("{DEFAULT_SOCKET_MODE:o}"),
+            |mode| parse_mode(mode).map(|_| mode.to_owned()),
+        )?;
+        let mode = parse_mode(&mode).map_err(|err| anyhow!(err))?;
+        Ok(Self::Unix { path, mode })
+    }
+}
+
+/// a unix socket file we created, removed again once we stop serving on it
+pub struct SocketFile(PathBuf);
+
+impl SocketFile {
+    /// binds `path`, replacing a socket left behind by a run that didnt get
+    /// to clean up, and gives it `mode`
+    pub fn bind(path: &Path, mode: u32) -> AppResult<(Self, tokio::net::UnixListener)> {
+        let stale = std::fs::symlink_metadata(path).is_ok_and(|meta| {
+            use std::os::unix::fs::FileTypeExt;
+            meta.file_type().is_socket()
+        });
+        if stale {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .map_err(|err| anyhow!("cant bind {}: {err}", path.display()))?;
+        let file = Self(path.to_owned());
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        Ok((file, listener))
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            tracing::warn!("cant remove socket {}: {err}", self.0.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind_addr() {
+        assert_eq!(
+            "127.0.0.1:8080".parse::<BindAddr>(),
+            Ok(BindAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080))))
+        );
+        assert!(matches!(
+            "[::1]:80".parse::<BindAddr>(),
+            Ok(BindAddr::Tcp(_))
+        ));
+        assert_eq!(
+            "unix:/run/tracker.sock".parse::<BindAddr>(),
+            Ok(BindAddr::Unix {
+                path: PathBuf::from("/run/tracker.sock"),
+                mode: DEFAULT_SOCKET_MODE,
+            })
+        );
+        assert!("unix:".parse::<BindAddr>().is_err());
+        assert!("localhost".parse::<BindAddr>().is_err());
+        assert!("0.0.0.0:99999".parse::<BindAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_file_is_removed() {
+        let path =
+            std::env::temp_dir().join(format!("lexicon-tracker-test-{}.sock", std::process::id()));
+        let (file, listener) = SocketFile::bind(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+        // a socket left behind is replaced
+        std::mem::forget(file);
+        let (file, _listener) = SocketFile::bind(&path, 0o600).unwrap();
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,3261 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::Write,
+    net::SocketAddr,
+    ops::{Bound, Deref, RangeBounds},
+    time::Duration,
+};
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::anyhow;
+use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
+    extract::State,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Request, StatusCode,
+        header::{
+            ACCEPT, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+            LAST_MODIFIED,
+        },
+    },
+    middleware,
+    response::{
+        IntoResponse, Response,
+        sse::{self, KeepAlive, Sse},
+    },
+    routing::get,
+};
+use axum_tws::{Message, WebSocket, WebSocketUpgrade};
+use futures_util::Stream;
+use itertools::Itertools;
+use rclite::Arc;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tower_http::{
+    classify::ServerErrorsFailureClass,
+    compression::{
+        CompressionLayer,
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+    },
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{Instrument, Span, field};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_axum::{
+    router::{OpenApiRouter, UtoipaMethodRouter},
+    routes,
+};
+use utoipa_scalar::{Scalar, Servable};
+
+use crate::{
+    build_info::BuildInfo,
+    config,
+    db::{
+        Admission, Alert, BlockCacheStats, BlockTrace, BroadcastStatus, DataSource, Db, Downsample,
+        EventListener, HistogramBucket, HistogramSeries, HitOp, HitsPage, IngestState, Item,
+        LabelMap, NegativeCacheStats, NsidCounts, NsidInfo, OverviewPoint, PinnedSnapshot,
+        QueryTrace, QuiesceState, RATE_WINDOW_SECS, RollupGranularity, RollupPoint,
+        SPARKLINE_HOURS, SnapshotCheck, SnapshotMarker, StorageState, SyncPaceStatus, Totals,
+        block_cache, is_valid_nsid, labels_match,
+    },
+    error::{AppError, AppResult, ErrorBody, ErrorCode, panic_count, with_request_id},
+    hits_bin,
+    jetstream::{malformed_frame_count, unknown_kind_count},
+    utils::{CLOCK, RateTracker, get_time, http_date, rfc3339},
+};
+
+struct LatencyMillis(u128);
+
+impl From<Duration> for LatencyMillis {
+    fn from(duration: Duration) -> Self {
+        LatencyMillis(duration.as_millis())
+    }
+}
+
+impl Display for LatencyMillis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}ms", self.0)
+    }
+}
+
+mod admin;
+mod batch;
+mod compare;
+mod counts_range;
+mod events_bin;
+mod extract;
+mod heavy;
+mod listen;
+mod multi_hits;
+mod pool;
+mod ratelimit;
+mod sample;
+mod search;
+mod stats;
+mod top;
+mod ws;
+
+use extract::Query;
+use heavy::HeavyQuery;
+pub use listen::BindAddr;
+use pool::run_query;
+pub(crate) use ratelimit::{RateLimiter, rate_limited};
+use sample::{Sampler, sampled_hits};
+
+// the api description, filled in with every route registered through
+// `OpenApiRouter`. admin routes are left out
+#[derive(OpenApi)]
+#[openapi(info(title = "lexicon tracker", description = "hit counts of atproto nsids"))]
+struct ApiDoc;
+
+// queries that read a lot of blocks, they wait their turn in `heavy`
+fn limited<S>((schemas, paths, route): UtoipaMethodRouter<S>) -> UtoipaMethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    (
+        schemas,
+        paths,
+        route.layer(middleware::from_fn(heavy::limit_heavy)),
+    )
+}
+
+// routes of a single instance and their description
+fn documented_routes() -> (Router<Arc<Db>>, utoipa::openapi::OpenApi) {
+    OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(events))
+        .routes(routes!(counts_dump))
+        .routes(routes!(stream_events))
+        .routes(routes!(stream_events_sse))
+        .routes(limited(routes!(hits)))
+        .routes(limited(routes!(histogram)))
+        .routes(limited(routes!(multi_series)))
+        .routes(routes!(overview))
+        .routes(limited(routes!(rollup)))
+        .routes(routes!(since))
+        .routes(routes!(alerts))
+        .routes(routes!(eps))
+        .routes(routes!(status))
+        .routes(routes!(healthz))
+        .routes(routes!(health))
+        .routes(routes!(debug_runtime))
+        .routes(routes!(debug_snapshot))
+        .routes(routes!(version))
+        .routes(routes!(error_codes))
+        .merge(batch::routes())
+        .merge(compare::routes())
+        .merge(counts_range::routes())
+        .merge(search::routes())
+        .merge(stats::routes())
+        .merge(top::routes())
+        .routes(routes!(active_nsids))
+        .routes(routes!(nsids))
+        .routes(routes!(nsid_info))
+        .routes(routes!(did_events))
+        .routes(limited(routes!(did_hits)))
+        .split_for_parts()
+}
+
+// routes of a single instance
+pub(crate) fn routes() -> Router<Arc<Db>> {
+    let (router, spec) = documented_routes();
+    let router = router
+        .route(
+            "/openapi.json",
+            get({
+                let spec = spec.clone();
+                move || async move { Json(spec) }
+            }),
+        )
+        .merge(Scalar::with_url("/docs", spec))
+        .layer(Extension(Arc::new(compare::CompareCache::default())))
+        .layer(Extension(Arc::new(top::TopCache::default())));
+    match admin::router() {
+        Some(admin) => router.nest("/admin", admin),
+        None => router,
+    }
+}
+
+// the routes of an instance, with its state
+pub(crate) fn instance(db: Arc<Db>) -> Router {
+    routes()
+        .layer(middleware::from_fn_with_state(
+            db.clone(),
+            with_timestamp_granularity,
+        ))
+        .with_state(db)
+}
+
+// what hit timestamps are rounded to, on every response so clients know how
+// precise the ones they got are. see `DbConfig::timestamp_granularity`
+const TIMESTAMP_GRANULARITY_HEADER: &str = "x-timestamp-granularity";
+
+async fn with_timestamp_granularity(
+    db: State<Arc<Db>>,
+    request: Request<Body>,
+    next: middleware::Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(
+        TIMESTAMP_GRANULARITY_HEADER,
+        HeaderValue::from(db.timestamp_granularity()),
+    );
+    response
+}
+
+/// serves the default (unnamed) instance on the flat routes and named
+/// instances under `/instances/{name}`
+pub async fn serve(
+    instances: Vec<(Option<SmolStr>, Arc<Db>)>,
+    bind: &BindAddr,
+    cancel_token: CancellationToken,
+) -> AppResult<()> {
+    if admin::router().is_some() {
+        tracing::info!("admin routes enabled");
+    }
+    // settings read on first use, read now so /admin/config has them from
+    // the start
+    heavy::init();
+    pool::init();
+    ws::init();
+    max_hits_limit();
+    max_range_span();
+    health_max_event_age();
+    block_cache();
+    let mut app = Router::new();
+    for (name, db) in instances {
+        let instance = instance(db);
+        app = match name {
+            Some(name) => app.nest(&format!("/instances/{name}"), instance),
+            None => app.merge(instance),
+        };
+    }
+    let app = rate_limited(app, RateLimiter::from_env())
+        .route_layer(middleware::from_fn(with_request_id))
+        .route_layer(
+            CompressionLayer::new()
+                .br(true)
+                .deflate(true)
+                .gzip(true)
+                .zstd(true)
+                // the default already skips event streams, spelled out since
+                // a compressed stream_events.sse would hold frames back
+                .compress_when(DefaultPredicate::new().and(NotForContentType::SSE)),
+        )
+        .route_layer(PropagateRequestIdLayer::x_request_id())
+        .route_layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<_>| {
+                    let span = tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        id = field::Empty,
+                        ip = field::Empty,
+                    );
+                    if let Some(id) = request.headers().get("x-request-id") {
+                        span.record("id", String::from_utf8_lossy(id.as_bytes()).deref());
+                    }
+                    if let Some(real_ip) = request.headers().get("x-real-ip") {
+                        span.record("ip", String::from_utf8_lossy(real_ip.as_bytes()).deref());
+                    }
+                    #[cfg(feature = "otel")]
+                    crate::telemetry::follow_traceparent(&span, request.headers());
+                    span
+                })
+                .on_request(|_request: &Request<_>, span: &Span| {
+                    let _ = span.enter();
+                    tracing::info!("processing")
+                })
+                .on_response(|response: &Response<_>, latency: Duration, span: &Span| {
+                    let _ = span.enter();
+                    tracing::info!({code = %response.status().as_u16(), latency = %LatencyMillis::from(latency)}, "processed")
+                })
+                .on_eos(())
+                .on_failure(|error: ServerErrorsFailureClass, _: Duration, span: &Span| {
+                    let _ = span.enter();
+                    if matches!(error, ServerErrorsFailureClass::StatusCode(status_code) if status_code.is_server_error()) || matches!(error, ServerErrorsFailureClass::Error(_)) {
+                        tracing::error!("server error: {}", error.to_string().to_lowercase());
+                    };
+                }),
+        )
+        .route_layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    match bind {
+        BindAddr::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(*addr)
+                .await
+                .map_err(|err| anyhow!("cant bind {addr}: {err}"))?;
+            tracing::info!("starting serve on {addr}");
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            serve_until(axum::serve(listener, app), cancel_token).await
+        }
+        BindAddr::Unix { path, mode } => {
+            // removes the socket file when we stop serving, also when this
+            // future is dropped on shutdown
+            let (_file, listener) = listen::SocketFile::bind(path, *mode)?;
+            tracing::info!("starting serve on {bind}");
+            // no peer address here, the rate limit goes by the x-real-ip the
+            // proxy in front sets
+            serve_until(axum::serve(listener, app.into_make_service()), cancel_token).await
+        }
+    }
+}
+
+async fn serve_until(
+    served: impl IntoFuture<Output = std::io::Result<()>>,
+    cancel_token: CancellationToken,
+) -> AppResult<()> {
+    let served = served.into_future();
+    tokio::select! {
+        res = served => res.map_err(AppError::from),
+        _ = cancel_token.cancelled() => Err(anyhow!("cancelled").into()),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct NsidCount {
+    count: u128,
+    /// deletions that werent part of an account purge
+    deleted_count: u128,
+    purged_count: u128,
+    /// epoch seconds
+    last_seen: u64,
+    /// only with detail=true, hits are only as current as the last flush
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_flushed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending_items: Option<usize>,
+    /// only with sparklines=true, hits per hour over the last day, oldest first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<u32>>)]
+    sparkline: Option<[u32; SPARKLINE_HOURS]>,
+    /// only with labels=true, and only for labeled nsids
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<HashMap<String, String>>)]
+    labels: Option<LabelMap>,
+    /// only with rates=true, see /eps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    per_second: Option<f64>,
+}
+
+impl From<&NsidCounts> for NsidCount {
+    fn from(counts: &NsidCounts) -> Self {
+        Self {
+            count: counts.count,
+            deleted_count: counts.deleted_count,
+            purged_count: counts.purged_count,
+            last_seen: counts.last_seen,
+            last_flushed: None,
+            pending_items: None,
+            sparkline: None,
+            labels: None,
+            per_second: None,
+        }
+    }
+}
+
+/// the counts of every nsid, the body of /events and the frames of
+/// /stream_events
+#[derive(Serialize, ToSchema)]
+struct Events {
+    per_second: usize,
+    /// only sent in the first stream_events frame, with server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals: Option<Totals>,
+    /// /events with sort, order, limit or offset sends an array of
+    /// `{nsid, ...}` sorted by them instead, and the `total` of matching nsids
+    #[schema(value_type = HashMap<String, NsidCount>)]
+    events: AHashMap<SmolStr, NsidCount>,
+    /// only sent in the first stream_events frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    server: Option<&'static BuildInfo>,
+    /// stream_events frames carrying every count the client is subscribed
+    /// to, sent on connect and when the filter changes
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    snapshot: bool,
+    /// stream_events frames with labels=true, the new labels of nsids whose
+    /// labels changed. an empty map when they were removed
+    #[serde(skip_serializing_if = "AHashMap::is_empty")]
+    #[schema(value_type = HashMap<String, HashMap<String, String>>)]
+    labels: AHashMap<SmolStr, LabelMap>,
+    /// stream_events frames with mode=delta after the snapshot, these have
+    /// no `events`
+    #[serde(skip_serializing_if = "AHashMap::is_empty")]
+    #[schema(value_type = HashMap<String, NsidDelta>)]
+    deltas: AHashMap<SmolStr, NsidDelta>,
+}
+
+/// how much the counts of an nsid went up since the last frame a client got
+#[derive(Debug, Serialize, ToSchema)]
+struct NsidDelta {
+    count_delta: u128,
+    deleted_delta: u128,
+    purged_delta: u128,
+    last_seen: u64,
+}
+
+impl NsidDelta {
+    // None if nothing changed, or if `after` is older than what the client
+    // has (updates from before its snapshot was taken)
+    fn between(before: (u128, u128, u128), after: &NsidCount) -> Option<Self> {
+        let (count, deleted, purged) = before;
+        let delta = Self {
+            count_delta: after.count.checked_sub(count)?,
+            deleted_delta: after.deleted_count.checked_sub(deleted)?,
+            purged_delta: after.purged_count.checked_sub(purged)?,
+            last_seen: after.last_seen,
+        };
+        (delta.count_delta > 0 || delta.deleted_delta > 0 || delta.purged_delta > 0)
+            .then_some(delta)
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EventsQuery {
+    /// when each nsid was last flushed and how many of its hits wait for it
+    #[serde(default)]
+    detail: bool,
+    /// hits per hour over the last day for every nsid
+    #[serde(default)]
+    sparklines: bool,
+    /// only nsids starting with one of these (comma separated) or equal to
+    /// `nsid`, all of them if none are given. totals are still over every nsid
+    prefix: Option<String>,
+    /// same as `prefix`
+    prefixes: Option<String>,
+    #[param(value_type = Option<String>)]
+    nsid: Option<SmolStr>,
+    /// include the labels of labeled nsids
+    #[serde(default)]
+    labels: bool,
+    /// only nsids with this label, `key` or `key=value`
+    label: Option<String>,
+    /// only nsids with at least this many created records
+    min_count: Option<u64>,
+    /// only nsids with at least this many deletions (purges excluded)
+    min_deleted: Option<u64>,
+    /// only nsids seen at or after this time
+    seen_since: Option<u64>,
+    /// the live rate of every nsid
+    #[serde(default)]
+    rates: bool,
+    /// sorts the nsids and sends them as an array of `{nsid, ...}` with the
+    /// `total` of matching nsids instead of a map. by count if only `order`,
+    /// `limit` or `offset` are given. ties are sorted by nsid
+    #[param(inline)]
+    sort: Option<EventsSort>,
+    /// desc by default, asc when sorting by nsid
+    #[param(inline)]
+    order: Option<SortOrder>,
+    /// at most this many nsids, all of them by default
+    limit: Option<usize>,
+    /// skips this many nsids after sorting
+    offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum EventsSort {
+    Count,
+    // deletions that werent part of a purge
+    Deleted,
+    LastSeen,
+    Nsid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+// what of the sorted nsids /events sends
+#[derive(Debug, Clone, Copy)]
+struct EventsPage {
+    sort: EventsSort,
+    order: SortOrder,
+    offset: usize,
+    limit: usize,
+}
+
+impl EventsPage {
+    fn cmp(&self, a: &(SmolStr, NsidCounts), b: &(SmolStr, NsidCounts)) -> std::cmp::Ordering {
+        let by_key = match self.sort {
+            EventsSort::Count => a.1.count.cmp(&b.1.count),
+            EventsSort::Deleted => a.1.deleted_count.cmp(&b.1.deleted_count),
+            EventsSort::LastSeen => a.1.last_seen.cmp(&b.1.last_seen),
+            EventsSort::Nsid => a.0.cmp(&b.0),
+        };
+        let by_key = match self.order {
+            SortOrder::Asc => by_key,
+            SortOrder::Desc => by_key.reverse(),
+        };
+        // ties always go by nsid, so pages dont overlap
+        by_key.then_with(|| a.0.cmp(&b.0))
+    }
+}
+
+impl EventsQuery {
+    // whether the counts of an nsid pass min_count, min_deleted and seen_since
+    fn counts_match(&self, counts: &NsidCounts) -> bool {
+        self.min_count
+            .is_none_or(|min| counts.count >= u128::from(min))
+            && self
+                .min_deleted
+                .is_none_or(|min| counts.deleted_count >= u128::from(min))
+            && self
+                .seen_since
+                .is_none_or(|since| counts.last_seen >= since)
+    }
+
+    // the page to send, None for the map of every nsid
+    fn page(&self) -> AppResult<Option<EventsPage>> {
+        if self.sort.is_none()
+            && self.order.is_none()
+            && self.limit.is_none()
+            && self.offset.is_none()
+        {
+            return Ok(None);
+        }
+        if self.limit == Some(0) {
+            return Err(AppError::new(
+                ErrorCode::InvalidLimit,
+                "limit must be at least 1",
+            ));
+        }
+        let sort = self.sort.unwrap_or(EventsSort::Count);
+        let order = self.order.unwrap_or(match sort {
+            EventsSort::Nsid => SortOrder::Asc,
+            _ => SortOrder::Desc,
+        });
+        Ok(Some(EventsPage {
+            sort,
+            order,
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit.unwrap_or(usize::MAX),
+        }))
+    }
+
+    // the prefix scans of `_counts` to do as (prefix, exact). they are sorted
+    // and the ones covered by another prefix are dropped, so rows come out in
+    // order and none is written twice
+    fn scans(&self) -> Vec<(&str, bool)> {
+        let mut scans = self
+            .prefix
+            .iter()
+            .chain(&self.prefixes)
+            .flat_map(|prefixes| prefixes.split(','))
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| (prefix, false))
+            .chain(self.nsid.as_deref().map(|nsid| (nsid, true)))
+            .collect::<Vec<_>>();
+        if scans.is_empty() {
+            return vec![("", false)];
+        }
+        scans.sort_unstable();
+        let mut kept: Vec<(&str, bool)> = Vec::with_capacity(scans.len());
+        for scan in scans {
+            let covered = kept
+                .last()
+                .is_some_and(|(prefix, exact)| !exact && scan.0.starts_with(prefix));
+            if !covered {
+                kept.push(scan);
+            }
+        }
+        kept
+    }
+}
+
+// the streamed /events body is sent in chunks of about this size
+const EVENTS_CHUNK_SIZE: usize = 16 * 1024;
+
+// what the body of /events depends on besides the query, pollers send it
+// back in If-None-Match and get a 304 while the firehose is quiet. weak since
+// compression changes the bytes. it is taken before the body is built, a
+// change that lands in between only makes the next poll a 200 again. the
+// top level `per_second` isnt part of it, it moves on every poll while the
+// counts it is about stay the same
+fn events_etag(db: &Db, params: &EventsQuery) -> String {
+    let mut etag = format!(
+        "W/\"{:x}.{:x}.{:x}",
+        db.opened_at(),
+        db.events_generation(),
+        db.last_event_at().unwrap_or_default()
+    );
+    // both are built from synced blocks
+    if params.detail || params.sparklines {
+        etag.push_str(&format!(".s{:x}", db.sync_generation()));
+    }
+    if params.sparklines {
+        etag.push_str(&format!(".h{:x}", get_time().as_secs() / 3600));
+    }
+    // rates keep falling for a window after the last event, never the same
+    // body until then
+    let settled_at = db.events_changed_at() + RATE_WINDOW_SECS + 1;
+    if params.rates && get_time().as_secs() <= settled_at {
+        etag.push_str(&format!(".r{:x}", CLOCK.raw()));
+    }
+    etag.push('"');
+    etag
+}
+
+// where a read response came from
+const DATA_SOURCE_HEADER: &str = "x-data-source";
+
+// how long a read response stays current, from the source that answered it.
+// every read endpoint takes its cache-control and `suggested_poll_secs` from
+// here, so they agree
+#[derive(Debug, Clone, Copy)]
+struct Freshness {
+    source: DataSource,
+    poll_secs: u64,
+}
+
+impl Freshness {
+    fn of(db: &Db, source: DataSource) -> Self {
+        Self {
+            source,
+            poll_secs: db.max_age(source),
+        }
+    }
+
+    fn headers(self) -> [(HeaderName, String); 2] {
+        [
+            (CACHE_CONTROL, format!("max-age={}", self.poll_secs)),
+            (
+                HeaderName::from_static(DATA_SOURCE_HEADER),
+                self.source.name().to_owned(),
+            ),
+        ]
+    }
+}
+
+// weak comparison, so `W/` is ignored on both sides
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque(etag);
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+// streams the body so clients get the first bytes before all counts are read
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "counts",
+    params(EventsQuery),
+    responses((status = 200, description = "the counts of every nsid", body = Events), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn events(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let page = match params.page() {
+        Ok(page) => page,
+        Err(err) => return err.into_response(),
+    };
+    let etag = events_etag(&db, &params);
+    let freshness = Freshness::of(&db, DataSource::Counts);
+    let cache_headers = [
+        (ETAG, etag.clone()),
+        (LAST_MODIFIED, http_date(db.events_changed_at())),
+    ];
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers, freshness.headers()).into_response();
+    }
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(4);
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.entered();
+        let res = write_events(&db, &params, page, freshness, |chunk| {
+            tx.blocking_send(Ok(chunk)).is_ok()
+        });
+        // the status went out already, failing the body is all that is left
+        if let Err(err) = res {
+            tracing::error!("cant write /events: {err}");
+            let _ = tx.blocking_send(Err(std::io::Error::other(err.to_string())));
+        }
+    });
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        let chunk = rx.recv().await?;
+        Some((chunk, rx))
+    });
+    (
+        cache_headers,
+        freshness.headers(),
+        [(CONTENT_TYPE, "application/json")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+// an nsid of a sorted /events, `{"nsid", ...NsidCount}`
+#[derive(Serialize)]
+struct NsidCountRow<'a> {
+    nsid: &'a str,
+    #[serde(flatten)]
+    count: NsidCount,
+}
+
+// the NsidCount of an nsid with the extras asked for, sets `partial` if one
+// of them cant be read
+fn events_row(
+    db: &Db,
+    params: &EventsQuery,
+    nsid: &str,
+    counts: &NsidCounts,
+    nsid_labels: Option<&LabelMap>,
+    now: u64,
+    partial: &mut bool,
+) -> NsidCount {
+    let flush = params.detail.then(|| db.flush_status(nsid)).flatten();
+    let sparkline = match params.sparklines.then(|| db.sparkline(nsid, now)) {
+        Some(Ok(sparkline)) => sparkline,
+        Some(Err(err)) => {
+            tracing::error!("cant build sparkline for {nsid}: {err}");
+            *partial = true;
+            None
+        }
+        None => None,
+    };
+    NsidCount {
+        last_flushed: flush.and_then(|flush| flush.last_flushed),
+        pending_items: flush.map(|flush| flush.pending_items),
+        sparkline,
+        labels: nsid_labels.filter(|_| params.labels).cloned(),
+        per_second: params.rates.then(|| db.nsid_rate(nsid)),
+        ..NsidCount::from(counts)
+    }
+}
+
+// writes the same shape as `Events`, with per_second and totals first since
+// we know them upfront. rows that cant be read are skipped and `"partial": true` is added
+// at the end, after `suggested_poll_secs`. stops early if `send` fails (client went away).
+// with a page the matching rows are collected and sorted first, and only the
+// ones on the page get their extras read. a row that doesnt serialize ends it
+// with an error instead of a panic
+fn write_events(
+    db: &Db,
+    params: &EventsQuery,
+    page: Option<EventsPage>,
+    freshness: Freshness,
+    mut send: impl FnMut(Bytes) -> bool,
+) -> AppResult<()> {
+    let mut buf = Vec::with_capacity(EVENTS_CHUNK_SIZE);
+    buf.extend_from_slice(format!(r#"{{"per_second":{},"totals":"#, db.eps()).as_bytes());
+    serde_json::to_writer(&mut buf, &db.totals())?;
+    buf.extend_from_slice(match page {
+        Some(_) => br#","events":["#,
+        None => br#","events":{"#,
+    });
+    let mut first = true;
+    let mut partial = false;
+    let mut matching = Vec::new();
+    let now = get_time().as_secs();
+    let labels = db.all_labels();
+    let scans = params.scans();
+    let rows = scans.iter().flat_map(|&(prefix, exact)| {
+        db.get_counts_with_prefix(prefix)
+            .filter(move |row| !exact || row.as_ref().map_or(true, |(nsid, _)| nsid == prefix))
+    });
+    for result in rows {
+        let (nsid, counts) = match result {
+            Ok(row) => row,
+            Err(err) => {
+                tracing::error!("skipping counts row: {err}");
+                partial = true;
+                continue;
+            }
+        };
+        if !params.counts_match(&counts) {
+            continue;
+        }
+        let nsid_labels = labels.get(&nsid);
+        let wanted = params
+            .label
+            .as_deref()
+            .is_none_or(|label| nsid_labels.is_some_and(|labels| labels_match(labels, label)));
+        if !wanted {
+            continue;
+        }
+        if page.is_some() {
+            matching.push((nsid, counts));
+            continue;
+        }
+        let count = events_row(db, params, &nsid, &counts, nsid_labels, now, &mut partial);
+        if !first {
+            buf.push(b',');
+        }
+        first = false;
+        serde_json::to_writer(&mut buf, &nsid)?;
+        buf.push(b':');
+        serde_json::to_writer(&mut buf, &count)?;
+        if buf.len() >= EVENTS_CHUNK_SIZE {
+            let chunk = std::mem::replace(&mut buf, Vec::with_capacity(EVENTS_CHUNK_SIZE));
+            if !send(Bytes::from(chunk)) {
+                return Ok(());
+            }
+        }
+    }
+    match page {
+        Some(page) => {
+            let total = matching.len();
+            let start = page.offset.min(total);
+            let end = page.offset.saturating_add(page.limit).min(total);
+            // only the rows up to the end of the page need to be in order
+            if end > 0 && end < total {
+                matching.select_nth_unstable_by(end - 1, |a, b| page.cmp(a, b));
+                matching.truncate(end);
+            }
+            matching.sort_unstable_by(|a, b| page.cmp(a, b));
+            for (nsid, counts) in &matching[start..end] {
+                let row = NsidCountRow {
+                    nsid,
+                    count: events_row(
+                        db,
+                        params,
+                        nsid,
+                        counts,
+                        labels.get(nsid),
+                        now,
+                        &mut partial,
+                    ),
+                };
+                if !first {
+                    buf.push(b',');
+                }
+                first = false;
+                serde_json::to_writer(&mut buf, &row)?;
+                if buf.len() >= EVENTS_CHUNK_SIZE {
+                    let chunk = std::mem::replace(&mut buf, Vec::with_capacity(EVENTS_CHUNK_SIZE));
+                    if !send(Bytes::from(chunk)) {
+                        return Ok(());
+                    }
+                }
+            }
+            buf.extend_from_slice(format!(r#"],"total":{total}"#).as_bytes());
+        }
+        None => buf.push(b'}'),
+    }
+    buf.extend_from_slice(format!(r#","suggested_poll_secs":{}"#, freshness.poll_secs).as_bytes());
+    if partial {
+        buf.extend_from_slice(br#","partial":true"#);
+    }
+    buf.push(b'}');
+    send(Bytes::from(buf));
+    Ok(())
+}
+
+// sends what is written to it in chunks of about EVENTS_CHUNK_SIZE, writes
+// fail once `send` does (client went away)
+struct ChunkWriter<F: FnMut(Bytes) -> bool> {
+    buf: Vec<u8>,
+    send: F,
+}
+
+impl<F: FnMut(Bytes) -> bool> ChunkWriter<F> {
+    fn send_buf(&mut self) -> std::io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(EVENTS_CHUNK_SIZE));
+        if !(self.send)(Bytes::from(chunk)) {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(Bytes) -> bool> Write for ChunkWriter<F> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= EVENTS_CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send_buf()
+    }
+}
+
+// runs `write` on the blocking pool and streams what it writes as the body,
+// writes fail once the client went away. if `write` fails the body ends with
+// an error, so the client sees it was cut off instead of a short body
+fn stream_body(
+    content_type: &'static str,
+    write: impl FnOnce(&mut dyn Write) -> AppResult<()> + Send + 'static,
+) -> Response {
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(4);
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.entered();
+        let mut writer = ChunkWriter {
+            buf: Vec::with_capacity(EVENTS_CHUNK_SIZE),
+            send: |chunk| tx.blocking_send(Ok(chunk)).is_ok(),
+        };
+        match write(&mut writer) {
+            Ok(()) => {
+                let _ = writer.flush();
+            }
+            Err(err) => {
+                tracing::error!("cant write body: {err}");
+                let _ = tx.blocking_send(Err(std::io::Error::other(err.to_string())));
+            }
+        }
+    });
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        let chunk = rx.recv().await?;
+        Some((chunk, rx))
+    });
+    ([(CONTENT_TYPE, content_type)], Body::from_stream(body)).into_response()
+}
+
+// the counts of every nsid in the format of the export-counts command, for
+// mirrors to import. if reading the counts fails the body errors, and even if
+// the client misses that the import rejects a dump without its end marker
+#[utoipa::path(
+    get,
+    path = "/counts_dump",
+    tag = "counts",
+    responses((status = 200, description = "the counts in the export-counts format", content_type = "application/octet-stream"))
+)]
+async fn counts_dump(State(db): State<Arc<Db>>) -> Response {
+    stream_body("application/octet-stream", move |writer| {
+        db.export_counts(writer).map(drop)
+    })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HitsQuery {
+    /// or several nsids separated by commas
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    /// epoch seconds, the newest end of the range. hits are read from here
+    /// back to `to`, now if left out
+    from: Option<u64>,
+    /// epoch seconds, the oldest end of the range
+    to: Option<u64>,
+    #[serde(default)]
+    #[param(inline)]
+    kind: HitKind,
+    /// short for kind=deleted (true) or kind=created (false)
+    deleted: Option<bool>,
+    /// how many of the newest hits (or buckets, see resolution) in the range
+    /// to return
+    limit: Option<usize>,
+    /// admin only, wraps the hits with per block timings
+    #[serde(default)]
+    debug: bool,
+    #[serde(default)]
+    #[param(inline)]
+    format: HitsFormat,
+    /// admin only, lifts the range span limit
+    #[serde(default)]
+    allow_large: bool,
+    /// only for csv
+    #[serde(default)]
+    #[param(inline)]
+    time_format: TimeFormat,
+    /// only for json, buckets the hits instead of returning each one
+    #[serde(default)]
+    #[param(inline)]
+    resolution: HitsResolution,
+    /// only for json, like resolution but buckets of any number of seconds,
+    /// returned as `{bucket, count, deleted_count}`
+    step: Option<u64>,
+    /// only for several nsids, merges their hits into one list
+    #[serde(default)]
+    merge: bool,
+    /// only for json, keeps each hit with this chance (above 0, at most 1)
+    /// and wraps them with the rate and an estimated total
+    sample: Option<f64>,
+    /// only for json, like sample but keeps every nth hit
+    every: Option<u64>,
+    /// with sample or every, picks the same hits of the same range again
+    seed: Option<u64>,
+}
+
+impl HitsQuery {
+    // bucket width in seconds of `step` or `resolution`, None for raw hits
+    fn width(&self) -> Option<u64> {
+        self.step.or(self.resolution.width())
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.sample.is_some() || self.every.is_some()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum HitsResolution {
+    #[default]
+    Raw,
+    Second,
+    Minute,
+}
+
+impl HitsResolution {
+    // bucket width in seconds, None for raw hits
+    fn width(self) -> Option<u64> {
+        match self {
+            HitsResolution::Raw => None,
+            HitsResolution::Second => Some(1),
+            HitsResolution::Minute => Some(60),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum HitsFormat {
+    // the newest `limit` hits as one array
+    #[default]
+    Json,
+    // every hit in the range oldest first, streamed one object per line
+    Ndjson,
+    // like ndjson but `timestamp,deleted` rows under a header
+    Csv,
+    // the same hits as json in a compact binary layout, see `hits_bin`
+    Bin,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum TimeFormat {
+    // unix seconds
+    #[default]
+    Unix,
+    // rfc3339 in utc
+    Iso,
+}
+
+/// which hits to return
+#[derive(Debug, Default, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum HitKind {
+    #[default]
+    All,
+    Created,
+    // purges included
+    Deleted,
+    DeletedExcludingPurges,
+    Purged,
+}
+
+impl HitKind {
+    fn matches(self, op: HitOp) -> bool {
+        match self {
+            HitKind::All => true,
+            HitKind::Created => op == HitOp::Create,
+            HitKind::Deleted => op.is_deleted(),
+            HitKind::DeletedExcludingPurges => op == HitOp::Delete,
+            HitKind::Purged => op == HitOp::Purge,
+        }
+    }
+
+    // `deleted` on top of `kind`, only one of them can narrow the hits down
+    fn with_deleted(self, deleted: Option<bool>) -> AppResult<Self> {
+        match (self, deleted) {
+            (kind, None) => Ok(kind),
+            (HitKind::All, Some(true)) => Ok(HitKind::Deleted),
+            (HitKind::All, Some(false)) => Ok(HitKind::Created),
+            _ => Err(AppError::bad_request(
+                "use either kind or deleted, not both",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Hit {
+    /// epoch seconds
+    timestamp: u64,
+    deleted: bool,
+    /// set for deletions that were part of an account purge
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    purge: bool,
+}
+
+impl Hit {
+    fn new(timestamp: u64, op: HitOp) -> Self {
+        Self {
+            timestamp,
+            deleted: op.is_deleted(),
+            purge: op == HitOp::Purge,
+        }
+    }
+
+    fn op(&self) -> HitOp {
+        match (self.deleted, self.purge) {
+            (_, true) => HitOp::Purge,
+            (true, false) => HitOp::Delete,
+            (false, false) => HitOp::Create,
+        }
+    }
+}
+
+// hits returned when no limit is asked for. the most that can be asked for is
+// `MAX_HITS_LIMIT` from the env
+const DEFAULT_HITS_LIMIT: usize = 100_000;
+const DEFAULT_MAX_HITS_LIMIT: usize = 1_000_000;
+// set on hits responses that left out older hits in the range
+const TRUNCATED_HEADER: &str = "x-truncated";
+// how many hits were read for a bucketed hits response
+const SCANNED_HEADER: &str = "x-scanned-hits";
+// widest range in seconds raw hits can be queried over, `MAX_RANGE_SPAN`
+// from the env. histograms and counts arent limited
+const DEFAULT_MAX_RANGE_SPAN: u64 = 60 * 60 * 24 * 31;
+// the range raw hits responses are from, unix seconds
+const RANGE_START_HEADER: &str = "x-range-start";
+const RANGE_END_HEADER: &str = "x-range-end";
+// debugged queries slower than this get logged
+const SLOW_QUERY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Serialize)]
+struct HitsStats {
+    took_ms: u128,
+    blocks: usize,
+    slowest_blocks: Vec<BlockTrace>,
+}
+
+#[derive(Debug, Serialize)]
+struct DebugHits {
+    hits: Vec<Hit>,
+    truncated: bool,
+    stats: HitsStats,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HitsRange {
+    from: Bound<u64>,
+    to: Bound<u64>,
+}
+
+impl HitsRange {
+    fn new(from: Option<u64>, to: Option<u64>) -> Self {
+        Self {
+            from: from.map(Bound::Included).unwrap_or(Bound::Unbounded),
+            to: to.map(Bound::Included).unwrap_or(Bound::Unbounded),
+        }
+    }
+
+    // None for unbounded sides
+    fn limits(&self) -> (Option<u64>, Option<u64>) {
+        let limit = |bound: Bound<u64>| match bound {
+            Bound::Included(limit) => Some(limit),
+            _ => None,
+        };
+        (limit(self.from), limit(self.to))
+    }
+}
+
+impl RangeBounds<u64> for HitsRange {
+    fn start_bound(&self) -> Bound<&u64> {
+        self.from.as_ref()
+    }
+
+    fn end_bound(&self) -> Bound<&u64> {
+        self.to.as_ref()
+    }
+}
+
+fn max_hits_limit() -> usize {
+    config::env_or("MAX_HITS_LIMIT", DEFAULT_MAX_HITS_LIMIT, |max| {
+        max.parse::<usize>().ok().filter(|max| *max > 0)
+    })
+}
+
+fn max_range_span() -> u64 {
+    config::env_or("MAX_RANGE_SPAN", DEFAULT_MAX_RANGE_SPAN, |max| {
+        max.parse::<u64>().ok().filter(|max| *max > 0)
+    })
+}
+
+// the range of a raw hits query. without an end it ends now, and without a
+// start it starts `max_range_span` before the end. wider ranges are a 400,
+// unless an admin asks for `allow_large`, then the range is taken as given.
+// so are ranges that end before they start
+fn hits_range(
+    start: Option<u64>,
+    end: Option<u64>,
+    allow_large: bool,
+    headers: &HeaderMap,
+) -> AppResult<HitsRange> {
+    if start.zip(end).is_some_and(|(start, end)| start > end) {
+        return Err(AppError::new(
+            ErrorCode::InvalidRange,
+            "to is after from, hits are read from `from` back to `to`",
+        ));
+    }
+    if allow_large {
+        if !admin::is_admin(headers) {
+            return Err(AppError::forbidden("allow_large needs the admin token"));
+        }
+        return Ok(HitsRange::new(start, end));
+    }
+    let max = max_range_span();
+    let end = end.unwrap_or_else(|| get_time().as_secs());
+    let start = start.unwrap_or_else(|| end.saturating_sub(max));
+    let span = end.saturating_sub(start);
+    if span > max {
+        return Err(AppError::new(
+            ErrorCode::RangeTooLarge,
+            format!(
+                "range spans {span}s but at most {max}s can be queried at once, \
+                split it up or ask for allow_large=true with the admin token"
+            ),
+        )
+        .with_detail(serde_json::json!({ "span": span, "max_span": max })));
+    }
+    Ok(HitsRange::new(Some(start), Some(end)))
+}
+
+// says which range the hits are from, it can differ from what was asked for
+fn with_range_headers(mut res: Response, range: HitsRange) -> Response {
+    let (start, end) = range.limits();
+    for (name, limit) in [(RANGE_START_HEADER, start), (RANGE_END_HEADER, end)] {
+        if let Some(limit) = limit {
+            res.headers_mut().insert(name, HeaderValue::from(limit));
+        }
+    }
+    res
+}
+
+// the limit to query with, or a 400 if it is out of range
+fn hits_limit(limit: Option<usize>) -> AppResult<usize> {
+    let max = max_hits_limit();
+    match limit {
+        None => Ok(DEFAULT_HITS_LIMIT.min(max)),
+        Some(limit) if (1..=max).contains(&limit) => Ok(limit),
+        Some(_) => Err(AppError::new(
+            ErrorCode::InvalidLimit,
+            format!("limit must be between 1 and {max}"),
+        )
+        .with_detail(serde_json::json!({ "min": 1, "max": max }))),
+    }
+}
+
+// the newest `limit` hits of the page, whole blocks are read so there can be
+// more. the bool is whether any hits in the range were left out
+fn collect_hits(
+    page: HitsPage<impl Iterator<Item = AppResult<Item>>>,
+    kind: HitKind,
+    limit: usize,
+    query: &HeavyQuery,
+) -> AppResult<(Vec<Hit>, bool)> {
+    let _span = tracing::info_span!("decode", limit).entered();
+    let mut acc = Vec::with_capacity(limit.min(DEFAULT_HITS_LIMIT));
+    for hit in page.hits {
+        query.check()?;
+        let hit = hit?;
+        let op = hit.deser()?.op;
+        if !kind.matches(op) {
+            continue;
+        }
+        acc.push(Hit::new(hit.timestamp, op));
+    }
+    let extra = acc.len().saturating_sub(limit);
+    acc.drain(..extra);
+    Ok((acc, page.truncated || extra > 0))
+}
+
+// the newest `limit` hits of `kind`, oldest first. unlike `collect_hits` only
+// the hits of `kind` count toward the limit, so a page of deletions is full
+// even if most hits in the range were creates. reads on past the page until
+// the next hit of `kind` to know whether any were left out
+fn collect_hits_of_kind(
+    hits: impl Iterator<Item = AppResult<Item>>,
+    kind: HitKind,
+    limit: usize,
+    query: &HeavyQuery,
+) -> AppResult<(Vec<Hit>, bool)> {
+    let _span = tracing::info_span!("decode", limit).entered();
+    let mut acc = Vec::with_capacity(limit.min(DEFAULT_HITS_LIMIT));
+    let mut truncated = false;
+    for hit in hits {
+        query.check()?;
+        let hit = hit?;
+        let op = hit.deser()?.op;
+        if !kind.matches(op) {
+            continue;
+        }
+        if acc.len() >= limit {
+            truncated = true;
+            break;
+        }
+        acc.push(Hit::new(hit.timestamp, op));
+    }
+    acc.reverse();
+    Ok((acc, truncated))
+}
+
+// the hits of one bucket of a `resolution` query, as
+// `[bucket_start, count, deleted_count]`
+#[derive(Debug, Serialize)]
+struct HitsRow(u64, u64, u64);
+
+// the same for a `step` query
+#[derive(Debug, Serialize)]
+struct HitsStep {
+    bucket: u64,
+    count: u64,
+    deleted_count: u64,
+}
+
+impl From<HitsRow> for HitsStep {
+    fn from(HitsRow(bucket, count, deleted_count): HitsRow) -> Self {
+        Self {
+            bucket,
+            count,
+            deleted_count,
+        }
+    }
+}
+
+// the newest `limit` buckets of `width` seconds that have hits, oldest first.
+// hits are read newest first and only their buckets are kept, so `limit` is
+// in buckets. returns how many hits were read, and whether older buckets in
+// the range were left out
+fn collect_rows(
+    hits: impl Iterator<Item = AppResult<Item>>,
+    kind: HitKind,
+    width: u64,
+    limit: usize,
+    query: &HeavyQuery,
+) -> AppResult<(Vec<HitsRow>, usize, bool)> {
+    let _span = tracing::info_span!("decode", limit).entered();
+    let mut rows = BTreeMap::<u64, HitsRow>::new();
+    let mut scanned = 0;
+    let mut truncated = false;
+    for hit in hits {
+        query.check()?;
+        let hit = hit?;
+        scanned += 1;
+        let op = hit.deser()?.op;
+        if !kind.matches(op) {
+            continue;
+        }
+        let start = hit.timestamp / width * width;
+        // older than every bucket we have, and we have enough of them
+        if rows.len() >= limit
+            && rows
+                .first_key_value()
+                .is_some_and(|(oldest, _)| start < *oldest)
+        {
+            truncated = true;
+            break;
+        }
+        // blocks can overlap a bit, so a hit can be newer than the last one
+        let row = rows.entry(start).or_insert(HitsRow(start, 0, 0));
+        row.1 += 1;
+        if op.is_deleted() {
+            row.2 += 1;
+        }
+        if rows.len() > limit {
+            rows.pop_first();
+            truncated = true;
+        }
+    }
+    Ok((rows.into_values().collect(), scanned, truncated))
+}
+
+fn hits_rows_response(
+    db: &Db,
+    params: &HitsQuery,
+    range: HitsRange,
+    width: u64,
+    limit: usize,
+    query: &HeavyQuery,
+) -> AppResult<Response> {
+    let snapshot = db.pin_snapshot(&params.nsid);
+    let hits = snapshot
+        .iter()
+        .flat_map(|snapshot| db.hits_newest_first(snapshot, range));
+    let (rows, scanned, truncated) = collect_rows(hits, params.kind, width, limit, query)?;
+    let mut res = match params.step {
+        Some(_) => hits_response(rows.into_iter().map(HitsStep::from).collect(), truncated),
+        None => hits_response(rows, truncated),
+    };
+    res.headers_mut()
+        .insert(SCANNED_HEADER, HeaderValue::from(scanned));
+    Ok(res)
+}
+
+// the newest `limit` hits of `kind` in the range, oldest first. the bool is
+// whether any were left out
+fn json_hits(
+    db: &Db,
+    nsid: &str,
+    range: HitsRange,
+    limit: usize,
+    kind: HitKind,
+    admission: Admission,
+    query: &HeavyQuery,
+) -> AppResult<(Vec<Hit>, bool)> {
+    match kind {
+        HitKind::All => {
+            let page = db.query_hits(nsid, range, limit, None, admission);
+            collect_hits(page, kind, limit, query)
+        }
+        kind => {
+            let snapshot = db.pin_snapshot(nsid);
+            let hits = snapshot
+                .iter()
+                .flat_map(|snapshot| db.hits_newest_first(snapshot, range));
+            collect_hits_of_kind(hits, kind, limit, query)
+        }
+    }
+}
+
+// allow_large queries are exports, the blocks they read shouldnt push out
+// the ones the dashboard keeps reading
+fn admission(params: &HitsQuery) -> Admission {
+    if params.allow_large {
+        Admission::Bypass
+    } else {
+        Admission::Admit
+    }
+}
+
+fn hits_response(hits: Vec<impl Serialize>, truncated: bool) -> Response {
+    let mut res = Json(hits).into_response();
+    if truncated {
+        res.headers_mut()
+            .insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    res
+}
+
+fn hits_bin_response(nsid: &str, hits: &[Hit], truncated: bool) -> AppResult<Response> {
+    let hits = hits
+        .iter()
+        .map(|hit| (hit.timestamp, hit.op()))
+        .collect::<Vec<_>>();
+    let body = hits_bin::encode(nsid, &hits)?;
+    let mut res = ([(CONTENT_TYPE, hits_bin::CONTENT_TYPE)], body).into_response();
+    if truncated {
+        res.headers_mut()
+            .insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    Ok(res)
+}
+
+// clients that only ask with `Accept` get the binary format too
+fn accepts_bin(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|mime| mime.split(';').next().map(str::trim) == Some(hits_bin::CONTENT_TYPE))
+}
+
+#[utoipa::path(
+    get,
+    path = "/hits",
+    tag = "hits",
+    params(HitsQuery),
+    responses((status = 200, description = "the newest hits in the range first", body = Vec<Hit>), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn hits(
+    State(db): State<Arc<Db>>,
+    Query(mut params): Query<HitsQuery>,
+    Extension(query): Extension<HeavyQuery>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    params.kind = params.kind.with_deleted(params.deleted)?;
+    if params.nsid.contains(',') {
+        return multi_hits::multi_hits(db, params, query, headers).await;
+    }
+    if params.format == HitsFormat::Json
+        && params.width().is_none()
+        && !params.debug
+        && !params.is_sampled()
+        && accepts_bin(&headers)
+    {
+        params.format = HitsFormat::Bin;
+    }
+    let (range, limit) = hits_params(&db, &params, &headers)?;
+    // decoding can take a while, keep it off the workers serving the streams
+    let res =
+        run_query(move || hits_response_of(db, params, range, limit, &headers, &query)).await??;
+    Ok(with_range_headers(res, range))
+}
+
+// checks the params of /hits for one nsid, and what range and limit they
+// ask for. /batch checks its hits with this too
+fn hits_params(db: &Db, params: &HitsQuery, headers: &HeaderMap) -> AppResult<(HitsRange, usize)> {
+    if !is_valid_nsid(&params.nsid) {
+        return Err(AppError::new(
+            ErrorCode::InvalidNsid,
+            format!("{} isnt a valid nsid", params.nsid),
+        ));
+    }
+    // the client asks from now back in time, so `to` is the start of the range
+    let range = hits_range(params.to, params.from, params.allow_large, headers)?;
+    let limit = hits_limit(params.limit)?;
+    if params.step == Some(0) {
+        return Err(AppError::bad_request("step has to be at least 1 second"));
+    }
+    if params.step.is_some() && params.resolution != HitsResolution::Raw {
+        return Err(AppError::bad_request(
+            "step and resolution cant be used together",
+        ));
+    }
+    if params.width().is_some() && (params.debug || params.format != HitsFormat::Json) {
+        return Err(AppError::bad_request(
+            "resolution and step only work with format=json",
+        ));
+    }
+    Sampler::of(params)?;
+    if params.is_sampled()
+        && (params.debug || params.format != HitsFormat::Json || params.width().is_some())
+    {
+        return Err(AppError::bad_request(
+            "sample and every only work with format=json, without resolution or step",
+        ));
+    }
+    // an empty 200 would look like an nsid that was quiet in the range
+    if !db.has_nsid(&params.nsid) {
+        return Err(AppError::new(
+            ErrorCode::NsidNotFound,
+            format!("no hits were ever seen for {}", params.nsid),
+        ));
+    }
+    Ok((range, limit))
+}
+
+fn hits_response_of(
+    db: Arc<Db>,
+    params: HitsQuery,
+    range: HitsRange,
+    limit: usize,
+    headers: &HeaderMap,
+    query: &HeavyQuery,
+) -> AppResult<Response> {
+    if let Some(width) = params.width() {
+        return hits_rows_response(&db, &params, range, width, limit, query);
+    }
+    if !params.debug {
+        match params.format {
+            HitsFormat::Json | HitsFormat::Bin => {}
+            HitsFormat::Ndjson => return Ok(hits_ndjson(db, params.nsid, range, params.kind)),
+            HitsFormat::Csv => {
+                let (start, end) = range.limits();
+                let filename = csv_filename(&params.nsid, start, end);
+                return Ok(hits_csv(
+                    db,
+                    params.nsid,
+                    range,
+                    params.kind,
+                    params.time_format,
+                    filename,
+                ));
+            }
+        }
+
+        if let Some(sampler) = Sampler::of(&params)? {
+            let sampled =
+                sampled_hits(&db, &params.nsid, range, limit, params.kind, sampler, query)?;
+            return Ok(Json(sampled).into_response());
+        }
+        let admission = admission(&params);
+        let (hits, truncated) = json_hits(
+            &db,
+            &params.nsid,
+            range,
+            limit,
+            params.kind,
+            admission,
+            query,
+        )?;
+        if params.format == HitsFormat::Bin {
+            return hits_bin_response(&params.nsid, &hits, truncated);
+        }
+        return Ok(hits_response(hits, truncated));
+    }
+    if !admin::is_admin(headers) {
+        return Err(AppError::forbidden("debug needs the admin token"));
+    }
+
+    let trace = QueryTrace::default();
+    let start = CLOCK.now();
+    let page = db.query_hits(&params.nsid, range, limit, Some(&trace), admission(&params));
+    let (hits, truncated) = collect_hits(page, params.kind, limit, query)?;
+    let took = start.elapsed();
+    let slowest_blocks = trace.slowest(5);
+    if took > SLOW_QUERY {
+        tracing::debug!(
+            { took = %LatencyMillis::from(took), blocks = %trace.block_count() },
+            "slow query, slowest blocks: {slowest_blocks:?}",
+        );
+    }
+    let stats = HitsStats {
+        took_ms: took.as_millis(),
+        blocks: trace.block_count(),
+        slowest_blocks,
+    };
+    Ok(Json(DebugHits {
+        hits,
+        truncated,
+        stats,
+    })
+    .into_response())
+}
+
+// every hit of `kind` in the range, oldest first. blocks are decoded as the
+// iterator gets to them, for the streamed formats
+fn export_hits(
+    db: &Db,
+    snapshot: Option<&PinnedSnapshot>,
+    range: HitsRange,
+    kind: HitKind,
+) -> impl Iterator<Item = AppResult<Hit>> {
+    snapshot
+        .into_iter()
+        .flat_map(move |snapshot| db.export_hits(snapshot, range))
+        .map(|hit| hit.and_then(|hit| Ok((hit.timestamp, hit.deser()?.op))))
+        .filter_ok(move |(_, op)| kind.matches(*op))
+        .map_ok(|(timestamp, op)| Hit::new(timestamp, op))
+}
+
+// unlike the json array this has no limit. if reading fails midway the last
+// line is an `{"error": ...}` object instead of the stream just ending
+fn hits_ndjson(db: Arc<Db>, nsid: SmolStr, range: HitsRange, kind: HitKind) -> Response {
+    stream_body("application/x-ndjson", move |writer| {
+        let snapshot = db.pin_snapshot(&nsid);
+        for hit in export_hits(&db, snapshot.as_ref(), range, kind) {
+            let hit = match hit {
+                Ok(hit) => hit,
+                Err(err) => {
+                    tracing::error!("cant read hits of {nsid}: {err}");
+                    let error = serde_json::json!({ "error": err.to_string() });
+                    let _ = serde_json::to_writer(&mut *writer, &error);
+                    let _ = writer.write_all(b"\n");
+                    return Ok(());
+                }
+            };
+            let written = serde_json::to_writer(&mut *writer, &hit)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"));
+            if written.is_err() {
+                // the client went away
+                return Ok(());
+            }
+        }
+        Ok(())
+    })
+}
+
+// like ndjson, but there is nowhere to put an error so the body errors instead
+fn hits_csv(
+    db: Arc<Db>,
+    nsid: SmolStr,
+    range: HitsRange,
+    kind: HitKind,
+    time_format: TimeFormat,
+    filename: String,
+) -> Response {
+    let mut res = stream_body("text/csv", move |writer| {
+        let snapshot = db.pin_snapshot(&nsid);
+        let mut written = writer.write_all(b"timestamp,deleted\n");
+        for hit in export_hits(&db, snapshot.as_ref(), range, kind) {
+            if written.is_err() {
+                // the client went away
+                return Ok(());
+            }
+            let hit = hit?;
+            written = match time_format {
+                TimeFormat::Unix => writeln!(writer, "{},{}", hit.timestamp, hit.deleted),
+                TimeFormat::Iso => writeln!(writer, "{},{}", rfc3339(hit.timestamp), hit.deleted),
+            };
+        }
+        Ok(())
+    });
+    res.headers_mut().insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{filename}\""))
+            .expect("filename is only ascii"),
+    );
+    res
+}
+
+// `{nsid}_{start}_{end}.csv`, with whatever isnt safe in a header replaced
+fn csv_filename(nsid: &str, start: Option<u64>, end: Option<u64>) -> String {
+    let nsid = nsid
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let start = start.map_or_else(|| "start".to_owned(), |start| start.to_string());
+    let end = end.map_or_else(|| "now".to_owned(), |end| end.to_string());
+    format!("{nsid}_{start}_{end}.csv")
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistogramQuery {
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    /// epoch seconds, a day before `to` if left out
+    from: Option<u64>,
+    /// epoch seconds, now if left out
+    to: Option<u64>,
+    /// bucket size in seconds
+    interval: Option<u64>,
+    /// only count deletions (true, purges included) or creates (false)
+    deleted: Option<bool>,
+}
+
+const DEFAULT_HISTOGRAM_INTERVAL: u64 = 60;
+const DEFAULT_HISTOGRAM_RANGE: u64 = 60 * 60 * 24;
+const MAX_HISTOGRAM_BUCKETS: u64 = 10_000;
+
+#[utoipa::path(
+    get,
+    path = "/histogram",
+    tag = "series",
+    params(HistogramQuery),
+    responses((status = 200, description = "one bucket per interval", body = Vec<HistogramBucket>), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn histogram(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<HistogramQuery>,
+) -> AppResult<Response> {
+    let (buckets, freshness) = histogram_buckets(db, params).await?;
+    Ok((freshness.headers(), Json(buckets)).into_response())
+}
+
+// the buckets of /histogram, /stats summarizes the same ones
+async fn histogram_buckets(
+    db: Arc<Db>,
+    params: HistogramQuery,
+) -> AppResult<(Vec<HistogramBucket>, Freshness)> {
+    let interval = params.interval.unwrap_or(DEFAULT_HISTOGRAM_INTERVAL);
+    let to = params.to.unwrap_or_else(|| get_time().as_secs());
+    let from = params
+        .from
+        .unwrap_or(to.saturating_sub(DEFAULT_HISTOGRAM_RANGE));
+    if interval == 0 {
+        return Err(AppError::bad_request("interval must be positive"));
+    }
+    if from > to {
+        return Err(AppError::new(
+            ErrorCode::InvalidRange,
+            "from must not be after to",
+        ));
+    }
+    let buckets = to / interval - from / interval + 1;
+    if buckets > MAX_HISTOGRAM_BUCKETS {
+        return Err(AppError::new(
+            ErrorCode::RangeTooLarge,
+            format!(
+                "range would have {buckets} buckets, at most {MAX_HISTOGRAM_BUCKETS} are allowed"
+            ),
+        )
+        .with_detail(serde_json::json!({
+            "buckets": buckets,
+            "max_buckets": MAX_HISTOGRAM_BUCKETS,
+        })));
+    }
+    let deleted = params.deleted;
+    let (mut buckets, freshness) = run_query(move || -> AppResult<_> {
+        let (buckets, source) = db.histogram_from_source(&params.nsid, from, to, interval)?;
+        Ok((buckets, Freshness::of(&db, source)))
+    })
+    .await??;
+    for bucket in &mut buckets {
+        match deleted {
+            Some(true) => bucket.count = 0,
+            Some(false) => (bucket.deleted_count, bucket.purged_count) = (0, 0),
+            None => {}
+        }
+    }
+    Ok((buckets, freshness))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MultiSeriesQuery {
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    /// epoch seconds, the start of the coarse series
+    from: Option<u64>,
+    /// epoch seconds, the end of the coarse series
+    to: Option<u64>,
+    /// epoch seconds, the start of the fine series
+    viewport_from: u64,
+    /// epoch seconds, the end of the fine series
+    viewport_to: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Series {
+    from: u64,
+    to: u64,
+    // bucket size in seconds
+    interval: u64,
+    buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Debug, Serialize)]
+struct MultiSeries {
+    coarse: Series,
+    fine: Series,
+    // see `Freshness`
+    suggested_poll_secs: u64,
+}
+
+// about how many buckets each series of /multi_series has
+const SERIES_POINTS: u64 = 300;
+const SERIES_INTERVALS: [u64; 15] = [
+    1,
+    5,
+    10,
+    30,
+    60,
+    5 * 60,
+    10 * 60,
+    30 * 60,
+    60 * 60,
+    3 * 60 * 60,
+    6 * 60 * 60,
+    12 * 60 * 60,
+    24 * 60 * 60,
+    7 * 24 * 60 * 60,
+    30 * 24 * 60 * 60,
+];
+
+// the smallest round bucket size that keeps `from..=to` to SERIES_POINTS
+// buckets, past the largest one buckets are whole multiples of it
+fn series_interval(from: u64, to: u64) -> u64 {
+    let span = (to - from).saturating_add(1);
+    SERIES_INTERVALS
+        .into_iter()
+        .find(|interval| span.div_ceil(*interval) <= SERIES_POINTS)
+        .unwrap_or_else(|| {
+            let largest = SERIES_INTERVALS[SERIES_INTERVALS.len() - 1];
+            span.div_ceil(SERIES_POINTS).div_ceil(largest) * largest
+        })
+}
+
+// a coarse series over the whole range and a fine one over the part a chart
+// is zoomed into, with bucket sizes picked for each. both come out of one
+// read of the blocks where they overlap
+#[utoipa::path(
+    get,
+    path = "/multi_series",
+    tag = "series",
+    params(MultiSeriesQuery),
+    responses((status = 200, description = "a coarse and a fine series"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn multi_series(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<MultiSeriesQuery>,
+) -> AppResult<Response> {
+    let to = params.to.unwrap_or_else(|| get_time().as_secs());
+    let from = params
+        .from
+        .unwrap_or(to.saturating_sub(DEFAULT_HISTOGRAM_RANGE));
+    if from > to || params.viewport_from > params.viewport_to {
+        return Err(AppError::new(
+            ErrorCode::InvalidRange,
+            "from must not be after to, nor viewport_from after viewport_to",
+        ));
+    }
+    let series = [(from, to), (params.viewport_from, params.viewport_to)].map(|(start, end)| {
+        HistogramSeries {
+            start,
+            end,
+            interval: series_interval(start, end),
+        }
+    });
+    let ([coarse, fine], freshness) = run_query(move || {
+        db.histograms(&params.nsid, &series).map(|histograms| {
+            let histograms = <[_; 2]>::try_from(histograms).expect("one per series");
+            (histograms, Freshness::of(&db, DataSource::Blocks))
+        })
+    })
+    .await??;
+    let series_of = |series: HistogramSeries, buckets| Series {
+        from: series.start,
+        to: series.end,
+        interval: series.interval,
+        buckets,
+    };
+    let series = MultiSeries {
+        coarse: series_of(series[0], coarse),
+        fine: series_of(series[1], fine),
+        suggested_poll_secs: freshness.poll_secs,
+    };
+    Ok((freshness.headers(), Json(series)).into_response())
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OverviewQuery {
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    #[serde(default)]
+    #[param(inline)]
+    downsample: Downsample,
+}
+
+#[derive(Debug, Serialize)]
+struct Overview {
+    since: u64,
+    now: u64,
+    downsample: Downsample,
+    points: Vec<OverviewPoint>,
+    // see `Freshness`, the recent tail is read from blocks
+    suggested_poll_secs: u64,
+}
+
+const OVERVIEW_POINTS: u64 = 500;
+
+// the whole tracked history of an nsid in about OVERVIEW_POINTS points, so
+// charts dont have to pick buckets
+#[utoipa::path(
+    get,
+    path = "/overview",
+    tag = "series",
+    params(OverviewQuery),
+    responses((status = 200, description = "the whole history of the nsid in a few hundred points"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn overview(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<OverviewQuery>,
+) -> AppResult<Response> {
+    let now = get_time().as_secs();
+    let downsample = params.downsample;
+    let nsid = params.nsid.clone();
+    let overview = run_query(move || -> AppResult<_> {
+        // nothing tracked yet, the series is just now
+        let since = match db.tracking_since()? {
+            0 => now,
+            since => since,
+        };
+        let points = db.overview(&params.nsid, since, now, OVERVIEW_POINTS, downsample)?;
+        let freshness = Freshness::of(&db, DataSource::Blocks);
+        Ok(points.map(|points| {
+            let overview = Overview {
+                since,
+                now,
+                downsample,
+                points,
+                suggested_poll_secs: freshness.poll_secs,
+            };
+            (overview, freshness)
+        }))
+    })
+    .await??;
+    match overview {
+        Some((overview, freshness)) => Ok((freshness.headers(), Json(overview)).into_response()),
+        None => Err(AppError::new(
+            ErrorCode::NsidNotFound,
+            format!("{nsid} was never seen"),
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RollupQuery {
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    /// epoch seconds, 90 days before `to` if left out
+    from: Option<u64>,
+    /// epoch seconds, now if left out
+    to: Option<u64>,
+    #[serde(default)]
+    #[param(inline)]
+    granularity: RollupGranularity,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Rollup {
+    granularity: RollupGranularity,
+    points: Vec<RollupPoint>,
+}
+
+const DEFAULT_ROLLUP_RANGE: u64 = 60 * 60 * 24 * 90;
+const MAX_ROLLUP_RANGE: u64 = 60 * 60 * 24 * 366 * 5;
+// days that are settled dont change anymore
+const SETTLED_MAX_AGE: u64 = 60 * 60 * 24 * 7;
+
+// hits per day or week for long range charts, from the daily rollups. a
+// range that is all settled days is cached for a long while
+#[utoipa::path(
+    get,
+    path = "/rollup",
+    tag = "series",
+    params(RollupQuery),
+    responses((status = 200, description = "one point per day or week", body = Rollup), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn rollup(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<RollupQuery>,
+) -> AppResult<Response> {
+    let to = params.to.unwrap_or_else(|| get_time().as_secs());
+    let from = params
+        .from
+        .unwrap_or(to.saturating_sub(DEFAULT_ROLLUP_RANGE));
+    if from > to {
+        return Err(AppError::new(
+            ErrorCode::InvalidRange,
+            "from must not be after to",
+        ));
+    }
+    if to - from > MAX_ROLLUP_RANGE {
+        return Err(AppError::new(
+            ErrorCode::RangeTooLarge,
+            format!("at most {MAX_ROLLUP_RANGE} seconds can be rolled up at once"),
+        )
+        .with_detail(serde_json::json!({ "span": to - from, "max_span": MAX_ROLLUP_RANGE })));
+    }
+    let granularity = params.granularity;
+    let nsid = params.nsid.clone();
+    let rollup = run_query(move || -> AppResult<_> {
+        let points = db.rollup(&params.nsid, from, to, granularity)?;
+        let freshness = match &points {
+            Some(points) if points.iter().all(|point| !point.estimated) => Freshness {
+                source: DataSource::Rollups,
+                poll_secs: SETTLED_MAX_AGE,
+            },
+            _ => Freshness::of(&db, DataSource::Blocks),
+        };
+        Ok(points.map(|points| (points, freshness)))
+    })
+    .await??;
+    match rollup {
+        Some((points, freshness)) => {
+            let rollup = Rollup {
+                granularity,
+                points,
+            };
+            Ok((freshness.headers(), Json(rollup)).into_response())
+        }
+        None => Err(AppError::new(
+            ErrorCode::NsidNotFound,
+            format!("{nsid} was never seen"),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Bucket {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    fn secs(self) -> u64 {
+        match self {
+            Bucket::Hour => 60 * 60,
+            Bucket::Day => 60 * 60 * 24,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ActiveNsidsQuery {
+    /// epoch seconds, a day before `to` if left out
+    from: Option<u64>,
+    /// epoch seconds, now if left out
+    to: Option<u64>,
+    #[serde(default)]
+    #[param(inline)]
+    bucket: Bucket,
+    /// include the nsids themselves for buckets with at most 200 of them
+    #[serde(default)]
+    nsids: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ActiveBucket {
+    start: u64,
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nsids: Option<Vec<SmolStr>>,
+}
+
+const MAX_LISTED_NSIDS: usize = 200;
+const MAX_ACTIVE_RANGE: u64 = 60 * 60 * 24 * 366;
+const DEFAULT_ACTIVE_RANGE: u64 = 60 * 60 * 24;
+
+#[utoipa::path(
+    get,
+    path = "/active_nsids",
+    tag = "nsids",
+    params(ActiveNsidsQuery),
+    responses((status = 200, description = "how many nsids were active per bucket"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn active_nsids(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<ActiveNsidsQuery>,
+) -> AppResult<Json<Vec<ActiveBucket>>> {
+    let bucket = params.bucket.secs();
+    let to = params.to.unwrap_or_else(|| get_time().as_secs());
+    let from = params
+        .from
+        .unwrap_or(to.saturating_sub(DEFAULT_ACTIVE_RANGE))
+        .max(to.saturating_sub(MAX_ACTIVE_RANGE));
+    let hours = run_query(move || db.active_nsids(from, to)).await??;
+
+    // every bucket in the range, empty ones included so charts dont have gaps
+    let first = from / bucket * bucket;
+    let mut buckets = (first..=to)
+        .step_by(bucket as usize)
+        .map(|start| (start, AHashSet::new()))
+        .collect::<BTreeMap<_, _>>();
+    for (hour, nsids) in hours {
+        if let Some(set) = buckets.get_mut(&(hour / bucket * bucket)) {
+            set.extend(nsids);
+        }
+    }
+    let buckets = buckets
+        .into_iter()
+        .map(|(start, nsids)| {
+            let count = nsids.len();
+            let nsids = (params.nsids && count <= MAX_LISTED_NSIDS).then(|| {
+                let mut nsids = nsids.into_iter().collect::<Vec<_>>();
+                nsids.sort_unstable();
+                nsids
+            });
+            ActiveBucket {
+                start,
+                count,
+                nsids,
+            }
+        })
+        .collect();
+    Ok(Json(buckets))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum NsidSort {
+    #[default]
+    Name,
+    // most created records first
+    Count,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct NsidsQuery {
+    #[serde(default)]
+    #[param(value_type = String)]
+    prefix: SmolStr,
+    #[serde(default)]
+    #[param(inline)]
+    sort: NsidSort,
+    /// include each nsid's count
+    #[serde(default)]
+    count: bool,
+    /// only nsids with this label, `key` or `key=value`
+    label: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct NsidEntry {
+    nsid: SmolStr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<u128>,
+}
+
+// the nsids with hit partitions, without reading their counts unless asked
+#[utoipa::path(
+    get,
+    path = "/nsids",
+    tag = "nsids",
+    params(NsidsQuery),
+    responses((status = 200, description = "every nsid with hits"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn nsids(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<NsidsQuery>,
+) -> AppResult<Json<Vec<NsidEntry>>> {
+    let with_counts = params.count || params.sort == NsidSort::Count;
+    let labels = db.all_labels();
+    let mut nsids = db
+        .get_nsids()
+        .filter(|nsid| nsid.starts_with(params.prefix.as_str()))
+        .filter(|nsid| {
+            params.label.as_deref().is_none_or(|filter| {
+                labels
+                    .get(nsid.deref())
+                    .is_some_and(|labels| labels_match(labels, filter))
+            })
+        })
+        .map(|nsid| {
+            let nsid = SmolStr::new(nsid.deref());
+            let count = with_counts
+                .then(|| db.get_count(&nsid))
+                .transpose()?
+                .map(|counts| counts.count);
+            AppResult::Ok(NsidEntry { nsid, count })
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+    match params.sort {
+        NsidSort::Name => nsids.sort_unstable_by(|a, b| a.nsid.cmp(&b.nsid)),
+        NsidSort::Count => {
+            nsids.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.nsid.cmp(&b.nsid)))
+        }
+    }
+    if !params.count {
+        nsids.iter_mut().for_each(|entry| entry.count = None);
+    }
+    Ok(Json(nsids))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct NsidQuery {
+    #[param(value_type = String)]
+    nsid: SmolStr,
+}
+
+// block layout of one nsid, to spot ones that need compacting
+#[utoipa::path(
+    get,
+    path = "/nsid_info",
+    tag = "nsids",
+    params(NsidQuery),
+    responses((status = 200, description = "the block layout of the nsid"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn nsid_info(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<NsidQuery>,
+) -> AppResult<Response> {
+    Ok(Json(nsid_info_of(db, params).await?).into_response())
+}
+
+// /nsid_info without the extractors, /batch runs it too
+async fn nsid_info_of(db: Arc<Db>, params: NsidQuery) -> AppResult<NsidInfo> {
+    let nsid = params.nsid.clone();
+    let info = run_query(move || db.nsid_info(&params.nsid)).await??;
+    info.ok_or_else(|| AppError::new(ErrorCode::NsidNotFound, format!("{nsid} was never seen")))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DidQuery {
+    /// a did on the watchlist
+    #[param(value_type = String)]
+    did: SmolStr,
+}
+
+#[derive(Serialize)]
+struct DidEvents {
+    did: SmolStr,
+    events: AHashMap<SmolStr, NsidCount>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/did_events",
+    tag = "dids",
+    params(DidQuery),
+    responses((status = 200, description = "the counts of a watched did"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn did_events(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<DidQuery>,
+) -> AppResult<Response> {
+    if !db.is_watched(&params.did) {
+        return Err(AppError::new(
+            ErrorCode::DidNotWatched,
+            format!("{} isnt watched", params.did),
+        ));
+    }
+    let events = db
+        .get_did_counts(&params.did)?
+        .into_iter()
+        .map(|(nsid, counts)| (nsid, NsidCount::from(&counts)))
+        .collect();
+    Ok(Json(DidEvents {
+        did: params.did,
+        events,
+    })
+    .into_response())
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DidHitsQuery {
+    /// a did on the watchlist
+    #[param(value_type = String)]
+    did: SmolStr,
+    #[param(value_type = String)]
+    nsid: SmolStr,
+    /// epoch seconds, the newest end of the range, like for /hits
+    from: Option<u64>,
+    /// epoch seconds, the oldest end of the range
+    to: Option<u64>,
+    #[serde(default)]
+    #[param(inline)]
+    kind: HitKind,
+    limit: Option<usize>,
+    /// admin only, lifts the range span limit
+    #[serde(default)]
+    allow_large: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/did_hits",
+    tag = "dids",
+    params(DidHitsQuery),
+    responses((status = 200, description = "hits of a watched did", body = Vec<Hit>), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn did_hits(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<DidHitsQuery>,
+    Extension(query): Extension<HeavyQuery>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    if !db.is_watched(&params.did) {
+        return Err(AppError::new(
+            ErrorCode::DidNotWatched,
+            format!("{} isnt watched", params.did),
+        ));
+    }
+    // same as /hits, `to` is the start of the range
+    let range = hits_range(params.to, params.from, params.allow_large, &headers)?;
+    let limit = hits_limit(params.limit)?;
+    let (hits, truncated) = run_query(move || {
+        let page = db.get_did_hits(&params.did, &params.nsid, range, limit);
+        collect_hits(page, params.kind, limit, &query)
+    })
+    .await??;
+    Ok(with_range_headers(hits_response(hits, truncated), range))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StreamQuery {
+    /// comma separated nsids, or prefixes ending in `*`
+    nsids: Option<String>,
+    /// include sparklines in snapshot frames
+    #[serde(default)]
+    sparklines: bool,
+    /// include labels in snapshot frames, and send label changes as they are
+    /// made
+    #[serde(default)]
+    labels: bool,
+    #[serde(default)]
+    #[param(inline)]
+    mode: StreamMode,
+    /// websocket only, the sse stream is always json
+    #[serde(default)]
+    #[param(inline)]
+    format: StreamFormat,
+    /// send what changed every this many milliseconds (at least 100)
+    /// instead of pacing frames by the event rate
+    interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    #[default]
+    Json,
+    // frames of plain counts as binary frames, see `events_bin`
+    Bin,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum StreamMode {
+    // every frame has the counts of the nsids in it
+    #[default]
+    Absolute,
+    // frames after a snapshot only have `NsidDelta`s
+    Delta,
+}
+
+impl StreamQuery {
+    fn filter(&self) -> NsidFilter {
+        NsidFilter::new(self.nsids.as_deref().unwrap_or("").split(','))
+    }
+
+    // the flush interval the client asked for, clamped to what we allow
+    fn interval(&self) -> Option<Duration> {
+        self.interval_ms
+            .map(|ms| Duration::from_millis(ms).clamp(MIN_STREAM_INTERVAL, MAX_STREAM_INTERVAL))
+    }
+}
+
+// bounds of the interval_ms of stream_events
+const MIN_STREAM_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_STREAM_INTERVAL: Duration = Duration::from_secs(60);
+// an idle stream gets an empty frame this often, so proxies dont drop it
+const STREAM_HEARTBEAT: Duration = Duration::from_secs(15);
+
+// the nsids a stream_events client wants. patterns ending in `*` (like
+// `app.bsky.*`) match by prefix, no patterns at all matches everything
+#[derive(Debug, Default, Clone)]
+struct NsidFilter {
+    exact: AHashSet<SmolStr>,
+    prefixes: Vec<SmolStr>,
+}
+
+impl NsidFilter {
+    fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut filter = Self::default();
+        for pattern in patterns.into_iter().map(str::trim) {
+            if pattern.is_empty() {
+                continue;
+            }
+            match pattern.strip_suffix('*') {
+                Some(prefix) => filter.prefixes.push(SmolStr::new(prefix)),
+                None => {
+                    filter.exact.insert(SmolStr::new(pattern));
+                }
+            }
+        }
+        filter
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.prefixes.is_empty()
+    }
+
+    fn matches(&self, nsid: &str) -> bool {
+        self.is_empty()
+            || self.exact.contains(nsid)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| nsid.starts_with(prefix.as_str()))
+    }
+}
+
+// clients send this to change their filter, an empty list is everything
+#[derive(Debug, Deserialize)]
+struct Subscribe {
+    nsids: Vec<SmolStr>,
+}
+
+// the current counts of every nsid the filter matches, so clients dont have
+// to wait for an nsid to be seen again before they can show it
+async fn counts_snapshot(
+    db: Arc<Db>,
+    filter: NsidFilter,
+    sparklines: bool,
+    labels: bool,
+) -> AppResult<Events> {
+    let per_second = db.eps();
+    let now = get_time().as_secs();
+    let events = run_query(move || {
+        let all_labels = labels.then(|| db.all_labels());
+        db.get_counts()
+            .filter_map(|result| match result {
+                Ok(row) => Some(row),
+                Err(err) => {
+                    tracing::error!("skipping counts row: {err}");
+                    None
+                }
+            })
+            .filter(|(nsid, _)| filter.matches(nsid))
+            .map(|(nsid, counts)| {
+                let sparkline = sparklines
+                    .then(|| db.sparkline(&nsid, now))
+                    .and_then(|res| {
+                        res.inspect_err(|err| {
+                            tracing::error!("cant build sparkline for {nsid}: {err}")
+                        })
+                        .ok()
+                    })
+                    .flatten();
+                let labels = all_labels
+                    .as_ref()
+                    .and_then(|labels| labels.get(&nsid).cloned());
+                let count = NsidCount {
+                    sparkline,
+                    labels,
+                    ..NsidCount::from(&counts)
+                };
+                (nsid, count)
+            })
+            .collect::<AHashMap<_, _>>()
+    })
+    .await?;
+    Ok(Events {
+        per_second,
+        totals: None,
+        events,
+        server: None,
+        snapshot: true,
+        labels: AHashMap::new(),
+        deltas: AHashMap::new(),
+    })
+}
+
+// the frame as it goes out, encoded before the socket is touched
+fn events_message(events: &Events, format: StreamFormat) -> Option<Message> {
+    let binary = match format {
+        StreamFormat::Json => None,
+        StreamFormat::Bin => events_bin::encode(events),
+    };
+    let msg = match binary {
+        Some(bytes) => bytes.map(Message::binary).map_err(AppError::from),
+        None => serde_json::to_string(events)
+            .map(Message::text)
+            .map_err(AppError::from),
+    };
+    msg.inspect_err(|err| tracing::error!("cant serialize events: {err}"))
+        .ok()
+}
+
+// false if the frame didnt make it to the client
+async fn send_events(
+    socket: &mut WebSocket,
+    conn: &ws::Connection,
+    events: &Events,
+    format: StreamFormat,
+) -> bool {
+    let Some(msg) = events_message(events, format) else {
+        return false;
+    };
+    conn.send(socket, msg).await
+}
+
+// the next label change of an nsid the client wants, never returns if the
+// client didnt ask for labels
+async fn next_label_change(
+    changes: &mut Option<broadcast::Receiver<(SmolStr, LabelMap)>>,
+    filter: &NsidFilter,
+) -> (SmolStr, LabelMap) {
+    let Some(changes) = changes.as_mut() else {
+        return std::future::pending().await;
+    };
+    loop {
+        match changes.recv().await {
+            Ok((nsid, labels)) if filter.matches(&nsid) => return (nsid, labels),
+            Ok(_) => {}
+            // labels are changed by hand, there arent enough to lag on
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+// ticks of the flush interval of the client, never if it didnt ask for one
+async fn next_tick(tick: &mut Option<tokio::time::Interval>) {
+    match tick {
+        Some(tick) => {
+            tick.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// the updates of one stream_events client, coalesced into frames. shared by
+// the websocket and the sse stream
+struct EventsStream {
+    db: Arc<Db>,
+    listener: EventListener,
+    filter: NsidFilter,
+    sparklines: bool,
+    // None unless the client asked for labels
+    label_changes: Option<broadcast::Receiver<(SmolStr, LabelMap)>>,
+    pending: AHashMap<SmolStr, NsidCount>,
+    mode: StreamMode,
+    // with mode=delta, the count, deleted and purged count of every nsid
+    // as the client last got them
+    sent: AHashMap<SmolStr, (u128, u128, u128)>,
+    updates: usize,
+    // updates that got through the filter
+    filtered: RateTracker<100>,
+    // None unless the client asked for an interval, frames are paced by the
+    // event rate then
+    tick: Option<tokio::time::Interval>,
+    // when the client was last sent a frame, for the heartbeat
+    last_frame: tokio::time::Instant,
+}
+
+impl EventsStream {
+    fn new(db: Arc<Db>, params: &StreamQuery) -> Self {
+        Self {
+            // listen before any snapshot is taken so no update falls between
+            listener: db.new_listener(),
+            label_changes: params.labels.then(|| db.label_changes()),
+            db,
+            filter: params.filter(),
+            sparklines: params.sparklines,
+            pending: AHashMap::with_capacity(10),
+            mode: params.mode,
+            sent: AHashMap::new(),
+            updates: 0,
+            filtered: RateTracker::new(Duration::from_secs(1)),
+            tick: params.interval().map(|period| {
+                let start = tokio::time::Instant::now() + period;
+                let mut tick = tokio::time::interval_at(start, period);
+                tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                tick
+            }),
+            last_frame: tokio::time::Instant::now(),
+        }
+    }
+
+    // a frame with nothing in it but the rate, for updates and heartbeats
+    fn frame(&self) -> Events {
+        Events {
+            events: AHashMap::new(),
+            per_second: self.db.eps(),
+            totals: None,
+            server: None,
+            snapshot: false,
+            labels: AHashMap::new(),
+            deltas: AHashMap::new(),
+        }
+    }
+
+    // lets clients know which build they are talking to
+    fn hello(&self) -> Events {
+        Events {
+            events: AHashMap::new(),
+            per_second: self.db.eps(),
+            totals: Some(self.db.totals()),
+            server: Some(BuildInfo::get()),
+            snapshot: false,
+            labels: AHashMap::new(),
+            deltas: AHashMap::new(),
+        }
+    }
+
+    async fn snapshot(&mut self) -> Events {
+        let labels = self.label_changes.is_some();
+        self.last_frame = tokio::time::Instant::now();
+        let snapshot = match counts_snapshot(
+            self.db.clone(),
+            self.filter.clone(),
+            self.sparklines,
+            labels,
+        )
+        .await
+        {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                // updates still flow, the client just starts out empty
+                tracing::error!("cant build counts snapshot: {err}");
+                Events {
+                    events: AHashMap::new(),
+                    per_second: self.db.eps(),
+                    totals: None,
+                    server: None,
+                    snapshot: true,
+                    labels: AHashMap::new(),
+                    deltas: AHashMap::new(),
+                }
+            }
+        };
+        if self.mode == StreamMode::Delta {
+            self.sent = snapshot
+                .events
+                .iter()
+                .map(|(nsid, count)| {
+                    let sent = (count.count, count.deleted_count, count.purged_count);
+                    (nsid.clone(), sent)
+                })
+                .collect();
+        }
+        snapshot
+    }
+
+    // what changed since the counts the client was last sent
+    fn deltas(&mut self, pending: AHashMap<SmolStr, NsidCount>) -> AHashMap<SmolStr, NsidDelta> {
+        let mut deltas = AHashMap::with_capacity(pending.len());
+        for (nsid, count) in pending {
+            let sent = self.sent.get(&nsid).copied().unwrap_or_default();
+            let Some(delta) = NsidDelta::between(sent, &count) else {
+                continue;
+            };
+            self.sent.insert(
+                nsid.clone(),
+                (count.count, count.deleted_count, count.purged_count),
+            );
+            deltas.insert(nsid, delta);
+        }
+        deltas
+    }
+
+    // drops what is pending, the snapshot clients get next covers it
+    fn set_filter(&mut self, filter: NsidFilter) {
+        self.filter = filter;
+        self.pending.clear();
+        self.updates = 0;
+    }
+
+    /// the next frame of updates, None once the db stops broadcasting
+    async fn next(&mut self) -> Option<Events> {
+        let frame = self.next_frame().await;
+        self.last_frame = tokio::time::Instant::now();
+        frame
+    }
+
+    async fn next_frame(&mut self) -> Option<Events> {
+        loop {
+            let heartbeat = tokio::time::sleep_until(self.last_frame + STREAM_HEARTBEAT);
+            let (nsid, counts) = tokio::select! {
+                update = self.listener.recv() => update?,
+                (nsid, labels) = next_label_change(&mut self.label_changes, &self.filter) => {
+                    // sent right away, these are rare
+                    return Some(Events {
+                        labels: AHashMap::from_iter([(nsid, labels)]),
+                        ..self.frame()
+                    });
+                }
+                _ = next_tick(&mut self.tick) => match self.flush() {
+                    Some(frame) => return Some(frame),
+                    None => continue,
+                },
+                _ = heartbeat => return Some(self.frame()),
+            };
+            if !self.filter.matches(&nsid) {
+                continue;
+            }
+            self.pending.insert(nsid, NsidCount::from(&counts));
+            if self.tick.is_some() {
+                continue;
+            }
+            self.updates += 1;
+            self.filtered.observe(1);
+            // send 16 times every second max, paced by what this client gets
+            // so filtered streams arent held back
+            let rate = if self.filter.is_empty() {
+                self.db.eps()
+            } else {
+                self.filtered.rate() as usize
+            };
+            if self.updates < rate / 16 {
+                continue;
+            }
+            if let Some(frame) = self.flush() {
+                return Some(frame);
+            }
+        }
+    }
+
+    // what is pending as a frame, None if nothing changed for the client
+    fn flush(&mut self) -> Option<Events> {
+        self.updates = 0;
+        if self.pending.is_empty() {
+            return None;
+        }
+        let pending = std::mem::replace(&mut self.pending, AHashMap::with_capacity(10));
+        let (events, deltas) = match self.mode {
+            StreamMode::Absolute => (pending, AHashMap::new()),
+            StreamMode::Delta => (AHashMap::new(), self.deltas(pending)),
+        };
+        if self.mode == StreamMode::Delta && deltas.is_empty() {
+            return None;
+        }
+        Some(Events {
+            events,
+            deltas,
+            ..self.frame()
+        })
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/stream_events",
+    tag = "streams",
+    params(StreamQuery),
+    responses((status = 101, description = "a websocket of `Events` frames"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn stream_events(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<StreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let span = tracing::info_span!(parent: Span::current(), "ws");
+    ws.on_upgrade(move |mut socket| {
+        (async move {
+            let mut conn = ws::Connection::open();
+            let mut stream = EventsStream::new(db, &params);
+            let format = params.format;
+            if !send_events(&mut socket, &conn, &stream.hello(), format).await {
+                return;
+            }
+            if !send_events(&mut socket, &conn, &stream.snapshot().await, format).await {
+                return;
+            }
+            loop {
+                tokio::select! {
+                    frame = stream.next() => {
+                        let Some(frame) = frame else {
+                            break;
+                        };
+                        if !send_events(&mut socket, &conn, &frame, format).await {
+                            break;
+                        }
+                    }
+                    alive = conn.ping_due() => {
+                        if !alive || !conn.ping(&mut socket).await {
+                            break;
+                        }
+                    }
+                    msg = socket.recv() => {
+                        let Some(Ok(msg)) = msg else {
+                            break;
+                        };
+                        conn.heard();
+                        if msg.is_close() {
+                            break;
+                        }
+                        let Some(text) = msg.as_text() else {
+                            continue;
+                        };
+                        match serde_json::from_str::<Subscribe>(text) {
+                            Ok(subscribe) => {
+                                stream.set_filter(NsidFilter::new(subscribe.nsids.iter().map(SmolStr::as_str)));
+                                if !send_events(&mut socket, &conn, &stream.snapshot().await, format).await {
+                                    break;
+                                }
+                            }
+                            Err(err) => tracing::debug!("ignoring client message: {err}"),
+                        }
+                    }
+                }
+            }
+        })
+        .instrument(span)
+    })
+}
+
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// stream_events for clients that cant use websockets, every frame is the
+// same json in a `data:` field. a client reconnecting with Last-Event-ID
+// gets a fresh snapshot but no hello frame
+#[utoipa::path(
+    get,
+    path = "/stream_events.sse",
+    tag = "streams",
+    params(StreamQuery),
+    responses((status = 200, description = "`Events` frames as server sent events", content_type = "text/event-stream"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn stream_events_sse(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let reconnect = headers.contains_key("last-event-id");
+    let span = tracing::info_span!(parent: Span::current(), "sse");
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(
+        async move {
+            let mut stream = EventsStream::new(db, &params);
+            let mut id = 0u64;
+            let mut event = |events: &Events| {
+                id += 1;
+                sse::Event::default().id(id.to_string()).json_data(events)
+            };
+            if !reconnect && tx.send(event(&stream.hello())).await.is_err() {
+                return;
+            }
+            if tx.send(event(&stream.snapshot().await)).await.is_err() {
+                return;
+            }
+            loop {
+                tokio::select! {
+                    frame = stream.next() => {
+                        let Some(frame) = frame else {
+                            break;
+                        };
+                        if tx.send(event(&frame)).await.is_err() {
+                            break;
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        }
+        .instrument(span),
+    );
+    let events = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (event, rx))
+    });
+    Sse::new(events).keep_alive(KeepAlive::new().interval(SSE_KEEPALIVE).text("keepalive"))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Since {
+    /// epoch seconds
+    since: u64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SinceQuery {
+    /// when this nsid was first seen instead of the oldest of all of them
+    #[param(value_type = Option<String>)]
+    nsid: Option<SmolStr>,
+}
+
+// 0 if nothing was stored yet
+#[utoipa::path(
+    get,
+    path = "/since",
+    tag = "counts",
+    params(SinceQuery),
+    responses((status = 200, description = "when tracking started", body = Since), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn since(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<SinceQuery>,
+) -> AppResult<Json<Since>> {
+    since_of(db, params).await.map(Json)
+}
+
+// /since without the extractors, /batch runs it too
+async fn since_of(db: Arc<Db>, params: SinceQuery) -> AppResult<Since> {
+    let since = run_query(move || match params.nsid {
+        Some(nsid) if !db.has_nsid(&nsid) => Err(AppError::new(
+            ErrorCode::NsidNotFound,
+            format!("{nsid} was never seen"),
+        )),
+        Some(nsid) => db.nsid_since(&nsid).map(Option::unwrap_or_default),
+        None => db.tracking_since(),
+    })
+    .await??;
+    Ok(Since { since })
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorCodeInfo {
+    code: ErrorCode,
+    status: u16,
+    description: &'static str,
+}
+
+// every `code` an error body can have
+#[utoipa::path(
+    get,
+    path = "/error_codes",
+    tag = "status",
+    responses((status = 200, description = "every code an error body can have", body = Vec<ErrorCodeInfo>))
+)]
+async fn error_codes() -> Json<Vec<ErrorCodeInfo>> {
+    Json(
+        ErrorCode::ALL
+            .iter()
+            .map(|&code| ErrorCodeInfo {
+                code,
+                status: code.kind().status().as_u16(),
+                description: code.description(),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct Alerts {
+    firing: Vec<Alert>,
+    // resolved within the last day
+    resolved: Vec<Alert>,
+}
+
+// alerts raised by the rules set up through the admin api, newest first
+#[utoipa::path(
+    get,
+    path = "/alerts",
+    tag = "status",
+    responses((status = 200, description = "firing and resolved alerts"))
+)]
+async fn alerts(State(db): State<Arc<Db>>) -> Json<Alerts> {
+    let (firing, resolved) = db.alerts().list(get_time().as_secs());
+    Json(Alerts { firing, resolved })
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct NsidRate {
+    #[schema(value_type = String)]
+    nsid: SmolStr,
+    per_second: f64,
+    /// how far back `per_second` looks
+    window_secs: u64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EpsQuery {
+    #[param(value_type = String)]
+    nsid: SmolStr,
+}
+
+// live rate of one nsid, 0 for ones that didnt get events since we started
+#[utoipa::path(
+    get,
+    path = "/eps",
+    tag = "counts",
+    params(EpsQuery),
+    responses((status = 200, description = "the live rate of the nsid", body = NsidRate), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn eps(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<EpsQuery>,
+) -> AppResult<Json<NsidRate>> {
+    eps_of(&db, params).map(Json)
+}
+
+// /eps without the extractors, /batch runs it too
+fn eps_of(db: &Db, params: EpsQuery) -> AppResult<NsidRate> {
+    if !is_valid_nsid(&params.nsid) {
+        return Err(AppError::new(
+            ErrorCode::InvalidNsid,
+            format!("{} isnt a valid nsid", params.nsid),
+        ));
+    }
+    Ok(NsidRate {
+        per_second: db.nsid_rate(&params.nsid),
+        window_secs: RATE_WINDOW_SECS,
+        nsid: params.nsid,
+    })
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Status {
+    per_second: usize,
+    totals: Totals,
+    /// epoch seconds
+    since: u64,
+    /// seconds hit timestamps are rounded to, 1 unless privacy mode is on
+    timestamp_granularity: u64,
+}
+
+// headline numbers for the dashboard, without reading every nsid's counts
+#[utoipa::path(
+    get,
+    path = "/status.json",
+    tag = "status",
+    responses((status = 200, description = "headline numbers", body = Status), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn status(db: State<Arc<Db>>) -> AppResult<Json<Status>> {
+    Ok(Json(Status {
+        per_second: db.eps(),
+        totals: db.totals(),
+        since: db.tracking_since()?,
+        timestamp_granularity: db.timestamp_granularity(),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    storage: StorageState,
+    write_errors: usize,
+    ingest: IngestState,
+    quiesce: QuiesceState,
+    broadcast: BroadcastStatus,
+    // false if the previous run didnt shut down cleanly
+    clean_start: bool,
+    // panics since we started, see `install_panic_hook`
+    panics: u64,
+    // jetstream events of kinds we dont know since we started
+    unknown_event_kinds: u64,
+    // jetstream frames we couldnt parse and skipped since we started
+    malformed_frames: u64,
+    // redelivered events dropped before ingest, see `DedupWindow`
+    duplicates_dropped: u64,
+    // open stream_events websockets over every instance, see `ws`
+    websockets: usize,
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "status",
+    responses((status = 200, description = "storage is healthy"), (status = 503, description = "storage is degraded"))
+)]
+async fn healthz(db: State<Arc<Db>>) -> (StatusCode, Json<Health>) {
+    let storage = db.storage_state();
+    let status = match storage {
+        StorageState::Healthy => StatusCode::OK,
+        StorageState::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        Json(Health {
+            storage,
+            write_errors: db.write_errors(),
+            ingest: db.ingest_state(),
+            quiesce: db.quiesce_state(),
+            broadcast: db.broadcast_status(),
+            clean_start: db.is_clean_start(),
+            panics: panic_count(),
+            unknown_event_kinds: unknown_kind_count(),
+            malformed_frames: malformed_frame_count(),
+            duplicates_dropped: db.duplicates_dropped(),
+            websockets: ws::live_connections(),
+        }),
+    )
+}
+
+// seconds without ingesting anything before /health reports 503,
+// `HEALTH_MAX_EVENT_AGE`
+const DEFAULT_HEALTH_MAX_EVENT_AGE: u64 = 60;
+
+fn health_max_event_age() -> u64 {
+    config::env_or(
+        "HEALTH_MAX_EVENT_AGE",
+        DEFAULT_HEALTH_MAX_EVENT_AGE,
+        |max| max.parse::<u64>().ok().filter(|max| *max > 0),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct IngestHealth {
+    jetstream_connected: bool,
+    // seconds, None until the first event since we started
+    last_event_age: Option<u64>,
+    per_second: usize,
+    open_partitions: usize,
+    // nsids that share `_longtail` instead of having a partition
+    longtail_nsids: usize,
+    buffered_items: usize,
+    disk_size: u64,
+    shutting_down: bool,
+}
+
+// for load balancers and uptime checks, unlike /healthz this is about whether
+// we are still ingesting. before the first event the time since the db was
+// opened counts as the age
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "status",
+    responses((status = 200, description = "events are coming in"), (status = 503, description = "no recent events, or shutting down"))
+)]
+async fn health(db: State<Arc<Db>>) -> (StatusCode, Json<IngestHealth>) {
+    let now = get_time().as_secs();
+    let last_event_age = db.last_event_at().map(|at| now.saturating_sub(at));
+    let age = last_event_age.unwrap_or_else(|| now.saturating_sub(db.opened_at()));
+    let shutting_down = db.is_shutting_down();
+    let status = if shutting_down || age > health_max_event_age() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (
+        status,
+        Json(IngestHealth {
+            jetstream_connected: db.upstream().is_connected(),
+            last_event_age,
+            per_second: db.eps(),
+            open_partitions: db.open_partitions(),
+            longtail_nsids: db.longtail_nsids(),
+            buffered_items: db.buffered_items(),
+            disk_size: db.disk_size(),
+            shutting_down,
+        }),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct Runtime {
+    // None until the maintenance task picked its first interval
+    sync: Option<SyncPaceStatus>,
+    buffered_items: usize,
+    per_second: usize,
+    // lookups of nsids that dont exist
+    negative_cache: NegativeCacheStats,
+    // decoded blocks, shared by every instance
+    block_cache: BlockCacheStats,
+}
+
+// what the maintenance task bases its decisions on
+#[utoipa::path(
+    get,
+    path = "/debug/runtime",
+    tag = "status",
+    responses((status = 200, description = "what the maintenance task sees"))
+)]
+async fn debug_runtime(db: State<Arc<Db>>) -> Json<Runtime> {
+    Json(Runtime {
+        sync: db.sync_pace(),
+        buffered_items: db.buffered_items(),
+        per_second: db.eps(),
+        negative_cache: db.negative_cache_stats(),
+        block_cache: block_cache().stats(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotDebug {
+    // what the last quiesce recorded in `_meta`
+    marker: Option<SnapshotMarker>,
+    // the marker file against the journal, see `check_snapshot_dir`
+    files: SnapshotCheck,
+    quiesce: QuiesceState,
+    sync_generation: u64,
+    cursor: Option<u64>,
+}
+
+// whether a filesystem snapshot of the data dir taken now would be a well
+// defined point in time, and which one
+#[utoipa::path(
+    get,
+    path = "/debug/snapshot",
+    tag = "status",
+    responses((status = 200, description = "whether a snapshot taken now is consistent"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn debug_snapshot(State(db): State<Arc<Db>>) -> AppResult<Json<SnapshotDebug>> {
+    tokio::task::spawn_blocking(move || {
+        AppResult::Ok(Json(SnapshotDebug {
+            marker: db.snapshot_marker()?,
+            files: db.check_snapshot()?,
+            quiesce: db.quiesce_state(),
+            sync_generation: db.sync_generation(),
+            cursor: db.stored_cursor()?,
+        }))
+    })
+    .await?
+}
+
+#[derive(Debug, Serialize)]
+struct Version {
+    #[serde(flatten)]
+    build: &'static BuildInfo,
+    schema_version: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "status",
+    responses((status = 200, description = "build and schema version"), (status = "4XX", description = "see /error_codes", body = ErrorBody))
+)]
+async fn version(db: State<Arc<Db>>) -> AppResult<Json<Version>> {
+    Ok(Json(Version {
+        build: BuildInfo::get(),
+        schema_version: db.schema_version()?,
+    }))
+}
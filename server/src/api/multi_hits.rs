@@ -0,0 +1,261 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use axum::{
+    Json,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use rclite::Arc;
+use serde::Serialize;
+use smol_str::SmolStr;
+
+use crate::{
+    api::{
+        DEFAULT_HITS_LIMIT, Hit, HitKind, HitsFormat, HitsQuery, HitsRange, admission,
+        heavy::HeavyQuery, hits_limit, hits_range, json_hits, pool::run_query, with_range_headers,
+    },
+    db::{Db, HitOp, Item, is_valid_nsid},
+    error::{AppError, AppResult, ErrorCode},
+};
+
+// at most this many nsids in one /hits (or /counts_range) request
+const MAX_NSIDS: usize = 10;
+
+/// the nsids of a comma separated `nsid`, in order and without repeats
+pub(super) fn parse_nsids(nsid: &str) -> AppResult<Vec<SmolStr>> {
+    let mut nsids = Vec::<SmolStr>::new();
+    for nsid in nsid.split(',').map(str::trim) {
+        if nsid.is_empty() {
+            return Err(AppError::new(
+                ErrorCode::InvalidNsid,
+                "empty nsid in the list",
+            ));
+        }
+        if !nsids.iter().any(|seen| seen == nsid) {
+            nsids.push(SmolStr::new(nsid));
+        }
+    }
+    if nsids.len() > MAX_NSIDS {
+        return Err(AppError::bad_request(format!(
+            "at most {MAX_NSIDS} nsids can be asked for at once"
+        )));
+    }
+    Ok(nsids)
+}
+
+// why the hits of `nsid` cant be read at all, like /hits with just it would
+// fail
+pub(super) fn unreadable(db: &Db, nsid: &str) -> Option<String> {
+    if !is_valid_nsid(nsid) {
+        return Some(format!("{nsid} isnt a valid nsid"));
+    }
+    if !db.has_nsid(nsid) {
+        return Some(format!("no hits were ever seen for {nsid}"));
+    }
+    None
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum NsidHits {
+    // the same as /hits of just this nsid, with `truncated` instead of the
+    // header
+    Hits { hits: Vec<Hit>, truncated: bool },
+    Error { error: String },
+}
+
+#[derive(Debug, Serialize)]
+struct MergedHit {
+    nsid: SmolStr,
+    #[serde(flatten)]
+    hit: Hit,
+}
+
+#[derive(Debug, Serialize)]
+struct MergedHits {
+    // the newest `limit` hits of all nsids together, oldest first
+    hits: Vec<MergedHit>,
+    // whether older hits in the range were left out
+    truncated: bool,
+    // nsids whose hits couldnt be read (or not all of them)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    errors: BTreeMap<SmolStr, String>,
+}
+
+/// /hits with a comma separated list of nsids. without `merge` the hits are
+/// returned per nsid, like separate requests would, with `merge` they are
+/// merged into one list. an nsid that cant be read gets an error in the
+/// payload instead of failing the rest
+pub async fn multi_hits(
+    db: Arc<Db>,
+    params: HitsQuery,
+    query: HeavyQuery,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let nsids = parse_nsids(&params.nsid)?;
+    if params.debug
+        || params.format != HitsFormat::Json
+        || params.width().is_some()
+        || params.is_sampled()
+    {
+        return Err(AppError::bad_request(
+            "several nsids only work with format=json, without debug, resolution, step or sampling",
+        ));
+    }
+    let range = hits_range(params.to, params.from, params.allow_large, &headers)?;
+    let limit = hits_limit(params.limit)?;
+    let admission = admission(&params);
+    let (kind, merge) = (params.kind, params.merge);
+    let res = run_query(move || {
+        if merge {
+            let merged = merged_hits(&db, &nsids, range, limit, kind, &query)?;
+            return AppResult::Ok(Json(merged).into_response());
+        }
+        let mut by_nsid = BTreeMap::new();
+        for nsid in nsids {
+            let hits = match unreadable(&db, &nsid) {
+                Some(error) => NsidHits::Error { error },
+                None => match json_hits(&db, &nsid, range, limit, kind, admission, &query) {
+                    Ok((hits, truncated)) => NsidHits::Hits { hits, truncated },
+                    // the whole request ran too long, not just this nsid
+                    Err(err) if err.is_cancelled() => return Err(err),
+                    Err(err) => NsidHits::Error {
+                        error: err.to_string(),
+                    },
+                },
+            };
+            by_nsid.insert(nsid, hits);
+        }
+        Ok(Json(by_nsid).into_response())
+    })
+    .await??;
+    Ok(with_range_headers(res, range))
+}
+
+// the hits of one nsid newest first, with the next one of `kind` up front
+struct Stream<I> {
+    nsid: SmolStr,
+    hits: I,
+    head: Option<(u64, HitOp)>,
+    taken: usize,
+}
+
+impl<I: Iterator<Item = AppResult<Item>>> Stream<I> {
+    fn advance(&mut self, kind: HitKind) -> AppResult<()> {
+        self.head = None;
+        for hit in self.hits.by_ref() {
+            let hit = hit?;
+            let op = hit.deser()?.op;
+            if kind.matches(op) {
+                self.head = Some((hit.timestamp, op));
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// a k-way merge of the nsids read newest first: the newest head of them all
+// is taken next until `limit` hits are. ties go to the nsid that got the
+// fewest hits in so far, so one busy nsid cant crowd the others out of the
+// limit. there are only a few nsids, so the heads are just scanned
+fn merged_hits(
+    db: &Db,
+    nsids: &[SmolStr],
+    range: HitsRange,
+    limit: usize,
+    kind: HitKind,
+    query: &HeavyQuery,
+) -> AppResult<MergedHits> {
+    let mut errors = BTreeMap::new();
+    let mut snapshots = Vec::with_capacity(nsids.len());
+    for nsid in nsids {
+        if let Some(error) = unreadable(db, nsid) {
+            errors.insert(nsid.clone(), error);
+            continue;
+        }
+        if let Some(snapshot) = db.pin_snapshot(nsid) {
+            snapshots.push(snapshot);
+        }
+    }
+    let mut streams = snapshots
+        .iter()
+        .map(|snapshot| Stream {
+            nsid: snapshot.nsid().clone(),
+            hits: db.hits_newest_first(snapshot, range),
+            head: None,
+            taken: 0,
+        })
+        .collect::<Vec<_>>();
+    let mut failed = |stream: &mut Stream<_>, err: AppError| {
+        if err.is_cancelled() {
+            return Err(err);
+        }
+        stream.head = None;
+        errors.insert(stream.nsid.clone(), err.to_string());
+        Ok(())
+    };
+    for stream in &mut streams {
+        if let Err(err) = stream.advance(kind) {
+            failed(stream, err)?;
+        }
+    }
+
+    let mut hits = Vec::with_capacity(limit.min(DEFAULT_HITS_LIMIT));
+    let mut truncated = false;
+    loop {
+        query.check()?;
+        let newest = streams
+            .iter()
+            .enumerate()
+            .filter_map(|(i, stream)| {
+                let (timestamp, _) = stream.head?;
+                Some((timestamp, Reverse(stream.taken), Reverse(i)))
+            })
+            .max();
+        let Some((_, _, Reverse(i))) = newest else {
+            break;
+        };
+        if hits.len() >= limit {
+            truncated = true;
+            break;
+        }
+        let stream = &mut streams[i];
+        let Some((timestamp, op)) = stream.head else {
+            break;
+        };
+        hits.push(MergedHit {
+            nsid: stream.nsid.clone(),
+            hit: Hit::new(timestamp, op),
+        });
+        stream.taken += 1;
+        if let Err(err) = stream.advance(kind) {
+            failed(stream, err)?;
+        }
+    }
+    hits.reverse();
+    Ok(MergedHits {
+        hits,
+        truncated,
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_nsids() {
+        let nsids = parse_nsids("app.bsky.feed.like, app.bsky.feed.repost,app.bsky.feed.like");
+        assert_eq!(
+            nsids.unwrap(),
+            ["app.bsky.feed.like", "app.bsky.feed.repost"]
+        );
+        assert!(parse_nsids("app.bsky.feed.like,").is_err());
+        let many = (0..=MAX_NSIDS)
+            .map(|i| format!("a.b.c{i}"))
+            .collect::<Vec<_>>();
+        assert!(parse_nsids(&many.join(",")).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use tracing::Span;
+
+use crate::{config, error::AppResult};
+
+// blocking query work (decoding blocks, scanning counts) runs on a pool of
+// its own with `QUERY_THREADS` threads, instead of on tokio's blocking
+// threads (which there are hundreds of). however many queries come in they
+// dont take more than that many cores, so the ingest thread and the rayon
+// pool sync and compaction run on keep theirs
+fn query_threads() -> usize {
+    let cores = std::thread::available_parallelism().map_or(4, |cores| cores.get());
+    config::env_or("QUERY_THREADS", (cores / 2).max(2), |threads| {
+        threads.parse::<usize>().ok().filter(|threads| *threads > 0)
+    })
+}
+
+fn query_pool() -> &'static threadpool::ThreadPool {
+    static POOL: OnceLock<threadpool::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        threadpool::Builder::new()
+            .num_threads(query_threads())
+            .thread_name("query".to_owned())
+            .build()
+    })
+}
+
+// reads the settings, which otherwise happens on the first query
+pub fn init() {
+    query_pool();
+}
+
+/// runs `f` on the query pool, like `spawn_blocking`. it waits for a free
+/// thread if all of them are busy
+pub async fn run_query<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> AppResult<T> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let span = Span::current();
+    query_pool().execute(move || {
+        let _entered = span.entered();
+        let _ = tx.send(f());
+    });
+    // the sender is dropped without sending if `f` panicked
+    rx.await.map_err(|_| anyhow!("query panicked").into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queries_dont_take_more_than_the_pool() {
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static MOST: AtomicUsize = AtomicUsize::new(0);
+        let queries = (0..query_threads() * 4).map(|_| {
+            run_query(|| {
+                let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+                MOST.fetch_max(running, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(10));
+                RUNNING.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        for result in futures_util::future::join_all(queries).await {
+            result.unwrap();
+        }
+        assert!(MOST.load(Ordering::SeqCst) <= query_threads());
+
+        let panicked = run_query(|| -> u32 { panic!("boom") }).await;
+        assert!(panicked.is_err());
+        // the pool replaces the thread that panicked
+        assert_eq!(run_query(|| 1).await.unwrap(), 1);
+    }
+}
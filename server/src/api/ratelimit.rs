@@ -0,0 +1,224 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use axum::{
+    Router,
+    extract::{ConnectInfo, Request, State},
+    http::header::RETRY_AFTER,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use rclite::Arc;
+
+use crate::{
+    api::admin,
+    config,
+    error::{AppError, ErrorCode},
+    utils::DefaultRateTracker,
+};
+
+const DEFAULT_REQUESTS_PER_MINUTE: u64 = 600;
+const DEFAULT_HITS_PER_MINUTE: u64 = 60;
+const DEFAULT_MAX_CLIENTS: usize = 10_000;
+const WINDOW: Duration = Duration::from_secs(60);
+
+fn env_limit(key: &str, default: u64) -> u64 {
+    config::env_or(key, default, |limit| {
+        limit.parse::<u64>().ok().filter(|limit| *limit > 0)
+    })
+}
+
+/// which budget of a client a request is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Requests,
+    // raw hits reads, they can cost a lot more disk than anything else
+    Hits,
+}
+
+impl Budget {
+    fn of(path: &str) -> Self {
+        if path.ends_with("/hits") || path.ends_with("/did_hits") {
+            Self::Hits
+        } else {
+            Self::Requests
+        }
+    }
+}
+
+struct Client {
+    requests: DefaultRateTracker,
+    hits: DefaultRateTracker,
+}
+
+#[derive(Default)]
+struct Clients {
+    by_ip: AHashMap<IpAddr, (u64, Arc<Client>)>,
+    // last use -> ip, least recently used first
+    lru: BTreeMap<u64, IpAddr>,
+    tick: u64,
+}
+
+// requests per minute per client ip, the ip is taken from `x-real-ip` (set by
+// our proxy) or the peer address. websocket and sse streams count as one
+// request when they are opened. at most `max_clients` are tracked, the least
+// recently seen are forgotten first
+pub struct RateLimiter {
+    requests_per_minute: u64,
+    hits_per_minute: u64,
+    max_clients: usize,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u64, hits_per_minute: u64, max_clients: usize) -> Self {
+        Self {
+            requests_per_minute,
+            hits_per_minute,
+            max_clients: max_clients.max(1),
+            clients: Default::default(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            env_limit("RATE_LIMIT_PER_MINUTE", DEFAULT_REQUESTS_PER_MINUTE),
+            env_limit("HITS_RATE_LIMIT_PER_MINUTE", DEFAULT_HITS_PER_MINUTE),
+            env_limit("RATE_LIMIT_MAX_CLIENTS", DEFAULT_MAX_CLIENTS as u64) as usize,
+        )
+    }
+
+    fn client(&self, ip: IpAddr) -> Arc<Client> {
+        let mut clients = self.clients.lock();
+        clients.tick += 1;
+        let tick = clients.tick;
+        if let Some((last_used, client)) = clients.by_ip.get_mut(&ip) {
+            let last_used = std::mem::replace(last_used, tick);
+            let client = client.clone();
+            clients.lru.remove(&last_used);
+            clients.lru.insert(tick, ip);
+            return client;
+        }
+        while clients.by_ip.len() >= self.max_clients {
+            let Some((_, oldest)) = clients.lru.pop_first() else {
+                break;
+            };
+            clients.by_ip.remove(&oldest);
+        }
+        let client = Arc::new(Client {
+            requests: DefaultRateTracker::new(WINDOW),
+            hits: DefaultRateTracker::new(WINDOW),
+        });
+        clients.by_ip.insert(ip, (tick, client.clone()));
+        clients.lru.insert(tick, ip);
+        client
+    }
+
+    /// takes a request of `ip` from `budget`, or says how long until it can
+    /// be taken
+    pub fn check(&self, ip: IpAddr, budget: Budget) -> Result<(), Duration> {
+        let client = self.client(ip);
+        let (tracker, limit) = match budget {
+            Budget::Requests => (&client.requests, self.requests_per_minute),
+            Budget::Hits => (&client.hits, self.hits_per_minute),
+        };
+        let wait = tracker.time_until_below(limit);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        tracker.observe(1);
+        Ok(())
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.lock().by_ip.len()
+    }
+}
+
+fn client_ip(request: &Request) -> Option<IpAddr> {
+    request
+        .headers()
+        .get("x-real-ip")
+        .and_then(|ip| ip.to_str().ok())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.0.ip())
+        })
+}
+
+async fn limit(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    // requests we cant tell apart and admins arent limited
+    let Some(ip) = client_ip(&request) else {
+        return next.run(request).await;
+    };
+    if admin::is_admin(request.headers()) {
+        return next.run(request).await;
+    }
+    let budget = Budget::of(request.uri().path());
+    match limiter.check(ip, budget) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            tracing::debug!({ ip = %ip, budget = ?budget }, "rate limited");
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let err = AppError::new(ErrorCode::RateLimited, "rate limited")
+                .with_detail(serde_json::json!({ "retry_after_secs": retry_after }));
+            ([(RETRY_AFTER, retry_after.to_string())], err).into_response()
+        }
+    }
+}
+
+/// puts every route of `router` behind `limiter`
+pub fn rate_limited(router: Router, limiter: RateLimiter) -> Router {
+    router.route_layer(middleware::from_fn_with_state(Arc::new(limiter), limit))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_budgets_are_per_client_and_route() {
+        let limiter = RateLimiter::new(3, 1, 10);
+        for _ in 0..3 {
+            assert!(limiter.check(ip(1), Budget::Requests).is_ok());
+        }
+        let wait = limiter.check(ip(1), Budget::Requests).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= WINDOW);
+
+        // hits have their own budget, and other clients theirs
+        assert!(limiter.check(ip(1), Budget::Hits).is_ok());
+        assert!(limiter.check(ip(1), Budget::Hits).is_err());
+        assert!(limiter.check(ip(2), Budget::Requests).is_ok());
+
+        assert_eq!(Budget::of("/hits"), Budget::Hits);
+        assert_eq!(Budget::of("/instances/backfill/did_hits"), Budget::Hits);
+        assert_eq!(Budget::of("/histogram"), Budget::Requests);
+    }
+
+    #[test]
+    fn test_least_recently_seen_clients_are_evicted() {
+        let limiter = RateLimiter::new(1, 1, 2);
+        assert!(limiter.check(ip(1), Budget::Requests).is_ok());
+        assert!(limiter.check(ip(2), Budget::Requests).is_ok());
+        // 1 is seen again, so 2 goes when 3 comes in
+        assert!(limiter.check(ip(1), Budget::Requests).is_err());
+        assert!(limiter.check(ip(3), Budget::Requests).is_ok());
+        assert_eq!(limiter.clients(), 2);
+        assert!(limiter.check(ip(1), Budget::Requests).is_err());
+        // forgotten, so it starts over with a full budget
+        assert!(limiter.check(ip(2), Budget::Requests).is_ok());
+        assert_eq!(limiter.clients(), 2);
+    }
+}
@@ -0,0 +1,213 @@
+use serde::Serialize;
+
+use crate::{
+    api::{DEFAULT_HITS_LIMIT, Hit, HitKind, HitsQuery, HitsRange, heavy::HeavyQuery},
+    db::Db,
+    error::{AppError, AppResult},
+    utils::get_time,
+};
+
+// which hits of the ones read a sampled /hits keeps
+#[derive(Debug, Clone, Copy)]
+enum Pick {
+    // each with the same chance, hits whose hash is below this are kept
+    Chance { below: u64, keep_all: bool },
+    // every nth, from the `offset`th
+    Every { n: u64, offset: u64 },
+}
+
+/// picks the hits a sampled /hits returns. a hit is picked by its position
+/// among the hits of the asked kind read newest first, so the same seed over
+/// the same (closed) range picks the same hits
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Sampler {
+    pick: Pick,
+    seed: u64,
+    position: u64,
+}
+
+impl Sampler {
+    /// the sampler `sample`, `every` and `seed` of /hits ask for, None if
+    /// they dont ask for sampling
+    pub(super) fn of(params: &HitsQuery) -> AppResult<Option<Self>> {
+        let seed = params
+            .seed
+            .unwrap_or_else(|| mix(get_time().as_nanos() as u64));
+        let pick = match (params.sample, params.every) {
+            (None, None) => {
+                if params.seed.is_some() {
+                    return Err(AppError::bad_request(
+                        "seed only works with sample or every",
+                    ));
+                }
+                return Ok(None);
+            }
+            (Some(_), Some(_)) => {
+                return Err(AppError::bad_request(
+                    "sample and every cant be used together",
+                ));
+            }
+            (Some(rate), None) => {
+                if !(rate > 0.0 && rate <= 1.0) {
+                    return Err(AppError::bad_request(
+                        "sample has to be above 0 and at most 1",
+                    ));
+                }
+                Pick::Chance {
+                    // saturates to u64::MAX for 1.0, so that one keeps all
+                    below: (rate * u64::MAX as f64) as u64,
+                    keep_all: rate == 1.0,
+                }
+            }
+            (None, Some(n)) => {
+                if n == 0 {
+                    return Err(AppError::bad_request("every has to be at least 1"));
+                }
+                Pick::Every {
+                    n,
+                    offset: seed % n,
+                }
+            }
+        };
+        Ok(Some(Self {
+            pick,
+            seed,
+            position: 0,
+        }))
+    }
+
+    /// the share of hits that are kept
+    pub(super) fn rate(&self) -> f64 {
+        match self.pick {
+            Pick::Chance { below, keep_all } => {
+                if keep_all {
+                    1.0
+                } else {
+                    below as f64 / u64::MAX as f64
+                }
+            }
+            Pick::Every { n, .. } => 1.0 / n as f64,
+        }
+    }
+
+    /// whether the next hit read is kept
+    pub(super) fn keep(&mut self) -> bool {
+        let position = self.position;
+        self.position += 1;
+        match self.pick {
+            Pick::Chance { below, keep_all } => keep_all || mix(self.seed ^ mix(position)) < below,
+            Pick::Every { n, offset } => position % n == offset,
+        }
+    }
+}
+
+// splitmix64, enough to spread consecutive positions over the whole range
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// /hits with `sample` or `every`
+#[derive(Debug, Serialize)]
+pub(super) struct SampledHits {
+    /// the newest `limit` sampled hits, oldest first
+    hits: Vec<Hit>,
+    /// whether reading stopped at the limit, before the end of the range
+    truncated: bool,
+    /// the share of hits that were kept
+    sample_rate: f64,
+    /// pass it back to get the same sample of the same range
+    seed: u64,
+    /// hits of the asked kind that were read, kept or not
+    scanned: u64,
+    /// how many hits of the asked kind the part of the range that was read
+    /// had, scaled up from the sample
+    estimated_total: u64,
+}
+
+// reads the hits of `kind` newest first and keeps the ones `sampler` picks
+// until `limit` are kept. hits that arent kept are only decoded, never
+// serialized
+pub(super) fn sampled_hits(
+    db: &Db,
+    nsid: &str,
+    range: HitsRange,
+    limit: usize,
+    kind: HitKind,
+    mut sampler: Sampler,
+    query: &HeavyQuery,
+) -> AppResult<SampledHits> {
+    let _span = tracing::info_span!("decode", limit, rate = sampler.rate()).entered();
+    let snapshot = db.pin_snapshot(nsid);
+    let hits = snapshot
+        .iter()
+        .flat_map(|snapshot| db.hits_newest_first(snapshot, range));
+    let mut acc = Vec::with_capacity(limit.min(DEFAULT_HITS_LIMIT));
+    let (mut scanned, mut truncated) = (0, false);
+    for hit in hits {
+        query.check()?;
+        let hit = hit?;
+        let op = hit.deser()?.op;
+        if !kind.matches(op) {
+            continue;
+        }
+        let keep = sampler.keep();
+        if keep && acc.len() >= limit {
+            truncated = true;
+            break;
+        }
+        scanned += 1;
+        if keep {
+            acc.push(Hit::new(hit.timestamp, op));
+        }
+    }
+    acc.reverse();
+    let rate = sampler.rate();
+    Ok(SampledHits {
+        estimated_total: (acc.len() as f64 / rate).round() as u64,
+        hits: acc,
+        truncated,
+        sample_rate: rate,
+        seed: sampler.seed,
+        scanned,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::*;
+
+    fn sampler(sample: Option<f64>, every: Option<u64>, seed: u64) -> Sampler {
+        let params = HitsQuery {
+            sample,
+            every,
+            seed: Some(seed),
+            ..serde_json::from_value(serde_json::json!({ "nsid": "a.b.c" })).unwrap()
+        };
+        Sampler::of(&params).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_samples_are_reproducible() {
+        let picks = |mut sampler: Sampler| (0..10_000).map(|_| sampler.keep()).collect::<Vec<_>>();
+        let a = picks(sampler(Some(0.01), None, 7));
+        assert_eq!(a, picks(sampler(Some(0.01), None, 7)));
+        assert_ne!(a, picks(sampler(Some(0.01), None, 8)));
+        let kept = a.iter().filter(|kept| **kept).count();
+        assert!((50..=150).contains(&kept), "{kept}");
+        assert!(picks(sampler(Some(1.0), None, 7)).iter().all(|kept| *kept));
+
+        let every = picks(sampler(None, Some(4), 6));
+        let kept = every
+            .iter()
+            .positions(|kept| *kept)
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, [2, 6, 10]);
+        assert_eq!(sampler(None, Some(4), 6).rate(), 0.25);
+    }
+}
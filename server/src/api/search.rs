@@ -0,0 +1,196 @@
+use std::ops::Deref;
+
+use axum::{Json, extract::State};
+use rclite::Arc;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    api::{NsidCount, extract::Query},
+    db::Db,
+    error::{AppError, AppResult, ErrorBody, ErrorCode},
+};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+// longer than any nsid can be
+const MAX_QUERY_LEN: usize = 317;
+
+/// how an nsid matched a search, better ones sort first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    /// the whole nsid
+    Exact,
+    /// whole segments of it, like `flashes` or `feed.post`
+    Segment,
+    /// the start of a segment
+    SegmentPrefix,
+    /// anywhere in it
+    Substring,
+}
+
+// what is searched for, lowercased. domain order (`flashes.blue`) is
+// searched for reversed too, the way nsids have it
+struct Needle {
+    forms: Vec<String>,
+}
+
+impl Needle {
+    fn new(query: &str) -> Self {
+        let query = query.to_ascii_lowercase();
+        let mut forms = vec![query.clone()];
+        if query.contains('.') {
+            let reversed = query.rsplit('.').collect::<Vec<_>>().join(".");
+            if reversed != query {
+                forms.push(reversed);
+            }
+        }
+        Self { forms }
+    }
+
+    fn match_kind(&self, nsid: &str) -> Option<MatchKind> {
+        let nsid = nsid.to_ascii_lowercase();
+        // dots around it so segments can be matched whole
+        let dotted = format!(".{nsid}.");
+        self.forms
+            .iter()
+            .filter_map(|form| {
+                if nsid == *form {
+                    Some(MatchKind::Exact)
+                } else if dotted.contains(&format!(".{form}.")) {
+                    Some(MatchKind::Segment)
+                } else if dotted.contains(&format!(".{form}")) {
+                    Some(MatchKind::SegmentPrefix)
+                } else if nsid.contains(form.as_str()) {
+                    Some(MatchKind::Substring)
+                } else {
+                    None
+                }
+            })
+            .min()
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    /// case insensitive, matched against nsids and their segments
+    #[param(value_type = String)]
+    q: SmolStr,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SearchResult {
+    #[schema(value_type = String)]
+    nsid: SmolStr,
+    #[serde(rename = "match")]
+    kind: MatchKind,
+    #[serde(flatten)]
+    count: NsidCount,
+}
+
+pub fn routes() -> OpenApiRouter<Arc<Db>> {
+    OpenApiRouter::new().routes(routes!(search))
+}
+
+// nsids matching `q`, best matches first and the busiest first among those.
+// only partition names and the counts of matches are read, no hits, so it
+// can run on every keystroke
+#[utoipa::path(
+    get,
+    path = "/search",
+    tag = "nsids",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "the nsids that match", body = Vec<SearchResult>),
+        (status = "4XX", description = "see /error_codes", body = ErrorBody)
+    )
+)]
+pub async fn search(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<SearchQuery>,
+) -> AppResult<Json<Vec<SearchResult>>> {
+    let q = params.q.trim();
+    if q.is_empty() || q.len() > MAX_QUERY_LEN {
+        return Err(AppError::bad_request(format!(
+            "q must be 1 to {MAX_QUERY_LEN} characters"
+        )));
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(AppError::new(
+            ErrorCode::InvalidLimit,
+            format!("limit must be 1 to {MAX_LIMIT}"),
+        )
+        .with_detail(serde_json::json!({ "min": 1, "max": MAX_LIMIT })));
+    }
+    let needle = Needle::new(q);
+    let mut matches = Vec::new();
+    for nsid in db.get_nsids() {
+        let Some(kind) = needle.match_kind(nsid.deref()) else {
+            continue;
+        };
+        let nsid = SmolStr::new(nsid.deref());
+        let counts = db.get_count(&nsid)?;
+        matches.push((kind, nsid, counts));
+    }
+    matches.sort_unstable_by(|(a_kind, a_nsid, a), (b_kind, b_nsid, b)| {
+        a_kind
+            .cmp(b_kind)
+            .then_with(|| b.count.cmp(&a.count))
+            .then_with(|| a_nsid.cmp(b_nsid))
+    });
+    let results = matches
+        .into_iter()
+        .take(limit)
+        .map(|(kind, nsid, counts)| SearchResult {
+            nsid,
+            kind,
+            count: NsidCount::from(&counts),
+        })
+        .collect();
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_match_kinds() {
+        let needle = Needle::new("Flashes");
+        assert_eq!(
+            needle.match_kind("blue.flashes.feed.post"),
+            Some(MatchKind::Segment)
+        );
+        assert_eq!(
+            needle.match_kind("blue.flashesapp.feed.post"),
+            Some(MatchKind::SegmentPrefix)
+        );
+        assert_eq!(
+            needle.match_kind("com.example.myFlashes"),
+            Some(MatchKind::Substring)
+        );
+        assert_eq!(needle.match_kind("app.bsky.feed.post"), None);
+
+        // domain order finds the nsids of that domain
+        let needle = Needle::new("flashes.blue");
+        assert_eq!(
+            needle.match_kind("blue.flashes.feed.post"),
+            Some(MatchKind::Segment)
+        );
+        let needle = Needle::new("app.bsky.feed.post");
+        assert_eq!(
+            needle.match_kind("app.bsky.feed.post"),
+            Some(MatchKind::Exact)
+        );
+        assert_eq!(
+            needle.match_kind("app.bsky.feed.postgate"),
+            Some(MatchKind::SegmentPrefix)
+        );
+    }
+}
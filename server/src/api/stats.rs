@@ -0,0 +1,139 @@
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use rclite::Arc;
+use serde::Serialize;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    api::{HistogramQuery, extract::Query, histogram_buckets, limited},
+    db::{Db, HistogramBucket},
+    error::{AppResult, ErrorBody},
+};
+
+/// a summary of the hits per bucket of /histogram
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct BucketStats {
+    /// how many buckets there were
+    buckets: usize,
+    total: u64,
+    mean: f64,
+    median: f64,
+    /// the smallest bucket at least 95% of the buckets are no bigger than
+    p95: u64,
+    max: u64,
+    /// start of the busiest bucket (the first one if several are), None if
+    /// every bucket is empty
+    busiest: Option<u64>,
+}
+
+impl BucketStats {
+    // `deleted` already zeroed what isnt counted, see `histogram_buckets`
+    fn of(buckets: &[HistogramBucket]) -> Self {
+        let mut counts = buckets
+            .iter()
+            .map(|bucket| bucket.count + bucket.deleted_count + bucket.purged_count)
+            .collect::<Vec<_>>();
+        let total = counts.iter().sum::<u64>();
+        let busiest = buckets
+            .iter()
+            .zip(&counts)
+            .filter(|(_, count)| **count > 0)
+            // max_by_key would take the last of equal ones
+            .min_by_key(|(_, count)| std::cmp::Reverse(**count))
+            .map(|(bucket, _)| bucket.bucket_start);
+        counts.sort_unstable();
+        let n = counts.len();
+        if n == 0 {
+            return Self {
+                buckets: 0,
+                total: 0,
+                mean: 0.0,
+                median: 0.0,
+                p95: 0,
+                max: 0,
+                busiest: None,
+            };
+        }
+        let median = if n % 2 == 0 {
+            (counts[n / 2 - 1] + counts[n / 2]) as f64 / 2.0
+        } else {
+            counts[n / 2] as f64
+        };
+        // nearest rank
+        let p95 = counts[(n * 95).div_ceil(100) - 1];
+        Self {
+            buckets: n,
+            total,
+            mean: total as f64 / n as f64,
+            median,
+            p95,
+            max: counts[n - 1],
+            busiest,
+        }
+    }
+}
+
+pub fn routes() -> OpenApiRouter<Arc<Db>> {
+    OpenApiRouter::new().routes(limited(routes!(stats)))
+}
+
+// the same buckets as /histogram with the same params, summarized. only
+// bucket counts are summarized, so it costs what the histogram does
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "series",
+    params(HistogramQuery),
+    responses(
+        (status = 200, description = "mean, median, p95 and max hits per bucket", body = BucketStats),
+        (status = "4XX", description = "see /error_codes", body = ErrorBody)
+    )
+)]
+pub async fn stats(
+    State(db): State<Arc<Db>>,
+    Query(params): Query<HistogramQuery>,
+) -> AppResult<Response> {
+    let (buckets, freshness) = histogram_buckets(db, params).await?;
+    Ok((freshness.headers(), Json(BucketStats::of(&buckets))).into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn buckets(counts: &[u64]) -> Vec<HistogramBucket> {
+        counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| HistogramBucket {
+                bucket_start: i as u64 * 60,
+                count: *count,
+                deleted_count: 0,
+                purged_count: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bucket_stats() {
+        let stats = BucketStats::of(&buckets(&[4, 0, 9, 1, 9, 7]));
+        assert_eq!(stats.buckets, 6);
+        assert_eq!(stats.total, 30);
+        assert_eq!(stats.mean, 5.0);
+        assert_eq!(stats.median, 5.5);
+        assert_eq!((stats.p95, stats.max), (9, 9));
+        assert_eq!(stats.busiest, Some(120));
+
+        let counts = (1..=100).collect::<Vec<_>>();
+        let stats = BucketStats::of(&buckets(&counts));
+        assert_eq!((stats.median, stats.p95), (50.5, 95));
+
+        let stats = BucketStats::of(&buckets(&[0, 0, 0]));
+        assert_eq!((stats.total, stats.busiest), (0, None));
+        assert_eq!(BucketStats::of(&[]).buckets, 0);
+    }
+}
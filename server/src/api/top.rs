@@ -0,0 +1,240 @@
+use std::{cmp::Ordering, time::Duration};
+
+use ahash::AHashMap;
+use axum::{Extension, Json, extract::State};
+use parking_lot::Mutex;
+use rclite::Arc;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    api::{extract::Query, heavy::HeavyQuery, limited, pool::run_query},
+    db::{Db, NsidCounts},
+    error::{AppError, AppResult, ErrorBody, ErrorCode},
+    utils::{CLOCK, get_time},
+};
+
+// every hit in the window is decoded, so it is kept to a few hours
+const MAX_WINDOW: Duration = Duration::from_secs(60 * 60 * 6);
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 1000;
+const CACHE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TopBy {
+    /// creates
+    #[default]
+    Count,
+    /// deletes (not counting purges)
+    Deleted,
+    /// deletes per create
+    Ratio,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TopQuery {
+    /// seconds up to now, all time if left out
+    window: Option<u64>,
+    limit: Option<usize>,
+    #[serde(default)]
+    #[param(inline)]
+    by: TopBy,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TopNsid {
+    #[schema(value_type = String)]
+    nsid: SmolStr,
+    count: u128,
+    deleted_count: u128,
+    /// deleted_count / count, nsids that only had deletes are counted as if
+    /// they had one create
+    ratio: f64,
+    /// epoch seconds
+    last_seen: u64,
+}
+
+impl TopNsid {
+    fn new(nsid: SmolStr, counts: NsidCounts) -> Self {
+        Self {
+            ratio: counts.deleted_count as f64 / counts.count.max(1) as f64,
+            nsid,
+            count: counts.count,
+            deleted_count: counts.deleted_count,
+            last_seen: counts.last_seen,
+        }
+    }
+
+    fn cmp_by(&self, other: &Self, by: TopBy) -> Ordering {
+        match by {
+            TopBy::Count => other.count.cmp(&self.count),
+            TopBy::Deleted => other.deleted_count.cmp(&self.deleted_count),
+            TopBy::Ratio => other.ratio.total_cmp(&self.ratio),
+        }
+        .then_with(|| self.nsid.cmp(&other.nsid))
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Top {
+    /// [start, end) in epoch seconds, None for all time
+    #[schema(value_type = Option<Vec<u64>>)]
+    window: Option<(u64, u64)>,
+    by: TopBy,
+    nsids: Vec<TopNsid>,
+}
+
+// windowed counts scan every active nsid, they are kept for a bit and
+// sorted per request
+#[derive(Default)]
+pub struct TopCache {
+    entries: Mutex<AHashMap<u64, (quanta::Instant, (u64, u64), Arc<Vec<TopNsid>>)>>,
+}
+
+fn window_counts_all(db: &Db, start: u64, end: u64, query: &HeavyQuery) -> AppResult<Vec<TopNsid>> {
+    let mut nsids = Vec::new();
+    for entry in db.get_counts() {
+        let (nsid, counts) = entry?;
+        // nothing of it can be in the window
+        if counts.last_seen < start {
+            continue;
+        }
+        query.check()?;
+        let counts = db.window_counts(&nsid, start, end)?;
+        if counts.count == 0 && counts.deleted_count == 0 {
+            continue;
+        }
+        nsids.push(TopNsid::new(nsid, counts));
+    }
+    Ok(nsids)
+}
+
+pub fn routes() -> OpenApiRouter<Arc<Db>> {
+    OpenApiRouter::new().routes(limited(routes!(top)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/top",
+    tag = "counts",
+    params(TopQuery),
+    responses(
+        (status = 200, description = "the nsids with the most hits", body = Top),
+        (status = "4XX", description = "see /error_codes", body = ErrorBody)
+    )
+)]
+pub async fn top(
+    State(db): State<Arc<Db>>,
+    Extension(cache): Extension<Arc<TopCache>>,
+    Extension(query): Extension<HeavyQuery>,
+    Query(params): Query<TopQuery>,
+) -> AppResult<Json<Top>> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(AppError::new(
+            ErrorCode::InvalidLimit,
+            format!("limit must be 1 to {MAX_LIMIT}"),
+        )
+        .with_detail(serde_json::json!({ "min": 1, "max": MAX_LIMIT })));
+    }
+    if params.window == Some(0) {
+        return Err(AppError::bad_request("window must be at least a second"));
+    }
+
+    let (window, nsids) = match params.window {
+        None => {
+            let nsids = run_query(move || {
+                db.get_counts()
+                    .map(|entry| entry.map(|(nsid, counts)| TopNsid::new(nsid, counts)))
+                    .collect::<AppResult<Vec<_>>>()
+            })
+            .await??;
+            (None, Arc::new(nsids))
+        }
+        Some(window) => {
+            let window = window.min(MAX_WINDOW.as_secs());
+            let cached = cache
+                .entries
+                .lock()
+                .get(&window)
+                .filter(|(at, _, _)| at.elapsed() < CACHE_TTL)
+                .map(|(_, range, nsids)| (*range, nsids.clone()));
+            let (range, nsids) = match cached {
+                Some(cached) => cached,
+                None => {
+                    // the current second is in the window too
+                    let end = get_time().as_secs() + 1;
+                    let start = end.saturating_sub(window);
+                    let nsids =
+                        run_query(move || window_counts_all(&db, start, end, &query)).await??;
+                    let nsids = Arc::new(nsids);
+                    let mut entries = cache.entries.lock();
+                    entries.retain(|_, (at, _, _)| at.elapsed() < CACHE_TTL);
+                    entries.insert(window, (CLOCK.now(), (start, end), nsids.clone()));
+                    ((start, end), nsids)
+                }
+            };
+            (Some(range), nsids)
+        }
+    };
+
+    let mut sorted = nsids.iter().collect::<Vec<_>>();
+    if sorted.len() > limit {
+        sorted.select_nth_unstable_by(limit - 1, |a, b| a.cmp_by(b, params.by));
+        sorted.truncate(limit);
+    }
+    sorted.sort_unstable_by(|a, b| a.cmp_by(b, params.by));
+    Ok(Json(Top {
+        window,
+        by: params.by,
+        nsids: sorted.into_iter().cloned().collect(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nsid(name: &str, count: u128, deleted_count: u128) -> TopNsid {
+        TopNsid::new(
+            SmolStr::new(name),
+            NsidCounts {
+                count,
+                deleted_count,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_top_orders() {
+        let mut nsids = vec![
+            nsid("a.b.likes", 100, 10),
+            nsid("a.b.posts", 40, 20),
+            nsid("a.b.spam", 0, 5),
+            nsid("a.b.follows", 100, 1),
+        ];
+        let names = |nsids: &[TopNsid]| nsids.iter().map(|n| n.nsid.clone()).collect::<Vec<_>>();
+
+        nsids.sort_unstable_by(|a, b| a.cmp_by(b, TopBy::Count));
+        // ties are by name
+        assert_eq!(
+            names(&nsids),
+            ["a.b.follows", "a.b.likes", "a.b.posts", "a.b.spam"]
+        );
+        nsids.sort_unstable_by(|a, b| a.cmp_by(b, TopBy::Deleted));
+        assert_eq!(
+            names(&nsids),
+            ["a.b.posts", "a.b.likes", "a.b.spam", "a.b.follows"]
+        );
+        nsids.sort_unstable_by(|a, b| a.cmp_by(b, TopBy::Ratio));
+        assert_eq!(
+            names(&nsids),
+            ["a.b.spam", "a.b.posts", "a.b.likes", "a.b.follows"]
+        );
+    }
+}
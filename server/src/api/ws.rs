@@ -0,0 +1,124 @@
+use std::{
+    sync::{
+        OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum_tws::{Message, WebSocket};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::config;
+
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(90);
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// websocket clients are pinged every `WS_PING_INTERVAL_SECS`. one we didnt
+// hear from (a pong or anything else) for `WS_PONG_TIMEOUT_SECS` is gone,
+// behind a nat that dropped it we would otherwise never find out. one that
+// takes over `WS_SEND_TIMEOUT_SECS` to take a frame is stuck and dropped
+struct Settings {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    send_timeout: Duration,
+}
+
+fn secs(key: &str, default: Duration) -> Duration {
+    let secs = config::env_or(key, default.as_secs(), |secs| {
+        secs.parse::<u64>().ok().filter(|secs| *secs > 0)
+    });
+    Duration::from_secs(secs)
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        ping_interval: secs("WS_PING_INTERVAL_SECS", DEFAULT_PING_INTERVAL),
+        pong_timeout: secs("WS_PONG_TIMEOUT_SECS", DEFAULT_PONG_TIMEOUT),
+        send_timeout: secs("WS_SEND_TIMEOUT_SECS", DEFAULT_SEND_TIMEOUT),
+    })
+}
+
+// reads the settings, which otherwise happens on the first connection
+pub fn init() {
+    settings();
+}
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// websocket connections open right now, over every instance
+pub fn live_connections() -> usize {
+    LIVE.load(Ordering::Relaxed)
+}
+
+/// the heartbeat of one websocket connection, it counts as live until this
+/// is dropped
+pub struct Connection {
+    settings: &'static Settings,
+    last_heard: Instant,
+    ping: Interval,
+}
+
+impl Connection {
+    pub fn open() -> Self {
+        LIVE.fetch_add(1, Ordering::Relaxed);
+        let settings = settings();
+        let mut ping = tokio::time::interval_at(
+            Instant::now() + settings.ping_interval,
+            settings.ping_interval,
+        );
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            settings,
+            last_heard: Instant::now(),
+            ping,
+        }
+    }
+
+    /// anything the client sent, pongs included
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    /// waits until the next ping is due, false if the client didnt answer
+    /// for too long and should be dropped
+    pub async fn ping_due(&mut self) -> bool {
+        self.ping.tick().await;
+        let silent = self.last_heard.elapsed();
+        if silent > self.settings.pong_timeout {
+            tracing::info!("websocket client silent for {silent:?}, closing");
+            return false;
+        }
+        true
+    }
+
+    /// false if the message didnt make it to the client in time
+    pub async fn send(&self, socket: &mut WebSocket, msg: Message) -> bool {
+        match tokio::time::timeout(self.settings.send_timeout, socket.send(msg)).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => {
+                tracing::error!("error sending to websocket: {err}");
+                false
+            }
+            Err(_) => {
+                tracing::warn!(
+                    "websocket client didnt take a frame within {:?}, dropping it",
+                    self.settings.send_timeout
+                );
+                false
+            }
+        }
+    }
+
+    pub async fn ping(&self, socket: &mut WebSocket) -> bool {
+        self.send(socket, Message::ping(Vec::new())).await
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        LIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
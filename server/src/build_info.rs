@@ -0,0 +1,33 @@
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub built_at: u64,
+    pub rustc: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn get() -> &'static Self {
+        &BUILD_INFO
+    }
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{} ({}, {})", self.version, self.commit, self.rustc)
+    }
+}
+
+static BUILD_INFO: std::sync::LazyLock<BuildInfo> = std::sync::LazyLock::new(|| BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    commit: env!("BUILD_GIT_COMMIT"),
+    built_at: env!("BUILD_TIMESTAMP").parse().unwrap_or(0),
+    rustc: env!("BUILD_RUSTC_VERSION"),
+    features: env!("BUILD_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect(),
+});
@@ -0,0 +1,353 @@
+use std::{collections::BTreeMap, fmt::Display, sync::OnceLock};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use smol_str::SmolStr;
+
+/// where the value of a setting came from, later ones win over earlier ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Default,
+    // the `LEXTRACK_CONFIG` file
+    File,
+    Env,
+    // `--set KEY=value` on the command line
+    Override,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Source::Default => "the default",
+            Source::File => "the config file",
+            Source::Env => "the environment",
+            Source::Override => "--set",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Setting {
+    pub value: serde_json::Value,
+    pub source: Source,
+}
+
+// every setting that was read so far, by env var name. settings are read
+// where they are used, so this is filled in as they are
+static SETTINGS: Mutex<BTreeMap<SmolStr, Setting>> = Mutex::new(BTreeMap::new());
+
+const REDACTED: &str = "<redacted>";
+
+/// settings are named like `MAX_BLOCK_SIZE` and read from `LEXTRACK_` plus
+/// the name. the bare name is read too, as every setting was before
+pub const ENV_PREFIX: &str = "LEXTRACK_";
+
+// bare names that warn when they are used, the prefixed name is the one to set
+const DEPRECATED: &[&str] = &["PORT"];
+
+// settings whose values arent shown, only whether they are set
+fn is_secret(key: &str) -> bool {
+    ["TOKEN", "SECRET", "PASSWORD", "API_KEY", "WEBHOOK"]
+        .iter()
+        .any(|word| key.contains(word))
+}
+
+/// a raw value and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Raw {
+    pub value: String,
+    pub source: Source,
+    // what it was set as, like `LEXTRACK_PORT` or `PORT` for env values
+    pub name: String,
+}
+
+/// the sources besides the environment, read once at startup by `init`
+#[derive(Debug, Default)]
+pub struct Layers {
+    overrides: BTreeMap<SmolStr, String>,
+    file: BTreeMap<SmolStr, String>,
+}
+
+impl Layers {
+    /// the value of `key` from the first source that has it
+    pub fn lookup(&self, key: &str, env: impl Fn(&str) -> Option<String>) -> Option<Raw> {
+        if let Some(value) = self.overrides.get(key) {
+            return Some(Raw {
+                value: value.clone(),
+                source: Source::Override,
+                name: key.to_owned(),
+            });
+        }
+        let prefixed = format!("{ENV_PREFIX}{key}");
+        for name in [prefixed.as_str(), key] {
+            if let Some(value) = env(name) {
+                return Some(Raw {
+                    value,
+                    source: Source::Env,
+                    name: name.to_owned(),
+                });
+            }
+        }
+        self.file.get(key).map(|value| Raw {
+            value: value.clone(),
+            source: Source::File,
+            name: key.to_owned(),
+        })
+    }
+
+    /// `KEY=value` lines, with or without the prefix. empty lines and ones
+    /// starting with `#` are skipped
+    pub fn parse_file(&mut self, path: &str, contents: &str) -> Result<(), String> {
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!(
+                    "{path}:{}: expected KEY=value, got {line:?}",
+                    i + 1
+                ));
+            };
+            let key = key.trim();
+            let key = key.strip_prefix(ENV_PREFIX).unwrap_or(key);
+            self.file.insert(SmolStr::new(key), value.trim().to_owned());
+        }
+        Ok(())
+    }
+
+    /// `KEY=value` as given to `--set`
+    pub fn set_override(&mut self, arg: &str) -> Result<(), String> {
+        let Some((key, value)) = arg.split_once('=') else {
+            return Err(format!("--set takes KEY=value, got {arg:?}"));
+        };
+        let key = key.strip_prefix(ENV_PREFIX).unwrap_or(key);
+        self.overrides.insert(SmolStr::new(key), value.to_owned());
+        Ok(())
+    }
+}
+
+static LAYERS: OnceLock<Layers> = OnceLock::new();
+
+/// reads the `LEXTRACK_CONFIG` file and takes the `--set` overrides, before
+/// any setting is read. without this only the environment is read
+pub fn init<'a>(overrides: impl IntoIterator<Item = &'a str>) -> Result<(), String> {
+    let mut layers = Layers::default();
+    let path = std::env::var(format!("{ENV_PREFIX}CONFIG")).ok();
+    if let Some(path) = path {
+        let contents =
+            std::fs::read_to_string(&path).map_err(|err| format!("cant read {path}: {err}"))?;
+        layers.parse_file(&path, &contents)?;
+    }
+    for arg in overrides {
+        layers.set_override(arg)?;
+    }
+    LAYERS
+        .set(layers)
+        .map_err(|_| "config was already initialized".to_owned())
+}
+
+fn lookup(key: &str) -> Option<Raw> {
+    static EMPTY: Layers = Layers {
+        overrides: BTreeMap::new(),
+        file: BTreeMap::new(),
+    };
+    let raw = LAYERS
+        .get()
+        .unwrap_or(&EMPTY)
+        .lookup(key, |name| std::env::var(name).ok())?;
+    if raw.name == key && raw.source == Source::Env && DEPRECATED.contains(&key) {
+        tracing::warn!("{key} is deprecated, set {ENV_PREFIX}{key} instead");
+    }
+    Some(raw)
+}
+
+/// a value that was set but doesnt parse, says which setting it was and
+/// where it was set so it can be fixed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub raw: Raw,
+    pub reason: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Raw {
+            value,
+            source,
+            name,
+        } = &self.raw;
+        let value = match is_secret(name) {
+            true => REDACTED,
+            false => value.as_str(),
+        };
+        write!(f, "invalid {name}={value:?} from {source}: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// reads the setting `key` with `parse`, falling back to `default` if it
+/// isnt set. a value that doesnt parse is an error naming the setting
+pub fn try_env<T: Serialize>(
+    key: &str,
+    default: T,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> Result<T, ConfigError> {
+    let Some(raw) = lookup(key) else {
+        record(key, &default, Source::Default);
+        return Ok(default);
+    };
+    match parse(&raw.value) {
+        Ok(value) => {
+            record(key, &value, raw.source);
+            Ok(value)
+        }
+        Err(reason) => Err(ConfigError { raw, reason }),
+    }
+}
+
+/// reads the setting `key` with `parse`, falling back to `default` if it
+/// isnt set or doesnt parse (with a warning), and records which it was
+pub fn env_or<T: Serialize>(key: &str, default: T, parse: impl FnOnce(&str) -> Option<T>) -> T {
+    let Some(raw) = lookup(key) else {
+        record(key, &default, Source::Default);
+        return default;
+    };
+    match parse(&raw.value) {
+        Some(value) => {
+            record(key, &value, raw.source);
+            value
+        }
+        None => {
+            let err = ConfigError {
+                raw,
+                reason: "it doesnt parse".to_owned(),
+            };
+            tracing::warn!("{err}, using the default");
+            record(key, &default, Source::Default);
+            default
+        }
+    }
+}
+
+pub fn record(key: &str, value: &impl Serialize, source: Source) {
+    let mut value = serde_json::to_value(value).unwrap_or_default();
+    if is_secret(key) && !value.is_null() {
+        value = REDACTED.into();
+    }
+    SETTINGS
+        .lock()
+        .insert(SmolStr::new(key), Setting { value, source });
+}
+
+/// the effective settings, with secrets redacted
+pub fn settings() -> BTreeMap<SmolStr, Setting> {
+    SETTINGS.lock().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_settings_record_their_source() {
+        let port = env_or("LEXICON_TRACKER_TEST_UNSET", 3713_u16, |port| {
+            port.parse().ok()
+        });
+        assert_eq!(port, 3713);
+        // set in any environment we run tests in
+        let path = env_or("PATH", String::new(), |path| Some(path.to_owned()));
+        assert!(!path.is_empty());
+        record("LEXICON_TRACKER_TEST_TOKEN", &"hunter2", Source::Env);
+        record(
+            "LEXICON_TRACKER_TEST_API_KEY",
+            &None::<String>,
+            Source::Default,
+        );
+
+        let settings = settings();
+        let unset = &settings["LEXICON_TRACKER_TEST_UNSET"];
+        assert_eq!(
+            (unset.source, unset.value.as_u64()),
+            (Source::Default, Some(3713))
+        );
+        assert_eq!(settings["PATH"].source, Source::Env);
+        assert_eq!(settings["LEXICON_TRACKER_TEST_TOKEN"].value, REDACTED);
+        assert!(settings["LEXICON_TRACKER_TEST_API_KEY"].value.is_null());
+    }
+
+    #[test]
+    fn test_layers_precedence() {
+        let mut layers = Layers::default();
+        layers
+            .parse_file(
+                "tracker.env",
+                "# comment\n\nLEXTRACK_MAX_HITS_LIMIT = 10\nQUERY_THREADS=4\nPORT=1\n",
+            )
+            .unwrap();
+        layers.set_override("LEXTRACK_PORT=2").unwrap();
+        let env = |name: &str| match name {
+            "LEXTRACK_QUERY_THREADS" => Some("8".to_owned()),
+            "QUERY_THREADS" => Some("6".to_owned()),
+            "PORT" => Some("3".to_owned()),
+            _ => None,
+        };
+        let found = |key| layers.lookup(key, env).map(|raw| (raw.value, raw.source));
+
+        // override > env > file > default
+        assert_eq!(found("PORT"), Some(("2".to_owned(), Source::Override)));
+        assert_eq!(found("QUERY_THREADS"), Some(("8".to_owned(), Source::Env)));
+        assert_eq!(
+            found("MAX_HITS_LIMIT"),
+            Some(("10".to_owned(), Source::File))
+        );
+        assert_eq!(found("MAX_RANGE_SPAN"), None);
+        // the bare name still counts as the environment
+        let bare = Layers::default().lookup("PORT", env);
+        assert_eq!(
+            bare.map(|raw| (raw.name, raw.source)),
+            Some(("PORT".to_owned(), Source::Env))
+        );
+
+        assert!(
+            layers
+                .parse_file("tracker.env", "QUERY_THREADS 4")
+                .unwrap_err()
+                .starts_with("tracker.env:1:")
+        );
+        assert!(layers.set_override("PORT").is_err());
+    }
+
+    #[test]
+    fn test_config_errors_name_the_setting() {
+        let err = ConfigError {
+            raw: Raw {
+                value: "lots".to_owned(),
+                source: Source::Env,
+                name: "LEXTRACK_QUERY_THREADS".to_owned(),
+            },
+            reason: "expected a number".to_owned(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "invalid LEXTRACK_QUERY_THREADS=\"lots\" from the environment: expected a number"
+        );
+        let secret = ConfigError {
+            raw: Raw {
+                value: "hunter2".to_owned(),
+                source: Source::File,
+                name: "ADMIN_TOKEN".to_owned(),
+            },
+            reason: "too short".to_owned(),
+        };
+        assert!(!secret.to_string().contains("hunter2"));
+
+        let unset = try_env("LEXICON_TRACKER_TEST_UNSET_TOO", 5_u32, |_| {
+            Err("unreachable".to_owned())
+        });
+        assert_eq!(unset, Ok(5));
+    }
+}
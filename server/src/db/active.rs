@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use ahash::AHashSet;
+use fjall::Partition;
+use parking_lot::Mutex;
+use rkyv::rancor::Error;
+use smol_str::SmolStr;
+
+use crate::error::AppResult;
+
+pub const HOUR: u64 = 60 * 60;
+
+// distinct nsids seen per hour (by event time). ingest adds to the pending
+// sets, sync flushes them into `_active_nsids` as
+// hour start (big endian) -> rkyv Vec<String>
+// flushes are unioned with what is stored, so events that arrive for an hour
+// after it was flushed (or after a restart) just add to it
+pub struct ActiveNsids {
+    partition: Partition,
+    pending: Mutex<BTreeMap<u64, AHashSet<SmolStr>>>,
+    // serializes the read-union-write of stored rows
+    flush_lock: Mutex<()>,
+}
+
+impl ActiveNsids {
+    pub fn new(partition: Partition) -> Self {
+        Self {
+            partition,
+            pending: Default::default(),
+            flush_lock: Mutex::new(()),
+        }
+    }
+
+    #[inline(always)]
+    pub fn hour_of(timestamp: u64) -> u64 {
+        timestamp / HOUR * HOUR
+    }
+
+    pub fn observe(&self, nsid: &SmolStr, hours: &[u64]) {
+        let mut pending = self.pending.lock();
+        for hour in hours {
+            let set = pending.entry(*hour).or_default();
+            if !set.contains(nsid) {
+                set.insert(nsid.clone());
+            }
+        }
+    }
+
+    fn read(&self, hour: u64) -> AppResult<AHashSet<SmolStr>> {
+        let Some(raw) = self.partition.get(hour.to_be_bytes())? else {
+            return Ok(AHashSet::new());
+        };
+        let nsids = rkyv::from_bytes::<Vec<String>, Error>(&raw)?;
+        Ok(nsids.into_iter().map(SmolStr::from).collect())
+    }
+
+    /// writes the pending sets out, only touching rows that gained nsids
+    pub fn flush(&self) -> AppResult<()> {
+        let _lock = self.flush_lock.lock();
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let mut failed = None;
+        let mut iter = pending.into_iter();
+        for (hour, nsids) in iter.by_ref() {
+            if let Err(err) = self.merge(hour, &nsids) {
+                failed = Some((hour, nsids, err));
+                break;
+            }
+        }
+        let Some((hour, nsids, err)) = failed else {
+            return Ok(());
+        };
+        // put back what we couldnt write so the next flush retries it
+        let mut pending = self.pending.lock();
+        for (hour, nsids) in std::iter::once((hour, nsids)).chain(iter) {
+            pending.entry(hour).or_default().extend(nsids);
+        }
+        Err(err)
+    }
+
+    fn merge(&self, hour: u64, nsids: &AHashSet<SmolStr>) -> AppResult<()> {
+        let mut stored = self.read(hour)?;
+        let before = stored.len();
+        stored.extend(nsids.iter().cloned());
+        if stored.len() == before {
+            return Ok(());
+        }
+        let mut nsids = stored.into_iter().map(String::from).collect::<Vec<_>>();
+        nsids.sort_unstable();
+        self.partition.insert(
+            hour.to_be_bytes(),
+            rkyv::to_bytes::<Error>(&nsids)?.as_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// active nsids of every hour in `start..=end` that had any, stored and
+    /// pending combined
+    pub fn range(&self, start: u64, end: u64) -> AppResult<BTreeMap<u64, AHashSet<SmolStr>>> {
+        let (start, end) = (Self::hour_of(start), Self::hour_of(end));
+        // so we dont miss sets that are taken out of pending but not written yet
+        let _lock = self.flush_lock.lock();
+        let mut hours = BTreeMap::new();
+        for res in self
+            .partition
+            .range(start.to_be_bytes()..=end.to_be_bytes())
+        {
+            let (key, value) = res?;
+            let hour = u64::from_be_bytes(
+                key[..]
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid active nsids key"))?,
+            );
+            let nsids = rkyv::from_bytes::<Vec<String>, Error>(&value)?;
+            hours.insert(
+                hour,
+                nsids
+                    .into_iter()
+                    .map(SmolStr::from)
+                    .collect::<AHashSet<_>>(),
+            );
+        }
+        for (hour, nsids) in self.pending.lock().range(start..=end) {
+            hours
+                .entry(*hour)
+                .or_insert_with(AHashSet::new)
+                .extend(nsids.iter().cloned());
+        }
+        Ok(hours)
+    }
+}
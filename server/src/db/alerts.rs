@@ -0,0 +1,430 @@
+use ahash::AHashMap;
+use fjall::Partition;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol_str::{SmolStr, format_smolstr};
+use tokio::sync::mpsc::{Receiver, Sender, error::TrySendError};
+
+use crate::{
+    db::NsidCounts,
+    error::AppResult,
+    utils::{ArcRefCnt, ArcliteSwap},
+};
+
+const RULE_PREFIX: &str = "alerts/rule/";
+const STATE_PREFIX: &str = "alerts/state/";
+// how long resolved alerts stay listed
+pub const RESOLVED_KEPT_SECS: u64 = 60 * 60 * 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    Count,
+    DeletedCount,
+    PurgedCount,
+    // deleted_count / count, f64::MAX if there are deletes but no creates
+    // (json has no infinity)
+    DeletedRatio,
+}
+
+impl AlertMetric {
+    pub fn of(&self, counts: &NsidCounts) -> f64 {
+        match self {
+            Self::Count => counts.count as f64,
+            Self::DeletedCount => counts.deleted_count as f64,
+            Self::PurgedCount => counts.purged_count as f64,
+            Self::DeletedRatio if counts.count == 0 && counts.deleted_count > 0 => f64::MAX,
+            Self::DeletedRatio if counts.count == 0 => 0.0,
+            Self::DeletedRatio => counts.deleted_count as f64 / counts.count as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertOp {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl AlertOp {
+    pub fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Self::Gt => value > threshold,
+            Self::Gte => value >= threshold,
+            Self::Lt => value < threshold,
+            Self::Lte => value <= threshold,
+        }
+    }
+}
+
+/// fires for every nsid matching `nsid_pattern` whose `metric` compared
+/// with `op` to `threshold` holds, and resolves once it doesnt anymore.
+/// without a `window` the metric is of all the counts of the nsid and
+/// checked as they change, with one it is of the hits in the last `window`
+/// seconds and checked by the maintenance loop
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub id: SmolStr,
+    // an nsid, or a prefix of them ending in `*` (`*` alone is every nsid)
+    pub nsid_pattern: SmolStr,
+    pub metric: AlertMetric,
+    pub op: AlertOp,
+    pub threshold: f64,
+    #[serde(default)]
+    pub window: Option<u64>,
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.id.is_empty() || self.id.contains('/') {
+            return Err("the id cant be empty or have a /");
+        }
+        if self.nsid_pattern.is_empty() {
+            return Err("the nsid pattern cant be empty");
+        }
+        if !self.threshold.is_finite() {
+            return Err("the threshold has to be a number");
+        }
+        if self.window == Some(0) {
+            return Err("the window has to be at least a second");
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, nsid: &str) -> bool {
+        match self.nsid_pattern.strip_suffix('*') {
+            Some(prefix) => nsid.starts_with(prefix),
+            None => nsid == self.nsid_pattern,
+        }
+    }
+}
+
+/// an alert of a rule for one nsid, as listed and sent to the webhook
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub rule: SmolStr,
+    pub nsid: SmolStr,
+    // the metric when it fired, or when it resolved
+    pub value: f64,
+    // unix seconds
+    pub fired_at: u64,
+    pub resolved_at: Option<u64>,
+}
+
+impl Alert {
+    pub fn is_firing(&self) -> bool {
+        self.resolved_at.is_none()
+    }
+}
+
+// alert rules and the state of their alerts, both in `_meta`:
+// alerts/rule/{id} is the json rule, alerts/state/{id}/{nsid} the json
+// alert. the state is written before the alert is sent, so a restart doesnt
+// send the same one again
+pub struct Alerts {
+    meta: Partition,
+    rules: ArcliteSwap<Vec<AlertRule>>,
+    states: Mutex<AHashMap<(SmolStr, SmolStr), Alert>>,
+    // None until a notifier subscribes
+    queue: Mutex<Option<Sender<Alert>>>,
+    // serializes rule updates, readers just load the current rules
+    write_lock: Mutex<()>,
+}
+
+impl Alerts {
+    /// `initial` rules (from the config) are added to the stored ones,
+    /// replacing any with the same id
+    pub fn open(meta: Partition, initial: &[AlertRule]) -> AppResult<Self> {
+        let mut rules = Vec::new();
+        for res in meta.prefix(RULE_PREFIX) {
+            let (key, value) = res?;
+            match serde_json::from_slice::<AlertRule>(&value) {
+                Ok(rule) => rules.push(rule),
+                Err(err) => tracing::warn!(
+                    "dropping unreadable alert rule {}: {err}",
+                    String::from_utf8_lossy(&key)
+                ),
+            }
+        }
+        let mut states = AHashMap::new();
+        for res in meta.prefix(STATE_PREFIX) {
+            let (key, value) = res?;
+            match serde_json::from_slice::<Alert>(&value) {
+                Ok(alert) => {
+                    states.insert((alert.rule.clone(), alert.nsid.clone()), alert);
+                }
+                Err(err) => tracing::warn!(
+                    "dropping unreadable alert {}: {err}",
+                    String::from_utf8_lossy(&key)
+                ),
+            }
+        }
+        let alerts = Self {
+            meta,
+            rules: ArcliteSwap::new(ArcRefCnt::new(rules)),
+            states: Mutex::new(states),
+            queue: Mutex::new(None),
+            write_lock: Mutex::new(()),
+        };
+        for rule in initial {
+            if let Err(err) = rule.validate() {
+                tracing::warn!("ignoring alert rule {}: {err}", rule.id);
+                continue;
+            }
+            alerts.put_rule(rule.clone())?;
+        }
+        Ok(alerts)
+    }
+
+    /// alerts are sent to the returned queue from now on. when it is full
+    /// they are only listed
+    pub fn subscribe(&self, capacity: usize) -> Receiver<Alert> {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity.max(1));
+        *self.queue.lock() = Some(tx);
+        rx
+    }
+
+    pub fn rules(&self) -> Vec<AlertRule> {
+        self.rules.load().as_ref().clone()
+    }
+
+    /// adds the rule, or replaces the one with the same id
+    pub fn put_rule(&self, rule: AlertRule) -> AppResult<()> {
+        let _lock = self.write_lock.lock();
+        self.meta.insert(
+            format_smolstr!("{RULE_PREFIX}{}", rule.id),
+            serde_json::to_vec(&rule)?,
+        )?;
+        let mut rules = self.rules.load().as_ref().clone();
+        rules.retain(|other| other.id != rule.id);
+        rules.push(rule);
+        self.rules.store(ArcRefCnt::new(rules));
+        Ok(())
+    }
+
+    /// removes the rule and its alerts, false if there was none
+    pub fn remove_rule(&self, id: &str) -> AppResult<bool> {
+        let _lock = self.write_lock.lock();
+        let mut rules = self.rules.load().as_ref().clone();
+        let before = rules.len();
+        rules.retain(|rule| rule.id != id);
+        if rules.len() == before {
+            return Ok(false);
+        }
+        self.meta.remove(format_smolstr!("{RULE_PREFIX}{id}"))?;
+        let mut states = self.states.lock();
+        let nsids = states
+            .keys()
+            .filter(|(rule, _)| rule == id)
+            .map(|(_, nsid)| nsid.clone())
+            .collect::<Vec<_>>();
+        for nsid in nsids {
+            self.meta
+                .remove(format_smolstr!("{STATE_PREFIX}{id}/{nsid}"))?;
+            states.remove(&(SmolStr::new(id), nsid));
+        }
+        drop(states);
+        self.rules.store(ArcRefCnt::new(rules));
+        Ok(true)
+    }
+
+    /// the rules that need windowed counts, for the maintenance loop
+    pub fn windowed_rules(&self) -> Vec<AlertRule> {
+        let rules = self.rules.load();
+        rules
+            .iter()
+            .filter(|rule| rule.window.is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// checks the rules without a window against the new counts of `nsid`,
+    /// called from ingest so it only does comparisons unless an alert
+    /// fires or resolves
+    pub fn observe(&self, nsid: &SmolStr, counts: &NsidCounts, now: u64) {
+        let rules = self.rules.load();
+        if rules.is_empty() {
+            return;
+        }
+        for rule in rules.iter() {
+            if rule.window.is_none() && rule.matches(nsid) {
+                self.evaluate(rule, nsid, rule.metric.of(counts), now);
+            }
+        }
+    }
+
+    /// fires or resolves the alert of `rule` for `nsid` if `value` changed
+    /// which side of the threshold it is on
+    pub fn evaluate(&self, rule: &AlertRule, nsid: &SmolStr, value: f64, now: u64) {
+        let holds = rule.op.holds(value, rule.threshold);
+        let mut states = self.states.lock();
+        let key = (rule.id.clone(), nsid.clone());
+        let firing = states.get(&key).is_some_and(Alert::is_firing);
+        let alert = match (holds, firing) {
+            (true, false) => Alert {
+                rule: rule.id.clone(),
+                nsid: nsid.clone(),
+                value,
+                fired_at: now,
+                resolved_at: None,
+            },
+            (false, true) => Alert {
+                value,
+                resolved_at: Some(now),
+                ..states[&key].clone()
+            },
+            _ => return,
+        };
+        if let Err(err) = self.store(&alert) {
+            // not sent either, it is tried again on the next change
+            tracing::error!("cant store alert {} for {nsid}: {err}", rule.id);
+            return;
+        }
+        match alert.is_firing() {
+            true => tracing::warn!("alert {} fired for {nsid} at {value}", rule.id),
+            false => tracing::info!("alert {} resolved for {nsid}", rule.id),
+        }
+        states.insert(key, alert.clone());
+        drop(states);
+        self.send(alert);
+    }
+
+    fn store(&self, alert: &Alert) -> AppResult<()> {
+        self.meta.insert(
+            format_smolstr!("{STATE_PREFIX}{}/{}", alert.rule, alert.nsid),
+            serde_json::to_vec(alert)?,
+        )?;
+        Ok(())
+    }
+
+    fn send(&self, alert: Alert) {
+        let queue = self.queue.lock();
+        let Some(queue) = queue.as_ref() else {
+            return;
+        };
+        match queue.try_send(alert) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(alert)) => tracing::warn!(
+                "alert queue is full, {} for {} is only listed",
+                alert.rule,
+                alert.nsid
+            ),
+        }
+    }
+
+    /// the firing alerts and the ones resolved since `RESOLVED_KEPT_SECS`
+    /// before `now`, newest first
+    pub fn list(&self, now: u64) -> (Vec<Alert>, Vec<Alert>) {
+        let cutoff = now.saturating_sub(RESOLVED_KEPT_SECS);
+        let (mut firing, mut resolved): (Vec<_>, Vec<_>) = self
+            .states
+            .lock()
+            .values()
+            .filter(|alert| alert.resolved_at.is_none_or(|at| at >= cutoff))
+            .cloned()
+            .partition(Alert::is_firing);
+        firing.sort_unstable_by(|a, b| b.fired_at.cmp(&a.fired_at));
+        resolved.sort_unstable_by(|a, b| b.resolved_at.cmp(&a.resolved_at));
+        (firing, resolved)
+    }
+
+    /// forgets alerts that resolved more than `RESOLVED_KEPT_SECS` ago
+    pub fn prune(&self, now: u64) -> AppResult<()> {
+        let cutoff = now.saturating_sub(RESOLVED_KEPT_SECS);
+        let mut states = self.states.lock();
+        let expired = states
+            .iter()
+            .filter(|(_, alert)| alert.resolved_at.is_some_and(|at| at < cutoff))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            let (rule, nsid) = &key;
+            self.meta
+                .remove(format_smolstr!("{STATE_PREFIX}{rule}/{nsid}"))?;
+            states.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alerts_fire_once() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-alerts-{}",
+            std::process::id()
+        ));
+        let ks = fjall::Config::new(&path).open().unwrap();
+        let meta = ks.open_partition("_meta", Default::default()).unwrap();
+        let milestone = AlertRule {
+            id: SmolStr::new("milestone"),
+            nsid_pattern: SmolStr::new("app.bsky.*"),
+            metric: AlertMetric::Count,
+            op: AlertOp::Gte,
+            threshold: 3.0,
+            window: None,
+        };
+        let alerts = Alerts::open(meta.clone(), std::slice::from_ref(&milestone)).unwrap();
+        let mut rx = alerts.subscribe(8);
+        let like = SmolStr::new("app.bsky.feed.like");
+        let counts = |count| NsidCounts {
+            count,
+            ..Default::default()
+        };
+
+        alerts.observe(&like, &counts(2), 1000);
+        alerts.observe(&SmolStr::new("com.example.thing"), &counts(5), 1000);
+        assert!(rx.try_recv().is_err());
+        alerts.observe(&like, &counts(3), 1001);
+        alerts.observe(&like, &counts(4), 1002);
+        let fired = rx.try_recv().unwrap();
+        assert_eq!((fired.nsid.as_str(), fired.fired_at), (like.as_str(), 1001));
+        assert!(rx.try_recv().is_err());
+
+        // a restart remembers it fired
+        let alerts = Alerts::open(meta.clone(), &[]).unwrap();
+        let mut rx = alerts.subscribe(8);
+        alerts.observe(&like, &counts(5), 1003);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(alerts.list(1003).0, [fired.clone()]);
+        assert_eq!(alerts.rules(), [milestone]);
+
+        // resolving is an alert too, and it stays listed for a while
+        let windowed = AlertRule {
+            id: SmolStr::new("deletes"),
+            nsid_pattern: SmolStr::new("*"),
+            metric: AlertMetric::DeletedRatio,
+            op: AlertOp::Gt,
+            threshold: 1.0,
+            window: Some(60),
+        };
+        alerts.put_rule(windowed.clone()).unwrap();
+        alerts.observe(&like, &counts(6), 1004);
+        assert!(rx.try_recv().is_err());
+        let deletes = NsidCounts {
+            deleted_count: 2,
+            ..counts(1)
+        };
+        alerts.evaluate(&windowed, &like, windowed.metric.of(&deletes), 1005);
+        assert!(rx.try_recv().unwrap().is_firing());
+        alerts.evaluate(&windowed, &like, 0.5, 1010);
+        assert_eq!(rx.try_recv().unwrap().resolved_at, Some(1010));
+        let (firing, resolved) = alerts.list(1010);
+        assert_eq!((firing.len(), resolved.len()), (1, 1));
+        alerts.prune(1010 + RESOLVED_KEPT_SECS + 1).unwrap();
+        assert!(alerts.list(1010).1.is_empty());
+
+        assert!(alerts.remove_rule("milestone").unwrap());
+        assert!(!alerts.remove_rule("milestone").unwrap());
+        assert!(alerts.list(1010).0.is_empty());
+        drop(alerts);
+        drop(ks);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -25,6 +25,17 @@ pub struct Item<T> {
     phantom: PhantomData<T>,
 }
 
+// by hand since a derive would want `T: Clone`, and only the bytes are cloned
+impl<T> Clone for Item<T> {
+    fn clone(&self) -> Self {
+        Item {
+            timestamp: self.timestamp,
+            data: self.data.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 impl<T> Item<T>
 where
     T: Archive,
@@ -46,11 +57,139 @@ impl<T: for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Er
     }
 }
 
+// a delta-of-delta of this means the next varint is the timestamp itself
+// (u64) and the delta chain starts over from it. blocks written before schema
+// version 2 dont have it, their timestamps are far too close together for a
+// delta-of-delta this big
+const RESET_MARKER: i64 = i64::MIN;
+
+// a block never has 0 items, so a leading 0 where the item count goes means
+// the granularity of its timestamps follows, and then the count. deltas of
+// such a block are in units of the granularity. blocks without it (all of
+// them before privacy mode) have a granularity of 1
+const QUANTIZED: usize = 0;
+
+// a granularity of 0 after the leading 0 marks a block that was recompressed
+// for long term storage. its tier and item count follow, then the whole
+// block as `ItemEncoder` wrote it, compressed with the codec of the tier
+const TIERED: u64 = 0;
+
+fn corrupt(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// how a stored block is compressed on top of what the partition does.
+/// blocks are written `Plain` and old ones move up, see `recompress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Plain = 0,
+    Zstd = 1,
+}
+
+impl Tier {
+    /// the tier compressing with `codec`, like `RECOMPRESS_CODEC` names it
+    pub fn from_codec(codec: &str) -> Option<Self> {
+        match codec {
+            "zstd" => Some(Tier::Zstd),
+            _ => None,
+        }
+    }
+
+    fn from_marker(marker: u64) -> io::Result<Self> {
+        match marker {
+            0 => Ok(Tier::Plain),
+            1 => Ok(Tier::Zstd),
+            _ => Err(corrupt("corrupt block: unknown tier")),
+        }
+    }
+}
+
+/// the header of a recompressed block, None for a plain one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TierHeader {
+    pub tier: Tier,
+    pub item_count: usize,
+    // where the compressed block starts
+    offset: usize,
+}
+
+impl TierHeader {
+    pub fn read(value: &[u8]) -> io::Result<Option<Self>> {
+        let mut reader = value;
+        if !matches!(reader.read_varint::<usize>(), Ok(QUANTIZED)) {
+            return Ok(None);
+        }
+        if !matches!(reader.read_varint::<u64>(), Ok(TIERED)) {
+            return Ok(None);
+        }
+        let tier = Tier::from_marker(reader.read_varint()?)?;
+        let item_count = reader.read_varint()?;
+        Ok(Some(TierHeader {
+            tier,
+            item_count,
+            offset: value.len() - reader.len(),
+        }))
+    }
+}
+
+/// the tier `value` is stored in, only reads its header
+pub fn block_tier(value: &[u8]) -> io::Result<Tier> {
+    Ok(TierHeader::read(value)?.map_or(Tier::Plain, |header| header.tier))
+}
+
+/// `value` moved to `tier`, `level` is the level of its codec. a block that
+/// already is in a tier is decompressed first
+pub fn recompress(value: &[u8], tier: Tier, level: i32) -> io::Result<Vec<u8>> {
+    let plain = decompress(value)?;
+    let plain = plain.as_deref().unwrap_or(value);
+    let mut out = Vec::with_capacity(plain.len() / 2);
+    match tier {
+        Tier::Plain => out.extend_from_slice(plain),
+        Tier::Zstd => {
+            out.write_varint(QUANTIZED)?;
+            out.write_varint(TIERED)?;
+            out.write_varint(tier as u64)?;
+            out.write_varint(plain_item_count(plain)?)?;
+            zstd::stream::copy_encode(plain, &mut out, level)?;
+        }
+    }
+    Ok(out)
+}
+
+// the item count in the header `ItemEncoder` writes
+fn plain_item_count(mut plain: &[u8]) -> io::Result<usize> {
+    let count = plain.read_varint()?;
+    if count != QUANTIZED {
+        return Ok(count);
+    }
+    plain.read_varint::<u64>()?;
+    plain.read_varint()
+}
+
+/// the block as `ItemEncoder` wrote it, None if `value` already is that
+pub fn decompress(value: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let Some(header) = TierHeader::read(value)? else {
+        return Ok(None);
+    };
+    let compressed = &value[header.offset..];
+    match header.tier {
+        Tier::Plain => Ok(Some(compressed.to_vec())),
+        Tier::Zstd => zstd::stream::decode_all(compressed).map(Some),
+    }
+}
+
 pub struct ItemEncoder<W: Write, T> {
     writer: W,
-    prev_timestamp: u64,
+    // None until the first item, its timestamp is the start of the block key
+    prev_timestamp: Option<u64>,
     prev_delta: i64,
     item_count: usize,
+    // deltas bigger than this (either way) reset the chain, see
+    // `ItemEncoder::reset_over`
+    reset_over: Option<u64>,
+    // see `ItemEncoder::quantized`
+    granularity: u64,
     _item: PhantomData<T>,
 }
 
@@ -59,13 +198,33 @@ impl<W: Write, T> ItemEncoder<W, T> {
         assert!(item_count > 0);
         ItemEncoder {
             writer,
-            prev_timestamp: 0,
+            prev_timestamp: None,
             prev_delta: 0,
             item_count,
+            reset_over: None,
+            granularity: 1,
             _item: PhantomData,
         }
     }
 
+    /// writes the timestamp of an item whose delta to the previous one is
+    /// over `max_delta` seconds as is, and starts the delta chain over from
+    /// it. an item with a wildly wrong timestamp (and the one after it) then
+    /// costs a plain timestamp, and deltas that would overflow cant happen
+    pub fn reset_over(mut self, max_delta: u64) -> Self {
+        self.reset_over = Some(max_delta);
+        self
+    }
+
+    /// stores deltas divided by `granularity`, for blocks whose timestamps
+    /// were rounded to it. a delta that isnt a multiple of it resets the
+    /// chain like `reset_over` does (and is an error without it), so a block
+    /// mixing rounded and precise timestamps still round trips
+    pub fn quantized(mut self, granularity: u64) -> Self {
+        self.granularity = granularity.max(1);
+        self
+    }
+
     /// NOTE: this is a best effort estimate of the encoded length of the block.
     /// if T contains variable-length data, the encoded length may be larger than this estimate.
     pub fn encoded_len(item_count: usize) -> usize {
@@ -74,21 +233,52 @@ impl<W: Write, T> ItemEncoder<W, T> {
     }
 
     pub fn encode(&mut self, item: &Item<T>) -> io::Result<()> {
-        if self.prev_timestamp == 0 {
+        self.encode_raw(item.timestamp, &item.data)
+    }
+
+    /// `encode` for the bytes of an item that is already serialized
+    pub fn encode_raw(&mut self, timestamp: u64, data: &[u8]) -> io::Result<()> {
+        let Some(prev_timestamp) = self.prev_timestamp else {
+            if self.granularity > 1 {
+                self.writer.write_varint(QUANTIZED)?;
+                self.writer.write_varint(self.granularity)?;
+            }
             self.writer.write_varint(self.item_count)?;
             // self.writer.write_varint(item.timestamp)?;
-            self.prev_timestamp = item.timestamp;
-            self.write_data(&item.data)?;
+            self.prev_timestamp = Some(timestamp);
+            self.write_data(data)?;
             return Ok(());
-        }
-
-        let delta = (item.timestamp as i128 - self.prev_timestamp as i128) as i64;
+        };
 
-        self.writer.write_varint(delta - self.prev_delta)?;
-        self.prev_timestamp = item.timestamp;
-        self.prev_delta = delta;
+        let granularity = self.granularity as i128;
+        let delta = Some(timestamp as i128 - prev_timestamp as i128)
+            .filter(|delta| delta % granularity == 0)
+            .and_then(|delta| i64::try_from(delta / granularity).ok());
+        let delta_of_delta = delta
+            .and_then(|delta| delta.checked_sub(self.prev_delta))
+            .filter(|delta_of_delta| *delta_of_delta != RESET_MARKER);
+        let reset = match (self.reset_over, delta) {
+            (Some(max_delta), Some(delta)) => {
+                delta.unsigned_abs().saturating_mul(self.granularity) > max_delta
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        match (delta, delta_of_delta) {
+            (Some(delta), Some(delta_of_delta)) if !reset => {
+                self.writer.write_varint(delta_of_delta)?;
+                self.prev_delta = delta;
+            }
+            _ if self.reset_over.is_some() => {
+                self.writer.write_varint(RESET_MARKER)?;
+                self.writer.write_varint(timestamp)?;
+                self.prev_delta = 0;
+            }
+            _ => return Err(corrupt("timestamp delta overflows, cant encode it")),
+        }
+        self.prev_timestamp = Some(timestamp);
 
-        self.write_data(&item.data)?;
+        self.write_data(data)?;
 
         Ok(())
     }
@@ -109,6 +299,7 @@ pub struct ItemDecoder<R, T> {
     reader: R,
     current_timestamp: u64,
     current_delta: i64,
+    granularity: u64,
     items_read: usize,
     expected: usize,
     _item: PhantomData<T>,
@@ -116,16 +307,30 @@ pub struct ItemDecoder<R, T> {
 
 impl<R: Read, T: Archive> ItemDecoder<R, T> {
     pub fn new(mut reader: R, start_timestamp: u64) -> io::Result<Self> {
-        let expected = match reader.read_varint() {
+        let mut expected = match reader.read_varint() {
             Ok(expected) => expected,
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
             Err(e) => return Err(e.into()),
         };
+        let mut granularity = 1;
+        if expected == QUANTIZED {
+            match reader.read_varint::<u64>() {
+                Ok(TIERED) => return Err(corrupt("block is tiered, decompress it first")),
+                Ok(read) => {
+                    granularity = read;
+                    expected = reader.read_varint()?;
+                }
+                // an empty block
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => return Err(e),
+            }
+        }
 
         Ok(ItemDecoder {
             reader,
             current_timestamp: start_timestamp,
             current_delta: 0,
+            granularity,
             items_read: 0,
             expected,
             _item: PhantomData,
@@ -136,6 +341,11 @@ impl<R: Read, T: Archive> ItemDecoder<R, T> {
         self.expected
     }
 
+    /// what the timestamps of this block were rounded to, 1 if they werent
+    pub fn granularity(&self) -> u64 {
+        self.granularity
+    }
+
     pub fn decode(&mut self) -> io::Result<Option<Item<T>>> {
         if self.items_read == 0 {
             // read the first timestamp
@@ -193,9 +403,18 @@ impl<R: Read, T: Archive> ItemDecoder<R, T> {
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        self.current_delta += delta;
-        self.current_timestamp =
-            (self.current_timestamp as i128 + self.current_delta as i128) as u64;
+        if delta == RESET_MARKER {
+            self.current_timestamp = self.reader.read_varint::<u64>()?;
+            self.current_delta = 0;
+            return Ok(Some(self.current_timestamp));
+        }
+        self.current_delta = self
+            .current_delta
+            .checked_add(delta)
+            .ok_or_else(|| corrupt("corrupt block: timestamp delta overflows"))?;
+        let delta = self.current_delta as i128 * self.granularity as i128;
+        self.current_timestamp = u64::try_from(self.current_timestamp as i128 + delta)
+            .map_err(|_| corrupt("corrupt block: timestamp out of range"))?;
         Ok(Some(self.current_timestamp))
     }
 
@@ -518,4 +737,179 @@ mod test {
             "a".repeat(1000)
         );
     }
+
+    fn encode_all(timestamps: &[u64], reset_over: Option<u64>) -> io::Result<Vec<u8>> {
+        let mut encoder = ItemEncoder::new(Vec::new(), timestamps.len());
+        if let Some(max_delta) = reset_over {
+            encoder = encoder.reset_over(max_delta);
+        }
+        for (id, timestamp) in timestamps.iter().enumerate() {
+            let data = TestData {
+                id: id as u32,
+                value: String::new(),
+            };
+            encoder.encode(&Item::new(*timestamp, &data))?;
+        }
+        encoder.finish()
+    }
+
+    fn decode_all(buffer: Vec<u8>, start: u64) -> io::Result<Vec<u64>> {
+        ItemDecoder::<_, TestData>::new(Cursor::new(buffer), start)?
+            .map(|item| item.map(|item| item.timestamp))
+            .collect()
+    }
+
+    #[test]
+    fn test_timestamp_outliers_round_trip() {
+        let outliers = [
+            0,
+            1,
+            9_214_646_400, // 2262
+            i64::MAX as u64,
+            1 << 63,
+            u64::MAX - 1,
+            u64::MAX,
+        ];
+        // xorshift, so failures can be replayed
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..500 {
+            let len = (next() % 64 + 1) as usize;
+            let mut timestamp = 1_700_000_000 + next() % 1_000_000;
+            let timestamps = (0..len)
+                .map(|_| {
+                    if next() % 8 == 0 {
+                        return outliers[(next() % outliers.len() as u64) as usize];
+                    }
+                    timestamp += next() % 5;
+                    timestamp
+                })
+                .collect::<Vec<_>>();
+
+            let buffer = encode_all(&timestamps, Some(60)).unwrap();
+            assert_eq!(decode_all(buffer, timestamps[0]).unwrap(), timestamps);
+            // without resets the deltas can overflow, that has to be an error
+            // and not a block that decodes to something else
+            if let Ok(buffer) = encode_all(&timestamps, None) {
+                assert_eq!(decode_all(buffer, timestamps[0]).unwrap(), timestamps);
+            }
+        }
+    }
+
+    #[test]
+    fn test_outlier_cost_is_bounded() {
+        let steady = (0..100).map(|i| 1_700_000_000 + i).collect::<Vec<_>>();
+        let mut jumped = steady.clone();
+        jumped[10] = 9_214_646_400;
+        let steady_len = encode_all(&steady, Some(60)).unwrap().len();
+        let buffer = encode_all(&jumped, Some(60)).unwrap();
+        // the reset to the outlier and the one back, a marker and a
+        // timestamp each
+        assert!(
+            buffer.len() <= steady_len + 48,
+            "{} vs {steady_len}",
+            buffer.len()
+        );
+        assert_eq!(decode_all(buffer, jumped[0]).unwrap(), jumped);
+    }
+
+    #[test]
+    fn test_quantized_blocks_round_trip() {
+        let rounded = (0..200)
+            .map(|i| 1_700_000_040 + (i / 3) * 60)
+            .collect::<Vec<_>>();
+        let encode = |timestamps: &[u64], granularity| {
+            let mut encoder = ItemEncoder::new(Vec::new(), timestamps.len())
+                .reset_over(60 * 60)
+                .quantized(granularity);
+            for timestamp in timestamps {
+                let data = TestData {
+                    id: 0,
+                    value: String::new(),
+                };
+                encoder.encode(&Item::new(*timestamp, &data)).unwrap();
+            }
+            encoder.finish().unwrap()
+        };
+        let plain = encode(&rounded, 1);
+        let quantized = encode(&rounded, 60);
+        assert!(quantized.len() < plain.len());
+        // blocks of either kind decode side by side
+        for buffer in [plain, quantized] {
+            assert_eq!(decode_all(buffer, rounded[0]).unwrap(), rounded);
+        }
+        let decoder = ItemDecoder::<_, TestData>::new(Cursor::new(encode(&rounded, 60)), 0);
+        assert_eq!(decoder.unwrap().granularity(), 60);
+
+        // precise timestamps in a quantized block cost a reset each, but
+        // still come back as they were
+        let mut mixed = rounded.clone();
+        mixed[5] += 7;
+        mixed[150] += 59;
+        let buffer = encode(&mixed, 60);
+        assert_eq!(decode_all(buffer, mixed[0]).unwrap(), mixed);
+    }
+
+    #[test]
+    fn test_corrupt_deltas_are_errors() {
+        // a delta that would take the timestamp below zero
+        let mut buffer = Vec::new();
+        buffer.write_varint(2_usize).unwrap();
+        buffer.write_varint(0_usize).unwrap();
+        buffer.write_varint(-2000_i64).unwrap();
+        buffer.write_varint(0_usize).unwrap();
+        let err = decode_all(buffer, 1000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // deltas that add up past i64
+        let mut buffer = Vec::new();
+        buffer.write_varint(3_usize).unwrap();
+        buffer.write_varint(0_usize).unwrap();
+        for _ in 0..2 {
+            buffer.write_varint(i64::MAX).unwrap();
+            buffer.write_varint(0_usize).unwrap();
+        }
+        let err = decode_all(buffer, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_tiered_blocks_round_trip() {
+        let timestamps = (0..500).map(|i| 1_700_000_000 + i * 3).collect::<Vec<_>>();
+        for granularity in [1, 3] {
+            let mut encoder = ItemEncoder::new(Vec::new(), timestamps.len()).quantized(granularity);
+            for timestamp in &timestamps {
+                let data = TestData {
+                    id: 7,
+                    value: "same every time".to_string(),
+                };
+                encoder.encode(&Item::new(*timestamp, &data)).unwrap();
+            }
+            let plain = encoder.finish().unwrap();
+            assert_eq!(block_tier(&plain).unwrap(), Tier::Plain);
+            assert_eq!(decompress(&plain).unwrap(), None);
+
+            let tiered = recompress(&plain, Tier::Zstd, 19).unwrap();
+            assert!(tiered.len() < plain.len());
+            let header = TierHeader::read(&tiered).unwrap().unwrap();
+            assert_eq!((header.tier, header.item_count), (Tier::Zstd, 500));
+            // tiered blocks arent read as they are
+            assert!(decode_all(tiered.clone(), timestamps[0]).is_err());
+            let restored = decompress(&tiered).unwrap().unwrap();
+            assert_eq!(restored, plain);
+            assert_eq!(decode_all(restored, timestamps[0]).unwrap(), timestamps);
+
+            // moving a block again starts from its plain form
+            assert_eq!(
+                recompress(&tiered, Tier::Zstd, 3).unwrap(),
+                recompress(&plain, Tier::Zstd, 3).unwrap()
+            );
+            assert_eq!(recompress(&tiered, Tier::Plain, 0).unwrap(), plain);
+        }
+    }
 }
@@ -0,0 +1,471 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+    },
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use rclite::Arc;
+use serde::Serialize;
+use smol_str::SmolStr;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    config,
+    db::handle::{BlockKey, BlockRef, Item, ItemDecoder},
+    error::{AppError, AppResult},
+};
+
+// decoded blocks of every partition (of every instance), up to a budget of
+// bytes. it is a segmented lru: blocks come in on probation and move to the
+// protected segment once they are read again. a scan that reads lots of
+// blocks once only cycles probation, so the blocks dashboards keep asking
+// for stay protected. exports dont even admit what they read, see
+// `Admission`.
+//
+// entries are found by partition and block key, but they also remember a
+// hash of the stored block and only hit if it matches. block keys get reused
+// (compaction can write a block with the key of one it replaced) and pinned
+// snapshots keep reading old blocks after they were invalidated, neither can
+// get a stale block out of the cache that way
+pub struct BlockCache {
+    budget: usize,
+    inner: Mutex<Segments>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+    admitted: AtomicU64,
+    evicted: AtomicU64,
+    invalidated: AtomicU64,
+}
+
+/// what a read does with blocks that arent cached yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// decode them whole and cache them, for reads that get repeated
+    Admit,
+    /// decode them as they are iterated and dont cache them, for exports
+    /// and other reads that go over lots of blocks once
+    Bypass,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BlockCacheStats {
+    pub budget: usize,
+    pub bytes: usize,
+    pub blocks: usize,
+    pub protected_bytes: usize,
+    pub hits: u64,
+    // lookups that missed and admitted the block
+    pub misses: u64,
+    // lookups that missed and didnt admit the block (exports)
+    pub bypassed: u64,
+    // hits / (hits + misses)
+    pub hit_rate: f64,
+    pub admitted: u64,
+    pub evicted: u64,
+    pub invalidated: u64,
+}
+
+// where a block is cached, see `BlockCache`
+struct BlockId {
+    key: (SmolStr, u64, u64),
+    hash: u64,
+}
+
+impl BlockId {
+    fn new(nsid: &SmolStr, block: &BlockRef) -> Self {
+        let BlockKey { start, end } = block.key();
+        Self {
+            key: (nsid.clone(), start, end),
+            hash: xxh3_64(block.value()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Probation,
+    Protected,
+}
+
+struct Entry {
+    hash: u64,
+    items: Arc<Vec<Item>>,
+    bytes: usize,
+    segment: Segment,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Segments {
+    entries: AHashMap<(SmolStr, u64, u64), Entry>,
+    // by last use, least recently used first
+    probation: BTreeMap<u64, (SmolStr, u64, u64)>,
+    protected: BTreeMap<u64, (SmolStr, u64, u64)>,
+    probation_bytes: usize,
+    protected_bytes: usize,
+    tick: u64,
+}
+
+impl Segments {
+    fn unlink(&mut self, key: &(SmolStr, u64, u64)) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        match entry.segment {
+            Segment::Probation => {
+                self.probation.remove(&entry.tick);
+                self.probation_bytes -= entry.bytes;
+            }
+            Segment::Protected => {
+                self.protected.remove(&entry.tick);
+                self.protected_bytes -= entry.bytes;
+            }
+        }
+        Some(entry)
+    }
+
+    // as the most recently used entry of `segment`
+    fn link(&mut self, key: (SmolStr, u64, u64), mut entry: Entry, segment: Segment) {
+        self.tick += 1;
+        entry.tick = self.tick;
+        entry.segment = segment;
+        match segment {
+            Segment::Probation => {
+                self.probation.insert(self.tick, key.clone());
+                self.probation_bytes += entry.bytes;
+            }
+            Segment::Protected => {
+                self.protected.insert(self.tick, key.clone());
+                self.protected_bytes += entry.bytes;
+            }
+        }
+        self.entries.insert(key, entry);
+    }
+
+    // moves what doesnt fit in protected back on probation, then evicts from
+    // probation (and protected once probation is empty) until it all fits.
+    // returns how many blocks were evicted
+    fn shrink(&mut self, budget: usize) -> u64 {
+        while self.protected_bytes > protected_budget(budget) {
+            let Some((_, key)) = self.protected.first_key_value() else {
+                break;
+            };
+            let key = key.clone();
+            if let Some(entry) = self.unlink(&key) {
+                self.link(key, entry, Segment::Probation);
+            }
+        }
+        let mut evicted = 0;
+        while self.probation_bytes + self.protected_bytes > budget {
+            let oldest = self
+                .probation
+                .first_key_value()
+                .or_else(|| self.protected.first_key_value());
+            let Some((_, key)) = oldest else {
+                break;
+            };
+            let key = key.clone();
+            self.unlink(&key);
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+// the rest is for probation, which is all new blocks can take up
+fn protected_budget(budget: usize) -> usize {
+    budget / 5 * 4
+}
+
+// roughly what the decoded items take up in memory
+fn items_bytes(items: &[Item]) -> usize {
+    size_of::<Entry>()
+        + items
+            .iter()
+            .map(|item| size_of::<Item>() + item.data.len())
+            .sum::<usize>()
+}
+
+/// the items of a block, either decoded as they are iterated or cloned out
+/// of the cache
+pub enum BlockItems {
+    Cached(Arc<Vec<Item>>, usize),
+    Decoding(ItemDecoder),
+}
+
+impl BlockItems {
+    pub fn item_count(&self) -> usize {
+        match self {
+            BlockItems::Cached(items, _) => items.len(),
+            BlockItems::Decoding(decoder) => decoder.item_count(),
+        }
+    }
+}
+
+impl Iterator for BlockItems {
+    type Item = AppResult<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            BlockItems::Cached(items, next) => {
+                let item = items.get(*next)?.clone();
+                *next += 1;
+                Some(Ok(item))
+            }
+            BlockItems::Decoding(decoder) => {
+                decoder.next().map(|item| item.map_err(AppError::from))
+            }
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            inner: Default::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+            admitted: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            invalidated: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, id: &BlockId) -> Option<Arc<Vec<Item>>> {
+        let mut inner = self.inner.lock();
+        if inner
+            .entries
+            .get(&id.key)
+            .is_none_or(|entry| entry.hash != id.hash)
+        {
+            return None;
+        }
+        // read again, so it is worth protecting
+        let entry = inner.unlink(&id.key)?;
+        let items = entry.items.clone();
+        inner.link(id.key.clone(), entry, Segment::Protected);
+        let evicted = inner.shrink(self.budget);
+        drop(inner);
+        self.evicted.fetch_add(evicted, AtomicOrdering::Relaxed);
+        self.hits.fetch_add(1, AtomicOrdering::Relaxed);
+        Some(items)
+    }
+
+    fn insert(&self, id: BlockId, items: Arc<Vec<Item>>) {
+        let bytes = items_bytes(&items);
+        // it would push everything else on probation out, and then itself
+        // once anything else comes in
+        if bytes > self.budget - protected_budget(self.budget) {
+            return;
+        }
+        let entry = Entry {
+            hash: id.hash,
+            items,
+            bytes,
+            segment: Segment::Probation,
+            tick: 0,
+        };
+        let mut inner = self.inner.lock();
+        // an older version of the block
+        inner.unlink(&id.key);
+        inner.link(id.key, entry, Segment::Probation);
+        let evicted = inner.shrink(self.budget);
+        drop(inner);
+        self.evicted.fetch_add(evicted, AtomicOrdering::Relaxed);
+        self.admitted.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// the items of `block` decoded whole, cached with `Admit`
+    pub fn items(
+        &self,
+        nsid: &SmolStr,
+        block: BlockRef,
+        admission: Admission,
+    ) -> AppResult<Arc<Vec<Item>>> {
+        let id = BlockId::new(nsid, &block);
+        if let Some(items) = self.get(&id) {
+            return Ok(items);
+        }
+        let items = Arc::new(block.into_decoder()?.collect::<Result<Vec<_>, _>>()?);
+        match admission {
+            Admission::Admit => {
+                self.misses.fetch_add(1, AtomicOrdering::Relaxed);
+                self.insert(id, items.clone());
+            }
+            Admission::Bypass => {
+                self.bypassed.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+        }
+        Ok(items)
+    }
+
+    /// like `BlockCache::items`, but blocks that arent cached and arent
+    /// admitted are decoded as they are iterated
+    pub fn read(
+        &self,
+        nsid: &SmolStr,
+        block: BlockRef,
+        admission: Admission,
+    ) -> AppResult<BlockItems> {
+        if admission == Admission::Admit {
+            return Ok(BlockItems::Cached(self.items(nsid, block, admission)?, 0));
+        }
+        if let Some(items) = self.get(&BlockId::new(nsid, &block)) {
+            return Ok(BlockItems::Cached(items, 0));
+        }
+        self.bypassed.fetch_add(1, AtomicOrdering::Relaxed);
+        Ok(BlockItems::Decoding(block.into_decoder()?))
+    }
+
+    /// call when a block is written or removed. reads would miss the stale
+    /// entry anyway, this frees it
+    pub fn invalidate(&self, nsid: &SmolStr, key: BlockKey) {
+        let removed = self
+            .inner
+            .lock()
+            .unlink(&(nsid.clone(), key.start, key.end));
+        if removed.is_some() {
+            self.invalidated.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let (bytes, blocks, protected_bytes) = {
+            let inner = self.inner.lock();
+            (
+                inner.probation_bytes + inner.protected_bytes,
+                inner.entries.len(),
+                inner.protected_bytes,
+            )
+        };
+        let hits = self.hits.load(AtomicOrdering::Relaxed);
+        let misses = self.misses.load(AtomicOrdering::Relaxed);
+        BlockCacheStats {
+            budget: self.budget,
+            bytes,
+            blocks,
+            protected_bytes,
+            hits,
+            misses,
+            bypassed: self.bypassed.load(AtomicOrdering::Relaxed),
+            hit_rate: hits as f64 / (hits + misses).max(1) as f64,
+            admitted: self.admitted.load(AtomicOrdering::Relaxed),
+            evicted: self.evicted.load(AtomicOrdering::Relaxed),
+            invalidated: self.invalidated.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+/// the cache every `Db` in the process shares, `BLOCK_CACHE_BYTES` big
+/// (0 turns it off)
+pub fn block_cache() -> &'static BlockCache {
+    static CACHE: OnceLock<BlockCache> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        BlockCache::new(config::env_or("BLOCK_CACHE_BYTES", 256 << 20, |bytes| {
+            bytes.parse::<usize>().ok()
+        }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use fjall::Slice;
+
+    use super::*;
+    use crate::db::{HitOp, NsidHit, handle::LexiconHandle};
+
+    fn block(start: u64, count: u64) -> BlockRef {
+        let items = (start..start + count).map(|timestamp| {
+            Item::new(
+                timestamp,
+                &NsidHit {
+                    op: HitOp::Create,
+                    ..Default::default()
+                },
+            )
+        });
+        let block = LexiconHandle::encode_block_from_items(items, count as usize, 1).unwrap();
+        BlockRef::new(Slice::from(&block.key[..]), Slice::from(block.data)).unwrap()
+    }
+
+    #[test]
+    fn test_blocks_are_cached_until_invalidated() {
+        let nsid = SmolStr::new_static("app.bsky.feed.like");
+        let cache = BlockCache::new(1 << 20);
+        let first = block(1000, 100);
+        let items = cache.items(&nsid, first.clone(), Admission::Admit).unwrap();
+        assert_eq!(items.len(), 100);
+        let again = cache.items(&nsid, first.clone(), Admission::Admit).unwrap();
+        assert!(Arc::ptr_eq(&items, &again));
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+
+        // a block with the same key but other hits doesnt get the cached ones
+        let rewritten = {
+            let items = (1000..1100).rev().map(|timestamp| {
+                Item::new(
+                    timestamp,
+                    &NsidHit {
+                        op: HitOp::Delete,
+                        ..Default::default()
+                    },
+                )
+            });
+            let block = LexiconHandle::encode_block_from_items(items, 100, 1).unwrap();
+            BlockRef::new(first.raw_key().clone(), Slice::from(block.data)).unwrap()
+        };
+        let items = cache.items(&nsid, rewritten, Admission::Admit).unwrap();
+        assert_eq!(items[0].deser().unwrap().op, HitOp::Delete);
+
+        cache.invalidate(&nsid, first.key());
+        assert_eq!(cache.stats().blocks, 0);
+        // bypassing reads dont fill it
+        let read = cache.read(&nsid, first, Admission::Bypass).unwrap();
+        assert!(matches!(read, BlockItems::Decoding(_)));
+        assert_eq!(read.count(), 100);
+        assert_eq!(cache.stats().blocks, 0);
+    }
+
+    // the dashboard reads the same few blocks over and over while an export
+    // reads every block once. even with the export admitting what it reads
+    // (it shouldnt, see `Admission`) the hot blocks stay
+    #[test]
+    fn test_scan_doesnt_evict_hot_set() {
+        let nsid = SmolStr::new_static("app.bsky.feed.like");
+        let blocks = (0..200).map(|i| block(i * 1000, 100)).collect::<Vec<_>>();
+        let block_bytes = items_bytes(&cache_items(&blocks[0]));
+        // room for 40 blocks, 32 of them protected
+        let cache = BlockCache::new(block_bytes * 40);
+        let hot = &blocks[..10];
+        for round in 0..blocks.len() {
+            for block in hot {
+                cache.items(&nsid, block.clone(), Admission::Admit).unwrap();
+            }
+            cache
+                .items(&nsid, blocks[round].clone(), Admission::Admit)
+                .unwrap();
+        }
+        let stats = cache.stats();
+        assert!(stats.hit_rate > 0.85, "{stats:?}");
+        assert!(stats.bytes <= stats.budget);
+        assert!(stats.evicted > 0);
+        let hits = stats.hits;
+        for block in hot {
+            cache.items(&nsid, block.clone(), Admission::Admit).unwrap();
+        }
+        assert_eq!(cache.stats().hits, hits + hot.len() as u64);
+    }
+
+    fn cache_items(block: &BlockRef) -> Vec<Item> {
+        block
+            .clone()
+            .into_decoder()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use fjall::{Partition, Slice};
+use itertools::{Either, Itertools};
+use parking_lot::Mutex;
+use rkyv::rancor::Error;
+use smol_str::{SmolStr, format_smolstr};
+
+use crate::{
+    db::handle::BlockRef,
+    error::{AppError, AppResult},
+    utils::{ReadVariableExt, WriteVariableExt, year_month},
+};
+
+/// index entry for one segment file, stored in `_meta`
+#[derive(
+    Debug, Clone, Default, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, serde::Serialize,
+)]
+pub struct ColdSegment {
+    pub start: u64,
+    pub end: u64,
+    pub blocks: u64,
+    pub items: u64,
+    pub bytes: u64,
+}
+
+// cold tier: blocks that are old enough get moved out of fjall into lz4
+// compressed segment files, one per nsid per month:
+// <dir>/<nsid>/<YYYY-MM>.seg
+// segment payload is varint key len + key + varint value len + value, sorted by key
+pub struct ColdStore {
+    dir: PathBuf,
+    meta: Partition,
+    // segments are rewritten whole, so serialize writers
+    write_lock: Mutex<()>,
+}
+
+impl ColdStore {
+    pub fn open(dir: impl AsRef<Path>, meta: Partition) -> AppResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            meta,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    #[inline(always)]
+    fn index_prefix(nsid: &str) -> SmolStr {
+        format_smolstr!("cold/{nsid}/")
+    }
+
+    #[inline(always)]
+    fn month_of(timestamp: u64) -> SmolStr {
+        let (year, month) = year_month(timestamp);
+        format_smolstr!("{year:04}-{month:02}")
+    }
+
+    fn segment_path(&self, nsid: &str, month: &str) -> PathBuf {
+        self.dir.join(nsid).join(format!("{month}.seg"))
+    }
+
+    /// segments of an nsid, ordered by month
+    pub fn segments(
+        &self,
+        nsid: &str,
+    ) -> impl Iterator<Item = AppResult<(SmolStr, ColdSegment)>> + use<> {
+        let prefix = Self::index_prefix(nsid);
+        let prefix_len = prefix.len();
+        self.meta.prefix(prefix.to_string()).map(move |res| {
+            let (key, value) = res?;
+            let month = SmolStr::new(String::from_utf8_lossy(&key[prefix_len..]));
+            let segment = rkyv::from_bytes::<ColdSegment, Error>(&value)?;
+            Ok((month, segment))
+        })
+    }
+
+    pub fn has_segments(&self, nsid: &str) -> bool {
+        self.segments(nsid).next().is_some()
+    }
+
+    fn read_segment(&self, nsid: &str, month: &str) -> AppResult<Vec<BlockRef>> {
+        let path = self.segment_path(nsid, month);
+        let compressed = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let data = lz4_flex::decompress_size_prepended(&compressed).map_err(AppError::from)?;
+        let len = data.len() as u64;
+        let mut reader = Cursor::new(data);
+        let mut blocks = Vec::new();
+        while reader.position() < len {
+            let key = read_bytes(&mut reader)?;
+            let value = read_bytes(&mut reader)?;
+            blocks.push(BlockRef::new(key, value)?);
+        }
+        Ok(blocks)
+    }
+
+    // writes the segment file and its index entry, removing both if empty
+    fn write_segment(
+        &self,
+        nsid: &str,
+        month: &str,
+        blocks: &BTreeMap<Slice, BlockRef>,
+    ) -> AppResult<()> {
+        let path = self.segment_path(nsid, month);
+        let index_key = format_smolstr!("{}{month}", Self::index_prefix(nsid));
+        if blocks.is_empty() {
+            self.meta.remove(index_key.as_bytes())?;
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        let mut segment = ColdSegment {
+            start: u64::MAX,
+            ..Default::default()
+        };
+        for (key, block) in blocks {
+            data.write_varint(key.len())?;
+            data.extend_from_slice(key);
+            data.write_varint(block.byte_len())?;
+            data.extend_from_slice(block.value());
+            segment.start = segment.start.min(block.key().start);
+            segment.end = segment.end.max(block.key().end);
+            segment.blocks += 1;
+            segment.items += block.item_count()? as u64;
+        }
+        let compressed = lz4_flex::compress_prepend_size(&data);
+        segment.bytes = compressed.len() as u64;
+
+        // write then rename so readers never see a partial segment
+        std::fs::create_dir_all(self.dir.join(nsid))?;
+        let tmp_path = path.with_extension("seg.tmp");
+        std::fs::write(&tmp_path, &compressed)?;
+        std::fs::rename(&tmp_path, &path)?;
+        self.meta.insert(
+            index_key.as_bytes(),
+            rkyv::to_bytes::<Error>(&segment)?.as_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// adds blocks to the segments of their month, merging with what is
+    /// already there. the caller removes them from fjall afterwards
+    pub fn append(&self, nsid: &str, blocks: Vec<BlockRef>) -> AppResult<()> {
+        let _lock = self.write_lock.lock();
+        let by_month = blocks
+            .into_iter()
+            .into_group_map_by(|block| Self::month_of(block.key().start));
+        for (month, blocks) in by_month {
+            let mut merged = self
+                .read_segment(nsid, &month)?
+                .into_iter()
+                .map(|block| (block.raw_key().clone(), block))
+                .collect::<BTreeMap<_, _>>();
+            merged.extend(
+                blocks
+                    .into_iter()
+                    .map(|block| (block.raw_key().clone(), block)),
+            );
+            self.write_segment(nsid, &month, &merged)?;
+        }
+        Ok(())
+    }
+
+    /// blocks whose start timestamp is in `start..=end`, oldest first or
+    /// newest first if `rev`. segments are loaded lazily one at a time
+    pub fn blocks(
+        &self,
+        nsid: &str,
+        start: u64,
+        end: u64,
+        rev: bool,
+    ) -> AppResult<impl Iterator<Item = AppResult<BlockRef>> + '_> {
+        let mut months = self
+            .segments(nsid)
+            .filter_ok(|(_, segment)| segment.start <= end && segment.end >= start)
+            .map_ok(|(month, _)| month)
+            .collect::<AppResult<Vec<_>>>()?;
+        if rev {
+            months.reverse();
+        }
+        let nsid = SmolStr::new(nsid);
+        Ok(months
+            .into_iter()
+            .flat_map(move |month| match self.read_segment(&nsid, &month) {
+                Ok(mut blocks) => {
+                    if rev {
+                        blocks.reverse();
+                    }
+                    Either::Left(blocks.into_iter().map(Ok))
+                }
+                Err(err) => Either::Right(std::iter::once(Err(err))),
+            })
+            .filter(move |block| {
+                block
+                    .as_ref()
+                    .map_or(true, |block| (start..=end).contains(&block.key().start))
+            }))
+    }
+
+    /// removes the blocks whose start timestamp is in `start..=end` from the
+    /// cold tier. `restore` is called for every block before its segment is
+    /// rewritten, so a crash in between only leaves a duplicate behind
+    pub fn take(
+        &self,
+        nsid: &str,
+        start: u64,
+        end: u64,
+        mut restore: impl FnMut(&BlockRef) -> AppResult<()>,
+    ) -> AppResult<usize> {
+        let _lock = self.write_lock.lock();
+        let months = self
+            .segments(nsid)
+            .filter_ok(|(_, segment)| segment.start <= end && segment.end >= start)
+            .map_ok(|(month, _)| month)
+            .collect::<AppResult<Vec<_>>>()?;
+        let mut taken = 0;
+        for month in months {
+            let (take, keep): (Vec<_>, Vec<_>) = self
+                .read_segment(nsid, &month)?
+                .into_iter()
+                .partition(|block| (start..=end).contains(&block.key().start));
+            if take.is_empty() {
+                continue;
+            }
+            for block in &take {
+                restore(block)?;
+            }
+            taken += take.len();
+            let keep = keep
+                .into_iter()
+                .map(|block| (block.raw_key().clone(), block))
+                .collect();
+            self.write_segment(nsid, &month, &keep)?;
+        }
+        Ok(taken)
+    }
+}
+
+fn read_bytes(reader: &mut Cursor<Vec<u8>>) -> AppResult<Slice> {
+    let len = reader.read_varint::<usize>()?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(Slice::from(buf))
+}
@@ -0,0 +1,155 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering as AtomicOrdering},
+    time::Duration,
+};
+
+use ahash::{AHashMap, AHashSet};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use smol_str::SmolStr;
+
+use crate::db::NsidCounts;
+
+// the counts of every nsid that was written since we started, so ingest
+// doesnt have to read them back from `_counts`. the dirty ones arent in
+// `_counts` yet: while quiesced nothing is written, and while the counts
+// flusher runs (see `Db::run_counts_flusher`) ingest leaves the writing to it
+pub struct CountsCache {
+    inner: Mutex<Inner>,
+    // held for the whole of a flush, so one that finds nothing left to take
+    // still waits for the one writing it
+    flushing: Mutex<()>,
+    wake: Condvar,
+    deferred: AtomicBool,
+    // the flusher is woken early once this many are dirty
+    max_dirty: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    counts: AHashMap<SmolStr, NsidCounts>,
+    dirty: AHashSet<SmolStr>,
+}
+
+impl CountsCache {
+    pub fn new(max_dirty: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            flushing: Mutex::new(()),
+            wake: Condvar::new(),
+            deferred: AtomicBool::new(false),
+            max_dirty: max_dirty.max(1),
+        }
+    }
+
+    pub fn get(&self, nsid: &str) -> Option<NsidCounts> {
+        self.inner.lock().counts.get(nsid).cloned()
+    }
+
+    /// `dirty` if it still has to be written
+    pub fn insert(&self, nsid: &str, counts: NsidCounts, dirty: bool) {
+        let mut inner = self.inner.lock();
+        let nsid = SmolStr::new(nsid);
+        if dirty {
+            inner.dirty.insert(nsid.clone());
+        }
+        inner.counts.insert(nsid, counts);
+        if inner.dirty.len() >= self.max_dirty {
+            self.wake.notify_one();
+        }
+    }
+
+    pub fn dirty_len(&self) -> usize {
+        self.inner.lock().dirty.len()
+    }
+
+    /// the ones that arent written yet, by nsid
+    pub fn dirty(&self) -> Vec<(SmolStr, NsidCounts)> {
+        let inner = self.inner.lock();
+        let mut dirty = inner
+            .dirty
+            .iter()
+            .filter_map(|nsid| Some((nsid.clone(), inner.counts.get(nsid)?.clone())))
+            .collect::<Vec<_>>();
+        dirty.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        dirty
+    }
+
+    /// hold it while writing what `take` returned
+    pub fn lock_flush(&self) -> MutexGuard<'_, ()> {
+        self.flushing.lock()
+    }
+
+    /// the dirty ones, which are clean from now on
+    pub fn take(&self) -> Vec<(SmolStr, NsidCounts)> {
+        let mut inner = self.inner.lock();
+        let dirty = std::mem::take(&mut inner.dirty);
+        dirty
+            .into_iter()
+            .filter_map(|nsid| {
+                let counts = inner.counts.get(&nsid)?.clone();
+                Some((nsid, counts))
+            })
+            .collect()
+    }
+
+    /// marks what `take` returned dirty again after it couldnt be written,
+    /// what they have now is at least as new
+    pub fn restore(&self, taken: impl IntoIterator<Item = SmolStr>) {
+        self.inner.lock().dirty.extend(taken);
+    }
+
+    #[inline(always)]
+    pub fn is_deferred(&self) -> bool {
+        self.deferred.load(AtomicOrdering::Acquire)
+    }
+
+    pub fn set_deferred(&self, deferred: bool) {
+        self.deferred.store(deferred, AtomicOrdering::Release);
+    }
+
+    /// waits until `timeout` passed, `max_dirty` are dirty or `wake`
+    pub fn wait(&self, timeout: Duration) {
+        let mut inner = self.inner.lock();
+        if inner.dirty.len() < self.max_dirty {
+            self.wake.wait_for(&mut inner, timeout);
+        }
+    }
+
+    pub fn wake(&self) {
+        self.wake.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_taken_counts_are_clean() {
+        let cache = CountsCache::new(2);
+        let counts = |count| NsidCounts {
+            count,
+            ..Default::default()
+        };
+        cache.insert("a.b.c", counts(1), false);
+        cache.insert("a.b.d", counts(2), true);
+        assert_eq!(cache.dirty_len(), 1);
+        assert_eq!(cache.get("a.b.c"), Some(counts(1)));
+
+        let taken = cache.take();
+        assert_eq!(taken, [(SmolStr::new("a.b.d"), counts(2))]);
+        assert!(cache.dirty().is_empty());
+        assert_eq!(cache.get("a.b.d"), Some(counts(2)));
+
+        // it changed again before the failed write was noticed
+        cache.insert("a.b.d", counts(3), true);
+        cache.restore(taken.into_iter().map(|(nsid, _)| nsid));
+        assert_eq!(cache.dirty(), [(SmolStr::new("a.b.d"), counts(3))]);
+
+        // enough are dirty, so this doesnt wait
+        cache.insert("a.b.e", counts(1), true);
+        let start = std::time::Instant::now();
+        cache.wait(Duration::from_secs(60));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use rkyv::rancor::Error;
+use smol_str::SmolStr;
+
+use crate::{
+    db::NsidCounts,
+    error::AppResult,
+    utils::{ReadVariableExt, WriteVariableExt},
+};
+
+// a dump of the counts of every nsid, for mirrors that dont need the hits:
+// the magic and a version (u32 le), then per nsid a varint length prefixed
+// name and varint length prefixed counts. counts are rkyv like in `_counts`
+// and read with `NsidCounts::decode`, so dumps of older layouts still load.
+// an empty name ends the dump, so a cut off download is an error instead of
+// a partial import
+const MAGIC: &[u8; 8] = b"LXCOUNTS";
+pub const COUNTS_DUMP_VERSION: u32 = 1;
+// nothing valid comes close, this is so garbage doesnt make us allocate a lot
+const MAX_FIELD_LEN: u64 = 4096;
+
+pub struct CountsDumpWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CountsDumpWriter<W> {
+    pub fn new(mut writer: W) -> AppResult<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&COUNTS_DUMP_VERSION.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, nsid: &str, counts: &NsidCounts) -> AppResult<()> {
+        let counts = rkyv::to_bytes::<Error>(counts)?;
+        self.writer.write_varint(nsid.len() as u64)?;
+        self.writer.write_all(nsid.as_bytes())?;
+        self.writer.write_varint(counts.len() as u64)?;
+        self.writer.write_all(&counts)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> AppResult<W> {
+        self.writer.write_varint(0_u64)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub struct CountsDumpReader<R> {
+    reader: R,
+    done: bool,
+}
+
+impl<R: Read> CountsDumpReader<R> {
+    pub fn new(mut reader: R) -> AppResult<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow::anyhow!("not a counts dump").into());
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version > COUNTS_DUMP_VERSION {
+            return Err(anyhow::anyhow!("counts dump version {version} is newer than us").into());
+        }
+        Ok(Self {
+            reader,
+            done: false,
+        })
+    }
+
+    fn read_field(&mut self) -> AppResult<Vec<u8>> {
+        let len = match self.reader.read_varint::<u64>() {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(anyhow::anyhow!("counts dump is cut off").into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if len > MAX_FIELD_LEN {
+            return Err(anyhow::anyhow!("counts dump field is too long ({len} bytes)").into());
+        }
+        let mut field = vec![0; len as usize];
+        self.reader.read_exact(&mut field)?;
+        Ok(field)
+    }
+
+    fn read_entry(&mut self) -> AppResult<Option<(SmolStr, NsidCounts)>> {
+        let nsid = self.read_field()?;
+        if nsid.is_empty() {
+            return Ok(None);
+        }
+        let nsid = String::from_utf8(nsid)
+            .map_err(|_| anyhow::anyhow!("nsid in counts dump isnt utf8"))?;
+        let counts = NsidCounts::decode(&self.read_field()?)?;
+        Ok(Some((SmolStr::from(nsid), counts)))
+    }
+}
+
+impl<R: Read> Iterator for CountsDumpReader<R> {
+    type Item = AppResult<(SmolStr, NsidCounts)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry();
+        // stop at the end marker and after the first error
+        self.done = !matches!(entry, Ok(Some(_)));
+        entry.transpose()
+    }
+}
+
+/// how imported counts are combined with the ones we have
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CountsMerge {
+    /// the imported row replaces ours
+    #[default]
+    Overwrite,
+    /// the imported counts replace ours, the latest last_seen is kept
+    Replace,
+    /// counts are added up, the latest last_seen is kept
+    Sum,
+}
+
+impl CountsMerge {
+    pub fn apply(self, ours: &NsidCounts, imported: NsidCounts) -> NsidCounts {
+        let last_seen = ours.last_seen.max(imported.last_seen);
+        match self {
+            CountsMerge::Overwrite => imported,
+            CountsMerge::Replace => NsidCounts {
+                last_seen,
+                ..imported
+            },
+            CountsMerge::Sum => NsidCounts {
+                count: ours.count + imported.count,
+                deleted_count: ours.deleted_count + imported.deleted_count,
+                purged_count: ours.purged_count + imported.purged_count,
+                last_seen,
+            },
+        }
+    }
+}
+
+impl FromStr for CountsMerge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(CountsMerge::Overwrite),
+            "replace" => Ok(CountsMerge::Replace),
+            "sum" => Ok(CountsMerge::Sum),
+            _ => Err(format!(
+                "unknown merge mode {s}, expected overwrite, replace or sum"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counts(count: u128, last_seen: u64) -> NsidCounts {
+        NsidCounts {
+            count,
+            deleted_count: 1,
+            last_seen,
+            purged_count: 0,
+        }
+    }
+
+    fn dump(entries: &[(&str, NsidCounts)]) -> Vec<u8> {
+        let mut writer = CountsDumpWriter::new(Vec::new()).unwrap();
+        for (nsid, counts) in entries {
+            writer.write(nsid, counts).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let entries = [
+            ("app.bsky.feed.like", counts(10, 100)),
+            ("fyi.unravel.frontpage.comment", counts(2, 50)),
+        ];
+        let raw = dump(&entries);
+        let read = CountsDumpReader::new(raw.as_slice())
+            .unwrap()
+            .collect::<AppResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        for ((nsid, counts), (expected_nsid, expected)) in read.iter().zip(&entries) {
+            assert_eq!(nsid, expected_nsid);
+            assert_eq!(counts, expected);
+        }
+    }
+
+    #[test]
+    fn test_cut_off_dump_is_an_error() {
+        let raw = dump(&[("app.bsky.feed.like", counts(10, 100))]);
+        // without the end marker
+        let cut = &raw[..raw.len() - 1];
+        let read = CountsDumpReader::new(cut)
+            .unwrap()
+            .collect::<AppResult<Vec<_>>>();
+        assert!(read.is_err());
+        assert!(CountsDumpReader::new(&raw[..4]).is_err());
+        assert!(CountsDumpReader::new(&b"not a dump at all"[..]).is_err());
+    }
+
+    #[test]
+    fn test_merge_modes() {
+        let ours = counts(10, 200);
+        let imported = counts(3, 100);
+        assert_eq!(
+            CountsMerge::Overwrite.apply(&ours, imported.clone()),
+            imported
+        );
+        assert_eq!(
+            CountsMerge::Replace.apply(&ours, imported.clone()),
+            counts(3, 200)
+        );
+        let summed = CountsMerge::Sum.apply(&ours, imported);
+        assert_eq!((summed.count, summed.deleted_count), (13, 2));
+        assert_eq!(summed.last_seen, 200);
+    }
+}
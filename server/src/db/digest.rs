@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use xxhash_rust::xxh3::{Xxh3, xxh3_64};
+
+// digest of an nsid's hits as (timestamp, deleted) pairs. every pair is hashed
+// on its own and the hashes are summed, so the digest doesnt depend on how the
+// hits are split into blocks or the order they come out of them, which is the
+// same as hashing the sorted pairs but doesnt need them all in memory. purges
+// count as deletions since classifying them depends on config
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HitsDigest {
+    hits: u64,
+    sum: u64,
+}
+
+impl HitsDigest {
+    pub fn add(&mut self, timestamp: u64, deleted: bool) {
+        let mut pair = [0; 9];
+        pair[..8].copy_from_slice(&timestamp.to_le_bytes());
+        pair[8] = deleted as u8;
+        self.hits += 1;
+        self.sum = self.sum.wrapping_add(xxh3_64(&pair));
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn finish(&self) -> u64 {
+        let mut hasher = Xxh3::new();
+        hasher.update(&self.hits.to_le_bytes());
+        hasher.update(&self.sum.to_le_bytes());
+        hasher.digest()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NsidDigest {
+    pub hits: u64,
+    pub digest: String,
+}
+
+/// digests of every nsid with hits in a range, and one over all of them.
+/// digests are hex so they survive json parsers that read numbers as floats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentDigest {
+    pub digest: String,
+    pub nsids: BTreeMap<SmolStr, NsidDigest>,
+}
+
+impl ContentDigest {
+    pub fn new(digests: impl IntoIterator<Item = (SmolStr, HitsDigest)>) -> Self {
+        let digests = digests.into_iter().collect::<BTreeMap<_, _>>();
+        // nsids cant contain a nul, so it separates them from their digest
+        let mut hasher = Xxh3::new();
+        for (nsid, digest) in &digests {
+            hasher.update(nsid.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(&digest.finish().to_le_bytes());
+        }
+        Self {
+            digest: hex(hasher.digest()),
+            nsids: digests
+                .into_iter()
+                .map(|(nsid, digest)| {
+                    let digest = NsidDigest {
+                        hits: digest.hits(),
+                        digest: hex(digest.finish()),
+                    };
+                    (nsid, digest)
+                })
+                .collect(),
+        }
+    }
+}
+
+fn hex(digest: u64) -> String {
+    format!("{digest:016x}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn digest_of(hits: &[(u64, bool)]) -> HitsDigest {
+        let mut digest = HitsDigest::default();
+        for (timestamp, deleted) in hits {
+            digest.add(*timestamp, *deleted);
+        }
+        digest
+    }
+
+    #[test]
+    fn test_digest_ignores_order() {
+        let a = digest_of(&[(1, false), (2, true), (2, false), (3, false)]);
+        let b = digest_of(&[(2, false), (3, false), (1, false), (2, true)]);
+        assert_eq!(a.finish(), b.finish());
+
+        // a deletion instead of a creation, a duplicate and a missing hit
+        let c = digest_of(&[(1, false), (2, true), (2, true), (3, false)]);
+        let d = digest_of(&[(1, false), (2, true), (2, false), (3, false), (3, false)]);
+        let e = digest_of(&[(1, false), (2, true), (2, false)]);
+        for other in [c, d, e] {
+            assert_ne!(a.finish(), other.finish());
+        }
+    }
+
+    #[test]
+    fn test_content_digest_is_per_nsid() {
+        let likes = digest_of(&[(1, false), (2, false)]);
+        let posts = digest_of(&[(3, true)]);
+        let a = ContentDigest::new([
+            (SmolStr::new("app.bsky.feed.like"), likes),
+            (SmolStr::new("app.bsky.feed.post"), posts),
+        ]);
+        let b = ContentDigest::new([
+            (SmolStr::new("app.bsky.feed.post"), posts),
+            (SmolStr::new("app.bsky.feed.like"), likes),
+        ]);
+        assert_eq!(a, b);
+        assert_eq!(a.nsids["app.bsky.feed.like"].hits, 2);
+
+        let c = ContentDigest::new([
+            (SmolStr::new("app.bsky.feed.like"), likes),
+            (SmolStr::new("app.bsky.feed.post"), digest_of(&[(3, false)])),
+        ]);
+        assert_ne!(a.digest, c.digest);
+        assert_eq!(a.nsids["app.bsky.feed.like"], c.nsids["app.bsky.feed.like"]);
+    }
+}
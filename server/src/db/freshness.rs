@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use crate::db::{
+    SyncPaceStatus,
+    rollup::{DAY, SETTLE},
+};
+
+/// where a read was answered from. each changes on its own schedule, so this
+/// decides how long a response stays current, see `max_age`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataSource {
+    // the per nsid counts, written every counts flush interval
+    Counts,
+    // blocks of hits, new ones show up with every sync
+    Blocks,
+    // daily rollups, a new day is rolled up `SETTLE` after it ends
+    Rollups,
+}
+
+impl DataSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            DataSource::Counts => "counts",
+            DataSource::Blocks => "blocks",
+            DataSource::Rollups => "rollups",
+        }
+    }
+}
+
+/// seconds until a response read from `source` at `now` can change, at
+/// least 1. `sync` is the pace the sync loop last picked, without one (no
+/// sync loop yet) a sync can come any `min_sync` seconds
+pub fn max_age(
+    source: DataSource,
+    now: u64,
+    counts_interval: Duration,
+    sync: Option<SyncPaceStatus>,
+    min_sync: Duration,
+) -> u64 {
+    let secs = match source {
+        DataSource::Counts => counts_interval.as_millis().div_ceil(1000) as u64,
+        DataSource::Blocks => match sync {
+            Some(sync) => sync
+                .pace
+                .interval_ms
+                .saturating_sub(sync.decided_ms_ago)
+                .div_ceil(1000),
+            None => min_sync.as_secs(),
+        },
+        DataSource::Rollups => {
+            let next_day = (now.saturating_sub(SETTLE) / DAY + 1) * DAY;
+            (next_day + SETTLE).saturating_sub(now)
+        }
+    };
+    secs.max(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::pacer::SyncPace;
+
+    const COUNTS: Duration = Duration::from_secs(1);
+    const MIN_SYNC: Duration = Duration::from_secs(2);
+
+    fn synced(interval_ms: u64, decided_ms_ago: u64) -> Option<SyncPaceStatus> {
+        Some(SyncPaceStatus {
+            pace: SyncPace {
+                buffered_items: 0,
+                eps: 0,
+                target_items: 0,
+                interval_ms,
+            },
+            decided_ms_ago,
+        })
+    }
+
+    #[test]
+    fn test_max_age_per_source() {
+        let now = 20_000 * DAY + 1234;
+        let age = |source, sync| max_age(source, now, COUNTS, sync, MIN_SYNC);
+
+        assert_eq!(age(DataSource::Counts, None), 1);
+        assert_eq!(
+            max_age(
+                DataSource::Counts,
+                now,
+                Duration::from_millis(1500),
+                None,
+                MIN_SYNC
+            ),
+            2
+        );
+
+        // whats left of the sync interval, rounded up
+        assert_eq!(age(DataSource::Blocks, synced(30_000, 12_500)), 18);
+        assert_eq!(age(DataSource::Blocks, synced(30_000, 30_000)), 1);
+        // a sync that is late
+        assert_eq!(age(DataSource::Blocks, synced(2_000, 5_000)), 1);
+        assert_eq!(age(DataSource::Blocks, None), 2);
+
+        // the day before is rolled up at 01:00
+        assert_eq!(age(DataSource::Rollups, None), SETTLE - 1234);
+        let after = 20_000 * DAY + SETTLE;
+        assert_eq!(
+            max_age(DataSource::Rollups, after, COUNTS, None, MIN_SYNC),
+            DAY
+        );
+        assert_eq!(
+            max_age(DataSource::Rollups, after - 1, COUNTS, None, MIN_SYNC),
+            1
+        );
+    }
+}
@@ -6,19 +6,26 @@ use std::{
     time::Duration,
 };
 
+use ahash::AHashSet;
 use byteview::ByteView;
-use fjall::{Keyspace, Partition, PartitionCreateOptions, Slice, Snapshot};
+use fjall::{Batch, Keyspace, Partition, PartitionCreateOptions, Slice};
 use itertools::Itertools;
-use parking_lot::Mutex;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use parking_lot::{Mutex, RwLock};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use rclite::Arc;
 use smol_str::SmolStr;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    db::{EventRecord, NsidHit, block},
+    db::{
+        EventRecord, NsidHit, block,
+        block_cache::block_cache,
+        partitions::{HitsPartition, HitsSnapshot},
+        sparkline::{SPARKLINE_HOURS, Sparkline, SparklineSlot},
+    },
     error::{AppError, AppResult},
     utils::{
-        ArcRefCnt, ArcliteSwap, CLOCK, DefaultRateTracker, RateTracker, ReadVariableExt,
+        ArcRefCnt, ArcliteSwap, CLOCK, DefaultRateTracker, RateTracker, ReadVariableExt, get_time,
         varints_unsigned_encoded,
     },
 };
@@ -27,19 +34,237 @@ pub type ItemDecoder = block::ItemDecoder<Cursor<Slice>, NsidHit>;
 pub type ItemEncoder = block::ItemEncoder<Vec<u8>, NsidHit>;
 pub type Item = block::Item<NsidHit>;
 
+// hits further apart than this within a block are taken for a clock jump
+// (or a bogus timestamp) and dont get delta encoded, see
+// `ItemEncoder::reset_over`
+const MAX_ITEM_DELTA: u64 = 60 * 60 * 24;
+/// the granularity to encode a block of `items` with, `granularity` if all of
+/// their timestamps are multiples of it. hits queued before privacy mode was
+/// turned on arent, those blocks keep plain deltas
+pub fn block_granularity(items: &[Item], granularity: u64) -> u64 {
+    if granularity > 1 && items.iter().all(|item| item.timestamp % granularity == 0) {
+        granularity
+    } else {
+        1
+    }
+}
+
+/// how far back the per nsid rate looks
+pub const RATE_WINDOW_SECS: u64 = 10;
+
+// how many times each partition was opened for a handle, lets tests check
+// that racing callers share one
+#[cfg(test)]
+pub static OPENED: Mutex<std::collections::BTreeMap<String, usize>> =
+    Mutex::new(std::collections::BTreeMap::new());
+
 pub struct Block {
     pub written: usize,
     pub key: ByteView,
     pub data: Vec<u8>,
 }
 
+/// what a recompression pass did, see `LexiconHandle::recompress`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct RecompressStats {
+    pub blocks: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl RecompressStats {
+    pub fn add(&mut self, other: RecompressStats) {
+        self.blocks += other.blocks;
+        self.bytes_before += other.bytes_before;
+        self.bytes_after += other.bytes_after;
+    }
+
+    /// bytes saved, negative if the blocks grew
+    pub fn saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+/// parsed block key: varint start time + varint end time (seconds)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockKey {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl BlockKey {
+    pub fn decode(raw: &[u8]) -> AppResult<Self> {
+        let mut reader = Cursor::new(raw);
+        let start = reader.read_varint()?;
+        let end = reader.read_varint()?;
+        Ok(Self { start, end })
+    }
+
+    #[inline(always)]
+    pub fn encode(&self) -> ByteView {
+        varints_unsigned_encoded([self.start, self.end])
+    }
+}
+
+/// a stored block, the decoder is only constructed when asked for
+#[derive(Clone)]
+pub struct BlockRef {
+    key: BlockKey,
+    raw_key: Slice,
+    value: Slice,
+}
+
+impl BlockRef {
+    pub fn new(raw_key: Slice, value: Slice) -> AppResult<Self> {
+        Ok(Self {
+            key: BlockKey::decode(&raw_key)?,
+            raw_key,
+            value,
+        })
+    }
+
+    #[inline(always)]
+    pub fn key(&self) -> BlockKey {
+        self.key
+    }
+
+    #[inline(always)]
+    pub fn raw_key(&self) -> &Slice {
+        &self.raw_key
+    }
+
+    #[inline(always)]
+    pub fn value(&self) -> &Slice {
+        &self.value
+    }
+
+    #[inline(always)]
+    pub fn byte_len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn decoder(&self) -> AppResult<ItemDecoder> {
+        self.clone().into_decoder()
+    }
+
+    /// recompressed blocks are decompressed whole first, see `block::Tier`
+    pub fn into_decoder(self) -> AppResult<ItemDecoder> {
+        let value = match block::decompress(&self.value)? {
+            Some(plain) => Slice::from(plain),
+            None => self.value,
+        };
+        ItemDecoder::new(Cursor::new(value), self.key.start).map_err(AppError::from)
+    }
+
+    // only reads the block header
+    pub fn item_count(&self) -> AppResult<usize> {
+        if let Some(header) = block::TierHeader::read(&self.value)? {
+            return Ok(header.item_count);
+        }
+        self.decoder().map(|decoder| decoder.item_count())
+    }
+
+    pub fn tier(&self) -> AppResult<block::Tier> {
+        block::block_tier(&self.value).map_err(AppError::from)
+    }
+}
+
+/// a read snapshot of the blocks of one nsid, pinned for as long as this (or an
+/// iterator made from it) is alive. blocks that sync or compaction write or
+/// remove afterwards dont show up, so reads that go over many blocks for a
+/// while (exports, migrations) see a consistent set of them. compaction
+/// swaps blocks in one batch, so a snapshot from before it sees only the old
+/// blocks and one from after it only the new ones
+#[derive(Clone)]
+pub struct PinnedSnapshot {
+    nsid: SmolStr,
+    snapshot: ArcRefCnt<HitsSnapshot>,
+    overlap: Option<(u64, u64)>,
+}
+
+impl PinnedSnapshot {
+    #[inline(always)]
+    pub fn nsid(&self) -> &SmolStr {
+        &self.nsid
+    }
+
+    /// where blocks overlap as of pinning, see `LexiconHandle::overlap`
+    #[inline(always)]
+    pub fn overlap(&self) -> Option<(u64, u64)> {
+        self.overlap
+    }
+
+    /// iterates over the blocks whose start timestamp is in `range`,
+    /// ordered by start timestamp. the iterator keeps the snapshot pinned
+    pub fn blocks<R: RangeBounds<u64>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = AppResult<BlockRef>> + use<R> {
+        let start_key = match range.start_bound().cloned() {
+            Bound::Included(start) => Bound::Included(varints_unsigned_encoded([start])),
+            Bound::Excluded(start) => match start.checked_add(1) {
+                Some(start) => Bound::Included(varints_unsigned_encoded([start])),
+                None => Bound::Excluded(varints_unsigned_encoded([u64::MAX, u64::MAX])),
+            },
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end_key = match range.end_bound().cloned() {
+            Bound::Included(end) => match end.checked_add(1) {
+                Some(end) => Bound::Excluded(varints_unsigned_encoded([end])),
+                None => Bound::Unbounded,
+            },
+            Bound::Excluded(end) => Bound::Excluded(varints_unsigned_encoded([end])),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        // the fjall iterator only has the seqno, the snapshot itself is what
+        // keeps the versions it reads from being dropped
+        let snapshot = self.snapshot.clone();
+        self.snapshot
+            .range(
+                start_key.map(|key| key.to_vec()),
+                end_key.map(|key| key.to_vec()),
+            )
+            .map(move |block| {
+                let _pinned = &snapshot;
+                block
+            })
+    }
+}
+
 pub struct LexiconHandle {
-    write_tree: Partition,
-    read_tree: ArcliteSwap<Snapshot>,
+    keyspace: Keyspace,
+    // swapped when the nsid moves out of the longtail, see `promote`. writes
+    // hold it shared so none of them land in the old place
+    write_tree: RwLock<HitsPartition>,
+    read_tree: ArcliteSwap<HitsSnapshot>,
     nsid: SmolStr,
     buf: Arc<Mutex<Vec<EventRecord>>>,
+    // ingest only ever appends to `buf`, but items are taken out of it in
+    // blocks sized from its length, so syncs drain one at a time. see `drain`
+    drain_lock: Mutex<()>,
+    // where the current drain was started from, to catch a drain that ends
+    // up draining the same handle again (which would deadlock)
+    #[cfg(debug_assertions)]
+    drainer: Mutex<
+        Option<(
+            std::thread::ThreadId,
+            &'static std::panic::Location<'static>,
+        )>,
+    >,
     last_insert: AtomicU64, // relaxed
+    last_flush: AtomicU64,  // unix seconds, 0 if never flushed since startup
     eps: DefaultRateTracker,
+    // locked before `buf` when both are
+    sparkline: Mutex<SparklineSlot>,
+    sparkline_rebuild: Mutex<()>,
+    // end of the newest block written, 0 until the first sync looks it up.
+    // see `observe_block`
+    newest_end: AtomicU64,
+    // start and end of where blocks overlap, until compaction merges them
+    overlap: Mutex<Option<(u64, u64)>>,
+    // compaction and recompression both replace blocks they read earlier, one
+    // of them at a time so neither writes back a block the other removed
+    rewrite_lock: Mutex<()>,
 }
 
 impl Debug for LexiconHandle {
@@ -51,31 +276,192 @@ impl Debug for LexiconHandle {
 }
 
 impl LexiconHandle {
-    pub fn new(keyspace: &Keyspace, nsid: &str) -> Self {
-        let opts = PartitionCreateOptions::default()
+    /// a handle over a partition of its own, created if it doesnt exist
+    pub fn new(keyspace: &Keyspace, nsid: &str) -> AppResult<Self> {
+        let write_tree = Self::open_partition(keyspace, nsid)?;
+        Ok(Self::with_tree(keyspace, nsid, write_tree))
+    }
+
+    /// a handle over the blocks of `nsid` in `_longtail`
+    pub fn longtail(keyspace: &Keyspace, longtail: &Partition, nsid: &str) -> Self {
+        Self::with_tree(
+            keyspace,
+            nsid,
+            HitsPartition::longtail(longtail.clone(), nsid),
+        )
+    }
+
+    /// the options every hits partition is opened with, `_longtail` too
+    pub fn partition_options() -> PartitionCreateOptions {
+        PartitionCreateOptions::default()
             .block_size(1024 * 48)
-            .compression(fjall::CompressionType::Miniz(9));
-        let write_tree = keyspace.open_partition(nsid, opts).unwrap();
+            .compression(fjall::CompressionType::Miniz(9))
+    }
+
+    fn open_partition(keyspace: &Keyspace, nsid: &str) -> AppResult<HitsPartition> {
+        let partition = keyspace.open_partition(nsid, Self::partition_options())?;
+        #[cfg(test)]
+        {
+            *OPENED.lock().entry(nsid.to_owned()).or_default() += 1;
+        }
+        Ok(HitsPartition::new(partition))
+    }
+
+    fn with_tree(keyspace: &Keyspace, nsid: &str, write_tree: HitsPartition) -> Self {
         let read_tree = ArcliteSwap::new(ArcRefCnt::new(write_tree.snapshot()));
         Self {
-            write_tree,
+            keyspace: keyspace.clone(),
+            write_tree: RwLock::new(write_tree),
             read_tree,
             nsid: nsid.into(),
             buf: Default::default(),
+            drain_lock: Mutex::new(()),
+            #[cfg(debug_assertions)]
+            drainer: Mutex::new(None),
             last_insert: AtomicU64::new(0),
-            eps: RateTracker::new(Duration::from_secs(10)),
+            last_flush: AtomicU64::new(0),
+            sparkline: Default::default(),
+            sparkline_rebuild: Mutex::new(()),
+            eps: RateTracker::new(Duration::from_secs(RATE_WINDOW_SECS)),
+            newest_end: AtomicU64::new(0),
+            overlap: Mutex::new(None),
+            rewrite_lock: Mutex::new(()),
         }
     }
 
-    #[inline(always)]
-    pub fn read(&self) -> arc_swap::Guard<ArcRefCnt<Snapshot>> {
-        self.read_tree.load()
-    }
-
     #[inline(always)]
     pub fn update_tree(&self) {
         self.read_tree
-            .store(ArcRefCnt::new(self.write_tree.snapshot()));
+            .store(ArcRefCnt::new(self.write_tree.read().snapshot()));
+    }
+
+    /// whether the blocks are in `_longtail`
+    pub fn is_longtail(&self) -> bool {
+        self.write_tree.read().is_longtail()
+    }
+
+    /// moves the blocks out of `_longtail` into a partition of their own,
+    /// in one batch with whatever `unmark` adds to it. reads that pinned a
+    /// snapshot before keep seeing the blocks where they were. returns how
+    /// many blocks were moved, None if the nsid wasnt in the longtail
+    pub fn promote(&self, unmark: impl FnOnce(&mut Batch)) -> AppResult<Option<usize>> {
+        let mut write_tree = self.write_tree.write();
+        if !write_tree.is_longtail() {
+            return Ok(None);
+        }
+        let own = Self::open_partition(&self.keyspace, &self.nsid)?;
+        let blocks = write_tree.blocks().collect::<AppResult<Vec<_>>>()?;
+        let mut batch = self.keyspace.batch();
+        for block in &blocks {
+            own.batch_restore_block(&mut batch, block);
+            write_tree.batch_remove_block(&mut batch, block);
+        }
+        unmark(&mut batch);
+        batch.commit()?;
+        // the blocks are the same, so is what the block cache has of them
+        *write_tree = own;
+        self.read_tree.store(ArcRefCnt::new(write_tree.snapshot()));
+        Ok(Some(blocks.len()))
+    }
+
+    /// pins the snapshot reads currently use, see `PinnedSnapshot`
+    pub fn pin_snapshot(&self) -> PinnedSnapshot {
+        PinnedSnapshot {
+            nsid: self.nsid.clone(),
+            snapshot: self.read_tree.load_full(),
+            overlap: self.overlap(),
+        }
+    }
+
+    /// start and end of the timestamps where blocks overlap (so a block
+    /// can have hits older than the one before it), None if they dont
+    pub fn overlap(&self) -> Option<(u64, u64)> {
+        *self.overlap.lock()
+    }
+
+    /// called by sync for every block it writes, in order. late hits (a
+    /// backfill, clock skew between relays) end up in a block that starts
+    /// before the last one ended, this returns the range of the blocks it
+    /// overlaps with
+    pub fn observe_block(&self, start: u64, end: u64) -> AppResult<Option<(u64, u64)>> {
+        if self.newest_end.load(AtomicOrdering::Relaxed) == 0 {
+            let newest = match self.blocks(..).next_back().transpose()? {
+                Some(block) => block.key().end,
+                None => 0,
+            };
+            self.newest_end.fetch_max(newest, AtomicOrdering::Relaxed);
+        }
+        let newest = self.newest_end.fetch_max(end, AtomicOrdering::Relaxed);
+        if start >= newest {
+            return Ok(None);
+        }
+        // the block the late hits fall into can start before them
+        let start = match self.blocks(..=start).next_back().transpose()? {
+            Some(block) if block.key().end >= start => block.key().start,
+            _ => start,
+        };
+        Ok(Some((start, newest)))
+    }
+
+    /// widens the recorded overlap to cover `range`. `store` gets the new
+    /// overlap with the lock held, so stored ones are in the order they were
+    /// made. returns whether it grew
+    pub fn record_overlap(
+        &self,
+        (start, end): (u64, u64),
+        store: impl FnOnce(Option<(u64, u64)>) -> AppResult<()>,
+    ) -> AppResult<bool> {
+        let mut overlap = self.overlap.lock();
+        let widened = match *overlap {
+            Some((lo, hi)) => (lo.min(start), hi.max(end)),
+            None => (start, end),
+        };
+        if *overlap == Some(widened) {
+            return Ok(false);
+        }
+        store(Some(widened))?;
+        *overlap = Some(widened);
+        Ok(true)
+    }
+
+    /// forgets the overlap once compaction merged `merged`, unless it grew
+    /// in the meantime. `store` is called like in `record_overlap`
+    pub fn clear_overlap(
+        &self,
+        merged: (u64, u64),
+        store: impl FnOnce(Option<(u64, u64)>) -> AppResult<()>,
+    ) -> AppResult<bool> {
+        let mut overlap = self.overlap.lock();
+        if *overlap != Some(merged) {
+            return Ok(false);
+        }
+        store(None)?;
+        *overlap = None;
+        Ok(true)
+    }
+
+    /// the overlap recorded before a restart
+    pub fn restore_overlap(&self, overlap: Option<(u64, u64)>) {
+        *self.overlap.lock() = overlap;
+    }
+
+    /// iterates over the blocks whose start timestamp is in `range`,
+    /// ordered by start timestamp
+    pub fn blocks<R: RangeBounds<u64>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = AppResult<BlockRef>> + use<R> {
+        self.pin_snapshot().blocks(range)
+    }
+
+    /// key of the oldest stored block, buffered hits arent in a block yet
+    pub fn first_block_key(&self) -> AppResult<Option<BlockKey>> {
+        self.write_tree.read().first_key()
+    }
+
+    /// bytes the partition takes up on disk, see `HitsPartition::disk_space`
+    pub fn disk_space(&self) -> AppResult<u64> {
+        self.write_tree.read().disk_space()
     }
 
     #[inline(always)]
@@ -99,65 +485,121 @@ impl LexiconHandle {
         )
     }
 
+    /// wall time (unix seconds) of the last block written by sync
+    pub fn last_flushed(&self) -> Option<u64> {
+        match self.last_flush.load(AtomicOrdering::Relaxed) {
+            0 => None,
+            ts => Some(ts),
+        }
+    }
+
+    /// events per second queued over the last `RATE_WINDOW_SECS`
+    pub fn rate(&self) -> f64 {
+        self.eps.rate()
+    }
+
     pub fn suggested_block_size(&self) -> usize {
         self.eps.rate() as usize * 60
     }
 
     pub fn queue(&self, events: impl IntoIterator<Item = EventRecord>) {
         let mut count = 0;
-        self.buf.lock().extend(events.into_iter().inspect(|_| {
+        let mut sparkline = self.sparkline.lock();
+        self.buf.lock().extend(events.into_iter().inspect(|event| {
             count += 1;
+            sparkline.observe(event.timestamp);
         }));
+        drop(sparkline);
         self.last_insert.store(CLOCK.raw(), AtomicOrdering::Relaxed);
         self.eps.observe(count);
     }
 
+    /// hits per hour over the trailing day, oldest first. the first call
+    /// rebuilds it, with `count_blocks` counting the hits before a cutoff
+    /// from blocks. hits synced while that runs are missed, they are never
+    /// counted twice
+    pub fn sparkline(
+        &self,
+        now: u64,
+        count_blocks: impl FnOnce(u64) -> AppResult<Sparkline>,
+    ) -> AppResult<[u32; SPARKLINE_HOURS]> {
+        // one rebuild at a time, whoever waited for it gets the live one
+        let _rebuild = self.sparkline_rebuild.lock();
+        let cutoff = {
+            let mut sparkline = self.sparkline.lock();
+            if let Some(points) = sparkline.points(now) {
+                return Ok(points);
+            }
+            sparkline.start_rebuild(now)
+        };
+        let mut counted = match count_blocks(cutoff) {
+            Ok(counted) => counted,
+            Err(err) => {
+                self.sparkline.lock().abort_rebuild();
+                return Err(err);
+            }
+        };
+        // after the blocks, so hits that move from the buffer to a block in
+        // between arent counted in both
+        for event in self.buf.lock().iter() {
+            if event.timestamp < cutoff {
+                counted.add(event.timestamp, 1);
+            }
+        }
+        let mut sparkline = self.sparkline.lock();
+        sparkline.finish_rebuild(&counted);
+        Ok(sparkline.points(now).unwrap_or_default())
+    }
+
+    /// nothing is written until all new blocks are encoded, and then the old
+    /// blocks are swapped for the new ones atomically. so cancelling leaves
+    /// the partition as it was
     pub fn compact(
         &self,
         compact_to: usize,
         range: impl RangeBounds<u64>,
         sort: bool,
+        cancel_token: &CancellationToken,
     ) -> AppResult<()> {
         let _span = self.span().entered();
+        let _rewrite = self.rewrite_lock.lock();
+        // the blocks are read and replaced in the same place
+        let write_tree = self.write_tree.read();
 
-        let start_limit = match range.start_bound().cloned() {
-            Bound::Included(start) => start,
-            Bound::Excluded(start) => start.saturating_add(1),
-            Bound::Unbounded => 0,
-        };
-        let end_limit = match range.end_bound().cloned() {
-            Bound::Included(end) => end,
-            Bound::Excluded(end) => end.saturating_sub(1),
-            Bound::Unbounded => u64::MAX,
-        };
-
-        let start_key = varints_unsigned_encoded([start_limit]);
-        let end_key = varints_unsigned_encoded([end_limit]);
-
-        let blocks_to_compact = self
-            .read()
-            .range(start_key..end_key)
-            .collect::<Result<Vec<_>, _>>()?;
+        let blocks_to_compact = self.blocks(range).collect::<AppResult<Vec<_>>>()?;
         if blocks_to_compact.len() < 2 {
             return Ok(());
         }
 
         let start_blocks_size = blocks_to_compact.len();
-        let keys_to_delete = blocks_to_compact.iter().map(|(key, _)| key);
-        let mut all_items =
-            blocks_to_compact
-                .iter()
-                .try_fold(Vec::new(), |mut acc, (key, value)| {
-                    let mut timestamps = Cursor::new(key);
-                    let start_timestamp = timestamps.read_varint()?;
-                    let decoder = block::ItemDecoder::new(Cursor::new(value), start_timestamp)?;
-                    let mut items = decoder.collect::<Result<Vec<_>, _>>()?;
-                    acc.append(&mut items);
-                    AppResult::Ok(acc)
-                })?;
-
-        if sort {
-            all_items.sort_unstable_by_key(|e| e.timestamp);
+        // the compacted blocks keep the granularity the old ones share, so
+        // compaction doesnt undo (or apply) privacy mode for old hits
+        let mut granularities = Vec::with_capacity(1);
+        let mut all_items = blocks_to_compact
+            .iter()
+            .try_fold(Vec::new(), |mut acc, block| {
+                if cancel_token.is_cancelled() {
+                    return Err(AppError::cancelled());
+                }
+                let decoder = block.decoder()?;
+                if !granularities.contains(&decoder.granularity()) {
+                    granularities.push(decoder.granularity());
+                }
+                let mut items = decoder.collect::<Result<Vec<_>, _>>()?;
+                acc.append(&mut items);
+                AppResult::Ok(acc)
+            })?;
+        let granularity = match granularities[..] {
+            [granularity] => granularity,
+            _ => 1,
+        };
+
+        // blocks that overlap cant be concatenated as they are
+        let overlapping = blocks_to_compact
+            .windows(2)
+            .any(|pair| pair[1].key().start < pair[0].key().end);
+        if sort || overlapping {
+            all_items.sort_by_key(|e| e.timestamp);
         }
 
         let new_blocks = all_items
@@ -168,17 +610,39 @@ impl LexiconHandle {
             .collect_vec()
             .into_par_iter()
             .map(|chunk| {
+                if cancel_token.is_cancelled() {
+                    return Err(AppError::cancelled());
+                }
                 let count = chunk.len();
-                Self::encode_block_from_items(chunk, count)
+                let granularity = block_granularity(&chunk, granularity);
+                Self::encode_block_from_items(chunk, count, granularity)
             })
             .collect::<Result<Vec<_>, _>>()?;
         let end_blocks_size = new_blocks.len();
+        if cancel_token.is_cancelled() {
+            return Err(AppError::cancelled());
+        }
 
-        for key in keys_to_delete {
-            self.write_tree.remove(key.clone())?;
+        // a new block can have the same key as an old one, dont remove those
+        // since everything in a batch shares a seqno
+        let new_keys = new_blocks
+            .iter()
+            .map(|block| &block.key[..])
+            .collect::<AHashSet<&[u8]>>();
+        let mut batch = self.keyspace.batch();
+        for block in &blocks_to_compact {
+            if !new_keys.contains(&block.raw_key()[..]) {
+                write_tree.batch_remove_block(&mut batch, block);
+            }
         }
+        drop(new_keys);
         for block in new_blocks {
-            self.write_tree.insert(block.key, block.data)?;
+            write_tree.batch_insert_block(&mut batch, block);
+        }
+        batch.commit()?;
+        // the new blocks arent cached yet, so this covers them too
+        for block in &blocks_to_compact {
+            block_cache().invalidate(&self.nsid, block.key());
         }
 
         let reduction =
@@ -194,15 +658,105 @@ impl LexiconHandle {
         Ok(())
     }
 
+    /// moves the blocks that ended before `cutoff` and arent in `tier` yet to
+    /// it. like `compact` every block is recompressed before any is written,
+    /// and they are then swapped in one batch under the keys they had
+    pub fn recompress(
+        &self,
+        cutoff: u64,
+        tier: block::Tier,
+        level: i32,
+        cancel_token: &CancellationToken,
+    ) -> AppResult<RecompressStats> {
+        let _span = self.span().entered();
+        let _rewrite = self.rewrite_lock.lock();
+        let write_tree = self.write_tree.read();
+
+        // the tier is in the header, so blocks that were moved already are
+        // skipped and running this again is cheap
+        let mut blocks = Vec::new();
+        for block in self.blocks(..cutoff) {
+            let block = block?;
+            if block.key().end < cutoff && block.tier()? < tier {
+                blocks.push(block);
+            }
+        }
+        if blocks.is_empty() {
+            return Ok(RecompressStats::default());
+        }
+
+        let recompressed = blocks
+            .par_iter()
+            .map(|block| {
+                if cancel_token.is_cancelled() {
+                    return Err(AppError::cancelled());
+                }
+                Ok(block::recompress(block.value(), tier, level)?)
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        if cancel_token.is_cancelled() {
+            return Err(AppError::cancelled());
+        }
+
+        let mut stats = RecompressStats::default();
+        let mut batch = self.keyspace.batch();
+        for (block, data) in blocks.iter().zip(recompressed) {
+            stats.blocks += 1;
+            stats.bytes_before += block.byte_len() as u64;
+            stats.bytes_after += data.len() as u64;
+            let recompressed = BlockRef::new(block.raw_key().clone(), Slice::from(data))?;
+            write_tree.batch_restore_block(&mut batch, &recompressed);
+        }
+        batch.commit()?;
+        for block in &blocks {
+            block_cache().invalidate(&self.nsid, block.key());
+        }
+
+        tracing::info!(
+            {
+                blocks = stats.blocks,
+                before = stats.bytes_before,
+                after = stats.bytes_after,
+            },
+            "blocks recompressed",
+        );
+        Ok(stats)
+    }
+
     pub fn insert_block(&self, block: Block) -> AppResult<()> {
-        self.write_tree
-            .insert(block.key, block.data)
-            .map_err(AppError::from)
+        let key = BlockKey::decode(&block.key)?;
+        self.write_tree.read().insert_block(block)?;
+        block_cache().invalidate(&self.nsid, key);
+        self.last_flush
+            .store(get_time().as_secs(), AtomicOrdering::Relaxed);
+        Ok(())
+    }
+
+    /// inserts a block as is, eg. when moving it back from the cold tier
+    pub fn restore_block(&self, block: &BlockRef) -> AppResult<()> {
+        self.write_tree.read().restore_block(block)?;
+        block_cache().invalidate(&self.nsid, block.key());
+        Ok(())
+    }
+
+    pub fn remove_blocks<'a>(
+        &self,
+        blocks: impl IntoIterator<Item = &'a BlockRef>,
+    ) -> AppResult<()> {
+        let write_tree = self.write_tree.read();
+        for block in blocks {
+            write_tree.remove_block(block)?;
+            block_cache().invalidate(&self.nsid, block.key());
+        }
+        Ok(())
     }
 
+    /// `granularity` is what the timestamps are rounded to, see
+    /// `block_granularity`
     pub fn encode_block_from_items(
         items: impl IntoIterator<Item = Item>,
         count: usize,
+        granularity: u64,
     ) -> AppResult<Block> {
         if count == 0 {
             return Err(std::io::Error::new(
@@ -212,7 +766,9 @@ impl LexiconHandle {
             .into());
         }
         let mut writer =
-            ItemEncoder::new(Vec::with_capacity(ItemEncoder::encoded_len(count)), count);
+            ItemEncoder::new(Vec::with_capacity(ItemEncoder::encoded_len(count)), count)
+                .reset_over(MAX_ITEM_DELTA)
+                .quantized(granularity);
         let mut start_timestamp = None;
         let mut end_timestamp = None;
         let mut written = 0_usize;
@@ -233,7 +789,11 @@ impl LexiconHandle {
         }
         if let (Some(start_timestamp), Some(end_timestamp)) = (start_timestamp, end_timestamp) {
             let value = writer.finish()?;
-            let key = varints_unsigned_encoded([start_timestamp, end_timestamp]);
+            let key = BlockKey {
+                start: start_timestamp,
+                end: end_timestamp,
+            }
+            .encode();
             return Ok(Block {
                 written,
                 key,
@@ -243,18 +803,69 @@ impl LexiconHandle {
         Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "no items are in queue").into())
     }
 
-    pub fn take_block_items(&self, item_count: usize) -> Vec<Item> {
+    /// takes items out of the buffer for syncing. `plan` gets the number of
+    /// buffered items and returns the sizes of the blocks to take, it is
+    /// called with the drain lock held so concurrent syncs dont plan from
+    /// the same items. blocks are returned in buffer order
+    #[track_caller]
+    pub fn drain(&self, plan: impl FnOnce(usize) -> Vec<usize>) -> Vec<Vec<Item>> {
+        #[cfg(debug_assertions)]
+        self.assert_not_draining();
+        let _lock = self.drain_lock.try_lock().unwrap_or_else(|| {
+            tracing::debug!(nsid = %self.nsid, "waiting for another sync to drain");
+            self.drain_lock.lock()
+        });
+        #[cfg(debug_assertions)]
+        let _drainer = DrainerGuard::enter(self);
+
+        plan(self.item_count())
+            .into_iter()
+            .map(|size| self.take_block_items(size))
+            .filter(|items| !items.is_empty())
+            .collect()
+    }
+
+    #[cfg(debug_assertions)]
+    fn assert_not_draining(&self) {
+        if let Some((thread, location)) = *self.drainer.lock() {
+            assert!(
+                thread != std::thread::current().id(),
+                "{} is already being drained by this thread (from {location})",
+                self.nsid,
+            );
+        }
+    }
+
+    fn take_block_items(&self, item_count: usize) -> Vec<Item> {
+        debug_assert!(
+            self.drain_lock.is_locked(),
+            "items taken without the drain lock"
+        );
         let mut buf = self.buf.lock();
         let end = item_count.min(buf.len());
         buf.drain(..end)
-            .map(|event| {
-                Item::new(
-                    event.timestamp,
-                    &NsidHit {
-                        deleted: event.deleted,
-                    },
-                )
-            })
+            .map(|event| Item::new(event.timestamp, &NsidHit { op: event.op }))
             .collect()
     }
 }
+
+// clears `LexiconHandle::drainer` when the drain is done (or panics)
+#[cfg(debug_assertions)]
+struct DrainerGuard<'a>(&'a LexiconHandle);
+
+#[cfg(debug_assertions)]
+impl<'a> DrainerGuard<'a> {
+    #[track_caller]
+    fn enter(handle: &'a LexiconHandle) -> Self {
+        *handle.drainer.lock() =
+            Some((std::thread::current().id(), std::panic::Location::caller()));
+        Self(handle)
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for DrainerGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.drainer.lock() = None;
+    }
+}
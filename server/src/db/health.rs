@@ -0,0 +1,326 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use rclite::Arc;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{error::AppError, utils::get_time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageState {
+    Healthy,
+    // writes are failing (probably disk full), we only serve reads
+    Degraded,
+}
+
+/// the kinds of writes whose failures are counted apart. a failing disk can
+/// still let some writes through (small ones, or ones that land in a memtable
+/// that has room), those shouldnt hide the others failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOp {
+    Blocks,
+    Counts,
+    DidCounts,
+    ActiveNsids,
+}
+
+impl WriteOp {
+    const COUNT: usize = 4;
+}
+
+/// tracks consecutive write failures and flips the db into a read-only
+/// state once they persist, so we stop piling up buffered events
+#[derive(Debug)]
+pub struct StorageHealth {
+    state: watch::Sender<StorageState>,
+    // per `WriteOp`
+    consecutive_errors: [AtomicUsize; WriteOp::COUNT],
+    max_consecutive_errors: usize,
+}
+
+impl StorageHealth {
+    pub fn new(max_consecutive_errors: usize) -> Self {
+        Self {
+            state: watch::channel(StorageState::Healthy).0,
+            consecutive_errors: Default::default(),
+            max_consecutive_errors: max_consecutive_errors.max(1),
+        }
+    }
+
+    #[inline(always)]
+    pub fn state(&self) -> StorageState {
+        *self.state.borrow()
+    }
+
+    #[inline(always)]
+    pub fn is_writable(&self) -> bool {
+        self.state() == StorageState::Healthy
+    }
+
+    /// the longest run of failures of one kind of write
+    #[inline(always)]
+    pub fn consecutive_errors(&self) -> usize {
+        self.consecutive_errors
+            .iter()
+            .map(|errors| errors.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// resolves once the storage is writable again
+    pub async fn writable(&self) {
+        let mut rx = self.state.subscribe();
+        let _ = rx.wait_for(|state| *state == StorageState::Healthy).await;
+    }
+
+    /// only resets the failures of the same kind of write
+    pub fn observe_ok(&self, op: WriteOp) {
+        self.consecutive_errors[op as usize].store(0, Ordering::Relaxed);
+    }
+
+    pub fn observe_err(&self, op: WriteOp, err: &AppError) {
+        let errors = self.consecutive_errors[op as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        if errors < self.max_consecutive_errors {
+            return;
+        }
+        if self.degrade() {
+            tracing::error!(
+                { op = ?op, errors = %errors, err = %err },
+                "storage writes keep failing, switching to read-only mode!",
+            );
+        }
+    }
+
+    /// there are only `free` bytes left on the disk of the data dir
+    pub fn observe_low_space(&self, free: u64, min_free: u64) {
+        if self.degrade() {
+            tracing::error!(
+                { free = %free, min_free = %min_free },
+                "disk is almost full, switching to read-only mode!",
+            );
+        }
+    }
+
+    // whether we werent degraded before
+    fn degrade(&self) -> bool {
+        self.state.send_if_modified(|state| {
+            let changed = *state != StorageState::Degraded;
+            *state = StorageState::Degraded;
+            changed
+        })
+    }
+
+    /// call after a probe write succeeded
+    pub fn recover(&self) {
+        for errors in &self.consecutive_errors {
+            errors.store(0, Ordering::Relaxed);
+        }
+        let changed = self.state.send_if_modified(|state| {
+            let changed = *state != StorageState::Healthy;
+            *state = StorageState::Healthy;
+            changed
+        });
+        if changed {
+            tracing::info!("storage writes succeeded again, resuming ingestion");
+        }
+    }
+}
+
+/// whether the jetstream client has a connection right now. the client sets
+/// it, the db only reports it
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamStatus(Arc<AtomicBool>);
+
+impl UpstreamStatus {
+    #[inline(always)]
+    pub fn set_connected(&self, connected: bool) {
+        self.0.store(connected, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn is_connected(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestState {
+    Running,
+    // paused by an operator, automatically resumes at `until` (unix seconds)
+    Paused { until: u64 },
+}
+
+/// operator controlled ingest pause, always time bounded so a forgotten
+/// pause can't silently drop hours of data
+#[derive(Debug)]
+pub struct IngestControl {
+    state: watch::Sender<IngestState>,
+}
+
+impl Default for IngestControl {
+    fn default() -> Self {
+        Self {
+            state: watch::channel(IngestState::Running).0,
+        }
+    }
+}
+
+impl IngestControl {
+    /// returns the current state, resuming if the pause has expired
+    pub fn state(&self) -> IngestState {
+        let state = *self.state.borrow();
+        match state {
+            IngestState::Paused { until } if until <= get_time().as_secs() => {
+                self.resume_inner("pause timed out, auto resuming ingest");
+                IngestState::Running
+            }
+            state => state,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_paused(&self) -> bool {
+        matches!(self.state(), IngestState::Paused { .. })
+    }
+
+    pub fn pause(&self, timeout: Duration) -> IngestState {
+        let until = (get_time() + timeout).as_secs();
+        let state = IngestState::Paused { until };
+        self.state.send_replace(state);
+        tracing::warn!({ until = %until }, "ingest paused");
+        state
+    }
+
+    pub fn resume(&self) -> IngestState {
+        self.resume_inner("ingest resumed");
+        IngestState::Running
+    }
+
+    fn resume_inner(&self, msg: &str) {
+        let changed = self.state.send_if_modified(|state| {
+            let changed = *state != IngestState::Running;
+            *state = IngestState::Running;
+            changed
+        });
+        if changed {
+            tracing::info!("{msg}");
+        }
+    }
+
+    /// resolves once ingest is running again, either because it was resumed
+    /// or because the pause timed out
+    pub async fn running(&self) {
+        let mut rx = self.state.subscribe();
+        loop {
+            let IngestState::Paused { until } = self.state() else {
+                return;
+            };
+            let left = Duration::from_secs(until).saturating_sub(get_time());
+            tokio::select! {
+                _ = tokio::time::sleep(left) => {}
+                res = rx.changed() => if res.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuiesceState {
+    Running,
+    // block writes are held for a backup, released at `until` (unix seconds).
+    // `generation` is the sync the on-disk state corresponds to
+    Held { until: u64, generation: u64 },
+}
+
+/// holds block writes while an external backup snapshots the data dir.
+/// like the ingest pause it always expires on its own
+#[derive(Debug)]
+pub struct QuiesceControl {
+    state: watch::Sender<QuiesceState>,
+}
+
+impl Default for QuiesceControl {
+    fn default() -> Self {
+        Self {
+            state: watch::channel(QuiesceState::Running).0,
+        }
+    }
+}
+
+impl QuiesceControl {
+    /// returns the current state, releasing the hold if it has expired
+    pub fn state(&self) -> QuiesceState {
+        let state = *self.state.borrow();
+        match state {
+            QuiesceState::Held { until, .. } if until <= get_time().as_secs() => {
+                self.release_inner("quiesce timed out, releasing writes");
+                QuiesceState::Running
+            }
+            state => state,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_held(&self) -> bool {
+        matches!(self.state(), QuiesceState::Held { .. })
+    }
+
+    pub fn hold(&self, timeout: Duration, generation: u64) -> QuiesceState {
+        let until = (get_time() + timeout).as_secs();
+        let state = QuiesceState::Held { until, generation };
+        self.state.send_replace(state);
+        tracing::warn!({ until = %until, generation = %generation }, "writes held");
+        state
+    }
+
+    pub fn release(&self) -> QuiesceState {
+        self.release_inner("writes released");
+        QuiesceState::Running
+    }
+
+    fn release_inner(&self, msg: &str) {
+        let changed = self.state.send_if_modified(|state| {
+            let changed = *state != QuiesceState::Running;
+            *state = QuiesceState::Running;
+            changed
+        });
+        if changed {
+            tracing::info!("{msg}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn test_ok_only_resets_the_same_write() {
+        let health = StorageHealth::new(3);
+        let err = AppError::new(ErrorCode::StorageDegraded, "disk full");
+        for _ in 0..3 {
+            health.observe_err(WriteOp::Blocks, &err);
+            // small writes going through shouldnt hide the blocks failing
+            health.observe_ok(WriteOp::Counts);
+            health.observe_ok(WriteOp::ActiveNsids);
+        }
+        assert_eq!(health.consecutive_errors(), 3);
+        assert!(!health.is_writable());
+
+        health.recover();
+        assert_eq!(health.consecutive_errors(), 0);
+        health.observe_err(WriteOp::Blocks, &err);
+        health.observe_ok(WriteOp::Blocks);
+        assert_eq!(health.consecutive_errors(), 0);
+        assert!(health.is_writable());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use smol_str::SmolStr;
+use tokio::sync::broadcast;
+
+use crate::{
+    db::partitions::LabelsPartition,
+    error::AppResult,
+    utils::{ArcRefCnt, ArcliteSwap},
+};
+
+pub const MAX_LABELS: usize = 16;
+const MAX_LABEL_KEY_LEN: usize = 64;
+const MAX_LABEL_VALUE_LEN: usize = 256;
+
+/// what people wrote about an nsid, like `{"official": "", "status":
+/// "deprecated"}`
+pub type LabelMap = BTreeMap<SmolStr, SmolStr>;
+
+/// why a label map was refused, for the 400 it turns into
+pub fn validate_labels(labels: &LabelMap) -> Result<(), String> {
+    if labels.len() > MAX_LABELS {
+        return Err(format!("at most {MAX_LABELS} labels per nsid"));
+    }
+    for (key, value) in labels {
+        if key.is_empty() || key.len() > MAX_LABEL_KEY_LEN {
+            return Err(format!("label keys must be 1 to {MAX_LABEL_KEY_LEN} bytes"));
+        }
+        // keeps `key=value` filters unambiguous
+        if key.contains('=') {
+            return Err(format!("label key {key} cant contain ="));
+        }
+        if value.len() > MAX_LABEL_VALUE_LEN {
+            return Err(format!(
+                "label values must be at most {MAX_LABEL_VALUE_LEN} bytes"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// whether `labels` match a filter, `key` to have the label at all or
+/// `key=value` to have it with that value
+pub fn labels_match(labels: &LabelMap, filter: &str) -> bool {
+    match filter.split_once('=') {
+        Some((key, value)) => labels.get(key).is_some_and(|have| have == value),
+        None => labels.contains_key(filter),
+    }
+}
+
+// labels of every nsid, from `_labels`. there are only a few (they are
+// written by hand) so all of them are kept in memory, readers load the
+// current map and writes swap in a new one
+pub struct Labels {
+    partition: LabelsPartition,
+    labels: ArcliteSwap<AHashMap<SmolStr, LabelMap>>,
+    // serializes updates
+    write_lock: Mutex<()>,
+    // an empty map when the labels of an nsid were removed
+    changes: broadcast::Sender<(SmolStr, LabelMap)>,
+}
+
+impl Labels {
+    pub fn open(partition: LabelsPartition) -> AppResult<Self> {
+        let labels = partition.iter().collect::<AppResult<AHashMap<_, _>>>()?;
+        Ok(Self {
+            partition,
+            labels: ArcliteSwap::new(ArcRefCnt::new(labels)),
+            write_lock: Mutex::new(()),
+            changes: broadcast::channel(16).0,
+        })
+    }
+
+    pub fn get(&self, nsid: &str) -> Option<LabelMap> {
+        self.labels.load().get(nsid).cloned()
+    }
+
+    pub fn all(&self) -> ArcRefCnt<AHashMap<SmolStr, LabelMap>> {
+        self.labels.load_full()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<(SmolStr, LabelMap)> {
+        self.changes.subscribe()
+    }
+
+    /// replaces the labels of `nsid`, an empty map removes them
+    pub fn set(&self, nsid: &str, labels: LabelMap) -> AppResult<()> {
+        let _lock = self.write_lock.lock();
+        let mut all = (*self.labels.load_full()).clone();
+        if labels.is_empty() {
+            self.partition.remove(nsid)?;
+            all.remove(nsid);
+        } else {
+            self.partition.insert(nsid, &labels)?;
+            all.insert(SmolStr::new(nsid), labels.clone());
+        }
+        self.labels.store(ArcRefCnt::new(all));
+        let _ = self.changes.send((SmolStr::new(nsid), labels));
+        Ok(())
+    }
+
+    /// false if the nsid had no labels
+    pub fn remove(&self, nsid: &str) -> AppResult<bool> {
+        if !self.labels.load().contains_key(nsid) {
+            return Ok(false);
+        }
+        self.set(nsid, LabelMap::new())?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels_of(pairs: &[(&str, &str)]) -> LabelMap {
+        pairs
+            .iter()
+            .map(|(key, value)| (SmolStr::new(key), SmolStr::new(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_label_filters_and_limits() {
+        let official = labels_of(&[("official", ""), ("status", "deprecated")]);
+        assert!(labels_match(&official, "official"));
+        assert!(labels_match(&official, "status=deprecated"));
+        assert!(!labels_match(&official, "status=active"));
+        assert!(!labels_match(&official, "spam"));
+
+        assert!(validate_labels(&official).is_ok());
+        assert!(validate_labels(&labels_of(&[("", "x")])).is_err());
+        assert!(validate_labels(&labels_of(&[("a=b", "")])).is_err());
+        let long = "x".repeat(MAX_LABEL_VALUE_LEN + 1);
+        assert!(validate_labels(&labels_of(&[("note", &long)])).is_err());
+        let many = (0..=MAX_LABELS)
+            .map(|i| (SmolStr::new(format!("label{i}")), SmolStr::default()))
+            .collect::<LabelMap>();
+        assert!(validate_labels(&many).is_err());
+    }
+
+    #[test]
+    fn test_labels_are_loaded_back() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-labels-{}",
+            std::process::id()
+        ));
+        let ks = fjall::Config::new(&path).open().unwrap();
+        let partition =
+            || LabelsPartition::new(ks.open_partition("_labels", Default::default()).unwrap());
+        let labels = Labels::open(partition()).unwrap();
+        let mut changes = labels.subscribe();
+        labels
+            .set("app.bsky.feed.post", labels_of(&[("official", "")]))
+            .unwrap();
+        labels
+            .set("com.example.spam", labels_of(&[("spam", "")]))
+            .unwrap();
+        assert!(labels.remove("com.example.spam").unwrap());
+        assert!(!labels.remove("com.example.spam").unwrap());
+        assert_eq!(changes.try_recv().unwrap().0, "app.bsky.feed.post");
+        assert_eq!(changes.try_recv().unwrap().0, "com.example.spam");
+        assert!(changes.try_recv().unwrap().1.is_empty());
+
+        let labels = Labels::open(partition()).unwrap();
+        assert_eq!(labels.all().len(), 1);
+        assert_eq!(
+            labels.get("app.bsky.feed.post"),
+            Some(labels_of(&[("official", "")]))
+        );
+
+        drop(labels);
+        drop(ks);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
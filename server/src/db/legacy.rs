@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use byteview::StrView;
+use fjall::{Keyspace, PartitionCreateOptions};
+use rkyv::rancor::Error;
+use smol_str::SmolStr;
+
+use crate::{
+    db::{EventRecord, NsidCounts, NsidHit, PartitionKind},
+    error::{AppError, AppResult},
+};
+
+// reader for the old layout: one key per event in a partition per nsid,
+// key is the big endian time_us of the event and value is an rkyv NsidHit.
+// we never write to it
+pub struct LegacyDb {
+    ks: Keyspace,
+}
+
+impl LegacyDb {
+    pub fn open(path: impl AsRef<Path>) -> AppResult<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(anyhow::anyhow!("{} does not exist", path.display()).into());
+        }
+        Ok(Self {
+            ks: fjall::Config::new(path).open()?,
+        })
+    }
+
+    pub fn get_nsids(&self) -> impl Iterator<Item = StrView> {
+        self.ks
+            .list_partitions()
+            .into_iter()
+            .filter(|k| PartitionKind::is_hits(k))
+    }
+
+    pub fn get_counts(&self) -> AppResult<impl Iterator<Item = AppResult<(SmolStr, NsidCounts)>>> {
+        let counts = self
+            .ks
+            .open_partition("_counts", PartitionCreateOptions::default())?;
+        Ok(counts.iter().map(|res| {
+            let (key, value) = res?;
+            let nsid = SmolStr::new(String::from_utf8_lossy(&key));
+            let counts = NsidCounts::decode(&value)?;
+            Ok((nsid, counts))
+        }))
+    }
+
+    pub fn get_events(
+        &self,
+        nsid: &str,
+    ) -> AppResult<impl Iterator<Item = AppResult<EventRecord>> + use<>> {
+        let partition = self
+            .ks
+            .open_partition(nsid, PartitionCreateOptions::default())?;
+        let nsid = SmolStr::new(nsid);
+        Ok(partition.iter().map(move |res| {
+            let (key, value) = res?;
+            let time_us = <[u8; 8]>::try_from(&key[..])
+                .map(u64::from_be_bytes)
+                .map_err(|_| anyhow::anyhow!("invalid legacy key in {nsid}"))?;
+            let hit = rkyv::from_bytes::<NsidHit, Error>(&value).map_err(AppError::from)?;
+            Ok(EventRecord {
+                nsid: nsid.clone(),
+                timestamp: time_us / 1_000_000,
+                op: hit.op,
+                did: None,
+                rkey: None,
+            })
+        }))
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use rclite::Arc;
+use smol_str::SmolStr;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{db::NsidCounts, utils::CLOCK};
+
+const LAG_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// lag stats reported by receivers, the sender can't see them
+#[derive(Debug, Default)]
+pub struct BroadcastStats {
+    lag_occurrences: AtomicU64,
+    lagged_events: AtomicU64,
+    // raw clock time of the last warning, relaxed
+    last_warn: AtomicU64,
+}
+
+impl BroadcastStats {
+    #[inline(always)]
+    pub fn lag_occurrences(&self) -> u64 {
+        self.lag_occurrences.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub fn lagged_events(&self) -> u64 {
+        self.lagged_events.load(Ordering::Relaxed)
+    }
+
+    fn observe_lag(&self, skipped: u64, capacity: usize) {
+        let occurrences = self.lag_occurrences.fetch_add(1, Ordering::Relaxed) + 1;
+        let lagged = self.lagged_events.fetch_add(skipped, Ordering::Relaxed) + skipped;
+
+        let now = CLOCK.raw();
+        let last_warn = self.last_warn.load(Ordering::Relaxed);
+        let since_warn = Duration::from_nanos(CLOCK.delta_as_nanos(last_warn, now));
+        if (last_warn == 0 || since_warn >= LAG_WARN_INTERVAL)
+            && self
+                .last_warn
+                .compare_exchange(last_warn, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            tracing::warn!(
+                { capacity = %capacity, occurrences = %occurrences, lagged = %lagged },
+                "event listeners are lagging behind, consider raising the broadcast capacity",
+            );
+        }
+    }
+}
+
+pub struct EventListener {
+    rx: broadcast::Receiver<(SmolStr, NsidCounts)>,
+    stats: Arc<BroadcastStats>,
+    capacity: usize,
+}
+
+impl EventListener {
+    pub fn new(
+        rx: broadcast::Receiver<(SmolStr, NsidCounts)>,
+        stats: Arc<BroadcastStats>,
+        capacity: usize,
+    ) -> Self {
+        Self {
+            rx,
+            stats,
+            capacity,
+        }
+    }
+
+    /// next update, skipping over (and reporting) lagged ones.
+    /// returns None once the db is gone
+    pub async fn recv(&mut self) -> Option<(SmolStr, NsidCounts)> {
+        loop {
+            match self.rx.recv().await {
+                Ok(update) => return Some(update),
+                Err(RecvError::Lagged(skipped)) => self.stats.observe_lag(skipped, self.capacity),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
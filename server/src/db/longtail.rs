@@ -0,0 +1,89 @@
+use ahash::AHashSet;
+use fjall::{Batch, Keyspace, Partition};
+use parking_lot::RwLock;
+use smol_str::SmolStr;
+
+use crate::{
+    db::{NsidCounts, handle::LexiconHandle},
+    error::AppResult,
+};
+
+const KEY_PREFIX: &str = "longtail/";
+
+// nsids with fewer than `threshold` hits keep their blocks in the shared
+// `_longtail` partition instead of one of their own, thousands of barely used
+// nsids would otherwise each cost a partition (file handles, manifest
+// entries). the ones in it are listed in `_meta` as longtail/{nsid}, an nsid
+// leaves once it crosses the threshold and never comes back
+pub struct Longtail {
+    partition: Partition,
+    meta: Partition,
+    // 0 turns it off, nsids already in it move out on their next sync
+    threshold: u128,
+    nsids: RwLock<AHashSet<SmolStr>>,
+}
+
+impl Longtail {
+    pub fn open(ks: &Keyspace, meta: Partition, threshold: u128) -> AppResult<Self> {
+        let partition = ks.open_partition("_longtail", LexiconHandle::partition_options())?;
+        let mut nsids = AHashSet::new();
+        for res in meta.prefix(KEY_PREFIX) {
+            let (key, _) = res?;
+            nsids.insert(SmolStr::new(String::from_utf8_lossy(
+                &key[KEY_PREFIX.len()..],
+            )));
+        }
+        Ok(Self {
+            partition,
+            meta,
+            threshold,
+            nsids: RwLock::new(nsids),
+        })
+    }
+
+    #[inline(always)]
+    pub fn partition(&self) -> &Partition {
+        &self.partition
+    }
+
+    /// whether new nsids start out here
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    pub fn should_promote(&self, counts: &NsidCounts) -> bool {
+        counts.count >= self.threshold
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, nsid: &str) -> bool {
+        self.nsids.read().contains(nsid)
+    }
+
+    pub fn count(&self) -> usize {
+        self.nsids.read().len()
+    }
+
+    pub fn list(&self) -> Vec<SmolStr> {
+        let mut nsids = self.nsids.read().iter().cloned().collect::<Vec<_>>();
+        nsids.sort_unstable();
+        nsids
+    }
+
+    pub fn add(&self, nsid: &str) -> AppResult<()> {
+        self.meta.insert(format!("{KEY_PREFIX}{nsid}"), "")?;
+        self.nsids.write().insert(SmolStr::new(nsid));
+        Ok(())
+    }
+
+    /// drops the nsid from the list as part of `batch`, `removed` once it
+    /// was committed
+    pub fn batch_remove(&self, batch: &mut Batch, nsid: &str) {
+        batch.remove(&self.meta, format!("{KEY_PREFIX}{nsid}"));
+    }
+
+    pub fn removed(&self, nsid: &str) {
+        self.nsids.write().remove(nsid);
+    }
+}
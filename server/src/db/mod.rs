@@ -1,16 +1,18 @@
 use std::{
+    collections::BTreeMap,
     fmt::Debug,
-    io::Cursor,
-    ops::{Bound, Deref, RangeBounds},
-    path::Path,
+    ops::{Bound, Range, RangeBounds},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
     time::Duration,
     u64,
 };
 
 use ahash::{AHashMap, AHashSet};
 use byteview::StrView;
-use fjall::{Keyspace, Partition, PartitionCreateOptions};
-use itertools::{Either, Itertools};
+use fjall::{Keyspace, PartitionCreateOptions};
+use itertools::{Either, EitherOrBoth, Itertools};
+use parking_lot::{Mutex, RwLock};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rclite::Arc;
 use rkyv::{Archive, Deserialize, Serialize, rancor::Error};
@@ -19,73 +21,481 @@ use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    db::handle::{ItemDecoder, LexiconHandle},
-    error::{AppError, AppResult},
+    db::{
+        active::ActiveNsids,
+        cold::ColdStore,
+        counts_cache::CountsCache,
+        handle::{BlockRef, LexiconHandle},
+        health::{IngestControl, QuiesceControl, StorageHealth, WriteOp},
+        labels::Labels,
+        listener::BroadcastStats,
+        longtail::Longtail,
+        negative::NegativeCache,
+        pacer::SyncPacer,
+        partitions::{CountsPartition, LabelsPartition, MetaKey, MetaPartition, RollupPartition},
+        purge::PurgeDetector,
+        rollup::DAY,
+        sparkline::Sparkline,
+        watchlist::{Watchlist, did_partition, did_prefix},
+    },
+    error::{AppError, AppResult, ErrorCode},
     jetstream::JetstreamEvent,
-    utils::{CLOCK, RateTracker, ReadVariableExt, varints_unsigned_encoded},
+    utils::{ArcRefCnt, CLOCK, RateTracker, free_space, get_time},
 };
 
+pub use alerts::{Alert, AlertMetric, AlertOp, AlertRule, Alerts};
+pub use block::Tier;
+pub use block_cache::{Admission, BlockCacheStats, block_cache};
+pub use cold::ColdSegment;
+pub use counts_dump::CountsMerge;
+pub use digest::ContentDigest;
+pub use freshness::DataSource;
+pub use handle::{
+    Item, ItemDecoder, ItemEncoder, PinnedSnapshot, RATE_WINDOW_SECS, RecompressStats,
+};
+pub use health::{IngestState, QuiesceState, StorageState, UpstreamStatus};
+pub use labels::{LabelMap, labels_match, validate_labels};
+pub use legacy::LegacyDb;
+pub use listener::EventListener;
+pub use negative::NegativeCacheStats;
+pub use onboarding::{NewNsid, Onboarding};
+pub use pacer::SyncPaceStatus;
+pub use rollup::{Downsample, OverviewPoint, RollupGranularity, RollupPoint};
+pub use shutdown::{ShutdownPhases, ShutdownReport};
+pub use snapshot::{SnapshotCheck, SnapshotMarker, SnapshotState, check_snapshot_dir};
+pub use sparkline::SPARKLINE_HOURS;
+pub use trace::{BlockTrace, QueryTrace};
+pub use watchlist::{WatchResult, is_valid_did};
+
+mod active;
+mod alerts;
 mod block;
+mod block_cache;
+mod cold;
+mod counts_cache;
+mod counts_dump;
+mod digest;
+mod freshness;
 mod handle;
+mod health;
+mod labels;
+mod legacy;
+mod listener;
+mod longtail;
+mod negative;
+mod onboarding;
+mod pacer;
+mod partitions;
+mod purge;
+mod rollup;
+mod shutdown;
+mod snapshot;
+mod sparkline;
+mod trace;
+mod watchlist;
+
+// bump when the on-disk layout changes
+// 2: blocks can reset their timestamp deltas, see `block::RESET_MARKER`
+// 3: blocks can store their deltas in units of a granularity, see
+//    `block::QUANTIZED`
+pub const SCHEMA_VERSION: u64 = 3;
+
+/// what a partition holds. nsids never start with `_`, so anything that does
+/// is ours (`_counts`, `_meta`, ...) and must not be treated as blocks of hits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    Hits,
+    // hits of a watched did, see `watchlist::did_partition`
+    DidHits,
+    Internal,
+}
+
+impl PartitionKind {
+    pub fn of(name: &str) -> Self {
+        if name.starts_with("_did#") {
+            Self::DidHits
+        } else if name.starts_with('_') {
+            Self::Internal
+        } else {
+            Self::Hits
+        }
+    }
+
+    pub fn is_hits(name: &str) -> bool {
+        Self::of(name) == Self::Hits
+    }
+}
+
+const MAX_NSID_LEN: usize = 317;
+const TRACKING_SINCE_TTL: Duration = Duration::from_secs(60 * 10);
+
+/// whether `nsid` is shaped like one (`app.bsky.feed.post`): at least three
+/// dot separated segments of letters, digits and `-`, the last one (the
+/// name) without `-`
+pub fn is_valid_nsid(nsid: &str) -> bool {
+    let segments = nsid.split('.').collect::<Vec<_>>();
+    let Some((name, authority)) = segments.split_last() else {
+        return false;
+    };
+    let valid_segment = |segment: &str| {
+        (1..=63).contains(&segment.len())
+            && !segment.starts_with('-')
+            && !segment.ends_with('-')
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    nsid.len() <= MAX_NSID_LEN
+        && segments.len() >= 3
+        && authority.iter().all(|segment| valid_segment(segment))
+        && valid_segment(name)
+        && !name.contains('-')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
 
 #[derive(Clone, Debug, Default, Archive, Deserialize, Serialize, PartialEq)]
 #[rkyv(compare(PartialEq), derive(Debug))]
 pub struct NsidCounts {
     pub count: u128,
+    // deletions that werent part of a purge
     pub deleted_count: u128,
     pub last_seen: u64,
+    pub purged_count: u128,
+}
+
+// NsidCounts before purged_count was added, rows written back then still
+// have this layout
+#[derive(Archive, Deserialize, Serialize)]
+struct NsidCountsV1 {
+    count: u128,
+    deleted_count: u128,
+    last_seen: u64,
+}
+
+impl NsidCounts {
+    pub fn decode(raw: &[u8]) -> AppResult<Self> {
+        if raw.len() == size_of::<ArchivedNsidCountsV1>() {
+            let old = rkyv::from_bytes::<NsidCountsV1, Error>(raw)?;
+            return Ok(Self {
+                count: old.count,
+                deleted_count: old.deleted_count,
+                last_seen: old.last_seen,
+                purged_count: 0,
+            });
+        }
+        Ok(rkyv::from_bytes::<Self, Error>(raw)?)
+    }
+
+    #[inline(always)]
+    pub fn observe(&mut self, op: HitOp) {
+        match op {
+            HitOp::Create => self.count += 1,
+            HitOp::Delete => self.deleted_count += 1,
+            HitOp::Purge => self.purged_count += 1,
+        }
+    }
+}
+
+/// sums of the counts of every nsid, kept up to date by `Db::update_count`
+/// and persisted to `_meta` on sync
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+pub struct Totals {
+    pub count: u128,
+    pub deleted_count: u128,
+    pub purged_count: u128,
+}
+
+impl Totals {
+    // counts can go down when they are overwritten, so add before subtracting
+    fn apply(&mut self, before: &NsidCounts, after: &NsidCounts) {
+        self.count = (self.count + after.count).saturating_sub(before.count);
+        self.deleted_count =
+            (self.deleted_count + after.deleted_count).saturating_sub(before.deleted_count);
+        self.purged_count =
+            (self.purged_count + after.purged_count).saturating_sub(before.purged_count);
+    }
+
+    fn add(&mut self, counts: &NsidCounts) {
+        self.apply(&NsidCounts::default(), counts);
+    }
+}
+
+/// what happened to a record. archived as one byte that matches the old
+/// `deleted: bool` of NsidHit, so older blocks decode as create or delete.
+/// only add new variants at the end
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Archive, Deserialize, Serialize)]
+#[rkyv(compare(PartialEq), derive(Debug))]
+pub enum HitOp {
+    #[default]
+    Create,
+    Delete,
+    // deleted as part of an account purge, see `PurgeDetector`
+    Purge,
+}
+
+impl HitOp {
+    #[inline(always)]
+    pub fn is_deleted(self) -> bool {
+        self != HitOp::Create
+    }
 }
 
 #[derive(Debug, Default, Archive, Deserialize, Serialize, PartialEq)]
 #[rkyv(compare(PartialEq), derive(Debug))]
 pub struct NsidHit {
-    pub deleted: bool,
+    pub op: HitOp,
 }
 
 #[derive(Clone)]
 pub struct EventRecord {
     pub nsid: SmolStr,
     pub timestamp: u64, // seconds
-    pub deleted: bool,
+    pub op: HitOp,
+    // only used to match the watchlist, taken out before the event is queued
+    pub did: Option<SmolStr>,
+    // only used to tell redelivered events apart at ingest, see `DedupWindow`.
+    // taken out with the did
+    pub rkey: Option<SmolStr>,
 }
 
 impl EventRecord {
     pub fn from_jetstream(event: JetstreamEvent) -> Option<Self> {
         match event {
             JetstreamEvent::Commit {
-                time_us, commit, ..
+                did,
+                time_us,
+                commit,
+                ..
             } => Some(Self {
                 nsid: commit.collection.into(),
                 timestamp: time_us / 1_000_000,
-                deleted: false,
+                op: HitOp::Create,
+                did: Some(did.into()),
+                rkey: Some(commit.rkey.into()),
             }),
             JetstreamEvent::Delete {
-                time_us, commit, ..
+                did,
+                time_us,
+                commit,
+                ..
             } => Some(Self {
                 nsid: commit.collection.into(),
                 timestamp: time_us / 1_000_000,
-                deleted: true,
+                op: HitOp::Delete,
+                did: Some(did.into()),
+                rkey: Some(commit.rkey.into()),
             }),
             _ => None,
         }
     }
+
+    /// what makes an event the same event when jetstream sends it twice,
+    /// None if it doesnt carry enough to tell
+    pub fn identity(&self, time_us: u64) -> Option<u128> {
+        let (did, rkey) = self.did.as_ref().zip(self.rkey.as_ref())?;
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        for part in [did.as_bytes(), self.nsid.as_bytes(), rkey.as_bytes()] {
+            hasher.update(part);
+            // so the parts cant run into each other
+            hasher.update(&[0]);
+        }
+        hasher.update(&time_us.to_be_bytes());
+        Some(hasher.digest128())
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BroadcastStatus {
+    pub capacity: usize,
+    pub listeners: usize,
+    pub lag_occurrences: u64,
+    pub lagged_events: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlushStatus {
+    pub last_flushed: Option<u64>,
+    pub pending_items: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TierStatus {
+    pub hot_blocks: usize,
+    pub hot_bytes: u64,
+    pub cold: Vec<(SmolStr, ColdSegment)>,
+    // rehydrated blocks are kept hot until this time (unix seconds)
+    pub hold_until: Option<u64>,
+}
+
+// result of `Db::verify` for one nsid
+#[derive(Debug, Default)]
+pub struct BlockCheck {
+    pub blocks: usize,
+    pub items: usize,
+    pub problems: Vec<String>,
+    // start and end of where blocks overlap, if they do
+    pub overlap: Option<(u64, u64)>,
+}
+
+// sizes of the blocks to write out of `count` buffered items. only full blocks
+// are written, unless we are writing everything or the handle went quiet
+// before filling one
+fn plan_blocks(count: usize, block_size: usize, all: bool, is_too_old: bool) -> Vec<usize> {
+    let full = count / block_size;
+    let mut sizes = vec![block_size; full];
+    let remainder = count % block_size;
+    if remainder > 0 && (all || (full == 0 && is_too_old)) {
+        sizes.push(remainder);
+    }
+    sizes
+}
+
+/// hits of a query, oldest first. whole blocks are read so there can be more
+/// than `max_items` of them, `truncated` is set if there are older hits in
+/// the range that werent read because of it
+pub struct HitsPage<I> {
+    pub hits: I,
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BlockInfo {
+    pub start: u64,
+    pub end: u64,
+    pub items: usize,
+    pub bytes: usize,
+}
+
+/// block layout of one nsid's hot tier, see `Db::nsid_info`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NsidInfo {
+    pub block_count: usize,
+    pub items: usize,
+    pub oldest: Option<u64>,
+    pub newest: Option<u64>,
+    pub disk_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<LabelMap>,
+    // oldest first
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// a range (`start..=end`) and bucket size of `Db::histograms`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistogramSeries {
+    pub start: u64,
+    pub end: u64,
+    pub interval: u64,
+}
+
+/// what a sync wrote out. blocks that fail to be inserted are counted too,
+/// those show up in the storage health
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct SyncStats {
+    pub blocks: usize,
+    pub items: usize,
+    pub elapsed_secs: f64,
+}
+
+/// hits of one interval of `Db::histogram`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct HistogramBucket {
+    pub bucket_start: u64,
+    pub count: u64,
+    pub deleted_count: u64,
+    pub purged_count: u64,
 }
 
 pub struct DbInfo {
     pub nsids: AHashMap<SmolStr, Vec<usize>>,
     pub disk_size: u64,
+    // the budget ran out before every nsid was read, the ones that werent
+    // arent in `nsids`
+    pub partial: bool,
+}
+
+/// what `Db::range_counts` found
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RangeCounts {
+    pub counts: NsidCounts,
+    // [start, end) of the range that stored hits cover, None if they dont
+    // reach into it
+    pub covered: Option<(u64, u64)>,
+}
+
+/// what `Db::info_with` reads
+#[derive(Debug, Clone, Default)]
+pub struct InfoOptions {
+    // only nsids starting with this
+    pub prefix: Option<SmolStr>,
+    // stop reading nsids after this long, and return what was read so far
+    pub budget: Option<Duration>,
 }
 
 pub struct DbConfig {
     pub ks_config: fjall::Config,
+    // the data dir, fjall doesnt expose it
+    pub path: PathBuf,
     pub min_block_size: usize,
     pub max_block_size: usize,
     pub max_last_activity: Duration,
+    // how many writes in a row can fail before we go read-only
+    pub max_write_errors: usize,
+    // we go read-only once the disk of `path` has less free bytes than
+    // this, 0 turns the check off
+    pub min_free_space: u64,
+    // blocks older than `cold_after` are moved to `cold_path` if set
+    pub cold_path: Option<PathBuf>,
+    pub cold_after: Duration,
+    // blocks older than `recompress_after` are moved to `recompress_tier`,
+    // compressed at `recompress_level`. see `Db::recompress_old`
+    pub recompress_after: Option<Duration>,
+    pub recompress_tier: Tier,
+    pub recompress_level: i32,
+    // how many count updates listeners can fall behind before they lose some
+    pub broadcast_capacity: usize,
+    // dids to add to the watchlist on startup, and how many can be watched
+    pub watchlist: Vec<SmolStr>,
+    pub max_watchlist: usize,
+    // a did deleting `purge_threshold` records within `purge_window` is
+    // treated as purging its account, at most `purge_tracked_dids` are kept
+    pub purge_threshold: usize,
+    pub purge_window: Duration,
+    pub purge_tracked_dids: usize,
+    // the sync interval adapts to the load within these, see `SyncPacer`
+    pub min_sync_interval: Duration,
+    pub max_sync_interval: Duration,
+    // nsids with fewer hits than this share `_longtail` instead of having a
+    // partition each, 0 is off. see `Longtail`
+    pub longtail_threshold: u64,
+    // while the counts flusher runs counts are written every
+    // `counts_flush_interval`, or once `counts_flush_max_dirty` nsids wait
+    // for it. see `Db::run_counts_flusher`
+    pub counts_flush_interval: Duration,
+    pub counts_flush_max_dirty: usize,
+    // alert rules to add on startup, see `Alerts`
+    pub alert_rules: Vec<AlertRule>,
+    // privacy mode, hit timestamps are rounded down to a multiple of this
+    // many seconds before they are queued. 1 keeps them as they are
+    pub timestamp_granularity: u64,
 }
 
 impl DbConfig {
     pub fn path(mut self, path: impl AsRef<Path>) -> Self {
-        self.ks_config = fjall::Config::new(path);
+        self.ks_config = fjall::Config::new(&path);
+        self.path = path.as_ref().to_path_buf();
         self
     }
 
@@ -93,6 +503,12 @@ impl DbConfig {
         self.ks_config = f(self.ks_config);
         self
     }
+
+    pub fn cold_tier(mut self, path: impl AsRef<Path>, after: Duration) -> Self {
+        self.cold_path = Some(path.as_ref().to_path_buf());
+        self.cold_after = after;
+        self
+    }
 }
 
 impl Default for DbConfig {
@@ -101,23 +517,99 @@ impl Default for DbConfig {
             ks_config: fjall::Config::default()
                 .cache_size(1024 * 1024 * 512)
                 .max_write_buffer_size(u64::MAX),
+            path: PathBuf::from(".fjall_data"),
             min_block_size: 1000,
             max_block_size: 250_000,
             max_last_activity: Duration::from_secs(10),
+            max_write_errors: 16,
+            min_free_space: 1024 * 1024 * 512,
+            cold_path: None,
+            cold_after: Duration::from_secs(60 * 60 * 24 * 90), // 90 days
+            recompress_after: None,
+            recompress_tier: Tier::Zstd,
+            recompress_level: 19,
+            broadcast_capacity: 1000,
+            watchlist: Vec::new(),
+            max_watchlist: 64,
+            purge_threshold: 50,
+            purge_window: Duration::from_secs(60),
+            purge_tracked_dids: 10_000,
+            min_sync_interval: Duration::from_secs(2),
+            max_sync_interval: Duration::from_secs(60),
+            longtail_threshold: 0,
+            counts_flush_interval: Duration::from_secs(1),
+            counts_flush_max_dirty: 10_000,
+            alert_rules: Vec::new(),
+            timestamp_granularity: 1,
         }
     }
 }
 
+// each partition of ours goes through a typed wrapper, see `partitions`
 // counts is nsid -> NsidCounts
+// did_counts is did partition name -> NsidCounts
+// active is hour -> nsids seen in that hour
+// labels is nsid -> labels people gave it
+// meta is misc internal state (eg. storage probes)
 // hits is tree per nsid: varint start time + varint end time -> block of hits
+// longtail is the same for the nsids with few hits, keyed by nsid too
 pub struct Db {
     pub cfg: DbConfig,
     pub ks: Keyspace,
-    counts: Partition,
+    counts: CountsPartition,
+    did_counts: CountsPartition,
+    meta: MetaPartition,
+    cold: Option<ColdStore>,
+    watchlist: Watchlist,
+    onboarding: Onboarding,
+    alerts: Alerts,
+    longtail: Longtail,
+    purges: Mutex<PurgeDetector>,
+    active: ActiveNsids,
+    rollups: RollupPartition,
+    labels: Labels,
+    // one handle per hits partition, for as long as the db is open. handles
+    // carry buffered hits, so a second one for the same name would lose
+    // whatever was written to it: they are only inserted by `ensure_handle`
+    // and never replaced
     hits: scc::HashIndex<SmolStr, Arc<LexiconHandle>, ahash::RandomState>,
+    // partition names that were looked up but dont exist
+    unknown: NegativeCache,
     sync_pool: threadpool::ThreadPool,
     event_broadcaster: broadcast::Sender<(SmolStr, NsidCounts)>,
+    broadcast_stats: Arc<BroadcastStats>,
     eps: RateTracker<100>, // 100 millis buckets
+    health: Arc<StorageHealth>,
+    ingest: IngestControl,
+    quiesce: QuiesceControl,
+    // ingest holds this shared, quiesce exclusively while it writes everything
+    write_gate: RwLock<()>,
+    // counts as last written by ingest, with the ones that arent in
+    // `counts` yet
+    counts_cache: CountsCache,
+    sync_generation: AtomicU64,
+    // bumped whenever counts or labels change, see `events_generation`
+    events_generation: AtomicU64,
+    // unix seconds of the last bump
+    events_changed_at: AtomicU64,
+    // held for the whole of a sync, so two never overlap
+    syncing: Mutex<()>,
+    // time_us of the last ingested jetstream event
+    cursor: AtomicU64,
+    // events ingested since we started
+    ingested: AtomicU64,
+    // redelivered events dropped before ingest since we started
+    duplicates_dropped: AtomicU64,
+    // unix seconds of the last ingest that had events, 0 before the first
+    last_event_at: AtomicU64,
+    opened_at: u64,
+    upstream: UpstreamStatus,
+    // whether the last run left a clean shutdown report
+    clean_start: bool,
+    totals: Mutex<Totals>,
+    // when `tracking_since` was last worked out, and what it was
+    tracking_since: Mutex<Option<(quanta::Instant, u64)>>,
+    pacer: SyncPacer,
     cancel_token: CancellationToken,
 }
 
@@ -125,21 +617,133 @@ impl Db {
     pub fn new(cfg: DbConfig, cancel_token: CancellationToken) -> AppResult<Self> {
         tracing::info!("opening db...");
         let ks = cfg.ks_config.clone().open()?;
-        Ok(Self {
-            cfg,
+        let meta = MetaPartition::new(ks.open_partition(
+            "_meta",
+            PartitionCreateOptions::default().compression(fjall::CompressionType::None),
+        )?);
+        let fresh = !meta.contains(MetaKey::SchemaVersion)?;
+        if fresh {
+            meta.insert_u64(MetaKey::SchemaVersion, SCHEMA_VERSION)?;
+        }
+        // older layouts are still readable, but the blocks we write from now
+        // on might not be for older versions
+        if let Some(version) = meta
+            .get_u64(MetaKey::SchemaVersion)?
+            .filter(|version| *version < SCHEMA_VERSION)
+        {
+            tracing::info!("upgrading db schema version {version} to {SCHEMA_VERSION}");
+            meta.insert_u64(MetaKey::SchemaVersion, SCHEMA_VERSION)?;
+        }
+        let clean_start = match ShutdownReport::take(&cfg.path) {
+            Ok(Some(report)) => {
+                let clean = report.is_clean();
+                let report = serde_json::to_string(&report)?;
+                if clean {
+                    tracing::info!(%report, "previous shutdown was clean");
+                } else {
+                    tracing::warn!(%report, "previous shutdown had problems");
+                }
+                clean
+            }
+            Ok(None) if fresh => true,
+            Ok(None) => {
+                tracing::warn!("no shutdown report, previous run didnt shut down cleanly");
+                false
+            }
+            Err(err) => {
+                tracing::warn!({ err = %err }, "cant read previous shutdown report");
+                false
+            }
+        };
+        let cold = cfg
+            .cold_path
+            .as_ref()
+            .map(|path| ColdStore::open(path, meta.raw().clone()))
+            .transpose()?;
+        let watchlist = Watchlist::open(meta.raw().clone(), cfg.max_watchlist, &cfg.watchlist)?;
+        let onboarding = Onboarding::open(meta.raw().clone());
+        let alerts = Alerts::open(meta.raw().clone(), &cfg.alert_rules)?;
+        let longtail = Longtail::open(&ks, meta.raw().clone(), cfg.longtail_threshold.into())?;
+        let db = Self {
             hits: Default::default(),
+            unknown: NegativeCache::new(Duration::from_secs(60), 10_000),
             sync_pool: threadpool::Builder::new()
                 .num_threads(rayon::current_num_threads() * 2)
                 .build(),
-            counts: ks.open_partition(
+            counts: CountsPartition::new(ks.open_partition(
                 "_counts",
                 PartitionCreateOptions::default().compression(fjall::CompressionType::None),
-            )?,
+            )?),
+            did_counts: CountsPartition::new(ks.open_partition(
+                "_did_counts",
+                PartitionCreateOptions::default().compression(fjall::CompressionType::None),
+            )?),
+            active: ActiveNsids::new(ks.open_partition(
+                "_active_nsids",
+                PartitionCreateOptions::default().compression(fjall::CompressionType::None),
+            )?),
+            rollups: RollupPartition::new(ks.open_partition(
+                "_rollup_daily",
+                PartitionCreateOptions::default().compression(fjall::CompressionType::None),
+            )?),
+            labels: Labels::open(LabelsPartition::new(ks.open_partition(
+                "_labels",
+                PartitionCreateOptions::default().compression(fjall::CompressionType::None),
+            )?))?,
+            meta,
+            cold,
+            watchlist,
+            onboarding,
+            alerts,
+            longtail,
+            purges: Mutex::new(PurgeDetector::new(
+                cfg.purge_threshold,
+                cfg.purge_window,
+                cfg.purge_tracked_dids,
+            )),
+            health: Arc::new(StorageHealth::new(cfg.max_write_errors)),
+            ingest: IngestControl::default(),
+            quiesce: QuiesceControl::default(),
+            write_gate: RwLock::new(()),
+            counts_cache: CountsCache::new(cfg.counts_flush_max_dirty),
+            sync_generation: AtomicU64::new(0),
+            events_generation: AtomicU64::new(0),
+            events_changed_at: AtomicU64::new(get_time().as_secs()),
+            syncing: Mutex::new(()),
+            cursor: AtomicU64::new(0),
+            ingested: AtomicU64::new(0),
+            duplicates_dropped: AtomicU64::new(0),
+            last_event_at: AtomicU64::new(0),
+            opened_at: get_time().as_secs(),
+            upstream: UpstreamStatus::default(),
+            clean_start,
+            totals: Mutex::new(Totals::default()),
+            tracking_since: Mutex::new(None),
+            pacer: SyncPacer::new(
+                cfg.min_sync_interval,
+                cfg.max_sync_interval,
+                cfg.min_block_size,
+                cfg.max_block_size,
+                CLOCK.clone(),
+            ),
             ks,
-            event_broadcaster: broadcast::channel(1000).0,
+            event_broadcaster: broadcast::channel(cfg.broadcast_capacity.max(1)).0,
+            broadcast_stats: Arc::new(BroadcastStats::default()),
             eps: RateTracker::new(Duration::from_secs(1)),
             cancel_token,
-        })
+            cfg,
+        };
+        let totals = match db.stored_totals()? {
+            Some(totals) => totals,
+            // first start with totals, count them up once
+            None => {
+                let totals = db.recount_totals()?;
+                db.store_totals(&totals)?;
+                totals
+            }
+        };
+        *db.totals.lock() = totals;
+        Ok(db)
     }
 
     #[inline(always)]
@@ -152,26 +756,321 @@ impl Db {
         self.cancel_token.is_cancelled()
     }
 
+    #[inline(always)]
+    pub fn storage_state(&self) -> StorageState {
+        self.health.state()
+    }
+
+    #[inline(always)]
+    pub fn is_writable(&self) -> bool {
+        self.health.is_writable()
+    }
+
+    #[inline(always)]
+    pub fn write_errors(&self) -> usize {
+        self.health.consecutive_errors()
+    }
+
+    /// resolves once storage is accepting writes
+    pub async fn writable(&self) {
+        self.health.writable().await
+    }
+
+    #[inline(always)]
+    pub fn ingest_state(&self) -> IngestState {
+        self.ingest.state()
+    }
+
+    #[inline(always)]
+    pub fn is_ingest_paused(&self) -> bool {
+        self.ingest.is_paused()
+    }
+
+    pub fn pause_ingest(&self, timeout: Duration) -> IngestState {
+        self.ingest.pause(timeout)
+    }
+
+    pub fn resume_ingest(&self) -> IngestState {
+        self.ingest.resume()
+    }
+
+    /// resolves once ingest is not paused anymore
+    pub async fn ingest_running(&self) {
+        self.ingest.running().await
+    }
+
+    #[inline(always)]
+    pub fn quiesce_state(&self) -> QuiesceState {
+        self.quiesce.state()
+    }
+
+    #[inline(always)]
+    pub fn is_quiesced(&self) -> bool {
+        self.quiesce.is_held()
+    }
+
+    /// writes everything out and makes it durable, then holds block writes
+    /// until `unquiesce` or `timeout` so the data dir can be snapshotted.
+    /// ingest keeps buffering in memory meanwhile
+    pub fn quiesce(&self, timeout: Duration) -> AppResult<QuiesceState> {
+        if !self.is_writable() {
+            return Err(AppError::new(
+                ErrorCode::StorageDegraded,
+                "storage is degraded, cant quiesce",
+            ));
+        }
+        // keep ingest out so the counts and hits on disk cover the same events
+        let _gate = self.write_gate.write();
+        self.quiesce.release();
+        self.sync(true)?;
+        let marker = SnapshotMarker {
+            generation: self
+                .snapshot_marker()?
+                .map_or(0, |marker| marker.generation)
+                + 1,
+            sync_generation: self.sync_generation(),
+            cursor: self.stored_cursor()?,
+            quiesced_at: get_time().as_secs(),
+        };
+        self.meta
+            .insert(MetaKey::SnapshotMarker, &serde_json::to_vec(&marker)?)?;
+        self.ks.persist(fjall::PersistMode::SyncAll)?;
+        // after everything else is on disk, see `check_snapshot_dir`
+        marker.write(&self.cfg.path)?;
+        Ok(self.quiesce.hold(timeout, marker.sync_generation))
+    }
+
+    /// what the last quiesce recorded in `_meta`, None if there wasnt one
+    pub fn snapshot_marker(&self) -> AppResult<Option<SnapshotMarker>> {
+        self.meta
+            .get(MetaKey::SnapshotMarker)?
+            .map(|raw| serde_json::from_slice(&raw).map_err(AppError::from))
+            .transpose()
+    }
+
+    /// whether the files in the data dir are still as of the last quiesce
+    pub fn check_snapshot(&self) -> AppResult<SnapshotCheck> {
+        check_snapshot_dir(&self.cfg.path)
+    }
+
+    pub fn unquiesce(&self) -> AppResult<QuiesceState> {
+        let state = self.quiesce.release();
+        self.flush_counts()?;
+        Ok(state)
+    }
+
+    #[inline(always)]
+    pub fn sync_generation(&self) -> u64 {
+        self.sync_generation.load(AtomicOrdering::Acquire)
+    }
+
+    /// changes whenever the counts or labels of any nsid do, so /events can
+    /// tell a client its copy is still current. starts over on every open
+    #[inline(always)]
+    pub fn events_generation(&self) -> u64 {
+        self.events_generation.load(AtomicOrdering::Acquire)
+    }
+
+    /// unix seconds of the last change `events_generation` counted, when we
+    /// were opened if there wasnt one yet
+    #[inline(always)]
+    pub fn events_changed_at(&self) -> u64 {
+        self.events_changed_at.load(AtomicOrdering::Relaxed)
+    }
+
+    fn events_changed(&self) {
+        self.events_changed_at
+            .store(get_time().as_secs(), AtomicOrdering::Relaxed);
+        self.events_generation.fetch_add(1, AtomicOrdering::Release);
+    }
+
+    #[inline(always)]
+    pub fn observe_cursor(&self, time_us: u64) {
+        self.cursor.fetch_max(time_us, AtomicOrdering::Relaxed);
+    }
+
+    /// jetstream cursor of the last full sync, events after it may not be on disk
+    pub fn stored_cursor(&self) -> AppResult<Option<u64>> {
+        self.meta.get_u64(MetaKey::Cursor)
+    }
+
+    /// writes the counts that changed since the last flush in one batch,
+    /// returns how many. nothing is written while quiesced
+    pub fn flush_counts(&self) -> AppResult<usize> {
+        let _flushing = self.counts_cache.lock_flush();
+        // checked with the lock held, a quiesce flushes (in its sync) before
+        // it holds
+        if self.is_quiesced() {
+            return Ok(0);
+        }
+        let dirty = self.counts_cache.take();
+        if dirty.is_empty() {
+            return Ok(0);
+        }
+        // already in the totals, those follow the cache
+        let res = self.write_counts(&dirty);
+        match &res {
+            Ok(_) => {
+                self.health.observe_ok(WriteOp::Counts);
+                // /events reads through the cache, but what it shows is
+                // only stored now
+                self.events_changed();
+            }
+            Err(err) => {
+                self.health.observe_err(WriteOp::Counts, err);
+                self.counts_cache
+                    .restore(dirty.iter().map(|(nsid, _)| nsid.clone()));
+            }
+        }
+        res.map(|_| dirty.len())
+    }
+
+    fn write_counts(&self, counts: &[(SmolStr, NsidCounts)]) -> AppResult<()> {
+        let mut batch = self.ks.batch();
+        for (nsid, counts) in counts {
+            self.counts.batch_insert(&mut batch, nsid, counts)?;
+        }
+        batch.commit()?;
+        Ok(())
+    }
+
+    /// writes counts every `counts_flush_interval` until `cancel`, ingest
+    /// only updates them in memory while this runs. meant for a thread of
+    /// its own. after a crash the counts can be behind by what the last
+    /// interval saw (the hits themselves are only as recent as the last
+    /// sync anyway), `reconcile_totals` makes the totals match them again
+    pub fn run_counts_flusher(&self, cancel: &CancellationToken) {
+        self.counts_cache.set_deferred(true);
+        while !cancel.is_cancelled() {
+            self.counts_cache.wait(self.cfg.counts_flush_interval);
+            // keeps a quiesce from starting halfway through a flush
+            let _gate = self.write_gate.read();
+            if let Err(err) = self.flush_counts() {
+                tracing::error!({ err = %err }, "failed to flush counts");
+            }
+        }
+        // straight to `counts` again, the final sync writes what is left
+        self.counts_cache.set_deferred(false);
+    }
+
+    /// gets `run_counts_flusher` to look at `cancel` (and flush) right away
+    pub fn wake_counts_flusher(&self) {
+        self.counts_cache.wake();
+    }
+
+    /// free bytes on the disk of the data dir, if they are below
+    /// `min_free_space` (and it can be told)
+    fn low_free_space(&self) -> Option<u64> {
+        if self.cfg.min_free_space == 0 {
+            return None;
+        }
+        free_space(&self.cfg.path).filter(|free| *free < self.cfg.min_free_space)
+    }
+
+    /// goes read-only if the disk is almost full, before writes start failing
+    pub fn check_free_space(&self) {
+        if let Some(free) = self.low_free_space() {
+            self.health.observe_low_space(free, self.cfg.min_free_space);
+        }
+    }
+
+    /// tries a small durable write, if it succeeds (and there is enough free
+    /// space again) we leave read-only mode
+    pub fn probe_storage(&self) -> AppResult<()> {
+        if let Some(free) = self.low_free_space() {
+            return Err(AppError::new(
+                ErrorCode::StorageDegraded,
+                format!("only {free} bytes are free on the disk"),
+            ));
+        }
+        let res = self
+            .meta
+            .insert_u64(MetaKey::Probe, get_time().as_secs())
+            .and_then(|_| {
+                self.ks
+                    .persist(fjall::PersistMode::SyncAll)
+                    .map_err(AppError::from)
+            });
+        match &res {
+            Ok(_) => self.health.recover(),
+            Err(err) => tracing::warn!({ err = %err }, "storage probe failed"),
+        }
+        res
+    }
+
     #[inline(always)]
     pub fn eps(&self) -> usize {
         self.eps.rate() as usize
     }
 
+    /// events per second of the nsid over the last `RATE_WINDOW_SECS`. 0 if
+    /// it has no live handle, nothing was ingested for it since we started
+    pub fn nsid_rate(&self, nsid: &str) -> f64 {
+        self.peek_handle(nsid).map_or(0.0, |handle| handle.rate())
+    }
+
     #[inline(always)]
-    pub fn new_listener(&self) -> broadcast::Receiver<(SmolStr, NsidCounts)> {
-        self.event_broadcaster.subscribe()
+    pub fn new_listener(&self) -> EventListener {
+        EventListener::new(
+            self.event_broadcaster.subscribe(),
+            self.broadcast_stats.clone(),
+            self.cfg.broadcast_capacity,
+        )
+    }
+
+    pub fn broadcast_status(&self) -> BroadcastStatus {
+        BroadcastStatus {
+            capacity: self.cfg.broadcast_capacity,
+            listeners: self.event_broadcaster.receiver_count(),
+            lag_occurrences: self.broadcast_stats.lag_occurrences(),
+            lagged_events: self.broadcast_stats.lagged_events(),
+        }
+    }
+
+    /// writes out buffered hits, waits for a sync that is already running
+    /// to finish first
+    pub fn sync(&self, all: bool) -> AppResult<SyncStats> {
+        let _syncing = self.syncing.lock();
+        self.sync_exclusive(all)
     }
 
-    pub fn sync(&self, all: bool) -> AppResult<()> {
+    /// like `Db::sync`, but None instead of waiting if a sync is running
+    pub fn try_sync(&self, all: bool) -> Option<AppResult<SyncStats>> {
+        let _syncing = self.syncing.try_lock()?;
+        Some(self.sync_exclusive(all))
+    }
+
+    fn sync_exclusive(&self, all: bool) -> AppResult<SyncStats> {
+        if !self.is_writable() {
+            // keep whatever is buffered, writing now would just lose it
+            tracing::warn!("storage is degraded, skipping sync");
+            return Ok(SyncStats::default());
+        }
+        // a paused ingest still has its buffered events written, nothing
+        // new comes in so they are soon old enough to go out whole. with
+        // nothing buffered there is nothing to plan below
+        if self.is_quiesced() {
+            return Ok(SyncStats::default());
+        }
+        let span = tracing::info_span!(
+            "sync",
+            all,
+            blocks = tracing::field::Empty,
+            items = tracing::field::Empty,
+        )
+        .entered();
+        self.flush_counts()?;
+        // read before taking items, so everything up to it is written below
+        let cursor = self.cursor.load(AtomicOrdering::Relaxed);
         let start = CLOCK.now();
-        // prepare all the data
+        // pick the handles that have something to write, what exactly is
+        // decided again when draining them since another sync can get there
+        // first
         let nsids_len = self.hits.len();
         let mut data = Vec::with_capacity(nsids_len);
         let mut nsids = AHashSet::with_capacity(nsids_len);
         let _guard = scc::ebr::Guard::new();
         for (nsid, handle) in self.hits.iter(&_guard) {
-            let mut nsid_data = Vec::with_capacity(2);
-            // let mut total_count = 0;
             let is_too_old = handle.since_last_activity() > self.cfg.max_last_activity;
             // if we disconnect for a long time, we want to sync all of what we
             // have to avoid having many small blocks (even if we run compaction
@@ -183,60 +1082,59 @@ impl Db {
                         .max_block_size
                         .min(self.cfg.min_block_size.max(handle.suggested_block_size()))
                 });
-            let count = handle.item_count();
-            let data_count = count / block_size;
-            if count > 0 && (all || data_count > 0 || is_too_old) {
-                for _ in 0..data_count {
-                    nsid_data.push((handle.clone(), block_size));
-                    // total_count += block_size;
-                }
-                // only sync remainder if we haven't met block size
-                let remainder = count % block_size;
-                if (all || data_count == 0) && remainder > 0 {
-                    nsid_data.push((handle.clone(), remainder));
-                    // total_count += remainder;
-                }
-            }
-            let _span = handle.span().entered();
-            if nsid_data.len() > 0 {
-                // tracing::info!(
-                //     {blocks = %nsid_data.len(), count = %total_count},
-                //     "will encode & sync",
-                // );
+            if !plan_blocks(handle.item_count(), block_size, all, is_too_old).is_empty() {
                 nsids.insert(nsid.clone());
-                data.push(nsid_data);
+                data.push((handle.clone(), block_size, is_too_old));
             }
         }
         drop(_guard);
 
         // process the blocks
+        let blocks_written = AtomicUsize::new(0);
+        let items_written = AtomicUsize::new(0);
         data.into_par_iter()
-            .map(|chunk| {
-                chunk
-                    .into_iter()
-                    .map(|(handle, max_block_size)| {
-                        (handle.take_block_items(max_block_size), handle)
-                    })
-                    .collect::<Vec<_>>()
+            .map(|(handle, block_size, is_too_old)| {
+                let mut blocks = handle.drain(|count| {
+                    // once we are shutting down leave the items buffered,
+                    // the final sync(true) picks them up
+                    if !all && self.is_shutting_down() {
+                        return Vec::new();
+                    }
+                    plan_blocks(count, block_size, all, is_too_old)
+                });
+                for items in &mut blocks {
+                    self.sort_block(&handle, items);
+                }
+                blocks
                     .into_par_iter()
-                    .map(|(items, handle)| {
+                    .map(|items| {
                         let count = items.len();
-                        let block = LexiconHandle::encode_block_from_items(items, count)?;
-                        AppResult::Ok((block, handle))
+                        let granularity =
+                            handle::block_granularity(&items, self.timestamp_granularity());
+                        let block =
+                            LexiconHandle::encode_block_from_items(items, count, granularity)?;
+                        AppResult::Ok((block, handle.clone()))
                     })
                     .collect::<Result<Vec<_>, _>>()
             })
             .try_for_each(|chunk| {
                 let chunk = chunk?;
                 for (block, handle) in chunk {
+                    blocks_written.fetch_add(1, AtomicOrdering::Relaxed);
+                    items_written.fetch_add(block.written, AtomicOrdering::Relaxed);
+                    let health = self.health.clone();
                     self.sync_pool.execute(move || {
                         let _span = handle.span().entered();
                         let written = block.written;
                         match handle.insert_block(block) {
                             Ok(_) => {
+                                health.observe_ok(WriteOp::Blocks);
                                 tracing::info!({count = %written}, "synced")
                             }
-                            Err(err) => tracing::error!({ err = %err }, "failed to sync block"),
+                            Err(err) => {
+                                health.observe_err(WriteOp::Blocks, &err);
+                                tracing::error!({ err = %err }, "failed to sync block")
+                            }
                         }
                     });
                 }
@@ -245,15 +1143,122 @@ impl Db {
         self.sync_pool.join();
 
         // update snapshots for all (changed) handles
-        for nsid in nsids {
-            self.hits.peek_with(&nsid, |_, handle| handle.update_tree());
+        for nsid in &nsids {
+            self.hits.peek_with(nsid, |_, handle| handle.update_tree());
+        }
+        self.promote_grown(&nsids);
+
+        match self.active.flush() {
+            Ok(_) => self.health.observe_ok(WriteOp::ActiveNsids),
+            Err(err) => {
+                self.health.observe_err(WriteOp::ActiveNsids, &err);
+                tracing::error!({ err = %err }, "failed to flush active nsids");
+            }
+        }
+
+        let totals = self.totals();
+        if let Err(err) = self.store_totals(&totals) {
+            tracing::error!({ err = %err }, "failed to persist totals");
+        }
+        if all && cursor > 0 {
+            self.meta.insert_u64(MetaKey::Cursor, cursor)?;
         }
+        self.sync_generation.fetch_add(1, AtomicOrdering::Release);
+
+        let stats = SyncStats {
+            blocks: blocks_written.into_inner(),
+            items: items_written.into_inner(),
+            elapsed_secs: start.elapsed().as_secs_f64(),
+        };
+        span.record("blocks", stats.blocks);
+        span.record("items", stats.items);
+        tracing::info!(time = %stats.elapsed_secs, "synced all blocks");
+
+        Ok(stats)
+    }
 
-        tracing::info!(time = %start.elapsed().as_secs_f64(), "synced all blocks");
+    // a block is keyed by its first and last hit, so its hits have to be in
+    // order. they mostly are, hits that arrive late make the block overlap
+    // older ones, which is recorded for compaction to merge them
+    fn sort_block(&self, handle: &LexiconHandle, items: &mut [handle::Item]) {
+        items.sort_by_key(|item| item.timestamp);
+        let (Some(first), Some(last)) = (items.first(), items.last()) else {
+            return;
+        };
+        let recorded = handle
+            .observe_block(first.timestamp, last.timestamp)
+            .and_then(|overlap| match overlap {
+                Some(overlap) => self.record_overlap(handle, overlap),
+                None => Ok(()),
+            });
+        if let Err(err) = recorded {
+            tracing::error!({ nsid = %handle.nsid(), err = %err }, "cant record overlapping blocks");
+        }
+    }
 
+    fn record_overlap(&self, handle: &LexiconHandle, overlap: (u64, u64)) -> AppResult<()> {
+        let nsid = handle.nsid();
+        let grown = handle.record_overlap(overlap, |overlap| self.store_overlap(nsid, overlap))?;
+        if grown {
+            tracing::warn!(
+                { nsid = %nsid, start = overlap.0, end = overlap.1 },
+                "blocks overlap, compaction will merge them"
+            );
+        }
         Ok(())
     }
 
+    fn store_overlap(&self, nsid: &str, overlap: Option<(u64, u64)>) -> AppResult<()> {
+        match overlap {
+            Some((start, end)) => self.meta.insert(
+                MetaKey::Overlap(nsid),
+                &[start.to_be_bytes(), end.to_be_bytes()].concat(),
+            ),
+            None => self.meta.remove(MetaKey::Overlap(nsid)),
+        }
+    }
+
+    fn load_overlap(&self, nsid: &str) -> AppResult<Option<(u64, u64)>> {
+        let Some(raw) = self.meta.get(MetaKey::Overlap(nsid))? else {
+            return Ok(None);
+        };
+        let raw = <[u8; 16]>::try_from(&raw[..])
+            .map_err(|_| AppError::from(anyhow::anyhow!("invalid {}", MetaKey::Overlap(nsid))))?;
+        let (start, end) = raw.split_at(8);
+        Ok(Some((
+            u64::from_be_bytes(start.try_into().expect("its 8 bytes")),
+            u64::from_be_bytes(end.try_into().expect("its 8 bytes")),
+        )))
+    }
+
+    /// merges and sorts the blocks where they overlap, for every nsid that
+    /// has some. returns how many nsids it merged blocks of
+    pub fn compact_overlaps(&self, max_count: usize) -> AppResult<usize> {
+        let guard = scc::ebr::Guard::new();
+        let handles = self
+            .hits
+            .iter(&guard)
+            .filter(|(_, handle)| handle.overlap().is_some())
+            .map(|(_, handle)| handle.clone())
+            .collect_vec();
+        drop(guard);
+        for handle in &handles {
+            if self.is_shutting_down() {
+                return Err(AppError::cancelled());
+            }
+            let Some((start, end)) = handle.overlap() else {
+                continue;
+            };
+            let _span =
+                tracing::info_span!("compact_overlap", nsid = %handle.nsid(), start, end).entered();
+            handle.compact(max_count, start..=end, true, &self.cancel_token)?;
+            handle.update_tree();
+            let nsid = handle.nsid();
+            handle.clear_overlap((start, end), |overlap| self.store_overlap(nsid, overlap))?;
+        }
+        Ok(handles.len())
+    }
+
     pub fn compact(
         &self,
         nsid: impl AsRef<str>,
@@ -264,7 +1269,8 @@ impl Db {
         let Some(handle) = self.get_handle(nsid) else {
             return Ok(());
         };
-        handle.compact(max_count, range, sort)?;
+        let _span = tracing::info_span!("compact", nsid = %handle.nsid(), sort).entered();
+        handle.compact(max_count, range, sort, &self.cancel_token)?;
         handle.update_tree();
         Ok(())
     }
@@ -276,6 +1282,9 @@ impl Db {
         sort: bool,
     ) -> AppResult<()> {
         for nsid in self.get_nsids() {
+            if self.is_shutting_down() {
+                return Err(AppError::cancelled());
+            }
             self.compact(nsid, max_count, range.clone(), sort)?;
         }
         Ok(())
@@ -286,219 +1295,3126 @@ impl Db {
         Ok(())
     }
 
-    #[inline(always)]
-    fn get_handle(&self, nsid: impl AsRef<str>) -> Option<Arc<LexiconHandle>> {
-        let _guard = scc::ebr::Guard::new();
-        let handle = match self.hits.peek(nsid.as_ref(), &_guard) {
-            Some(handle) => handle.clone(),
-            None => {
-                if self.ks.partition_exists(nsid.as_ref()) {
-                    let handle = Arc::new(LexiconHandle::new(&self.ks, nsid.as_ref()));
-                    let _ = self.hits.insert(SmolStr::new(nsid), handle.clone());
-                    handle
-                } else {
-                    return None;
-                }
-            }
-        };
-        Some(handle)
+    /// on-disk schema version recorded in `_meta`
+    pub fn schema_version(&self) -> AppResult<u64> {
+        Ok(self
+            .meta
+            .get_u64(MetaKey::SchemaVersion)?
+            .unwrap_or(SCHEMA_VERSION))
     }
 
     #[inline(always)]
-    fn ensure_handle(&self, nsid: &SmolStr) -> impl Deref<Target = Arc<LexiconHandle>> + use<'_> {
-        self.hits
-            .entry(nsid.clone())
-            .or_insert_with(|| Arc::new(LexiconHandle::new(&self.ks, &nsid)))
-    }
-
-    pub fn ingest_events(&self, events: impl Iterator<Item = EventRecord>) -> AppResult<()> {
-        let mut seen_events = 0;
-        for (key, chunk) in events.chunk_by(|event| event.nsid.clone()).into_iter() {
-            let mut counts = self.get_count(&key)?;
-            self.ensure_handle(&key).queue(chunk.inspect(|e| {
-                // increment count
-                counts.last_seen = e.timestamp;
-                if e.deleted {
-                    counts.deleted_count += 1;
-                } else {
-                    counts.count += 1;
-                }
-                seen_events += 1;
-            }));
-            self.insert_count(&key, &counts)?;
-            if self.event_broadcaster.receiver_count() > 0 {
-                let _ = self.event_broadcaster.send((key, counts));
-            }
-        }
-        self.eps.observe(seen_events);
-        Ok(())
+    pub fn has_cold_tier(&self) -> bool {
+        self.cold.is_some()
     }
 
-    #[inline(always)]
-    fn insert_count(&self, nsid: &str, counts: &NsidCounts) -> AppResult<()> {
-        self.counts
-            .insert(
-                nsid,
-                unsafe { rkyv::to_bytes::<Error>(counts).unwrap_unchecked() }.as_slice(),
-            )
-            .map_err(AppError::from)
+    fn cold_hold_until(&self, nsid: &str) -> AppResult<Option<u64>> {
+        self.meta.get_u64(MetaKey::ColdHold(nsid))
     }
 
-    pub fn get_count(&self, nsid: &str) -> AppResult<NsidCounts> {
-        let Some(raw) = self.counts.get(nsid)? else {
-            return Ok(NsidCounts::default());
+    /// moves blocks that ended before `cfg.cold_after` ago to the cold tier
+    pub fn tier_cold(&self) -> AppResult<()> {
+        let Some(cold) = self.cold.as_ref() else {
+            return Ok(());
         };
-        Ok(unsafe { rkyv::from_bytes_unchecked::<_, Error>(&raw).unwrap_unchecked() })
-    }
-
-    pub fn get_counts(&self) -> impl Iterator<Item = AppResult<(SmolStr, NsidCounts)>> {
-        self.counts.iter().map(|res| {
-            res.map_err(AppError::from).map(|(key, val)| {
-                (
-                    SmolStr::new(unsafe { str::from_utf8_unchecked(&key) }),
-                    unsafe { rkyv::from_bytes_unchecked::<_, Error>(&val).unwrap_unchecked() },
-                )
-            })
-        })
-    }
-
-    pub fn get_nsids(&self) -> impl Iterator<Item = StrView> {
-        self.ks
-            .list_partitions()
-            .into_iter()
-            .filter(|k| k.deref() != "_counts")
+        let now = get_time();
+        let cutoff = now.saturating_sub(self.cfg.cold_after).as_secs();
+        for nsid in self.get_nsids() {
+            if self.is_shutting_down() {
+                return Err(AppError::cancelled());
+            }
+            if self
+                .cold_hold_until(&nsid)?
+                .is_some_and(|until| until > now.as_secs())
+            {
+                continue;
+            }
+            let Some(handle) = self.get_handle(&nsid) else {
+                continue;
+            };
+            let blocks = handle
+                .blocks(..cutoff)
+                .filter_ok(|block| block.key().end < cutoff)
+                .collect::<AppResult<Vec<_>>>()?;
+            if blocks.is_empty() {
+                continue;
+            }
+            let _span = handle.span().entered();
+            let count = blocks.len();
+            // write the cold copy first, reads prefer fjall so a crash here
+            // only leaves duplicates around
+            cold.append(&nsid, blocks.clone())?;
+            handle.remove_blocks(&blocks)?;
+            handle.update_tree();
+            tracing::info!({ blocks = %count }, "moved blocks to cold tier");
+        }
+        Ok(())
     }
 
-    pub fn info(&self) -> AppResult<DbInfo> {
-        let mut nsids = AHashMap::new();
+    /// moves blocks that ended before `cfg.recompress_after` ago to
+    /// `cfg.recompress_tier`, returns what it saved over every nsid
+    pub fn recompress_old(&self) -> AppResult<RecompressStats> {
+        let Some(after) = self.cfg.recompress_after else {
+            return Ok(RecompressStats::default());
+        };
+        let cutoff = get_time().saturating_sub(after).as_secs();
+        let mut total = RecompressStats::default();
         for nsid in self.get_nsids() {
+            if self.is_shutting_down() {
+                return Err(AppError::cancelled());
+            }
             let Some(handle) = self.get_handle(&nsid) else {
                 continue;
             };
-            let block_lens = handle
-                .read()
-                .iter()
-                .rev()
-                .try_fold(Vec::new(), |mut acc, item| {
-                    let (key, value) = item?;
-                    let mut timestamps = Cursor::new(key);
-                    let start_timestamp = timestamps.read_varint()?;
-                    let decoder = ItemDecoder::new(Cursor::new(value), start_timestamp)?;
-                    acc.push(decoder.item_count());
-                    AppResult::Ok(acc)
-                })?;
-            nsids.insert(nsid.to_smolstr(), block_lens);
+            let stats = handle.recompress(
+                cutoff,
+                self.cfg.recompress_tier,
+                self.cfg.recompress_level,
+                &self.cancel_token,
+            )?;
+            if stats.blocks > 0 {
+                handle.update_tree();
+            }
+            total.add(stats);
         }
-        Ok(DbInfo {
-            nsids,
-            disk_size: self.ks.disk_space(),
-        })
+        tracing::info!(
+            {
+                blocks = total.blocks,
+                before = total.bytes_before,
+                after = total.bytes_after,
+            },
+            "recompressed old blocks, saved {} bytes",
+            total.saved(),
+        );
+        Ok(total)
     }
 
-    pub fn get_hits(
+    /// moves cold blocks in `range` back into fjall and keeps them there for `hold`
+    pub fn rehydrate(
         &self,
         nsid: &str,
-        range: impl RangeBounds<u64> + std::fmt::Debug,
-        max_items: usize,
-    ) -> impl Iterator<Item = AppResult<handle::Item>> {
-        let start_limit = match range.start_bound().cloned() {
-            Bound::Included(start) => start,
-            Bound::Excluded(start) => start.saturating_add(1),
-            Bound::Unbounded => 0,
+        range: impl RangeBounds<u64>,
+        hold: Duration,
+    ) -> AppResult<usize> {
+        let Some(cold) = self.cold.as_ref() else {
+            return Err(anyhow::anyhow!("cold tier is not enabled").into());
+        };
+        let Some(handle) = self.get_handle(nsid) else {
+            return Ok(0);
+        };
+        let (start, end) = bounds_to_limits(&range);
+        let until = (get_time() + hold).as_secs();
+        self.meta.insert_u64(MetaKey::ColdHold(nsid), until)?;
+        let restored = cold.take(nsid, start, end, |block| handle.restore_block(block))?;
+        handle.update_tree();
+        tracing::info!({ nsid = %nsid, blocks = %restored }, "rehydrated blocks from cold tier");
+        Ok(restored)
+    }
+
+    pub fn tier_status(&self, nsid: &str) -> AppResult<TierStatus> {
+        let (hot_blocks, hot_bytes) = match self.get_handle(nsid) {
+            Some(handle) => handle
+                .blocks(..)
+                .try_fold((0, 0), |(blocks, bytes), block| {
+                    AppResult::Ok((blocks + 1, bytes + block?.byte_len() as u64))
+                })?,
+            None => (0, 0),
         };
-        let end_limit = match range.end_bound().cloned() {
-            Bound::Included(end) => end,
-            Bound::Excluded(end) => end.saturating_sub(1),
-            Bound::Unbounded => u64::MAX,
+        let cold = match self.cold.as_ref() {
+            Some(cold) => cold.segments(nsid).collect::<AppResult<Vec<_>>>()?,
+            None => Vec::new(),
         };
-        let end_key = varints_unsigned_encoded([end_limit]);
+        Ok(TierStatus {
+            hot_blocks,
+            hot_bytes,
+            cold,
+            hold_until: self.cold_hold_until(nsid)?,
+        })
+    }
+
+    /// whether the nsid has a hits partition, it has been seen at some point
+    pub fn has_nsid(&self, nsid: &str) -> bool {
+        self.get_handle(nsid).is_some()
+    }
+
+    #[inline(always)]
+    fn get_handle(&self, nsid: impl AsRef<str>) -> Option<Arc<LexiconHandle>> {
+        if !PartitionKind::is_hits(nsid.as_ref()) {
+            return None;
+        }
+        self.open_handle(nsid.as_ref())
+    }
+
+    // handle of any hits partition (nsid or did), None if it doesnt exist
+    fn open_handle(&self, name: &str) -> Option<Arc<LexiconHandle>> {
+        if let Some(handle) = self.peek_handle(name) {
+            return Some(handle);
+        }
+        if self.unknown.contains(name) {
+            return None;
+        }
+        let generation = self.unknown.generation();
+        if !self.longtail.contains(name) && !self.ks.partition_exists(name) {
+            self.unknown.insert(name, generation);
+            return None;
+        }
+        self.ensure_handle(name)
+            .inspect_err(|err| tracing::error!("cant open partition {name}: {err}"))
+            .ok()
+    }
+
+    #[inline(always)]
+    fn peek_handle(&self, name: &str) -> Option<Arc<LexiconHandle>> {
+        let guard = scc::ebr::Guard::new();
+        self.hits.peek(name, &guard).cloned()
+    }
+
+    // the only place handles are made, see `hits`. only allocates the name
+    // when the handle is new, ingest calls this for every run of events
+    #[inline(always)]
+    fn ensure_handle(&self, name: &str) -> AppResult<Arc<LexiconHandle>> {
+        if let Some(handle) = self.peek_handle(name) {
+            return Ok(handle);
+        }
+        // the entry keeps the slot locked while the partition is opened, so
+        // callers racing for the same name wait for this handle instead of
+        // opening their own
+        match self.hits.entry(SmolStr::new(name)) {
+            scc::hash_index::Entry::Occupied(entry) => Ok(entry.get().clone()),
+            scc::hash_index::Entry::Vacant(entry) => {
+                let handle = Arc::new(self.new_handle(name)?);
+                // the partition exists now
+                self.unknown.invalidate(name);
+                entry.insert_entry(handle.clone());
+                Ok(handle)
+            }
+        }
+    }
 
+    fn new_handle(&self, name: &str) -> AppResult<LexiconHandle> {
+        let handle = self.build_handle(name)?;
+        handle.restore_overlap(self.load_overlap(name)?);
+        Ok(handle)
+    }
+
+    // new nsids start out in the longtail if it is on, an nsid that already
+    // has a partition keeps it
+    fn build_handle(&self, name: &str) -> AppResult<LexiconHandle> {
+        let longtail = self.longtail.contains(name)
+            || (self.longtail.is_enabled()
+                && PartitionKind::is_hits(name)
+                && !self.ks.partition_exists(name));
+        if !longtail {
+            return LexiconHandle::new(&self.ks, name);
+        }
+        if !self.longtail.contains(name) {
+            self.longtail.add(name)?;
+        }
+        Ok(LexiconHandle::longtail(
+            &self.ks,
+            self.longtail.partition(),
+            name,
+        ))
+    }
+
+    /// moves an nsid out of the longtail into a partition of its own.
+    /// returns whether it was in the longtail
+    pub fn promote(&self, nsid: &str) -> AppResult<bool> {
         let Some(handle) = self.get_handle(nsid) else {
-            return Either::Right(std::iter::empty());
-        };
-
-        // let mut ts = CLOCK.now();
-        let map_block = move |(res, current_item_count)| -> AppResult<(Option<_>, usize)> {
-            if current_item_count >= max_items {
-                return Ok((None, current_item_count));
-            }
-            let (key, val) = res?;
-            let mut key_reader = Cursor::new(key);
-            let start_timestamp = key_reader.read_varint::<u64>()?;
-            // let end_timestamp = key_reader.read_varint::<u64>()?;
-            if start_timestamp < start_limit {
-                // tracing::info!(
-                //     "stopped at block with timestamps {start_timestamp}..{end_timestamp} because {start_limit} is greater"
-                // );
-                return Ok((None, current_item_count));
-            }
-            let decoder = handle::ItemDecoder::new(Cursor::new(val), start_timestamp)?;
-            let current_item_count = current_item_count + decoder.item_count();
-            // tracing::info!(
-            //     "took {}ns to get block with size {}",
-            //     ts.elapsed().as_nanos(),
-            //     decoder.item_count()
-            // );
-            // ts = CLOCK.now();
-            Ok((
-                Some(
-                    decoder
-                        .take_while(move |item| {
-                            item.as_ref().map_or(true, |item| {
-                                item.timestamp <= end_limit && item.timestamp >= start_limit
-                            })
-                        })
-                        .map(|res| res.map_err(AppError::from)),
-                ),
-                current_item_count,
-            ))
-        };
-
-        let (blocks, _counted) = handle
-            .read()
-            .range(..end_key)
-            .map(|res| res.map_err(AppError::from))
-            .rev()
-            .fold_while(
-                (Vec::with_capacity(20), 0),
-                |(mut blocks, current_item_count), res| {
-                    use itertools::FoldWhile::*;
+            return Ok(false);
+        };
+        let moved = handle.promote(|batch| self.longtail.batch_remove(batch, nsid))?;
+        let Some(moved) = moved else {
+            return Ok(false);
+        };
+        self.longtail.removed(nsid);
+        tracing::info!("moved {nsid} out of the longtail with {moved} blocks");
+        Ok(true)
+    }
 
-                    match map_block((res, current_item_count)) {
-                        Ok((Some(block), current_item_count)) => {
-                            blocks.push(Ok(block));
-                            Continue((blocks, current_item_count))
-                        }
-                        Ok((None, current_item_count)) => Done((blocks, current_item_count)),
-                        Err(err) => {
-                            blocks.push(Err(err));
-                            Done((blocks, current_item_count))
-                        }
+    // the longtail nsids that crossed the threshold, after a sync so their
+    // new blocks move too
+    fn promote_grown(&self, nsids: &AHashSet<SmolStr>) {
+        for nsid in nsids {
+            if !self.longtail.contains(nsid) {
+                continue;
+            }
+            let grown = self
+                .get_count(nsid)
+                .map(|counts| self.longtail.should_promote(&counts));
+            let res = match grown {
+                Ok(true) => self.promote(nsid).map(drop),
+                Ok(false) => Ok(()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                tracing::error!({ err = %err }, "cant move {nsid} out of the longtail");
+            }
+        }
+    }
+
+    /// how many nsids keep their blocks in `_longtail`
+    pub fn longtail_nsids(&self) -> usize {
+        self.longtail.count()
+    }
+
+    pub fn negative_cache_stats(&self) -> NegativeCacheStats {
+        self.unknown.stats()
+    }
+
+    pub fn ingest_events(&self, events: impl Iterator<Item = EventRecord>) -> AppResult<()> {
+        if !self.is_writable() {
+            return Err(AppError::new(
+                ErrorCode::StorageDegraded,
+                "storage is degraded, not accepting events",
+            ));
+        }
+        let _gate = self.write_gate.read();
+        // counts held by a quiesce that timed out, the flusher gets those
+        // if it runs
+        if !self.counts_cache.is_deferred() {
+            self.flush_counts()?;
+        }
+        let mut seen_events = 0;
+        let watched = self.watchlist.snapshot();
+        let mut watched_events = Vec::new();
+        let mut purges = self.purges.lock();
+        let now = get_time().as_secs();
+        let events = events.collect::<Vec<_>>();
+        // nsids are interleaved in a batch, so we group the whole batch by
+        // reference and clone each distinct nsid once. groups keep the order
+        // their nsid first showed up in, and events keep theirs in a group
+        let mut groups: AHashMap<&str, Vec<usize>> = AHashMap::new();
+        for (idx, e) in events.iter().enumerate() {
+            groups.entry(e.nsid.as_str()).or_default().push(idx);
+        }
+        let mut groups = groups
+            .into_values()
+            .map(|idxs| (events[idxs[0]].nsid.clone(), idxs))
+            .collect::<Vec<_>>();
+        groups.sort_unstable_by_key(|(_, idxs)| idxs[0]);
+        let mut events = events.into_iter().map(Some).collect::<Vec<_>>();
+        for (key, idxs) in groups {
+            if !PartitionKind::is_hits(&key) {
+                tracing::warn!("dropping events for reserved name {key}");
+                continue;
+            }
+            let first_seen = events[idxs[0]]
+                .as_ref()
+                .map_or(0, |first| self.quantize(first.timestamp));
+            let chunk = idxs.into_iter().filter_map(|idx| events[idx].take());
+            let before = self.get_count(&key)?;
+            let mut counts = before.clone();
+            let mut hours = Vec::with_capacity(1);
+            self.ensure_handle(&key)?.queue(chunk.map(|mut e| {
+                e.timestamp = self.quantize(e.timestamp);
+                let hour = ActiveNsids::hour_of(e.timestamp);
+                if !hours.contains(&hour) {
+                    hours.push(hour);
+                }
+                // the did is only used here, it isnt stored with the hit
+                let did = e.did.take();
+                e.rkey = None;
+                if e.op == HitOp::Delete
+                    && did
+                        .as_ref()
+                        .is_some_and(|did| purges.observe(did, e.timestamp))
+                {
+                    e.op = HitOp::Purge;
+                }
+                // increment count
+                counts.last_seen = e.timestamp;
+                counts.observe(e.op);
+                seen_events += 1;
+                if let Some(did) = did {
+                    if watched.as_ref().is_some_and(|dids| dids.contains(&did)) {
+                        watched_events.push((did_partition(&did, &key), e.clone()));
                     }
-                },
-            )
-            .into_inner();
+                }
+                e
+            }));
+            self.active.observe(&key, &hours);
+            self.update_count(&key, &before, &counts)?;
+            if before == NsidCounts::default() {
+                self.onboard(&key, first_seen, &counts);
+            }
+            self.alerts.observe(&key, &counts, now);
+            if self.event_broadcaster.receiver_count() > 0 {
+                let _ = self.event_broadcaster.send((key, counts));
+            }
+        }
+        self.eps.observe(seen_events);
+        if seen_events > 0 {
+            self.last_event_at
+                .store(get_time().as_secs(), AtomicOrdering::Relaxed);
+        }
+        self.ingested
+            .fetch_add(seen_events as u64, AtomicOrdering::Relaxed);
+        if !watched_events.is_empty() {
+            self.ingest_watched(watched_events)?;
+        }
+        Ok(())
+    }
 
-        // tracing::info!(
-        //     "got blocks with size {}, item count {counted}",
-        //     blocks.len()
-        // );
+    /// what hit timestamps are rounded to, see `DbConfig::timestamp_granularity`
+    pub fn timestamp_granularity(&self) -> u64 {
+        self.cfg.timestamp_granularity.max(1)
+    }
 
-        Either::Left(blocks.into_iter().rev().flatten().flatten())
+    fn quantize(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.timestamp_granularity()
     }
 
-    pub fn tracking_since(&self) -> AppResult<u64> {
-        // HACK: we should actually store when we started tracking but im lazy
-        // this should be accurate enough
-        let Some(handle) = self.get_handle("app.bsky.feed.like") else {
-            return Ok(0);
+    // a failure here only loses the announcement, not the events
+    fn onboard(&self, nsid: &SmolStr, first_seen: u64, counts: &NsidCounts) {
+        let new = NewNsid {
+            nsid: nsid.clone(),
+            first_seen,
+            initial_count: counts.count,
         };
-        let Some((timestamps_raw, _)) = handle.read().first_key_value()? else {
-            return Ok(0);
+        if let Err(err) = self.onboarding.observe(new) {
+            tracing::error!("cant record new nsid {nsid} for onboarding: {err}");
+        }
+    }
+
+    // same as the normal path, but into the did partitions and without
+    // broadcasting, these are our own accounts so there arent many
+    fn ingest_watched(&self, mut events: Vec<(SmolStr, EventRecord)>) -> AppResult<()> {
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        for (partition, chunk) in events
+            .into_iter()
+            .chunk_by(|(partition, _)| partition.clone())
+            .into_iter()
+        {
+            let mut counts = self.get_did_count(&partition)?;
+            self.ensure_handle(&partition)?.queue(chunk.map(|(_, e)| {
+                counts.last_seen = e.timestamp;
+                counts.observe(e.op);
+                e
+            }));
+            let res = self.did_counts.insert(&partition, &counts);
+            match &res {
+                Ok(_) => self.health.observe_ok(WriteOp::DidCounts),
+                Err(err) => self.health.observe_err(WriteOp::DidCounts, err),
+            }
+            res?;
+        }
+        Ok(())
+    }
+
+    fn get_did_count(&self, partition: &str) -> AppResult<NsidCounts> {
+        Ok(self.did_counts.get(partition)?.unwrap_or_default())
+    }
+
+    /// distinct nsids seen in every hour of `start..=end` that had any
+    pub fn active_nsids(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> AppResult<BTreeMap<u64, AHashSet<SmolStr>>> {
+        self.active.range(start, end)
+    }
+
+    /// per nsid counts recorded for a watched did
+    pub fn get_did_counts(&self, did: &str) -> AppResult<Vec<(SmolStr, NsidCounts)>> {
+        let prefix = did_prefix(did);
+        self.did_counts
+            .prefix(&prefix)
+            .map_ok(|(partition, counts)| (SmolStr::new(&partition[prefix.len()..]), counts))
+            .collect()
+    }
+
+    pub fn get_did_hits(
+        &self,
+        did: &str,
+        nsid: &str,
+        range: impl RangeBounds<u64> + std::fmt::Debug,
+        max_items: usize,
+    ) -> HitsPage<impl Iterator<Item = AppResult<handle::Item>>> {
+        let snapshot = self
+            .open_handle(&did_partition(did, nsid))
+            .map(|handle| handle.pin_snapshot());
+        self.hits_of(snapshot, range, max_items, None)
+    }
+
+    #[inline(always)]
+    pub fn is_watched(&self, did: &str) -> bool {
+        self.watchlist.contains(did)
+    }
+
+    /// labels of the nsid, None if it has none
+    pub fn labels(&self, nsid: &str) -> Option<LabelMap> {
+        self.labels.get(nsid)
+    }
+
+    /// labels of every labeled nsid
+    pub fn all_labels(&self) -> ArcRefCnt<AHashMap<SmolStr, LabelMap>> {
+        self.labels.all()
+    }
+
+    /// replaces the labels of the nsid, an empty map removes them. check
+    /// them with `validate_labels` first
+    pub fn set_labels(&self, nsid: &str, labels: LabelMap) -> AppResult<()> {
+        self.labels.set(nsid, labels)?;
+        self.events_changed();
+        Ok(())
+    }
+
+    pub fn remove_labels(&self, nsid: &str) -> AppResult<bool> {
+        let removed = self.labels.remove(nsid)?;
+        if removed {
+            self.events_changed();
+        }
+        Ok(removed)
+    }
+
+    /// label changes as they are made, an empty map when labels were removed
+    pub fn label_changes(&self) -> broadcast::Receiver<(SmolStr, LabelMap)> {
+        self.labels.subscribe()
+    }
+
+    /// new nsids waiting to be announced, see `webhook`
+    pub fn onboarding(&self) -> &Onboarding {
+        &self.onboarding
+    }
+
+    /// alert rules and the alerts they raised, see `webhook` for delivery
+    pub fn alerts(&self) -> &Alerts {
+        &self.alerts
+    }
+
+    /// checks the alert rules with a window against the hits of every
+    /// nsid they match in the last `window` seconds, and forgets alerts
+    /// that resolved long ago
+    pub fn evaluate_alert_windows(&self) -> AppResult<()> {
+        let now = get_time().as_secs();
+        self.alerts.prune(now)?;
+        let rules = self.alerts.windowed_rules();
+        if rules.is_empty() {
+            return Ok(());
+        }
+        for entry in self.get_counts() {
+            let (nsid, total) = entry?;
+            for rule in rules.iter().filter(|rule| rule.matches(&nsid)) {
+                let start = now.saturating_sub(rule.window.unwrap_or_default());
+                // nothing of it can be in the window
+                let counts = match total.last_seen < start {
+                    true => NsidCounts::default(),
+                    false => self.window_counts(&nsid, start, now + 1)?,
+                };
+                self.alerts
+                    .evaluate(rule, &nsid, rule.metric.of(&counts), now);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn watchlist(&self) -> Vec<SmolStr> {
+        self.watchlist.list()
+    }
+
+    pub fn watch(&self, did: &str) -> AppResult<WatchResult> {
+        self.watchlist.add(did)
+    }
+
+    pub fn unwatch(&self, did: &str) -> AppResult<bool> {
+        self.watchlist.remove(did)
+    }
+
+    // every change to `counts` goes through here so the totals follow it.
+    // `before` is what the nsid had until now
+    fn update_count(&self, nsid: &str, before: &NsidCounts, after: &NsidCounts) -> AppResult<()> {
+        self.store_count(nsid, after)?;
+        self.totals.lock().apply(before, after);
+        self.events_changed();
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn store_count(&self, nsid: &str, counts: &NsidCounts) -> AppResult<()> {
+        if self.is_quiesced() || self.counts_cache.is_deferred() {
+            self.counts_cache.insert(nsid, counts.clone(), true);
+            return Ok(());
+        }
+        let res = self.counts.insert(nsid, counts);
+        match &res {
+            Ok(_) => {
+                self.health.observe_ok(WriteOp::Counts);
+                self.counts_cache.insert(nsid, counts.clone(), false);
+            }
+            Err(err) => self.health.observe_err(WriteOp::Counts, err),
+        }
+        res
+    }
+
+    /// overwrites the counts of an nsid, used when migrating
+    pub fn put_count(&self, nsid: &str, counts: &NsidCounts) -> AppResult<()> {
+        let before = self.get_count(nsid)?;
+        self.update_count(nsid, &before, counts)
+    }
+
+    #[inline(always)]
+    pub fn totals(&self) -> Totals {
+        *self.totals.lock()
+    }
+
+    /// totals as of the last sync
+    pub fn stored_totals(&self) -> AppResult<Option<Totals>> {
+        let Some(raw) = self.meta.get(MetaKey::Totals)? else {
+            return Ok(None);
         };
-        let mut timestamp_reader = Cursor::new(timestamps_raw);
-        timestamp_reader
-            .read_varint::<u64>()
-            .map_err(AppError::from)
+        Ok(Some(rkyv::from_bytes::<Totals, Error>(&raw)?))
+    }
+
+    fn store_totals(&self, totals: &Totals) -> AppResult<()> {
+        self.meta
+            .insert(MetaKey::Totals, rkyv::to_bytes::<Error>(totals)?.as_slice())
+    }
+
+    /// sums the counts of every nsid
+    pub fn recount_totals(&self) -> AppResult<Totals> {
+        let mut totals = Totals::default();
+        for res in self.get_counts() {
+            let (_, counts) = res?;
+            totals.add(&counts);
+        }
+        Ok(totals)
+    }
+
+    /// replaces the running totals with a recount, returns what they were
+    pub fn reconcile_totals(&self) -> AppResult<Totals> {
+        // keep ingest out so nothing is counted twice or missed
+        let _gate = self.write_gate.write();
+        let totals = self.recount_totals()?;
+        self.store_totals(&totals)?;
+        Ok(std::mem::replace(&mut *self.totals.lock(), totals))
+    }
+
+    pub fn get_count(&self, nsid: &str) -> AppResult<NsidCounts> {
+        if let Some(counts) = self.counts_cache.get(nsid) {
+            return Ok(counts);
+        }
+        Ok(self.counts.get(nsid)?.unwrap_or_default())
+    }
+
+    /// whether the previous run shut down cleanly, false if it left no
+    /// shutdown report or one with errors or unsynced items
+    #[inline(always)]
+    pub fn is_clean_start(&self) -> bool {
+        self.clean_start
+    }
+
+    /// what the final sync left behind, to be written with `ShutdownReport::write`
+    pub fn shutdown_report(
+        &self,
+        phases: ShutdownPhases,
+        mut errors: Vec<String>,
+    ) -> ShutdownReport {
+        let guard = scc::ebr::Guard::new();
+        let buffered = self
+            .hits
+            .iter(&guard)
+            .map(|(nsid, handle)| (nsid.clone(), handle.item_count()))
+            .filter(|(_, items)| *items > 0)
+            .collect();
+        let cursor = self.stored_cursor().unwrap_or_else(|err| {
+            errors.push(format!("cant read cursor: {err}"));
+            None
+        });
+        ShutdownReport {
+            shut_down_at: get_time().as_secs(),
+            events_ingested: self.ingested.load(AtomicOrdering::Relaxed),
+            buffered,
+            cursor,
+            sync_generation: self.sync_generation(),
+            phases,
+            errors,
+        }
+    }
+
+    /// items waiting in memory for sync, across all nsids
+    /// counts an event that was dropped as a duplicate before ingest
+    #[inline(always)]
+    pub fn observe_duplicate(&self) {
+        self.duplicates_dropped
+            .fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// events dropped as duplicates since we started, see `DedupWindow`
+    #[inline(always)]
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.duplicates_dropped.load(AtomicOrdering::Relaxed)
+    }
+
+    /// unix seconds of when events were last ingested, None before the first
+    #[inline(always)]
+    pub fn last_event_at(&self) -> Option<u64> {
+        let at = self.last_event_at.load(AtomicOrdering::Relaxed);
+        (at > 0).then_some(at)
+    }
+
+    #[inline(always)]
+    pub fn opened_at(&self) -> u64 {
+        self.opened_at
+    }
+
+    /// the connection status the jetstream client should report to
+    #[inline(always)]
+    pub fn upstream(&self) -> UpstreamStatus {
+        self.upstream.clone()
+    }
+
+    #[inline(always)]
+    pub fn open_partitions(&self) -> usize {
+        self.hits.len()
+    }
+
+    #[inline(always)]
+    pub fn disk_size(&self) -> u64 {
+        self.ks.disk_space()
+    }
+
+    pub fn buffered_items(&self) -> usize {
+        let guard = scc::ebr::Guard::new();
+        self.hits
+            .iter(&guard)
+            .map(|(_, handle)| handle.item_count())
+            .sum()
+    }
+
+    /// how long to wait until the next sync, for the current load
+    pub fn next_sync_interval(&self) -> Duration {
+        self.pacer.next(self.buffered_items(), self.eps())
+    }
+
+    /// the last interval `next_sync_interval` picked, and its inputs
+    pub fn sync_pace(&self) -> Option<SyncPaceStatus> {
+        self.pacer.last()
+    }
+
+    /// flush state of a loaded nsid, None if nothing was ingested for it yet
+    pub fn flush_status(&self, nsid: &str) -> Option<FlushStatus> {
+        self.hits.peek_with(nsid, |_, handle| FlushStatus {
+            last_flushed: handle.last_flushed(),
+            pending_items: handle.item_count(),
+        })
+    }
+
+    pub fn get_counts(&self) -> impl Iterator<Item = AppResult<(SmolStr, NsidCounts)>> {
+        self.get_counts_with_prefix("")
+    }
+
+    /// counts of the nsids starting with `prefix`, a range scan of `_counts`
+    pub fn get_counts_with_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl Iterator<Item = AppResult<(SmolStr, NsidCounts)>> {
+        // the ones that arent written yet, nsids that are new have no row
+        let dirty = self
+            .counts_cache
+            .dirty()
+            .into_iter()
+            .filter(|(nsid, _)| nsid.starts_with(prefix))
+            .map(AppResult::Ok)
+            .collect_vec();
+        self.counts
+            .prefix(prefix)
+            .merge_join_by(dirty, |stored, dirty| match (stored, dirty) {
+                (Ok((stored, _)), Ok((dirty, _))) => stored.cmp(dirty),
+                (Err(_), _) => std::cmp::Ordering::Less,
+                (_, Err(_)) => std::cmp::Ordering::Greater,
+            })
+            .map(|counts| match counts {
+                EitherOrBoth::Both(_, dirty) | EitherOrBoth::Right(dirty) => dirty,
+                EitherOrBoth::Left(stored) => stored,
+            })
+    }
+
+    /// writes the counts of every nsid as a counts dump, returns how many
+    pub fn export_counts<W: std::io::Write>(&self, writer: W) -> AppResult<(usize, W)> {
+        let mut dump = counts_dump::CountsDumpWriter::new(writer)?;
+        let mut exported = 0;
+        for res in self.get_counts() {
+            let (nsid, counts) = res?;
+            dump.write(&nsid, &counts)?;
+            exported += 1;
+        }
+        Ok((exported, dump.finish()?))
+    }
+
+    /// merges a counts dump into our counts. the whole dump is read before
+    /// anything is written, so a broken one changes nothing. returns how many
+    /// nsids were imported
+    pub fn import_counts(
+        &self,
+        reader: impl std::io::Read,
+        merge: CountsMerge,
+    ) -> AppResult<usize> {
+        let entries = counts_dump::CountsDumpReader::new(reader)?.collect::<AppResult<Vec<_>>>()?;
+        // keep ingest out so counts it adds arent lost to the merge
+        let _gate = self.write_gate.write();
+        let mut imported = 0;
+        for (nsid, counts) in entries {
+            if !PartitionKind::is_hits(&nsid) {
+                tracing::warn!("skipping counts for reserved name {nsid}");
+                continue;
+            }
+            let before = self.get_count(&nsid)?;
+            let after = merge.apply(&before, counts);
+            self.update_count(&nsid, &before, &after)?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+
+    pub fn get_nsids(&self) -> impl Iterator<Item = StrView> {
+        let partitions = self
+            .ks
+            .list_partitions()
+            .into_iter()
+            .filter(|k| PartitionKind::is_hits(k))
+            .collect::<Vec<_>>();
+        // a crash while moving out of the longtail can leave both
+        let names = partitions
+            .iter()
+            .map(|name| &**name)
+            .collect::<AHashSet<_>>();
+        let longtail = self
+            .longtail
+            .list()
+            .into_iter()
+            .filter(|nsid| !names.contains(nsid.as_str()))
+            .map(|nsid| StrView::from(nsid.as_str()))
+            .collect::<Vec<_>>();
+        partitions.into_iter().chain(longtail)
+    }
+
+    /// decodes every block of an nsid (both tiers) and checks it against its
+    /// key and its neighbours. problems are collected instead of returned,
+    /// blocks that overlap are also recorded for compaction to merge
+    pub fn verify(&self, nsid: &str) -> AppResult<BlockCheck> {
+        let mut check = BlockCheck::default();
+        let Some(handle) = self.get_handle(nsid) else {
+            return Ok(check);
+        };
+        // blocks come newest first
+        let mut newer: Option<handle::BlockKey> = None;
+        for block in self.tiered_blocks(&handle.pin_snapshot(), 0, u64::MAX, true) {
+            let block = block?;
+            let key = block.key();
+            check.blocks += 1;
+            if key.start > key.end {
+                check
+                    .problems
+                    .push(format!("block {key:?}: start is after end"));
+            }
+            if let Some(newer) = newer.filter(|newer| newer.start < key.end) {
+                check
+                    .problems
+                    .push(format!("block {key:?}: overlaps with {newer:?}"));
+                check.overlap = Some(match check.overlap {
+                    Some((start, end)) => (start.min(key.start), end.max(key.end)),
+                    None => (key.start, key.end),
+                });
+            }
+            newer = Some(key);
+
+            let decoder = match block.decoder() {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    check.problems.push(format!("block {key:?}: {err}"));
+                    continue;
+                }
+            };
+            let expected = decoder.item_count();
+            let mut decoded = 0;
+            let mut last = key.start;
+            for item in decoder {
+                let item = match item {
+                    Ok(item) => item,
+                    Err(err) => {
+                        check.problems.push(format!("block {key:?}: {err}"));
+                        break;
+                    }
+                };
+                if !(key.start..=key.end).contains(&item.timestamp) {
+                    check.problems.push(format!(
+                        "block {key:?}: item at {} is outside of the block",
+                        item.timestamp
+                    ));
+                } else if item.timestamp < last {
+                    check.problems.push(format!(
+                        "block {key:?}: item at {} is out of order",
+                        item.timestamp
+                    ));
+                }
+                last = item.timestamp;
+                decoded += 1;
+            }
+            if decoded != expected {
+                check.problems.push(format!(
+                    "block {key:?}: header says {expected} items, decoded {decoded}"
+                ));
+            }
+            check.items += decoded;
+        }
+        // so the next compaction merges them, even if they were written
+        // before sync kept track
+        if let Some(overlap) = check.overlap {
+            self.record_overlap(&handle, overlap)?;
+        }
+        Ok(check)
+    }
+
+    pub fn info(&self) -> AppResult<DbInfo> {
+        self.info_with(InfoOptions::default())
+    }
+
+    /// the item count of every block, newest first. counts come from the
+    /// block headers, no items are decoded and the block cache isnt touched.
+    /// nsids are read in parallel
+    pub fn info_with(&self, options: InfoOptions) -> AppResult<DbInfo> {
+        let deadline = options
+            .budget
+            .map(|budget| std::time::Instant::now() + budget);
+        let out_of_budget =
+            || deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline);
+        let nsids = self
+            .get_nsids()
+            .filter(|nsid| {
+                options
+                    .prefix
+                    .as_deref()
+                    .is_none_or(|prefix| nsid.starts_with(prefix))
+            })
+            .filter_map(|nsid| Some((nsid.to_smolstr(), self.get_handle(&nsid)?)))
+            .collect_vec();
+        // None for nsids left out once the budget ran out
+        let read = nsids
+            .into_par_iter()
+            .map(|(nsid, handle)| {
+                if self.is_shutting_down() {
+                    return Err(AppError::cancelled());
+                }
+                if out_of_budget() {
+                    return Ok(None);
+                }
+                let mut block_lens = Vec::new();
+                for block in handle.blocks(..).rev() {
+                    // checked every so often, a clock read per block adds up
+                    if block_lens.len() % 1024 == 1023 && out_of_budget() {
+                        return Ok(None);
+                    }
+                    block_lens.push(block?.item_count()?);
+                }
+                Ok(Some((nsid, block_lens)))
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        let partial = read.iter().any(Option::is_none);
+        Ok(DbInfo {
+            nsids: read.into_iter().flatten().collect(),
+            disk_size: self.ks.disk_space(),
+            partial,
+        })
+    }
+
+    /// like `info` but for one nsid, with the range of every block. None if
+    /// the nsid has no partition
+    pub fn nsid_info(&self, nsid: &str) -> AppResult<Option<NsidInfo>> {
+        let Some(handle) = self.get_handle(nsid) else {
+            return Ok(None);
+        };
+        let blocks = handle
+            .blocks(..)
+            .map(|block| {
+                let block = block?;
+                let key = block.key();
+                AppResult::Ok(BlockInfo {
+                    start: key.start,
+                    end: key.end,
+                    items: block.item_count()?,
+                    bytes: block.byte_len(),
+                })
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        Ok(Some(NsidInfo {
+            block_count: blocks.len(),
+            items: blocks.iter().map(|block| block.items).sum(),
+            oldest: blocks.iter().map(|block| block.start).min(),
+            newest: blocks.iter().map(|block| block.end).max(),
+            disk_size: handle.disk_space()?,
+            labels: self.labels(nsid),
+            blocks,
+        }))
+    }
+
+    pub fn get_hits(
+        &self,
+        nsid: &str,
+        range: impl RangeBounds<u64> + std::fmt::Debug,
+        max_items: usize,
+    ) -> impl Iterator<Item = AppResult<handle::Item>> {
+        self.hits_of(
+            self.pin_snapshot(nsid),
+            range,
+            max_items,
+            None,
+            Admission::Bypass,
+        )
+        .hits
+    }
+
+    /// same as `get_hits` but also says whether hits were left out because
+    /// of `max_items`, and records per block decode timings into `trace`.
+    /// blocks it reads are cached with `Admission::Admit`
+    pub fn query_hits(
+        &self,
+        nsid: &str,
+        range: impl RangeBounds<u64> + std::fmt::Debug,
+        max_items: usize,
+        trace: Option<&QueryTrace>,
+        admission: Admission,
+    ) -> HitsPage<impl Iterator<Item = AppResult<handle::Item>>> {
+        self.hits_of(
+            self.pin_snapshot(nsid),
+            range,
+            max_items,
+            trace.cloned(),
+            admission,
+        )
+    }
+
+    /// pins what reads of the nsid currently see, so a series of reads (like
+    /// an export done in pages) isnt affected by syncs and compactions that
+    /// run in between. None if the nsid has no hits
+    pub fn pin_snapshot(&self, nsid: &str) -> Option<PinnedSnapshot> {
+        self.get_handle(nsid).map(|handle| handle.pin_snapshot())
+    }
+
+    /// every hit in `range` from a pinned snapshot, oldest first. unlike
+    /// `get_hits` blocks are decoded as the iterator gets to them, so this is
+    /// what long running exports should use. it doesnt fill the block cache
+    pub fn export_hits(
+        &self,
+        snapshot: &PinnedSnapshot,
+        range: impl RangeBounds<u64>,
+    ) -> impl Iterator<Item = AppResult<handle::Item>> {
+        self.scan_hits(snapshot, range, Admission::Bypass)
+    }
+
+    /// `Db::export_hits`, for reads that get repeated (and so should be
+    /// cached) with `Admission::Admit`
+    pub fn scan_hits(
+        &self,
+        snapshot: &PinnedSnapshot,
+        range: impl RangeBounds<u64>,
+        admission: Admission,
+    ) -> impl Iterator<Item = AppResult<handle::Item>> {
+        let (start, end) = bounds_to_limits(&range);
+        let nsid = snapshot.nsid().clone();
+        self.tiered_blocks(snapshot, 0, end, false)
+            .filter_ok(move |block| block.key().end >= start)
+            .flat_map(move |block| {
+                let items =
+                    match block.and_then(|block| block_cache().read(&nsid, block, admission)) {
+                        Ok(items) => items,
+                        Err(err) => return Either::Right(std::iter::once(Err(err))),
+                    };
+                Either::Left(items.filter_ok(move |item| (start..=end).contains(&item.timestamp)))
+            })
+    }
+
+    /// the hits of the blocks that start in `range`, newest first, like the
+    /// raw queries read them. a block is decoded whole once the iterator gets
+    /// to it, so memory is bounded by the block size and not the range
+    pub fn hits_newest_first(
+        &self,
+        snapshot: &PinnedSnapshot,
+        range: impl RangeBounds<u64>,
+    ) -> impl Iterator<Item = AppResult<handle::Item>> {
+        let (start, end) = bounds_to_limits(&range);
+        let nsid = snapshot.nsid().clone();
+        self.tiered_blocks(snapshot, start, end, true)
+            .flat_map(move |block| {
+                let items =
+                    block.and_then(|block| block_cache().items(&nsid, block, Admission::Admit));
+                match items {
+                    Ok(items) => Either::Left(
+                        (0..items.len())
+                            .rev()
+                            .map(move |i| items[i].clone())
+                            .filter(move |item| (start..=end).contains(&item.timestamp))
+                            .map(Ok),
+                    ),
+                    Err(err) => Either::Right(std::iter::once(Err(err))),
+                }
+            })
+    }
+
+    /// digests of the synced hits in `range` of every nsid starting with
+    /// `prefix`, for comparing with another instance. nsids are read one at a
+    /// time from a pinned snapshot, so memory doesnt grow with the range
+    pub fn content_digest(
+        &self,
+        prefix: &str,
+        range: impl RangeBounds<u64>,
+    ) -> AppResult<ContentDigest> {
+        let (start, end) = bounds_to_limits(&range);
+        let nsids = self
+            .get_nsids()
+            .filter(|nsid| nsid.starts_with(prefix))
+            .map(|nsid| nsid.to_smolstr())
+            .collect_vec();
+        let mut digests = Vec::with_capacity(nsids.len());
+        for nsid in nsids {
+            let Some(snapshot) = self.pin_snapshot(&nsid) else {
+                continue;
+            };
+            let mut digest = digest::HitsDigest::default();
+            for hit in self.export_hits(&snapshot, start..=end) {
+                let hit = hit?;
+                digest.add(hit.timestamp, hit.deser()?.op.is_deleted());
+            }
+            // so an nsid without hits in the range matches one that doesnt exist
+            if digest.hits() > 0 {
+                digests.push((nsid, digest));
+            }
+        }
+        Ok(ContentDigest::new(digests))
+    }
+
+    fn hits_of(
+        &self,
+        snapshot: Option<PinnedSnapshot>,
+        range: impl RangeBounds<u64> + std::fmt::Debug,
+        max_items: usize,
+        trace: Option<QueryTrace>,
+        admission: Admission,
+    ) -> HitsPage<impl Iterator<Item = AppResult<handle::Item>>> {
+        let (start_limit, end_limit) = bounds_to_limits(&range);
+
+        let Some(snapshot) = snapshot else {
+            return HitsPage {
+                hits: Either::Right(std::iter::empty()),
+                truncated: false,
+            };
+        };
+        // blocks are picked and opened here, their items are decoded by
+        // whoever reads the page
+        let span = tracing::info_span!(
+            "scan",
+            nsid = %snapshot.nsid(),
+            max_items,
+            blocks_scanned = tracing::field::Empty,
+        )
+        .entered();
+        let nsid = snapshot.nsid().clone();
+        // where blocks overlap a block can have hits newer than the one
+        // read before it, and a block that starts before the range can still
+        // have hits in it. so the scan starts where the overlap does, and
+        // only stops once it gets to blocks that end before everything read
+        let overlap = snapshot
+            .overlap()
+            .filter(|(start, end)| *start <= end_limit && *end >= start_limit);
+        let scan_start = overlap.map_or(start_limit, |(start, _)| start.min(start_limit));
+        let mut oldest_start = u64::MAX;
+
+        // the bool is set if we stopped at a block that is still in range
+        let mut map_block =
+            move |(res, current_item_count)| -> AppResult<(Option<_>, usize, bool)> {
+                let block: BlockRef = match res {
+                    Ok(block) => block,
+                    // we are past the limit, this one wouldnt have been read anyway
+                    Err(_) if current_item_count >= max_items => {
+                        return Ok((None, current_item_count, true));
+                    }
+                    Err(err) => return Err(err),
+                };
+                let key = block.key();
+                if current_item_count >= max_items && (overlap.is_none() || key.end < oldest_start)
+                {
+                    return Ok((None, current_item_count, true));
+                }
+                oldest_start = key.start;
+                let bytes = block.byte_len();
+                let items = block_cache().read(&nsid, block, admission)?;
+                let item_count = items.item_count();
+                let items = items
+                    .skip_while(move |item| {
+                        item.as_ref().is_ok_and(|item| item.timestamp < start_limit)
+                    })
+                    .take_while(move |item| {
+                        item.as_ref()
+                            .map_or(true, |item| item.timestamp <= end_limit)
+                    });
+                let items = match trace.as_ref() {
+                    Some(trace) => Either::Right(trace.wrap(key, item_count, bytes, items)),
+                    None => Either::Left(items),
+                };
+                Ok((Some(items), current_item_count + item_count, false))
+            };
+
+        let (blocks, _counted, truncated) = self
+            .tiered_blocks(&snapshot, scan_start, end_limit, true)
+            .filter(|res| {
+                res.as_ref()
+                    .map_or(true, |block| block.key().end >= start_limit)
+            })
+            .fold_while(
+                (Vec::with_capacity(20), 0, false),
+                |(mut blocks, current_item_count, _), res| {
+                    use itertools::FoldWhile::*;
+
+                    match map_block((res, current_item_count)) {
+                        Ok((Some(block), current_item_count, _)) => {
+                            blocks.push(Ok(block));
+                            Continue((blocks, current_item_count, false))
+                        }
+                        Ok((None, current_item_count, truncated)) => {
+                            Done((blocks, current_item_count, truncated))
+                        }
+                        Err(err) => {
+                            blocks.push(Err(err));
+                            Done((blocks, current_item_count, false))
+                        }
+                    }
+                },
+            )
+            .into_inner();
+        span.record("blocks_scanned", blocks.len());
+
+        // tracing::info!(
+        //     "got blocks with size {}, item count {counted}",
+        //     blocks.len()
+        // );
+
+        let blocks = blocks.into_iter().rev().flatten();
+        let hits = match overlap {
+            None => Either::Left(blocks.flatten()),
+            Some(_) => Either::Right(blocks.kmerge_by(|a, b| match (a, b) {
+                (Ok(a), Ok(b)) => a.timestamp < b.timestamp,
+                (Err(_), _) => true,
+                (_, Err(_)) => false,
+            })),
+        };
+        HitsPage {
+            hits: Either::Left(hits),
+            truncated,
+        }
+    }
+
+    // blocks with start timestamps in `start..=end` from both tiers, oldest
+    // first or newest first if `rev`. when a block is in both tiers
+    // (interrupted move) the fjall copy wins
+    fn tiered_blocks(
+        &self,
+        snapshot: &PinnedSnapshot,
+        start: u64,
+        end: u64,
+        rev: bool,
+    ) -> impl Iterator<Item = AppResult<BlockRef>> {
+        let hot = snapshot.blocks(start..=end);
+        let hot = if rev {
+            Either::Left(hot.rev())
+        } else {
+            Either::Right(hot)
+        };
+        let cold = match self.cold.as_ref() {
+            Some(cold) if cold.has_segments(snapshot.nsid()) => {
+                match cold.blocks(snapshot.nsid(), start, end, rev) {
+                    Ok(blocks) => blocks,
+                    Err(err) => return Either::Right(Either::Left(std::iter::once(Err(err)))),
+                }
+            }
+            _ => return Either::Left(hot),
+        };
+        let merged = hot
+            .merge_join_by(cold, move |hot, cold| match (hot, cold) {
+                (Ok(hot), Ok(cold)) if rev => cold.raw_key().cmp(hot.raw_key()),
+                (Ok(hot), Ok(cold)) => hot.raw_key().cmp(cold.raw_key()),
+                // surface errors right away
+                (Err(_), _) => std::cmp::Ordering::Less,
+                (_, Err(_)) => std::cmp::Ordering::Greater,
+            })
+            .map(|block| match block {
+                EitherOrBoth::Left(block)
+                | EitherOrBoth::Both(block, _)
+                | EitherOrBoth::Right(block) => block,
+            });
+        Either::Right(Either::Right(merged))
+    }
+
+    /// number of hits with timestamps in `start..end`, from both tiers.
+    /// blocks that are fully inside the range only have their header read
+    pub fn count_hits(&self, nsid: &str, start: u64, end: u64) -> AppResult<u64> {
+        let Some(handle) = self.get_handle(nsid) else {
+            return Ok(0);
+        };
+        if end <= start {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for block in self.tiered_blocks(&handle.pin_snapshot(), 0, end - 1, true) {
+            let block = block?;
+            let key = block.key();
+            if key.end < start {
+                break;
+            }
+            if key.start >= start && key.end < end {
+                count += block.item_count()? as u64;
+                continue;
+            }
+            for item in block.into_decoder()? {
+                if (start..end).contains(&item?.timestamp) {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// counts of the hits of `nsid` with timestamps in `start..end`, every
+    /// hit in the range is decoded so keep it short. `last_seen` is that of
+    /// the newest hit in it
+    pub fn window_counts(&self, nsid: &str, start: u64, end: u64) -> AppResult<NsidCounts> {
+        let mut counts = NsidCounts::default();
+        let Some(snapshot) = self.pin_snapshot(nsid) else {
+            return Ok(counts);
+        };
+        for hit in self.export_hits(&snapshot, start..end) {
+            let hit = hit?;
+            counts.last_seen = counts.last_seen.max(hit.timestamp);
+            counts.observe(hit.deser()?.op);
+        }
+        Ok(counts)
+    }
+
+    /// `Db::window_counts` and the part of `start..end` the stored hits
+    /// cover, from the oldest hit to the end of the newest block. hits that
+    /// are still buffered arent counted, the covered range ends before them.
+    /// blocks only know how many hits they have and not of which op, so even
+    /// blocks that are fully in the range are decoded
+    pub fn range_counts(&self, nsid: &str, start: u64, end: u64) -> AppResult<RangeCounts> {
+        let counts = self.window_counts(nsid, start, end)?;
+        let Some(since) = self.nsid_since(nsid)? else {
+            return Ok(RangeCounts {
+                counts,
+                covered: None,
+            });
+        };
+        let newest = match self.pin_snapshot(nsid) {
+            Some(snapshot) => self
+                .tiered_blocks(&snapshot, 0, u64::MAX, true)
+                .next()
+                .transpose()?
+                .map(|block| block.key().end),
+            None => None,
+        };
+        let covered = newest
+            .map(|newest| (since.max(start), newest.saturating_add(1).min(end)))
+            .filter(|(from, to)| from < to);
+        Ok(RangeCounts { counts, covered })
+    }
+
+    /// hits with timestamps in `start..=end` counted per `interval` seconds.
+    /// every bucket overlapping the range is returned, empty ones included,
+    /// so callers should bound `(end - start) / interval`
+    pub fn histogram(
+        &self,
+        nsid: &str,
+        start: u64,
+        end: u64,
+        interval: u64,
+    ) -> AppResult<Vec<HistogramBucket>> {
+        let series = HistogramSeries {
+            start,
+            end,
+            interval,
+        };
+        Ok(self.histograms(nsid, &[series])?.pop().unwrap_or_default())
+    }
+
+    /// `Db::histogram`, read from the daily rollups when its buckets are
+    /// whole days that are all settled, and from blocks otherwise. says which
+    /// one answered
+    pub fn histogram_from_source(
+        &self,
+        nsid: &str,
+        start: u64,
+        end: u64,
+        interval: u64,
+    ) -> AppResult<(Vec<HistogramBucket>, DataSource)> {
+        let boundary = get_time().as_secs().saturating_sub(rollup::SETTLE) / DAY * DAY;
+        let settled = interval > 0
+            && interval % DAY == 0
+            && start % interval == 0
+            && end
+                .checked_add(1)
+                .is_some_and(|end| end % interval == 0 && end <= boundary);
+        // rollups of an nsid we dont know would only be stored zeros
+        if !settled || self.get_handle(nsid).is_none() {
+            let buckets = self.histogram(nsid, start, end, interval)?;
+            return Ok((buckets, DataSource::Blocks));
+        }
+        let mut buckets = (start / interval..=end / interval)
+            .map(|bucket| HistogramBucket {
+                bucket_start: bucket * interval,
+                count: 0,
+                deleted_count: 0,
+                purged_count: 0,
+            })
+            .collect_vec();
+        for day in self.daily_counts(nsid, start..end + 1)? {
+            let bucket = &mut buckets[((day.bucket_start - start) / interval) as usize];
+            bucket.count += day.count;
+            bucket.deleted_count += day.deleted_count;
+            bucket.purged_count += day.purged_count;
+        }
+        Ok((buckets, DataSource::Rollups))
+    }
+
+    /// seconds until a response read from `source` can change, see
+    /// `freshness::max_age`
+    pub fn max_age(&self, source: DataSource) -> u64 {
+        freshness::max_age(
+            source,
+            get_time().as_secs(),
+            self.cfg.counts_flush_interval,
+            self.sync_pace(),
+            self.cfg.min_sync_interval,
+        )
+    }
+
+    /// `Db::histogram` of several series at once. series whose ranges
+    /// overlap are counted in the same pass over the blocks, so a hit is
+    /// decoded once however many series it lands in
+    pub fn histograms(
+        &self,
+        nsid: &str,
+        series: &[HistogramSeries],
+    ) -> AppResult<Vec<Vec<HistogramBucket>>> {
+        let mut histograms = series
+            .iter()
+            .map(|series| {
+                if series.end < series.start || series.interval == 0 {
+                    return Vec::new();
+                }
+                (series.start / series.interval..=series.end / series.interval)
+                    .map(|bucket| HistogramBucket {
+                        bucket_start: bucket * series.interval,
+                        count: 0,
+                        deleted_count: 0,
+                        purged_count: 0,
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+        let Some(snapshot) = self.pin_snapshot(nsid) else {
+            return Ok(histograms);
+        };
+        let span = tracing::info_span!(
+            "histogram",
+            nsid,
+            series = series.len(),
+            hits = tracing::field::Empty,
+        )
+        .entered();
+        let mut hits = 0_u64;
+        // the series with buckets by start, merged into runs of overlapping
+        // ranges that are read in one pass each
+        let mut order = (0..series.len())
+            .filter(|i| !histograms[*i].is_empty())
+            .collect_vec();
+        order.sort_unstable_by_key(|i| series[*i].start);
+        let mut passes: Vec<(u64, u64, Vec<usize>)> = Vec::new();
+        for i in order {
+            let HistogramSeries { start, end, .. } = series[i];
+            match passes.last_mut() {
+                Some((_, pass_end, members)) if start <= *pass_end => {
+                    *pass_end = (*pass_end).max(end);
+                    members.push(i);
+                }
+                _ => passes.push((start, end, vec![i])),
+            }
+        }
+        // dashboards ask for the same histograms over and over
+        for (start, end, members) in passes {
+            for hit in self.scan_hits(&snapshot, start..=end, Admission::Admit) {
+                let hit = hit?;
+                hits += 1;
+                let op = hit.deser()?.op;
+                for i in &members {
+                    let HistogramSeries {
+                        start,
+                        end,
+                        interval,
+                    } = series[*i];
+                    if !(start..=end).contains(&hit.timestamp) {
+                        continue;
+                    }
+                    let first = start / interval;
+                    let bucket = &mut histograms[*i][(hit.timestamp / interval - first) as usize];
+                    match op {
+                        HitOp::Create => bucket.count += 1,
+                        HitOp::Delete => bucket.deleted_count += 1,
+                        HitOp::Purge => bucket.purged_count += 1,
+                    }
+                }
+            }
+        }
+        span.record("hits", hits);
+        Ok(histograms)
+    }
+
+    /// totals of `nsid` per day for the day starts in `days`, which must all
+    /// be settled. days that arent rolled up yet are counted from blocks and
+    /// rolled up
+    fn daily_counts(&self, nsid: &str, days: Range<u64>) -> AppResult<Vec<HistogramBucket>> {
+        let days = self.daily_counts_sourced(nsid, days)?;
+        Ok(days.into_iter().map(|(day, _)| day).collect())
+    }
+
+    /// `Db::daily_counts`, with whether each day was read from its rollup
+    /// (and not counted from blocks just now)
+    fn daily_counts_sourced(
+        &self,
+        nsid: &str,
+        days: Range<u64>,
+    ) -> AppResult<Vec<(HistogramBucket, bool)>> {
+        let mut stored = self
+            .rollups
+            .get(nsid, days.clone())?
+            .into_iter()
+            .map(|(day, bucket)| (day, (bucket, true)))
+            .collect::<BTreeMap<_, _>>();
+        let missing = days
+            .step_by(DAY as usize)
+            .filter(|day| !stored.contains_key(day))
+            .collect_vec();
+        // one pass over the blocks per run of missing days
+        for run in missing.chunk_by(|a, b| b - a == DAY) {
+            let (first, last) = (run[0], run[run.len() - 1]);
+            for bucket in self.histogram(nsid, first, last + DAY - 1, DAY)? {
+                // rollups are only a cache, reads dont fail over them
+                if self.is_writable() && !self.is_quiesced() {
+                    self.rollups.put(nsid, &bucket).unwrap_or_else(
+                        |err| tracing::warn!({ nsid = %nsid, err = %err }, "cant store rollup"),
+                    );
+                }
+                stored.insert(bucket.bucket_start, (bucket, false));
+            }
+        }
+        Ok(stored.into_values().collect())
+    }
+
+    /// hits of `nsid` per day or week over the days `start` and `end` are in.
+    /// settled days come from their rollups. the ones without one yet (rolled
+    /// up on the way) and the days that arent settled are counted from
+    /// blocks, the points they are in are marked as estimated. None if the
+    /// nsid isnt known
+    pub fn rollup(
+        &self,
+        nsid: &str,
+        start: u64,
+        end: u64,
+        granularity: RollupGranularity,
+    ) -> AppResult<Option<Vec<RollupPoint>>> {
+        if self.get_handle(nsid).is_none() {
+            return Ok(None);
+        }
+        if start > end {
+            return Ok(Some(Vec::new()));
+        }
+        let (first_day, last_day) = (start / DAY * DAY, end / DAY * DAY);
+        // days before this are settled
+        let boundary = (get_time().as_secs().saturating_sub(rollup::SETTLE) / DAY * DAY)
+            .clamp(first_day, last_day + DAY);
+        let mut days = self.daily_counts_sourced(nsid, first_day..boundary)?;
+        if boundary <= last_day {
+            let tail = self.histogram(nsid, boundary, last_day + DAY - 1, DAY)?;
+            days.extend(tail.into_iter().map(|day| (day, false)));
+        }
+        Ok(Some(rollup::rollup_points(&days, granularity)))
+    }
+
+    /// hits of `nsid` from `since` to `now` (inclusive) as points of the same
+    /// width, about `points` of them. settled days come from daily rollups and
+    /// the rest from an hourly histogram of the blocks, so the tail of the
+    /// series has finer points than the history before it. None if the nsid
+    /// isnt known
+    pub fn overview(
+        &self,
+        nsid: &str,
+        since: u64,
+        now: u64,
+        points: u64,
+        downsample: Downsample,
+    ) -> AppResult<Option<Vec<OverviewPoint>>> {
+        if self.get_handle(nsid).is_none() {
+            return Ok(None);
+        }
+        if since > now || points == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        let width = (now - since + 1).div_ceil(points);
+        let first_day = since / DAY * DAY;
+        // days before this are settled
+        let boundary = (now.saturating_sub(rollup::SETTLE) / DAY * DAY).max(first_day);
+        let days = self.daily_counts(nsid, first_day..boundary)?;
+        let mut series = rollup::downsample(
+            &days,
+            DAY,
+            width.div_ceil(DAY) * DAY,
+            since,
+            boundary,
+            downsample,
+        );
+        let tail_start = boundary.max(since);
+        let hours = self.histogram(nsid, tail_start, now, active::HOUR)?;
+        series.extend(rollup::downsample(
+            &hours,
+            active::HOUR,
+            width.div_ceil(active::HOUR) * active::HOUR,
+            tail_start,
+            now + 1,
+            downsample,
+        ));
+        Ok(Some(series))
+    }
+
+    /// hits of `nsid` per hour over the trailing day, oldest first. None if
+    /// the nsid isnt known
+    pub fn sparkline(&self, nsid: &str, now: u64) -> AppResult<Option<[u32; SPARKLINE_HOURS]>> {
+        let Some(handle) = self.get_handle(nsid) else {
+            return Ok(None);
+        };
+        let points = handle.sparkline(now, |cutoff| {
+            let mut counted = Sparkline::new(cutoff);
+            let start =
+                (cutoff / active::HOUR + 1).saturating_sub(SPARKLINE_HOURS as u64) * active::HOUR;
+            let snapshot = handle.pin_snapshot();
+            for hit in self.export_hits(&snapshot, start..cutoff) {
+                counted.add(hit?.timestamp, 1);
+            }
+            Ok(counted)
+        })?;
+        Ok(Some(points))
+    }
+
+    /// start of the oldest block of the nsid in either tier, None if it
+    /// has none (yet)
+    pub fn nsid_since(&self, nsid: &str) -> AppResult<Option<u64>> {
+        let Some(handle) = self.get_handle(nsid) else {
+            return Ok(None);
+        };
+        let hot = handle.first_block_key()?.map(|key| key.start);
+        // months sort in order, so the first segment is the oldest
+        let cold = match self.cold.as_ref() {
+            Some(cold) => cold
+                .segments(nsid)
+                .next()
+                .transpose()?
+                .map(|(_, segment)| segment.start),
+            None => None,
+        };
+        Ok(hot.into_iter().chain(cold).min())
+    }
+
+    /// the oldest hit of any nsid, 0 if there are none. it goes over every
+    /// nsid so it is cached for a while, it only moves when old blocks go
+    pub fn tracking_since(&self) -> AppResult<u64> {
+        let cached = *self.tracking_since.lock();
+        if let Some((_, since)) = cached.filter(|(at, _)| at.elapsed() < TRACKING_SINCE_TTL) {
+            return Ok(since);
+        }
+        let mut since = None::<u64>;
+        for nsid in self.get_nsids() {
+            if let Some(start) = self.nsid_since(&nsid)? {
+                since = Some(since.map_or(start, |since| since.min(start)));
+            }
+        }
+        // nothing to cache until the first block is written
+        let Some(since) = since else {
+            return Ok(0);
+        };
+        *self.tracking_since.lock() = Some((CLOCK.now(), since));
+        Ok(since)
+    }
+}
+
+// inclusive start and end limits of a range
+fn bounds_to_limits(range: &impl RangeBounds<u64>) -> (u64, u64) {
+    let start = match range.start_bound().cloned() {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound().cloned() {
+        Bound::Included(end) => end,
+        Bound::Excluded(end) => end.saturating_sub(1),
+        Bound::Unbounded => u64::MAX,
+    };
+    (start, end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(timestamp: u64) -> EventRecord {
+        EventRecord {
+            nsid: SmolStr::new_static("app.bsky.feed.like"),
+            timestamp,
+            op: HitOp::Create,
+            did: None,
+            rkey: None,
+        }
+    }
+
+    #[test]
+    fn test_nsid_syntax() {
+        assert!(is_valid_nsid("app.bsky.feed.post"));
+        assert!(is_valid_nsid("com.example-site.fooBar2"));
+        assert!(!is_valid_nsid("app.bsky"));
+        assert!(!is_valid_nsid("app..feed.post"));
+        assert!(!is_valid_nsid("app.bsky.feed.post-thing"));
+        assert!(!is_valid_nsid("app.bsky.feed.2post"));
+        assert!(!is_valid_nsid("_counts"));
+        assert!(!is_valid_nsid("app.bsky.feed.post "));
+    }
+
+    #[test]
+    fn test_cancelled_compaction_keeps_blocks() {
+        let path =
+            std::env::temp_dir().join(format!("lexicon-tracker-test-{}", std::process::id()));
+        let cancel_token = CancellationToken::new();
+        let db = Db::new(DbConfig::default().path(&path), cancel_token.clone()).unwrap();
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        db.ingest_events((0..10).map(|ts| record(2000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+
+        cancel_token.cancel();
+        let err = db
+            .compact("app.bsky.feed.like", 1000, .., true)
+            .unwrap_err();
+        assert!(err.is_cancelled());
+
+        let hits = db
+            .get_hits("app.bsky.feed.like", .., 100)
+            .collect::<AppResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(hits.len(), 20);
+        assert!(db.info().is_err_and(|err| err.is_cancelled()));
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    fn timestamps(db: &Db, nsid: &str) -> Vec<u64> {
+        db.get_hits(nsid, .., usize::MAX)
+            .map(|hit| hit.unwrap().timestamp)
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_longtail_nsids_share_a_partition() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-longtail-{}",
+            std::process::id()
+        ));
+        let open = || {
+            let mut cfg = DbConfig::default().path(&path);
+            cfg.longtail_threshold = 1000;
+            Db::new(cfg, CancellationToken::new()).unwrap()
+        };
+        // one is a prefix of the other, their keys cant run into each other
+        let (short, long) = ("a.b.c", "a.b.cd");
+        let event = |nsid: &'static str, timestamp| EventRecord {
+            nsid: SmolStr::new_static(nsid),
+            ..record(timestamp)
+        };
+        let db = open();
+        for start in [1000, 2000] {
+            db.ingest_events((0..10).map(|ts| event(short, start + ts)))
+                .unwrap();
+            db.ingest_events((0..5).map(|ts| event(long, start + ts)))
+                .unwrap();
+            db.sync(true).unwrap();
+        }
+        assert!(!db.ks.partition_exists(short));
+        assert!(!db.ks.partition_exists(long));
+        assert_eq!(db.longtail_nsids(), 2);
+        let mut nsids = db.get_nsids().map(|nsid| nsid.to_smolstr()).collect_vec();
+        nsids.sort();
+        assert_eq!(nsids, [short, long]);
+        assert_eq!(timestamps(&db, short).len(), 20);
+        assert_eq!(timestamps(&db, long).len(), 10);
+        assert_eq!(
+            db.get_handle(long).unwrap().first_block_key().unwrap(),
+            Some(handle::BlockKey {
+                start: 1000,
+                end: 1004
+            })
+        );
+
+        db.compact_all(1000, .., true).unwrap();
+        assert_eq!(db.get_handle(short).unwrap().blocks(..).count(), 1);
+        assert_eq!(
+            timestamps(&db, short),
+            (1000..1010).chain(2000..2010).collect_vec()
+        );
+        assert_eq!(timestamps(&db, long).len(), 10);
+        drop(db);
+
+        let db = open();
+        assert!(db.has_nsid(short));
+        assert_eq!(db.longtail_nsids(), 2);
+        assert_eq!(timestamps(&db, long).len(), 10);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_longtail_nsid_is_promoted() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-longtail-promote-{}",
+            std::process::id()
+        ));
+        let open = |threshold| {
+            let mut cfg = DbConfig::default().path(&path);
+            cfg.longtail_threshold = threshold;
+            Db::new(cfg, CancellationToken::new()).unwrap()
+        };
+        let like = "app.bsky.feed.like";
+        let db = open(100);
+        db.ingest_events((0..60).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        assert!(!db.ks.partition_exists(like));
+        let before = db.pin_snapshot(like).unwrap();
+
+        // crossing the threshold moves every block
+        db.ingest_events((0..60).map(|ts| record(2000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        assert!(db.ks.partition_exists(like));
+        assert_eq!(db.longtail_nsids(), 0);
+        assert!(!db.get_handle(like).unwrap().is_longtail());
+        assert_eq!(
+            timestamps(&db, like),
+            (1000..1060).chain(2000..2060).collect_vec()
+        );
+        assert!(db.longtail.partition().is_empty().unwrap());
+        // reads from before still see the blocks where they were
+        assert_eq!(db.export_hits(&before, ..).count(), 60);
+        drop(before);
+        // and new blocks go to its own partition
+        db.ingest_events((0..10).map(|ts| record(3000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(timestamps(&db, like).len(), 130);
+        drop(db);
+
+        let db = open(100);
+        assert_eq!(db.longtail_nsids(), 0);
+        assert_eq!(db.get_nsids().count(), 1);
+        assert_eq!(timestamps(&db, like).len(), 130);
+        drop(db);
+
+        // turning it off moves whats left out on the next sync
+        let db = open(100);
+        let small = EventRecord {
+            nsid: SmolStr::new_static("a.b.c"),
+            ..record(1000)
+        };
+        db.ingest_events(std::iter::once(small.clone())).unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(db.longtail_nsids(), 1);
+        drop(db);
+        let db = open(0);
+        assert_eq!(timestamps(&db, "a.b.c"), [1000]);
+        db.ingest_events(std::iter::once(EventRecord {
+            timestamp: 1001,
+            ..small
+        }))
+        .unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(db.longtail_nsids(), 0);
+        assert!(db.ks.partition_exists("a.b.c"));
+        assert_eq!(timestamps(&db, "a.b.c"), [1000, 1001]);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_internal_partitions_are_skipped() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-internal-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        // garbage that would fail to decode as a block
+        for name in ["_rollup_daily", "_outages", "_tombstones"] {
+            let partition = db
+                .ks
+                .open_partition(name, PartitionCreateOptions::default())
+                .unwrap();
+            partition.insert([0xff; 4], [0xff; 16]).unwrap();
+        }
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.ingest_events(std::iter::once(EventRecord {
+            nsid: SmolStr::new_static("_tombstones"),
+            timestamp: 1000,
+            op: HitOp::Create,
+            did: None,
+            rkey: None,
+        }))
+        .unwrap();
+        db.sync(true).unwrap();
+
+        let nsids = db.get_nsids().map(|nsid| nsid.to_smolstr()).collect_vec();
+        assert_eq!(nsids, vec![SmolStr::new_static("app.bsky.feed.like")]);
+        let info = db.info().unwrap();
+        assert_eq!(info.nsids.len(), 1);
+        db.compact_all(1000, .., true).unwrap();
+        for name in [
+            "_counts",
+            "_meta",
+            "_rollup_daily",
+            "_outages",
+            "_tombstones",
+        ] {
+            assert_eq!(PartitionKind::of(name), PartitionKind::Internal);
+            assert_eq!(db.get_hits(name, .., 100).count(), 0);
+        }
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_active_nsids_union_late_events() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-active-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let hour = 1_700_000_000 / active::HOUR * active::HOUR;
+        let event = |nsid: &'static str, timestamp: u64| EventRecord {
+            nsid: SmolStr::new_static(nsid),
+            timestamp,
+            op: HitOp::Create,
+            did: None,
+            rkey: None,
+        };
+
+        db.ingest_events(
+            [
+                event("app.bsky.feed.like", hour + 10),
+                event("app.bsky.feed.post", hour + 20),
+                event("app.bsky.feed.like", hour + active::HOUR + 5),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        db.sync(true).unwrap();
+        // arrives after the first hour was already flushed
+        db.ingest_events(std::iter::once(event("app.bsky.graph.follow", hour + 30)))
+            .unwrap();
+
+        let active = db.active_nsids(hour, hour + active::HOUR).unwrap();
+        assert_eq!(active[&hour].len(), 3);
+        assert_eq!(active[&(hour + active::HOUR)].len(), 1);
+        db.sync(true).unwrap();
+        let active = db.active_nsids(hour, hour).unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[&hour].len(), 3);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                std::fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_quiesce_snapshot_cycle() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-quiesce-{}",
+            std::process::id()
+        ));
+        let snapshot_path = path.with_extension("snapshot");
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.observe_cursor(1010 * 1_000_000);
+        let state = db.quiesce(Duration::from_secs(60)).unwrap();
+        let QuiesceState::Held { generation, .. } = state else {
+            panic!("expected writes to be held, got {state:?}");
+        };
+        assert_eq!(generation, db.sync_generation());
+        let marker = db.snapshot_marker().unwrap().unwrap();
+        assert_eq!(marker.generation, 1);
+        assert_eq!(marker.cursor, Some(1010 * 1_000_000));
+        let checked = db.check_snapshot().unwrap();
+        assert_eq!(checked.state, SnapshotState::Consistent);
+        assert_eq!(checked.marker, Some(marker.clone()));
+
+        // these stay in memory until we unquiesce
+        db.ingest_events((0..5).map(|ts| record(2000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(db.get_count("app.bsky.feed.like").unwrap().count, 15);
+        copy_dir(&path, &snapshot_path);
+
+        db.unquiesce().unwrap();
+        db.sync(true).unwrap();
+        let hits = db.get_hits("app.bsky.feed.like", .., 100).count();
+        assert_eq!(hits, 15);
+        db.quiesce(Duration::from_secs(60)).unwrap();
+        assert_eq!(db.snapshot_marker().unwrap().unwrap().generation, 2);
+        db.unquiesce().unwrap();
+        assert_eq!(db.get_count("app.bsky.feed.like").unwrap().count, 15);
+        drop(db);
+
+        let snapshot = Db::new(
+            DbConfig::default().path(&snapshot_path),
+            CancellationToken::new(),
+        )
+        .unwrap();
+        let hits = snapshot.get_hits("app.bsky.feed.like", .., 100).count();
+        assert_eq!(hits, 10);
+        assert_eq!(snapshot.get_count("app.bsky.feed.like").unwrap().count, 10);
+        assert_eq!(snapshot.stored_cursor().unwrap(), Some(1010 * 1_000_000));
+        assert_eq!(snapshot.snapshot_marker().unwrap(), Some(marker));
+        drop(snapshot);
+
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_dir_all(&snapshot_path);
+    }
+
+    #[test]
+    fn test_overlapping_blocks() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-overlap-{}",
+            std::process::id()
+        ));
+        let nsid = "app.bsky.feed.like";
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let timestamps = |db: &Db, range: std::ops::RangeInclusive<u64>, max_items| {
+            db.get_hits(nsid, range, max_items)
+                .map(|hit| hit.unwrap().timestamp)
+                .collect_vec()
+        };
+
+        db.ingest_events((1000..1010).map(record)).unwrap();
+        db.sync(true).unwrap();
+        db.ingest_events((1010..1020).map(record)).unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(db.get_handle(nsid).unwrap().overlap(), None);
+        // a late one, this block starts before both of the others ended
+        db.ingest_events((1020..1031).chain([1005]).map(record))
+            .unwrap();
+        db.sync(true).unwrap();
+        let handle = db.get_handle(nsid).unwrap();
+        // from where the block the late hit is in starts
+        assert_eq!(handle.overlap(), Some((1000, 1019)));
+        let keys = handle
+            .blocks(..)
+            .map(|block| block.unwrap().key())
+            .map(|key| (key.start, key.end))
+            .collect_vec();
+        assert_eq!(keys, [(1000, 1009), (1005, 1030), (1010, 1019)]);
+
+        let all = timestamps(&db, 0..=u64::MAX, usize::MAX);
+        let mut expected = (1000..1031u64).chain([1005]).collect_vec();
+        expected.sort();
+        assert_eq!(all, expected);
+        // the newest hits are in the block that starts second
+        let newest = timestamps(&db, 0..=u64::MAX, 5);
+        assert_eq!(
+            newest[newest.len() - 5..],
+            [1026u64, 1027, 1028, 1029, 1030]
+        );
+        // and so are some from before the block that starts in range
+        let since = timestamps(&db, 1012..=u64::MAX, usize::MAX);
+        assert_eq!(since, (1012..1031u64).collect_vec());
+
+        // a restart keeps it
+        drop(handle);
+        drop(db);
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        assert_eq!(db.get_handle(nsid).unwrap().overlap(), Some((1000, 1019)));
+        let check = db.verify(nsid).unwrap();
+        assert!(!check.problems.is_empty());
+        assert_eq!(check.overlap, Some((1000, 1030)));
+
+        assert_eq!(db.compact_overlaps(1000).unwrap(), 1);
+        let handle = db.get_handle(nsid).unwrap();
+        assert_eq!(handle.overlap(), None);
+        assert_eq!(handle.blocks(..).count(), 1);
+        let check = db.verify(nsid).unwrap();
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+        assert_eq!(check.items, 32);
+        assert_eq!(timestamps(&db, 0..=u64::MAX, usize::MAX), expected);
+        assert_eq!(db.compact_overlaps(1000).unwrap(), 0);
+
+        drop(handle);
+        drop(db);
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        assert_eq!(db.get_handle(nsid).unwrap().overlap(), None);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_purges_are_counted_separately() {
+        let path =
+            std::env::temp_dir().join(format!("lexicon-tracker-test-purge-{}", std::process::id()));
+        let mut cfg = DbConfig::default().path(&path);
+        cfg.purge_threshold = 3;
+        let db = Db::new(cfg, CancellationToken::new()).unwrap();
+
+        let delete = |did: &'static str, timestamp| EventRecord {
+            op: HitOp::Delete,
+            did: Some(SmolStr::new_static(did)),
+            ..record(timestamp)
+        };
+        // the first two of the burst are below the threshold
+        db.ingest_events((0..5).map(|ts| delete("did:plc:gone", 1000 + ts)))
+            .unwrap();
+        db.ingest_events([delete("did:plc:other", 1010)].into_iter())
+            .unwrap();
+        db.ingest_events([record(1011)].into_iter()).unwrap();
+        db.sync(true).unwrap();
+
+        let counts = db.get_count("app.bsky.feed.like").unwrap();
+        assert_eq!(
+            (counts.count, counts.deleted_count, counts.purged_count),
+            (1, 3, 3)
+        );
+        let purged = db
+            .get_hits("app.bsky.feed.like", .., 100)
+            .filter(|hit| hit.as_ref().unwrap().deser().unwrap().op == HitOp::Purge)
+            .count();
+        assert_eq!(purged, 3);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_pinned_export_is_consistent() {
+        let path =
+            std::env::temp_dir().join(format!("lexicon-tracker-test-pin-{}", std::process::id()));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        let expected = (0..5)
+            .flat_map(|block| (0..10).map(move |ts| 1000 + block * 100 + ts))
+            .collect_vec();
+        for chunk in expected.chunks(10) {
+            db.ingest_events(chunk.iter().map(|ts| record(*ts)))
+                .unwrap();
+            db.sync(true).unwrap();
+        }
+
+        let snapshot = db.pin_snapshot(nsid).unwrap();
+        let mut export = db.export_hits(&snapshot, ..);
+        let mut exported = export
+            .by_ref()
+            .take(15)
+            .map(|hit| hit.unwrap().timestamp)
+            .collect_vec();
+
+        // rewrite every block the export is reading, and add new ones
+        db.compact(nsid, 7, .., true).unwrap();
+        db.ingest_events((0..10).map(|ts| record(2000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        db.major_compact().unwrap();
+
+        exported.extend(export.map(|hit| hit.unwrap().timestamp));
+        assert_eq!(exported, expected);
+
+        // while new reads see the churned partition
+        let hits = db.get_hits(nsid, .., 1000).count();
+        assert_eq!(hits, 60);
+
+        drop(snapshot);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_privacy_mode_rounds_timestamps() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-privacy-{}",
+            std::process::id()
+        ));
+        let mut db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        // turned on later, the precise hits stay as they are
+        db.cfg.timestamp_granularity = 60;
+        db.ingest_events((0..10).map(|ts| record(2000 + ts * 30)))
+            .unwrap();
+        db.sync(true).unwrap();
+
+        let expected = (0..10)
+            .map(|ts| 1000 + ts)
+            .chain((0..10).map(|ts| (2000 + ts * 30) / 60 * 60))
+            .collect_vec();
+        let timestamps = |db: &Db| {
+            let mut timestamps = db
+                .get_hits(nsid, .., 100)
+                .map(|hit| hit.unwrap().timestamp)
+                .collect_vec();
+            timestamps.sort_unstable();
+            timestamps
+        };
+        let granularities = |db: &Db| {
+            db.get_handle(nsid)
+                .unwrap()
+                .blocks(..)
+                .map(|block| block.unwrap().decoder().unwrap().granularity())
+                .collect_vec()
+        };
+        assert_eq!(timestamps(&db), expected);
+        assert_eq!(granularities(&db), [1, 60]);
+        assert_eq!(db.get_count(nsid).unwrap().last_seen, 2220);
+
+        // compacting blocks of both kinds falls back to plain deltas
+        db.compact(nsid, 100, .., true).unwrap();
+        assert_eq!(timestamps(&db), expected);
+        assert_eq!(granularities(&db), [1]);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_content_digest_ignores_block_layout() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-digest-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        for block in 0..5 {
+            db.ingest_events((0..10).map(|ts| record(1000 + block * 100 + ts)))
+                .unwrap();
+            db.sync(true).unwrap();
+        }
+        let before = db.content_digest("", ..).unwrap();
+        assert_eq!(before.nsids[nsid].hits, 50);
+
+        db.compact(nsid, 7, .., true).unwrap();
+        assert_eq!(db.content_digest("", ..).unwrap(), before);
+
+        db.ingest_events([record(2000)]).unwrap();
+        db.sync(true).unwrap();
+        assert_ne!(db.content_digest("", ..).unwrap(), before);
+        // the new hit is outside of this window
+        assert_eq!(
+            db.content_digest("app.bsky", 1000..=1500).unwrap().digest,
+            before.digest
+        );
+        assert!(
+            db.content_digest("app.bsky.graph", ..)
+                .unwrap()
+                .nsids
+                .is_empty()
+        );
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_histogram_has_every_bucket() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-histogram-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        // two runs of hits with a gap in between
+        db.ingest_events((1000..1010).map(record)).unwrap();
+        db.sync(true).unwrap();
+        db.ingest_events((1100..1105).map(record)).unwrap();
+        db.ingest_events([EventRecord {
+            op: HitOp::Delete,
+            ..record(1101)
+        }])
+        .unwrap();
+        db.sync(true).unwrap();
+
+        let buckets = db.histogram(nsid, 1005, 1119, 20).unwrap();
+        let counts = buckets
+            .iter()
+            .map(|bucket| (bucket.bucket_start, bucket.count, bucket.deleted_count))
+            .collect_vec();
+        assert_eq!(
+            counts,
+            [
+                (1000, 5, 0),
+                (1020, 0, 0),
+                (1040, 0, 0),
+                (1060, 0, 0),
+                (1080, 0, 0),
+                (1100, 5, 1),
+            ]
+        );
+        assert_eq!(
+            db.histogram("app.bsky.feed.post", 0, 59, 60).unwrap().len(),
+            1
+        );
+        assert!(db.histogram(nsid, 10, 0, 60).unwrap().is_empty());
+
+        // overlapping and disjoint series come out like separate histograms
+        let series = [
+            HistogramSeries {
+                start: 900,
+                end: 1199,
+                interval: 60,
+            },
+            HistogramSeries {
+                start: 1005,
+                end: 1119,
+                interval: 20,
+            },
+            HistogramSeries {
+                start: 1102,
+                end: 1103,
+                interval: 1,
+            },
+            HistogramSeries {
+                start: 5000,
+                end: 5059,
+                interval: 60,
+            },
+        ];
+        let histograms = db.histograms(nsid, &series).unwrap();
+        for (series, histogram) in series.iter().zip(histograms) {
+            assert_eq!(
+                histogram,
+                db.histogram(nsid, series.start, series.end, series.interval)
+                    .unwrap()
+            );
+        }
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_concurrent_syncs_keep_every_item() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-concurrent-sync-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+        let total = 20_000;
+
+        let ingested = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let (db, ingested) = (&db, &ingested);
+                scope.spawn(move || {
+                    while !ingested.load(AtomicOrdering::Acquire) {
+                        db.sync(i % 2 == 0).unwrap();
+                    }
+                });
+            }
+            for chunk in &(1..=total).chunks(100) {
+                db.ingest_events(chunk.map(record)).unwrap();
+            }
+            ingested.store(true, AtomicOrdering::Release);
+        });
+        db.sync(true).unwrap();
+
+        let hits = db
+            .get_hits(nsid, .., usize::MAX)
+            .map(|hit| hit.unwrap().timestamp)
+            .sorted()
+            .collect_vec();
+        assert_eq!(hits, (1..=total).collect_vec());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_try_sync_refuses_while_syncing() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-try-sync-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        db.ingest_events((1..=10).map(record)).unwrap();
+
+        let syncing = db.syncing.lock();
+        assert!(db.try_sync(true).is_none());
+        drop(syncing);
+        let stats = db.try_sync(true).unwrap().unwrap();
+        assert_eq!((stats.blocks, stats.items), (1, 10));
+        let stats = db.sync(true).unwrap();
+        assert_eq!((stats.blocks, stats.items), (0, 0));
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_old_blocks_are_recompressed_once() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-recompress-{}",
+            std::process::id()
+        ));
+        let nsid = "app.bsky.feed.like";
+        let mut cfg = DbConfig::default().path(&path);
+        cfg.recompress_after = Some(Duration::from_secs(60 * 60 * 24));
+        let db = Db::new(cfg, CancellationToken::new()).unwrap();
+        for block in 0..3 {
+            db.ingest_events((0..200).map(|ts| record(1000 + block * 1000 + ts)))
+                .unwrap();
+            db.sync(true).unwrap();
+        }
+        // too new to be recompressed
+        let now = get_time().as_secs();
+        db.ingest_events((0..10).map(|ts| record(now - 10 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        let before = timestamps(&db, nsid);
+
+        let stats = db.recompress_old().unwrap();
+        assert_eq!(stats.blocks, 3);
+        assert!(stats.saved() > 0, "{stats:?}");
+        let handle = db.get_handle(nsid).unwrap();
+        let tiers = handle
+            .blocks(..)
+            .map(|block| block.unwrap().tier().unwrap())
+            .collect_vec();
+        assert_eq!(tiers, [Tier::Zstd, Tier::Zstd, Tier::Zstd, Tier::Plain]);
+        assert_eq!(timestamps(&db, nsid), before);
+        let check = db.verify(nsid).unwrap();
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+        assert_eq!(check.items, 610);
+
+        // already done
+        assert_eq!(db.recompress_old().unwrap(), RecompressStats::default());
+
+        // compaction reads them and writes plain blocks again
+        db.compact(nsid, 1000, ..now - 60, false).unwrap();
+        let handle = db.get_handle(nsid).unwrap();
+        assert_eq!(handle.blocks(..).count(), 2);
+        assert_eq!(timestamps(&db, nsid), before);
+        assert_eq!(db.recompress_old().unwrap().blocks, 1);
+
+        drop(handle);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_nsid_info_lists_blocks() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-nsid-info-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        for block in 0..3 {
+            db.ingest_events((0..10).map(|ts| record(1000 + block * 100 + ts)))
+                .unwrap();
+            db.sync(true).unwrap();
+        }
+
+        let info = db.nsid_info("app.bsky.feed.like").unwrap().unwrap();
+        assert_eq!((info.block_count, info.items), (3, 30));
+        assert_eq!((info.oldest, info.newest), (Some(1000), Some(1209)));
+        assert_eq!((info.blocks[1].start, info.blocks[1].end), (1100, 1109));
+        assert!(db.nsid_info("app.bsky.feed.post").unwrap().is_none());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_info_filters_and_stops_at_the_budget() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-info-budget-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        for block in 0..3 {
+            db.ingest_events((0..10).map(|ts| record(1000 + block * 100 + ts)))
+                .unwrap();
+            db.ingest_events((0..5).map(|ts| EventRecord {
+                nsid: SmolStr::new_static("app.bsky.feed.post"),
+                ..record(1000 + block * 100 + ts)
+            }))
+            .unwrap();
+            db.sync(true).unwrap();
+        }
+
+        let info = db.info().unwrap();
+        assert!(!info.partial);
+        assert_eq!(info.nsids["app.bsky.feed.like"], [10, 10, 10]);
+        assert_eq!(info.nsids["app.bsky.feed.post"], [5, 5, 5]);
+
+        let posts = db
+            .info_with(InfoOptions {
+                prefix: Some(SmolStr::new_static("app.bsky.feed.p")),
+                budget: None,
+            })
+            .unwrap();
+        assert_eq!(posts.nsids.keys().collect_vec(), ["app.bsky.feed.post"]);
+
+        // nothing fits in no time at all, and it says so
+        let none = db
+            .info_with(InfoOptions {
+                prefix: None,
+                budget: Some(Duration::ZERO),
+            })
+            .unwrap();
+        assert!(none.partial);
+        assert!(none.nsids.is_empty());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_tracking_since_is_per_nsid() {
+        let path =
+            std::env::temp_dir().join(format!("lexicon-tracker-test-since-{}", std::process::id()));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        assert_eq!(db.tracking_since().unwrap(), 0);
+        let post = |timestamp| EventRecord {
+            nsid: SmolStr::new_static("app.bsky.feed.post"),
+            ..record(timestamp)
+        };
+        db.ingest_events([post(3000), record(5000), post(3100)].into_iter())
+            .unwrap();
+        db.sync(true).unwrap();
+
+        assert_eq!(db.nsid_since("app.bsky.feed.like").unwrap(), Some(5000));
+        assert_eq!(db.nsid_since("app.bsky.feed.post").unwrap(), Some(3000));
+        assert_eq!(db.nsid_since("app.bsky.feed.repost").unwrap(), None);
+        // the oldest of every nsid, not the likes
+        assert_eq!(db.tracking_since().unwrap(), 3000);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_query_hits_reports_truncation() {
+        let path =
+            std::env::temp_dir().join(format!("lexicon-tracker-test-page-{}", std::process::id()));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        for block in 0..5 {
+            db.ingest_events((0..10).map(|ts| record(1000 + block * 100 + ts)))
+                .unwrap();
+            db.sync(true).unwrap();
+        }
+
+        let page = db.query_hits(nsid, .., 15, None, Admission::Admit);
+        assert!(page.truncated);
+        // whole blocks are read, newest first
+        let hits = page.hits.map(|hit| hit.unwrap().timestamp).collect_vec();
+        assert_eq!(hits.len(), 20);
+        assert_eq!(hits[0], 1300);
+
+        // the range ends where the limit does
+        assert!(
+            !db.query_hits(nsid, 1300.., 15, None, Admission::Admit)
+                .truncated
+        );
+        assert!(
+            !db.query_hits(nsid, .., 1000, None, Admission::Admit)
+                .truncated
+        );
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_totals_follow_counts() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-totals-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.ingest_events((0..5).map(|ts| EventRecord {
+            nsid: SmolStr::new_static("app.bsky.feed.post"),
+            op: HitOp::Delete,
+            ..record(1000 + ts)
+        }))
+        .unwrap();
+        // overwriting counts adjusts the totals by the difference
+        db.put_count(
+            "app.bsky.feed.like",
+            &NsidCounts {
+                count: 4,
+                ..db.get_count("app.bsky.feed.like").unwrap()
+            },
+        )
+        .unwrap();
+        let expected = Totals {
+            count: 4,
+            deleted_count: 5,
+            purged_count: 0,
+        };
+        assert_eq!(db.totals(), expected);
+        assert_eq!(db.recount_totals().unwrap(), expected);
+
+        db.sync(true).unwrap();
+        drop(db);
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        assert_eq!(db.totals(), expected);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_events_generation_follows_counts() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-events-generation-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let start = db.events_generation();
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        let ingested = db.events_generation();
+        assert!(ingested > start);
+        // syncing writes blocks, the counts stay the same
+        db.sync(true).unwrap();
+        assert_eq!(db.events_generation(), ingested);
+
+        // held counts change what /events shows once they are written out
+        db.quiesce(Duration::from_secs(60)).unwrap();
+        db.ingest_events((0..5).map(|ts| record(2000 + ts)))
+            .unwrap();
+        let held = db.events_generation();
+        assert!(held > ingested);
+        db.unquiesce().unwrap();
+        assert!(db.events_generation() > held);
+
+        let labeled = db.events_generation();
+        assert!(!db.remove_labels("app.bsky.feed.like").unwrap());
+        assert_eq!(db.events_generation(), labeled);
+        db.set_labels(
+            "app.bsky.feed.like",
+            LabelMap::from([("team".into(), "feeds".into())]),
+        )
+        .unwrap();
+        assert!(db.events_generation() > labeled);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_counts_flusher_batches_writes() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-counts-flusher-{}",
+            std::process::id()
+        ));
+        let mut cfg = DbConfig::default().path(&path);
+        cfg.counts_flush_interval = Duration::from_secs(60);
+        cfg.counts_flush_max_dirty = 2;
+        let db = Arc::new(Db::new(cfg, CancellationToken::new()).unwrap());
+        let cancel = CancellationToken::new();
+        let flusher = std::thread::spawn({
+            let db = db.clone();
+            let cancel = cancel.clone();
+            move || db.run_counts_flusher(&cancel)
+        });
+        while !db.counts_cache.is_deferred() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let (like, post) = ("app.bsky.feed.like", "app.bsky.feed.post");
+
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        // only in memory so far, reads see it anyway
+        assert_eq!(db.counts.get(like).unwrap(), None);
+        assert_eq!(db.get_count(like).unwrap().count, 10);
+        let listed = db.get_counts().map(|res| res.unwrap().0).collect_vec();
+        assert_eq!(listed, [like]);
+
+        // a second dirty nsid gets it to flush before the interval is up
+        db.ingest_events(
+            [EventRecord {
+                nsid: SmolStr::new_static(post),
+                ..record(1000)
+            }]
+            .into_iter(),
+        )
+        .unwrap();
+        let start = std::time::Instant::now();
+        while db.counts.get(post).unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(db.counts.get(like).unwrap().unwrap().count, 10);
+
+        // what it didnt get to before stopping is written by the final sync
+        db.ingest_events((0..5).map(|ts| record(2000 + ts)))
+            .unwrap();
+        cancel.cancel();
+        db.wake_counts_flusher();
+        flusher.join().unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(db.counts.get(like).unwrap().unwrap().count, 15);
+        drop(db);
+
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        assert_eq!(db.get_count(like).unwrap().count, 15);
+        assert_eq!(db.get_count(post).unwrap().count, 1);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_counts_dump_merges_into_counts() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-counts-dump-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+
+        let (exported, dump) = db.export_counts(Vec::new()).unwrap();
+        assert_eq!(exported, 1);
+        db.import_counts(dump.as_slice(), CountsMerge::Sum).unwrap();
+        let counts = db.get_count("app.bsky.feed.like").unwrap();
+        assert_eq!((counts.count, counts.last_seen), (20, 1009));
+        assert_eq!(db.totals().count, 20);
+
+        // a dump that is cut off changes nothing
+        assert!(
+            db.import_counts(&dump[..dump.len() - 1], CountsMerge::Overwrite)
+                .is_err()
+        );
+        db.import_counts(dump.as_slice(), CountsMerge::Overwrite)
+            .unwrap();
+        assert_eq!(db.get_count("app.bsky.feed.like").unwrap().count, 10);
+        assert_eq!(db.totals().count, 10);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_counts_prefix_scan() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-counts-prefix-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsids = [
+            "app.bsky.feed.like",
+            "app.bsky.feed.post",
+            "app.bsky.graph.follow",
+            "fyi.unravel.frontpage.post",
+        ];
+        db.ingest_events(nsids.iter().map(|nsid| EventRecord {
+            nsid: SmolStr::new(nsid),
+            ..record(1000)
+        }))
+        .unwrap();
+
+        let scan = |prefix| {
+            db.get_counts_with_prefix(prefix)
+                .map(|res| res.unwrap().0)
+                .collect_vec()
+        };
+        assert_eq!(scan("app.bsky.feed."), nsids[..2]);
+        assert_eq!(scan("fyi."), nsids[3..]);
+        assert!(scan("com.").is_empty());
+        assert_eq!(scan(""), nsids);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_missing_shutdown_report_is_unclean() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-clean-start-{}",
+            std::process::id()
+        ));
+        let open = || Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        // nothing to shut down before the first start
+        let db = open();
+        assert!(db.is_clean_start());
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        drop(db);
+
+        let db = open();
+        assert!(!db.is_clean_start());
+        db.ingest_events((0..10).map(|ts| record(2000 + ts)))
+            .unwrap();
+        db.observe_cursor(2009 * 1_000_000);
+        db.sync(true).unwrap();
+        let report = db.shutdown_report(ShutdownPhases::default(), Vec::new());
+        assert_eq!(report.events_ingested, 10);
+        assert_eq!(report.cursor, Some(2009 * 1_000_000));
+        assert!(report.is_clean());
+        report.write(&path).unwrap();
+        drop(db);
+
+        assert!(open().is_clean_start());
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_unknown_nsids_are_cached_until_created() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-unknown-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        assert_eq!(db.get_hits(nsid, .., 100).count(), 0);
+        assert_eq!(db.get_hits(nsid, .., 100).count(), 0);
+        let stats = db.negative_cache_stats();
+        assert_eq!((stats.inserts, stats.hits), (1, 1));
+
+        // creating the partition drops it from the cache right away
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.sync(true).unwrap();
+        assert_eq!(db.get_hits(nsid, .., 100).count(), 10);
+        assert_eq!(db.negative_cache_stats().size, 0);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_nsid_rate_follows_ingest() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-nsid-rate-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+
+        assert_eq!(db.nsid_rate(nsid), 0.0);
+        assert!(!db.ks.partition_exists(nsid));
+
+        db.ingest_events((0..20).map(|ts| record(1000 + ts)))
+            .unwrap();
+        assert_eq!(db.nsid_rate(nsid), 20.0 / RATE_WINDOW_SECS as f64);
+        assert_eq!(db.nsid_rate("app.bsky.feed.post"), 0.0);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_decode_counts_without_purges() {
+        let old = NsidCountsV1 {
+            count: 5,
+            deleted_count: 2,
+            last_seen: 1000,
+        };
+        let raw = rkyv::to_bytes::<Error>(&old).unwrap();
+        let counts = NsidCounts::decode(&raw).unwrap();
+        assert_eq!(
+            counts,
+            NsidCounts {
+                count: 5,
+                deleted_count: 2,
+                last_seen: 1000,
+                purged_count: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_overview_stitches_rollups_and_tail() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-overview-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.feed.like";
+        let hour = active::HOUR;
+        let base = 1_700_000_000 / (4 * DAY) * (4 * DAY);
+        // day d has d + 1 hits at 06:00, one of day 10 is a delete
+        let events = (0..40u64).flat_map(|day| {
+            (0..=day).map(move |i| EventRecord {
+                op: if day == 10 && i == 0 {
+                    HitOp::Delete
+                } else {
+                    HitOp::Create
+                },
+                ..record(base + day * DAY + 6 * hour + i * 60)
+            })
+        });
+        db.ingest_events(events).unwrap();
+        db.sync(true).unwrap();
+
+        // tracking started at 05:00 of day 0, its 12:00 of day 39 now
+        let since = base + 5 * hour;
+        let now = base + 39 * DAY + 12 * hour;
+        let series = db
+            .overview(nsid, since, now, 10, Downsample::Sum)
+            .unwrap()
+            .unwrap();
+
+        // the points cover everything from since to now without gaps
+        assert_eq!(series[0].start, since);
+        for pair in series.windows(2) {
+            assert_eq!(pair[0].start + pair[0].width, pair[1].start);
+        }
+        let last = series.last().unwrap();
+        assert_eq!(last.start + last.width, now + 1);
+        let total = series
+            .iter()
+            .map(|point| point.count + point.deleted_count)
+            .sum::<u64>();
+        assert_eq!(total, (1..=40).sum::<u64>());
+
+        // days 0..39 are settled and come from rollups in 4 day points, the
+        // last cut off where the tail starts
+        let (days, tail) = series.split_at(10);
+        let days = days
+            .iter()
+            .map(|point| (point.start, point.width, point.resolution, point.count))
+            .collect_vec();
+        let mut expected = (0..9)
+            .map(|k| (base + 4 * k * DAY, 4 * DAY, DAY, 16 * k + 10))
+            .collect_vec();
+        expected[0] = (since, 4 * DAY - 5 * hour, DAY, 10);
+        // the delete
+        expected[2].3 -= 1;
+        expected.push((base + 36 * DAY, 3 * DAY, DAY, 37 + 38 + 39));
+        assert_eq!(days, expected);
+        // day 39 is still in the hourly tail
+        assert!(tail.iter().all(|point| point.resolution == hour));
+        assert_eq!(tail.iter().map(|point| point.count).sum::<u64>(), 40);
+
+        // settled days are read back from the rollups, not counted again
+        db.ingest_events(std::iter::once(record(base + 2 * DAY)))
+            .unwrap();
+        db.sync(true).unwrap();
+        let again = db
+            .overview(nsid, since, now, 10, Downsample::Sum)
+            .unwrap()
+            .unwrap();
+        assert_eq!(again, series);
+
+        let peaks = db
+            .overview(nsid, since, now, 10, Downsample::Max)
+            .unwrap()
+            .unwrap();
+        assert_eq!(peaks[1].count, 8);
+        assert_eq!(
+            db.overview("app.bsky.nope", since, now, 10, Downsample::Sum)
+                .unwrap(),
+            None
+        );
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_racing_handle_lookups_open_once() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-handle-race-{}",
+            std::process::id()
+        ));
+        let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let nsid = "app.bsky.test.race";
+        let barrier = std::sync::Barrier::new(16);
+        let handles = std::thread::scope(|scope| {
+            let threads = (0..16)
+                .map(|i| {
+                    let (db, barrier) = (&db, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        (0..100)
+                            .filter_map(|_| {
+                                if i % 2 == 0 {
+                                    Some(db.ensure_handle(nsid).unwrap())
+                                } else {
+                                    db.get_handle(nsid)
+                                }
+                            })
+                            .collect_vec()
+                    })
+                })
+                .collect_vec();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect_vec()
+        });
+
+        assert_eq!(handle::OPENED.lock().get(nsid), Some(&1));
+        let first = &handles[0];
+        assert!(
+            handles
+                .iter()
+                .all(|handle| std::ptr::eq(&**handle, &**first))
+        );
+
+        drop(handles);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_new_nsids_get_a_handle() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-new-handle-{}",
+            std::process::id()
+        ));
+        let open = |longtail_threshold| {
+            let mut cfg = DbConfig::default().path(&path);
+            cfg.longtail_threshold = longtail_threshold;
+            Db::new(cfg, CancellationToken::new()).unwrap()
+        };
+        let (own, shared) = ("app.bsky.test.own", "app.bsky.test.shared");
+
+        let db = open(0);
+        assert!(!db.has_nsid(own));
+        db.ensure_handle(own).unwrap();
+        assert!(db.ks.partition_exists(own));
+        assert!(db.has_nsid(own));
+        drop(db);
+
+        // with the longtail on, new nsids go there but old ones keep their
+        // partition
+        let db = open(1000);
+        db.ensure_handle(shared).unwrap();
+        assert!(!db.ks.partition_exists(shared));
+        assert!(db.has_nsid(shared));
+        assert_eq!(db.longtail_nsids(), 1);
+        db.ensure_handle(own).unwrap();
+        assert_eq!(db.longtail_nsids(), 1);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_paused_ingest_keeps_syncing() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-paused-sync-{}",
+            std::process::id()
+        ));
+        let mut cfg = DbConfig::default().path(&path);
+        cfg.max_last_activity = Duration::ZERO;
+        let db = Db::new(cfg, CancellationToken::new()).unwrap();
+        db.ingest_events((0..10).map(|ts| record(1000 + ts)))
+            .unwrap();
+        db.pause_ingest(Duration::from_secs(60));
+
+        let stats = db.sync(false).unwrap();
+        assert_eq!((stats.blocks, stats.items), (1, 10));
+        // nothing left to write
+        let stats = db.sync(false).unwrap();
+        assert_eq!((stats.blocks, stats.items), (0, 0));
+
+        db.resume_ingest();
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_sparkline_rebuilds_then_follows_ingest() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-sparkline-{}",
+            std::process::id()
+        ));
+        let open = || Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+        let db = open();
+        let nsid = "app.bsky.feed.like";
+        let hour = active::HOUR;
+        let now = 1_700_000_000 / hour * hour + 30 * 60;
+        let last = SPARKLINE_HOURS - 1;
+
+        // synced hits, one too old for the window, and one still buffered
+        db.ingest_events(
+            [now - 30 * hour, now - 5 * hour, now - 5 * hour]
+                .into_iter()
+                .chain([now - hour; 3])
+                .map(record),
+        )
+        .unwrap();
+        db.sync(true).unwrap();
+        db.ingest_events(std::iter::once(record(now - hour)))
+            .unwrap();
+
+        let points = db.sparkline(nsid, now).unwrap().unwrap();
+        assert_eq!(points[last - 5], 2);
+        assert_eq!(points[last - 1], 4);
+        assert_eq!(points.iter().sum::<u32>(), 6);
+
+        // live now, ingest keeps it current
+        db.ingest_events(std::iter::once(record(now))).unwrap();
+        let points = db.sparkline(nsid, now).unwrap().unwrap();
+        assert_eq!(points[last], 1);
+        // and an hour later everything moved back one
+        let points = db.sparkline(nsid, now + hour).unwrap().unwrap();
+        assert_eq!(
+            (points[last - 2], points[last - 1], points[last]),
+            (4, 1, 0)
+        );
+        assert_eq!(db.sparkline("app.bsky.nope", now).unwrap(), None);
+
+        // a fresh start has only blocks to rebuild from
+        db.sync(true).unwrap();
+        let before = db.sparkline(nsid, now + 1).unwrap();
+        drop(db);
+        let db = open();
+        assert_eq!(db.sparkline(nsid, now + 1).unwrap(), before);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
     }
 }
@@ -0,0 +1,147 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use smol_str::SmolStr;
+
+use crate::utils::CLOCK;
+
+// names of partitions that were looked up and didnt exist, so repeated lookups
+// of garbage nsids dont go to the keyspace every time. entries expire after
+// `ttl`, and are dropped as soon as a partition with the name is created.
+//
+// a lookup can race with the creation: it checks the keyspace, the partition
+// gets created and invalidated, then the lookup caches the name as missing.
+// so lookups take the generation before checking the keyspace and the name is
+// only cached if nothing was invalidated in between
+pub struct NegativeCache {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    inserts: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    names: AHashMap<SmolStr, quanta::Instant>,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NegativeCacheStats {
+    pub size: usize,
+    pub hits: u64,
+    pub inserts: u64,
+    pub invalidations: u64,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            inner: Default::default(),
+            hits: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// whether `name` is known not to exist
+    pub fn contains(&self, name: &str) -> bool {
+        let mut inner = self.inner.lock();
+        let Some(expires) = inner.names.get(name) else {
+            return false;
+        };
+        if *expires <= CLOCK.now() {
+            inner.names.remove(name);
+            return false;
+        }
+        self.hits.fetch_add(1, AtomicOrdering::Relaxed);
+        true
+    }
+
+    /// take this before checking whether the partition exists
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().generation
+    }
+
+    /// caches `name` as missing, unless something was invalidated since
+    /// `generation` was taken
+    pub fn insert(&self, name: &str, generation: u64) {
+        let mut inner = self.inner.lock();
+        if inner.generation != generation {
+            return;
+        }
+        let now = CLOCK.now();
+        if inner.names.len() >= self.capacity {
+            inner.names.retain(|_, expires| *expires > now);
+            // all still live, a scan is going on. start over
+            if inner.names.len() >= self.capacity {
+                inner.names.clear();
+            }
+        }
+        inner.names.insert(SmolStr::new(name), now + self.ttl);
+        self.inserts.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// call once the partition was created
+    pub fn invalidate(&self, name: &str) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.names.remove(name);
+        self.invalidations.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    pub fn stats(&self) -> NegativeCacheStats {
+        NegativeCacheStats {
+            size: self.inner.lock().names.len(),
+            hits: self.hits.load(AtomicOrdering::Relaxed),
+            inserts: self.inserts.load(AtomicOrdering::Relaxed),
+            invalidations: self.invalidations.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_create_during_lookup_isnt_cached() {
+        let cache = NegativeCache::new(Duration::from_secs(60), 10);
+        // lookup checked the keyspace and didnt find it...
+        let generation = cache.generation();
+        // ...but the partition got created before it could cache that
+        cache.invalidate("app.bsky.feed.like");
+        cache.insert("app.bsky.feed.like", generation);
+        assert!(!cache.contains("app.bsky.feed.like"));
+
+        let generation = cache.generation();
+        cache.insert("app.bsky.feed.like", generation);
+        assert!(cache.contains("app.bsky.feed.like"));
+        cache.invalidate("app.bsky.feed.like");
+        assert!(!cache.contains("app.bsky.feed.like"));
+    }
+
+    #[test]
+    fn test_entries_expire_and_are_bounded() {
+        let cache = NegativeCache::new(Duration::ZERO, 2);
+        let generation = cache.generation();
+        cache.insert("a", generation);
+        assert!(!cache.contains("a"));
+
+        let cache = NegativeCache::new(Duration::from_secs(60), 2);
+        for name in ["a", "b", "c"] {
+            cache.insert(name, generation);
+        }
+        assert!(cache.stats().size <= 2);
+        assert!(cache.contains("c"));
+    }
+}
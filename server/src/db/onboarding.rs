@@ -0,0 +1,140 @@
+use fjall::Partition;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use tokio::sync::mpsc::{Receiver, Sender, error::TrySendError};
+
+use crate::error::AppResult;
+
+const PENDING_PREFIX: &str = "onboarding/pending/";
+const SENT_PREFIX: &str = "onboarding/sent/";
+
+/// an nsid we just got its first event for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewNsid {
+    pub nsid: SmolStr,
+    pub first_seen: u64,
+    pub initial_count: u128,
+}
+
+// nsids to announce once, stored in `_meta` so a restart neither loses nor
+// repeats them: onboarding/pending/{nsid} until it was delivered, then
+// onboarding/sent/{nsid}
+pub struct Onboarding {
+    meta: Partition,
+    // None until a notifier subscribes, nothing is recorded without one
+    queue: Mutex<Option<Sender<NewNsid>>>,
+}
+
+impl Onboarding {
+    pub fn open(meta: Partition) -> Self {
+        Self {
+            meta,
+            queue: Mutex::new(None),
+        }
+    }
+
+    /// starts recording new nsids, they are sent to the returned queue. the
+    /// ones that werent delivered before are returned to be sent first
+    pub fn subscribe(&self, capacity: usize) -> AppResult<(Vec<NewNsid>, Receiver<NewNsid>)> {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity.max(1));
+        *self.queue.lock() = Some(tx);
+        Ok((self.pending()?, rx))
+    }
+
+    /// called from ingest, so it never waits: if the queue is full the nsid
+    /// stays pending and is picked up by the notifiers next rescan
+    pub fn observe(&self, new: NewNsid) -> AppResult<()> {
+        let queue = self.queue.lock();
+        let Some(queue) = queue.as_ref() else {
+            return Ok(());
+        };
+        if self.is_delivered(&new.nsid)? {
+            return Ok(());
+        }
+        self.meta.insert(
+            format!("{PENDING_PREFIX}{}", new.nsid),
+            serde_json::to_vec(&new)?,
+        )?;
+        match queue.try_send(new) {
+            Ok(()) => {}
+            Err(TrySendError::Full(new)) => {
+                tracing::debug!("onboarding queue is full, {} waits for a rescan", new.nsid)
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+        Ok(())
+    }
+
+    /// the nsids that were recorded but not delivered yet
+    pub fn pending(&self) -> AppResult<Vec<NewNsid>> {
+        let mut pending = Vec::new();
+        for res in self.meta.prefix(PENDING_PREFIX) {
+            let (key, value) = res?;
+            match serde_json::from_slice(&value) {
+                Ok(new) => pending.push(new),
+                Err(err) => tracing::warn!(
+                    "dropping unreadable onboarding entry {}: {err}",
+                    String::from_utf8_lossy(&key)
+                ),
+            }
+        }
+        Ok(pending)
+    }
+
+    pub fn is_delivered(&self, nsid: &str) -> AppResult<bool> {
+        Ok(self.meta.contains_key(format!("{SENT_PREFIX}{nsid}"))?)
+    }
+
+    pub fn delivered(&self, nsid: &str) -> AppResult<()> {
+        // marked sent first, a crash in between cant make it pending again
+        self.meta.insert(format!("{SENT_PREFIX}{nsid}"), "")?;
+        self.meta.remove(format!("{PENDING_PREFIX}{nsid}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new_nsids_are_kept_until_delivered() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-onboarding-{}",
+            std::process::id()
+        ));
+        let ks = fjall::Config::new(&path).open().unwrap();
+        let meta = ks.open_partition("_meta", Default::default()).unwrap();
+        let onboarding = Onboarding::open(meta.clone());
+        let new = |nsid: &str| NewNsid {
+            nsid: SmolStr::new(nsid),
+            first_seen: 1000,
+            initial_count: 1,
+        };
+
+        // nobody is listening
+        onboarding.observe(new("a.b.c")).unwrap();
+        assert!(onboarding.pending().unwrap().is_empty());
+
+        let (pending, mut rx) = onboarding.subscribe(1).unwrap();
+        assert!(pending.is_empty());
+        onboarding.observe(new("a.b.c")).unwrap();
+        // the queue is full, this one is only recorded
+        onboarding.observe(new("a.b.d")).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), new("a.b.c"));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(onboarding.pending().unwrap().len(), 2);
+
+        onboarding.delivered("a.b.c").unwrap();
+        onboarding.observe(new("a.b.c")).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // a restart only brings back what wasnt delivered
+        let onboarding = Onboarding::open(meta);
+        let (pending, _rx) = onboarding.subscribe(1).unwrap();
+        assert_eq!(pending, [new("a.b.d")]);
+        drop(ks);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
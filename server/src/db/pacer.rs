@@ -0,0 +1,207 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+// picks how long to wait until the next sync. a block is about a minute of
+// events (see `LexiconHandle::suggested_block_size`), and we want to come back
+// once half a block more has been buffered, which at the current rate is
+// `target / eps` away. sync only writes full blocks so the buffers never empty
+// out, what is left over is not counted against the target. if a whole block
+// is still buffered after a sync (a spike, or sync couldnt write) we come
+// back after `min`. quiet periods end up at `max`
+pub struct SyncPacer {
+    min: Duration,
+    max: Duration,
+    min_block_size: usize,
+    max_block_size: usize,
+    clock: quanta::Clock,
+    // the last pace we picked and when (raw clock)
+    last: Mutex<Option<(SyncPace, u64)>>,
+}
+
+/// a sync interval and what it was computed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SyncPace {
+    pub buffered_items: usize,
+    pub eps: usize,
+    pub target_items: usize,
+    pub interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SyncPaceStatus {
+    #[serde(flatten)]
+    pub pace: SyncPace,
+    pub decided_ms_ago: u64,
+}
+
+impl SyncPacer {
+    pub fn new(
+        min: Duration,
+        max: Duration,
+        min_block_size: usize,
+        max_block_size: usize,
+        clock: quanta::Clock,
+    ) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            min_block_size,
+            max_block_size: max_block_size.max(min_block_size),
+            clock,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn pace(&self, buffered_items: usize, eps: usize) -> SyncPace {
+        let block_items = eps
+            .saturating_mul(60)
+            .clamp(self.min_block_size, self.max_block_size);
+        let target_items = block_items / 2;
+        let interval = if buffered_items >= block_items {
+            self.min
+        } else if eps == 0 {
+            self.max
+        } else {
+            Duration::from_secs_f64(target_items as f64 / eps as f64)
+        };
+        SyncPace {
+            buffered_items,
+            eps,
+            target_items,
+            interval_ms: interval.clamp(self.min, self.max).as_millis() as u64,
+        }
+    }
+
+    /// picks the next interval and remembers it for `last`
+    pub fn next(&self, buffered_items: usize, eps: usize) -> Duration {
+        let pace = self.pace(buffered_items, eps);
+        *self.last.lock() = Some((pace, self.clock.raw()));
+        Duration::from_millis(pace.interval_ms)
+    }
+
+    pub fn last(&self) -> Option<SyncPaceStatus> {
+        let (pace, at) = (*self.last.lock())?;
+        let ago = Duration::from_nanos(self.clock.delta_as_nanos(at, self.clock.raw()));
+        Some(SyncPaceStatus {
+            pace,
+            decided_ms_ago: ago.as_millis() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MIN: Duration = Duration::from_secs(2);
+    const MAX: Duration = Duration::from_secs(60);
+
+    fn pacer(clock: quanta::Clock) -> SyncPacer {
+        SyncPacer::new(MIN, MAX, 1000, 250_000, clock)
+    }
+
+    #[test]
+    fn test_pace_bounds() {
+        let pacer = pacer(quanta::Clock::new());
+        // nothing coming in
+        assert_eq!(pacer.pace(0, 0).interval_ms, 60_000);
+        assert_eq!(pacer.pace(10, 0).interval_ms, 60_000);
+        // half a minute of events is half a block
+        assert_eq!(pacer.pace(0, 2000).interval_ms, 30_000);
+        assert_eq!(pacer.pace(100_000, 2000).interval_ms, 30_000);
+        // blocks are capped, so fast rates sync more often
+        assert_eq!(pacer.pace(0, 20_000).interval_ms, 6_250);
+        // a whole block left over
+        assert_eq!(pacer.pace(120_000, 2000).interval_ms, 2_000);
+    }
+
+    // events come in at `rate(seconds since start)`, and like the real sync
+    // only full blocks are written out. returns a
+    // (seconds since start, interval, buffered before the sync) per sync
+    fn simulate(rate: impl Fn(u64) -> usize, duration: u64) -> Vec<(u64, Duration, usize)> {
+        let (clock, mock) = quanta::Clock::mock();
+        let pacer = pacer(clock.clone());
+        let start = clock.raw();
+        let now = || Duration::from_nanos(clock.delta_as_nanos(start, clock.raw())).as_secs();
+        let mut buffered = 0;
+        let mut before_sync = 0;
+        let mut syncs = Vec::new();
+        while now() < duration {
+            let interval = pacer.next(buffered, rate(now()));
+            syncs.push((now(), interval, before_sync));
+            // events keep coming at whatever the rate is while we wait
+            for _ in 0..interval.as_millis() / 100 {
+                buffered += rate(now()) / 10;
+                mock.increment(Duration::from_millis(100));
+            }
+            assert_eq!(
+                pacer.last().unwrap().decided_ms_ago,
+                interval.as_millis() as u64
+            );
+            before_sync = buffered;
+            let block = (rate(now()) * 60).clamp(1000, 250_000);
+            buffered %= block;
+        }
+        syncs
+    }
+
+    #[test]
+    fn test_quiet_firehose_syncs_rarely() {
+        let syncs = simulate(|_| 5, 60 * 10);
+        assert!(syncs.iter().all(|(_, interval, _)| *interval == MAX));
+    }
+
+    #[test]
+    fn test_steady_firehose_syncs_at_half_a_block() {
+        let syncs = simulate(|_| 2000, 60 * 10);
+        assert!(
+            syncs
+                .iter()
+                .all(|(_, interval, _)| *interval == Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_spike_shortens_interval() {
+        // quiet, then a 5 minute spike that starts between two syncs
+        let rate = |secs| {
+            if (330..630).contains(&secs) {
+                20_000
+            } else {
+                5
+            }
+        };
+        let syncs = simulate(rate, 60 * 15);
+        assert!(
+            syncs
+                .iter()
+                .filter(|(at, _, _)| *at < 330)
+                .all(|(_, interval, _)| *interval == MAX)
+        );
+
+        // the first sync in the spike writes out what piled up during the
+        // last quiet interval
+        let (_, _, buffered) = syncs.iter().find(|(at, _, _)| *at >= 330).unwrap();
+        assert!(*buffered > 250_000);
+
+        // during the spike we sync about every 6s (125k items at 20k eps)
+        let during = syncs
+            .iter()
+            .filter(|(at, _, _)| (340..630).contains(at))
+            .collect::<Vec<_>>();
+        assert!(!during.is_empty());
+        assert!(
+            during
+                .iter()
+                .all(|(_, interval, _)| *interval <= Duration::from_millis(6250))
+        );
+        // so buffers never grow past one interval at the quiet maximum
+        assert!(
+            syncs
+                .iter()
+                .all(|(_, _, buffered)| *buffered <= 20_000 * 60 + 250_000)
+        );
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::{Bound, Range},
+};
+
+use fjall::{Batch, Partition, Slice, Snapshot};
+use rkyv::{Archive, Deserialize, Serialize, rancor::Error};
+use smol_str::SmolStr;
+
+use crate::{
+    db::{
+        HistogramBucket, NsidCounts,
+        handle::{Block, BlockKey, BlockRef},
+        labels::LabelMap,
+    },
+    error::{AppError, AppResult},
+    utils::WriteVariableExt,
+};
+
+// typed views over our partitions, each only takes the keys and values of
+// its own schema. `raw` is the escape hatch, for tooling and for the parts
+// that keep a key space of their own inside a partition (the cold tier and
+// the watchlist in `_meta`)
+
+/// a hits partition (of an nsid or of a watched did):
+/// varint start time + varint end time -> block of hits
+///
+/// or the part of `_longtail` that belongs to one nsid. nsids with few hits
+/// share that partition instead of having one each, their keys are the
+/// varint length of the nsid + the nsid + the block key. either way the
+/// `BlockRef`s read from it only have the block key
+#[derive(Clone)]
+pub struct HitsPartition {
+    partition: Partition,
+    // empty for a partition of its own
+    prefix: Vec<u8>,
+}
+
+/// the part of a `_longtail` key before the block key
+pub fn longtail_prefix(nsid: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(nsid.len() + 2);
+    prefix
+        .write_varint(nsid.len())
+        .expect("writing to a vec cant fail");
+    prefix.extend_from_slice(nsid.as_bytes());
+    prefix
+}
+
+// the first key after every key that starts with `prefix`, None if there is
+// none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl HitsPartition {
+    pub fn new(partition: Partition) -> Self {
+        Self {
+            partition,
+            prefix: Vec::new(),
+        }
+    }
+
+    /// the blocks of `nsid` in the shared `_longtail` partition
+    pub fn longtail(partition: Partition, nsid: &str) -> Self {
+        Self {
+            partition,
+            prefix: longtail_prefix(nsid),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_longtail(&self) -> bool {
+        !self.prefix.is_empty()
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.prefix[..], key].concat()
+    }
+
+    pub fn insert_block(&self, block: Block) -> AppResult<()> {
+        if self.is_longtail() {
+            self.partition.insert(self.key(&block.key), block.data)?;
+        } else {
+            self.partition.insert(block.key, block.data)?;
+        }
+        Ok(())
+    }
+
+    /// writes a stored block back as is, eg. one from the cold tier
+    pub fn restore_block(&self, block: &BlockRef) -> AppResult<()> {
+        self.partition
+            .insert(self.key(block.raw_key()), block.value().clone())?;
+        Ok(())
+    }
+
+    pub fn remove_block(&self, block: &BlockRef) -> AppResult<()> {
+        self.partition.remove(self.key(block.raw_key()))?;
+        Ok(())
+    }
+
+    pub fn batch_insert_block(&self, batch: &mut Batch, block: Block) {
+        if self.is_longtail() {
+            batch.insert(&self.partition, self.key(&block.key), block.data);
+        } else {
+            batch.insert(&self.partition, block.key, block.data);
+        }
+    }
+
+    pub fn batch_restore_block(&self, batch: &mut Batch, block: &BlockRef) {
+        batch.insert(
+            &self.partition,
+            self.key(block.raw_key()),
+            block.value().clone(),
+        );
+    }
+
+    pub fn batch_remove_block(&self, batch: &mut Batch, block: &BlockRef) {
+        batch.remove(&self.partition, self.key(block.raw_key()));
+    }
+
+    /// key of the oldest block, without reading the block
+    pub fn first_key(&self) -> AppResult<Option<BlockKey>> {
+        let first = if self.is_longtail() {
+            self.partition.prefix(&self.prefix).next().transpose()?
+        } else {
+            self.partition.first_key_value()?
+        };
+        first
+            .map(|(key, _)| BlockKey::decode(&key[self.prefix.len()..]))
+            .transpose()
+    }
+
+    /// every block as stored right now, oldest first
+    pub fn blocks(&self) -> impl Iterator<Item = AppResult<BlockRef>> + use<> {
+        let strip = self.prefix.len();
+        self.partition.prefix(self.prefix.clone()).map(move |res| {
+            let (key, value) = res?;
+            BlockRef::new(Slice::from(&key[strip..]), value)
+        })
+    }
+
+    #[inline(always)]
+    pub fn snapshot(&self) -> HitsSnapshot {
+        HitsSnapshot {
+            snapshot: self.partition.snapshot(),
+            prefix: self.prefix.clone(),
+        }
+    }
+
+    /// bytes the partition takes up on disk. for the longtail the blocks
+    /// are summed up, before compression
+    pub fn disk_space(&self) -> AppResult<u64> {
+        if !self.is_longtail() {
+            return Ok(self.partition.disk_space());
+        }
+        self.blocks()
+            .map(|block| block.map(|block| block.byte_len() as u64))
+            .sum()
+    }
+
+    pub fn raw(&self) -> &Partition {
+        &self.partition
+    }
+}
+
+/// a snapshot of a `HitsPartition`
+pub struct HitsSnapshot {
+    snapshot: Snapshot,
+    prefix: Vec<u8>,
+}
+
+impl HitsSnapshot {
+    /// the blocks with keys in the range, the keys given and returned are
+    /// block keys
+    pub fn range(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = AppResult<BlockRef>> + use<> {
+        let prefixed = |key: Vec<u8>| [&self.prefix[..], &key[..]].concat();
+        let start = match start {
+            Bound::Unbounded if !self.prefix.is_empty() => Bound::Included(self.prefix.clone()),
+            bound => bound.map(prefixed),
+        };
+        let end = match end {
+            Bound::Unbounded if !self.prefix.is_empty() => match prefix_end(&self.prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+            bound => bound.map(prefixed),
+        };
+        let strip = self.prefix.len();
+        self.snapshot.range((start, end)).map(move |res| {
+            let (key, value) = res?;
+            let key = if strip == 0 {
+                key
+            } else {
+                Slice::from(&key[strip..])
+            };
+            BlockRef::new(key, value)
+        })
+    }
+}
+
+/// `_counts` (nsid -> NsidCounts) and `_did_counts` (did partition name ->
+/// NsidCounts), both keyed by a name
+#[derive(Clone)]
+pub struct CountsPartition(Partition);
+
+impl CountsPartition {
+    pub fn new(partition: Partition) -> Self {
+        Self(partition)
+    }
+
+    pub fn get(&self, name: &str) -> AppResult<Option<NsidCounts>> {
+        self.0
+            .get(name)?
+            .map(|raw| NsidCounts::decode(&raw))
+            .transpose()
+    }
+
+    pub fn insert(&self, name: &str, counts: &NsidCounts) -> AppResult<()> {
+        self.0
+            .insert(name, rkyv::to_bytes::<Error>(counts)?.as_slice())?;
+        Ok(())
+    }
+
+    pub fn batch_insert(
+        &self,
+        batch: &mut Batch,
+        name: &str,
+        counts: &NsidCounts,
+    ) -> AppResult<()> {
+        batch.insert(&self.0, name, rkyv::to_bytes::<Error>(counts)?.as_slice());
+        Ok(())
+    }
+
+    /// the names starting with `prefix` and their counts, by name
+    pub fn prefix(&self, prefix: &str) -> impl Iterator<Item = AppResult<(SmolStr, NsidCounts)>> {
+        self.0.prefix(prefix).map(|res| {
+            let (key, value) = res?;
+            let name = str::from_utf8(&key)
+                .map(SmolStr::new)
+                .map_err(|_| anyhow::anyhow!("counts key isnt utf8"))?;
+            let counts = NsidCounts::decode(&value)
+                .map_err(|err| anyhow::anyhow!("cant decode counts of {name}: {err}"))?;
+            AppResult::Ok((name, counts))
+        })
+    }
+
+    pub fn raw(&self) -> &Partition {
+        &self.0
+    }
+}
+
+// totals of settled days per nsid in `_rollup_daily` as
+// nsid \0 day start (big endian) -> rkyv DayCounts
+// nothing is rolled up on ingest, days without a row (everything from before
+// rollups existed, and days nobody asked for yet) are counted from blocks
+// the first time they are read and written then. settled days dont change so
+// rows are never rewritten
+pub struct RollupPartition(Partition);
+
+#[derive(Archive, Deserialize, Serialize)]
+struct DayCounts {
+    count: u64,
+    deleted_count: u64,
+    purged_count: u64,
+}
+
+// the separator keeps the days of an nsid apart from those of nsids it is a
+// prefix of
+fn rollup_key(nsid: &str, day: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(nsid.len() + 9);
+    key.extend_from_slice(nsid.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&day.to_be_bytes());
+    key
+}
+
+impl RollupPartition {
+    pub fn new(partition: Partition) -> Self {
+        Self(partition)
+    }
+
+    /// the stored days of `nsid` starting in `days`, by day start
+    pub fn get(&self, nsid: &str, days: Range<u64>) -> AppResult<BTreeMap<u64, HistogramBucket>> {
+        self.0
+            .range(rollup_key(nsid, days.start)..rollup_key(nsid, days.end))
+            .map(|row| {
+                let (key, value) = row?;
+                let day = u64::from_be_bytes(key[key.len() - 8..].try_into()?);
+                let counts = rkyv::from_bytes::<DayCounts, Error>(&value)?;
+                let bucket = HistogramBucket {
+                    bucket_start: day,
+                    count: counts.count,
+                    deleted_count: counts.deleted_count,
+                    purged_count: counts.purged_count,
+                };
+                Ok((day, bucket))
+            })
+            .collect()
+    }
+
+    pub fn put(&self, nsid: &str, bucket: &HistogramBucket) -> AppResult<()> {
+        let counts = rkyv::to_bytes::<Error>(&DayCounts {
+            count: bucket.count,
+            deleted_count: bucket.deleted_count,
+            purged_count: bucket.purged_count,
+        })?;
+        self.0
+            .insert(rollup_key(nsid, bucket.bucket_start), counts.as_slice())?;
+        Ok(())
+    }
+
+    pub fn raw(&self) -> &Partition {
+        &self.0
+    }
+}
+
+/// `_labels`, nsid -> json object of its labels
+#[derive(Clone)]
+pub struct LabelsPartition(Partition);
+
+impl LabelsPartition {
+    pub fn new(partition: Partition) -> Self {
+        Self(partition)
+    }
+
+    pub fn insert(&self, nsid: &str, labels: &LabelMap) -> AppResult<()> {
+        self.0.insert(nsid, serde_json::to_vec(labels)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, nsid: &str) -> AppResult<()> {
+        self.0.remove(nsid)?;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = AppResult<(SmolStr, LabelMap)>> {
+        self.0.iter().map(|res| {
+            let (key, value) = res?;
+            let nsid = str::from_utf8(&key)
+                .map(SmolStr::new)
+                .map_err(|_| anyhow::anyhow!("labels key isnt utf8"))?;
+            let labels = serde_json::from_slice(&value)
+                .map_err(|err| anyhow::anyhow!("cant decode labels of {nsid}: {err}"))?;
+            AppResult::Ok((nsid, labels))
+        })
+    }
+
+    pub fn raw(&self) -> &Partition {
+        &self.0
+    }
+}
+
+/// the keys of `_meta` that are ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaKey<'a> {
+    SchemaVersion,
+    // jetstream cursor of the last full sync
+    Cursor,
+    // rkyv Totals as of the last sync
+    Totals,
+    // written to check if storage takes writes again
+    Probe,
+    // until when the blocks of an nsid stay out of the cold tier
+    ColdHold(&'a str),
+    // json `SnapshotMarker` of the last quiesce
+    SnapshotMarker,
+    // big endian start and end of where blocks of an nsid overlap, until
+    // compaction merges them
+    Overlap(&'a str),
+}
+
+impl MetaKey<'_> {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::SchemaVersion => b"schema_version".to_vec(),
+            Self::Cursor => b"cursor".to_vec(),
+            Self::Totals => b"totals".to_vec(),
+            Self::Probe => b"probe".to_vec(),
+            Self::ColdHold(nsid) => format!("cold_hold/{nsid}").into_bytes(),
+            Self::SnapshotMarker => b"snapshot_marker".to_vec(),
+            Self::Overlap(nsid) => format!("overlap/{nsid}").into_bytes(),
+        }
+    }
+}
+
+impl Display for MetaKey<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SchemaVersion => f.write_str("schema version"),
+            Self::Cursor => f.write_str("cursor"),
+            Self::Totals => f.write_str("totals"),
+            Self::Probe => f.write_str("probe"),
+            Self::ColdHold(nsid) => write!(f, "cold hold for {nsid}"),
+            Self::SnapshotMarker => f.write_str("snapshot marker"),
+            Self::Overlap(nsid) => write!(f, "overlap of {nsid}"),
+        }
+    }
+}
+
+/// `_meta`, misc internal state
+#[derive(Clone)]
+pub struct MetaPartition(Partition);
+
+impl MetaPartition {
+    pub fn new(partition: Partition) -> Self {
+        Self(partition)
+    }
+
+    pub fn contains(&self, key: MetaKey) -> AppResult<bool> {
+        Ok(self.0.contains_key(key.encode())?)
+    }
+
+    pub fn get(&self, key: MetaKey) -> AppResult<Option<Slice>> {
+        Ok(self.0.get(key.encode())?)
+    }
+
+    pub fn insert(&self, key: MetaKey, value: &[u8]) -> AppResult<()> {
+        self.0.insert(key.encode(), value)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: MetaKey) -> AppResult<()> {
+        self.0.remove(key.encode())?;
+        Ok(())
+    }
+
+    /// a big endian u64 value
+    pub fn get_u64(&self, key: MetaKey) -> AppResult<Option<u64>> {
+        let Some(raw) = self.get(key)? else {
+            return Ok(None);
+        };
+        <[u8; 8]>::try_from(&raw[..])
+            .map(|raw| Some(u64::from_be_bytes(raw)))
+            .map_err(|_| AppError::from(anyhow::anyhow!("invalid {key}")))
+    }
+
+    pub fn insert_u64(&self, key: MetaKey, value: u64) -> AppResult<()> {
+        self.insert(key, value.to_be_bytes().as_slice())
+    }
+
+    pub fn raw(&self) -> &Partition {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_key_roundtrips() {
+        for key in [
+            BlockKey { start: 0, end: 0 },
+            BlockKey {
+                start: 1_700_000_000,
+                end: 1_700_000_060,
+            },
+            BlockKey {
+                start: u64::MAX - 1,
+                end: u64::MAX,
+            },
+        ] {
+            assert_eq!(BlockKey::decode(&key.encode()).unwrap(), key);
+        }
+        // a truncated key doesnt decode
+        let raw = BlockKey {
+            start: 1_700_000_000,
+            end: 1_700_000_060,
+        }
+        .encode();
+        assert!(BlockKey::decode(&raw[..2]).is_err());
+    }
+
+    #[test]
+    fn test_rollup_keys_sort_by_nsid_then_day() {
+        let day = 1_700_006_400;
+        let key = rollup_key("app.a", day);
+        assert_eq!(&key[..6], b"app.a\0");
+        assert_eq!(u64::from_be_bytes(key[6..].try_into().unwrap()), day);
+        // the days of app.a all sort before any of app.ab
+        assert!(rollup_key("app.a", u64::MAX) < rollup_key("app.ab", 0));
+        assert!(rollup_key("app.a", day) < rollup_key("app.a", day + 1));
+        assert!(rollup_key("app.a", 255) < rollup_key("app.a", 256));
+    }
+
+    #[test]
+    fn test_meta_keys() {
+        assert_eq!(MetaKey::SchemaVersion.encode(), b"schema_version");
+        assert_eq!(MetaKey::Cursor.encode(), b"cursor");
+        assert_eq!(MetaKey::Totals.encode(), b"totals");
+        assert_eq!(MetaKey::Probe.encode(), b"probe");
+        assert_eq!(
+            MetaKey::ColdHold("app.bsky.feed.like").encode(),
+            b"cold_hold/app.bsky.feed.like"
+        );
+        // the watchlist and cold tier keep their own keys in `_meta`
+        for key in [MetaKey::Cursor, MetaKey::ColdHold("app.bsky.feed.like")] {
+            assert!(!key.encode().starts_with(b"watchlist/"));
+        }
+    }
+
+    #[test]
+    fn test_counts_prefix_scan() {
+        let path = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-partitions-{}",
+            std::process::id()
+        ));
+        let ks = fjall::Config::new(&path).open().unwrap();
+        let counts =
+            CountsPartition::new(ks.open_partition("_counts", Default::default()).unwrap());
+        let like = NsidCounts {
+            count: 3,
+            ..Default::default()
+        };
+        counts.insert("app.bsky.feed.like", &like).unwrap();
+        counts.insert("app.bsky.feed.post", &like).unwrap();
+        counts.insert("app.bsky.graph.follow", &like).unwrap();
+        assert_eq!(
+            counts.get("app.bsky.feed.like").unwrap(),
+            Some(like.clone())
+        );
+        assert_eq!(counts.get("app.bsky.feed").unwrap(), None);
+
+        let names = counts
+            .prefix("app.bsky.feed.")
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["app.bsky.feed.like", "app.bsky.feed.post"]);
+
+        // values that dont decode are errors, not panics
+        counts
+            .raw()
+            .insert("app.bsky.feed.repost", [0xff; 3])
+            .unwrap();
+        assert!(counts.get("app.bsky.feed.repost").is_err());
+        assert_eq!(
+            counts
+                .prefix("app.bsky.feed.")
+                .filter(Result::is_err)
+                .count(),
+            1
+        );
+
+        drop(counts);
+        drop(ks);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{collections::VecDeque, time::Duration};
+
+use ahash::AHashMap;
+use smol_str::SmolStr;
+
+// classifies deletions as part of an account purge: a did deleting at least
+// `threshold` records within `window`. dids are only kept in memory, in two
+// generations so the number tracked stays bounded (roughly an lru): once the
+// current generation is full it becomes the previous one and the old previous
+// is dropped, dids seen again are moved back into the current one
+pub struct PurgeDetector {
+    threshold: usize,
+    window: u64,
+    generation_size: usize,
+    current: AHashMap<SmolStr, VecDeque<u64>>,
+    previous: AHashMap<SmolStr, VecDeque<u64>>,
+}
+
+impl PurgeDetector {
+    /// a zero threshold disables detection
+    pub fn new(threshold: usize, window: Duration, max_tracked: usize) -> Self {
+        Self {
+            threshold,
+            window: window.as_secs(),
+            generation_size: (max_tracked / 2).max(1),
+            current: AHashMap::new(),
+            previous: AHashMap::new(),
+        }
+    }
+
+    /// records a deletion by `did` at `timestamp` (seconds), returns whether
+    /// it is part of a purge
+    pub fn observe(&mut self, did: &SmolStr, timestamp: u64) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        if !self.current.contains_key(did) {
+            let times = self.previous.remove(did).unwrap_or_default();
+            if self.current.len() >= self.generation_size {
+                self.previous = std::mem::take(&mut self.current);
+            }
+            self.current.insert(did.clone(), times);
+        }
+        let times = self.current.get_mut(did).expect("just inserted");
+        let cutoff = timestamp.saturating_sub(self.window);
+        while times.front().is_some_and(|time| *time < cutoff) {
+            times.pop_front();
+        }
+        times.push_back(timestamp);
+        if times.len() > self.threshold {
+            times.pop_front();
+        }
+        times.len() >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_burst_within_window() {
+        let mut detector = PurgeDetector::new(3, Duration::from_secs(10), 100);
+        let did = SmolStr::new_static("did:plc:abc");
+        assert!(!detector.observe(&did, 100));
+        assert!(!detector.observe(&did, 101));
+        assert!(detector.observe(&did, 102));
+        assert!(detector.observe(&did, 103));
+        // the earlier deletions fell out of the window
+        assert!(!detector.observe(&did, 200));
+    }
+
+    #[test]
+    fn test_tracked_dids_are_bounded() {
+        let mut detector = PurgeDetector::new(3, Duration::from_secs(10), 10);
+        for i in 0..100 {
+            detector.observe(&smol_str::format_smolstr!("did:plc:{i}"), 100);
+        }
+        assert!(detector.current.len() + detector.previous.len() <= 10);
+    }
+}
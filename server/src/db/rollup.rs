@@ -0,0 +1,237 @@
+use crate::db::{HistogramBucket, active::HOUR};
+
+pub const DAY: u64 = 60 * 60 * 24;
+const WEEK: u64 = DAY * 7;
+// the unix epoch was a thursday, weeks start on mondays
+const WEEK_OFFSET: u64 = DAY * 4;
+// how long after its end a day is rolled up. hits only get into blocks once
+// their nsid is synced, so the last ones of a day can show up a bit later
+pub const SETTLE: u64 = HOUR;
+
+/// how the buckets that make up an overview point are combined
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Downsample {
+    // totals over the point
+    #[default]
+    Sum,
+    // the busiest bucket of the point, per field
+    Max,
+}
+
+/// one point of `Db::overview`, covering `start..start + width`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct OverviewPoint {
+    pub start: u64,
+    pub width: u64,
+    // size of the buckets the point was made of, a day for rolled up history
+    // and an hour for the recent tail. with `Downsample::Max` the values are
+    // per bucket of this size
+    pub resolution: u64,
+    pub count: u64,
+    pub deleted_count: u64,
+    pub purged_count: u64,
+}
+
+impl OverviewPoint {
+    fn add(&mut self, bucket: &HistogramBucket, downsample: Downsample) {
+        let combine: fn(u64, u64) -> u64 = match downsample {
+            Downsample::Sum => |a, b| a + b,
+            Downsample::Max => u64::max,
+        };
+        self.count = combine(self.count, bucket.count);
+        self.deleted_count = combine(self.deleted_count, bucket.deleted_count);
+        self.purged_count = combine(self.purged_count, bucket.purged_count);
+    }
+}
+
+/// how many days a point of `Db::rollup` covers
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RollupGranularity {
+    #[default]
+    Day,
+    /// weeks start on monday (UTC)
+    Week,
+}
+
+impl RollupGranularity {
+    /// start of the point the day starting at `day` is in
+    fn point_start(self, day: u64) -> u64 {
+        match self {
+            RollupGranularity::Day => day,
+            // the days before the first monday are a week of their own
+            RollupGranularity::Week if day < WEEK_OFFSET => 0,
+            RollupGranularity::Week => (day - WEEK_OFFSET) / WEEK * WEEK + WEEK_OFFSET,
+        }
+    }
+}
+
+/// one point of `Db::rollup`, the hits of the day or week from `start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct RollupPoint {
+    pub start: u64,
+    pub count: u64,
+    pub deleted_count: u64,
+    pub purged_count: u64,
+    /// a day of it wasnt rolled up and was counted from blocks. days that
+    /// arent over (and settled) yet are, so this point can still change
+    pub estimated: bool,
+}
+
+/// days (oldest first, with whether they were read from their rollup)
+/// summed into points of `granularity`
+pub fn rollup_points(
+    days: &[(HistogramBucket, bool)],
+    granularity: RollupGranularity,
+) -> Vec<RollupPoint> {
+    let mut points: Vec<RollupPoint> = Vec::new();
+    for (day, rolled_up) in days {
+        let start = granularity.point_start(day.bucket_start);
+        let point = match points.last_mut() {
+            Some(point) if point.start == start => point,
+            _ => {
+                points.push(RollupPoint {
+                    start,
+                    count: 0,
+                    deleted_count: 0,
+                    purged_count: 0,
+                    estimated: false,
+                });
+                points.last_mut().expect("just pushed")
+            }
+        };
+        point.count += day.count;
+        point.deleted_count += day.deleted_count;
+        point.purged_count += day.purged_count;
+        point.estimated |= !rolled_up;
+    }
+    points
+}
+
+/// combines consecutive `resolution` sized buckets (oldest first) into points
+/// of `width` seconds, a multiple of `resolution`. points are aligned to
+/// multiples of `width` so they stay put as time goes on, and clipped to
+/// `start..end`
+pub fn downsample(
+    buckets: &[HistogramBucket],
+    resolution: u64,
+    width: u64,
+    start: u64,
+    end: u64,
+    downsample: Downsample,
+) -> Vec<OverviewPoint> {
+    let mut points: Vec<OverviewPoint> = Vec::new();
+    for bucket in buckets {
+        if bucket.bucket_start + resolution <= start {
+            continue;
+        }
+        let aligned = bucket.bucket_start / width * width;
+        let point_start = aligned.max(start);
+        if point_start >= end {
+            break;
+        }
+        match points.last_mut() {
+            Some(point) if point.start == point_start => point.add(bucket, downsample),
+            _ => {
+                let mut point = OverviewPoint {
+                    start: point_start,
+                    width: (aligned + width).min(end) - point_start,
+                    resolution,
+                    count: 0,
+                    deleted_count: 0,
+                    purged_count: 0,
+                };
+                point.add(bucket, downsample);
+                points.push(point);
+            }
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bucket(bucket_start: u64, count: u64) -> HistogramBucket {
+        HistogramBucket {
+            bucket_start,
+            count,
+            deleted_count: 0,
+            purged_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_downsample_aligns_and_clips() {
+        let buckets = (0..10)
+            .map(|day| bucket(day * DAY, day))
+            .collect::<Vec<_>>();
+        // starts mid day 1, ends with day 8
+        let points = downsample(&buckets, DAY, 4 * DAY, DAY + 10, 9 * DAY, Downsample::Sum);
+        let points = points
+            .iter()
+            .map(|point| (point.start, point.width, point.count))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            points,
+            [
+                (DAY + 10, 3 * DAY - 10, 1 + 2 + 3),
+                (4 * DAY, 4 * DAY, 4 + 5 + 6 + 7),
+                (8 * DAY, DAY, 8),
+            ]
+        );
+
+        let points = downsample(&buckets, DAY, 4 * DAY, 0, 10 * DAY, Downsample::Max);
+        let counts = points.iter().map(|point| point.count).collect::<Vec<_>>();
+        assert_eq!(counts, [3, 7, 9]);
+    }
+
+    #[test]
+    fn test_days_roll_up_into_weeks() {
+        // 2023-11-13 00:00:00 UTC, a monday
+        let monday = 1_699_833_600;
+        let days = (0..10)
+            .map(|day| (bucket(monday - 2 * DAY + day * DAY, 1), day != 9))
+            .collect::<Vec<_>>();
+        let points = rollup_points(&days, RollupGranularity::Week);
+        let points = points
+            .iter()
+            .map(|point| (point.start, point.count, point.estimated))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            points,
+            [
+                (monday - WEEK, 2, false),
+                (monday, 7, false),
+                (monday + WEEK, 1, true),
+            ]
+        );
+
+        let points = rollup_points(&days, RollupGranularity::Day);
+        assert_eq!(points.len(), 10);
+        assert_eq!(points[0].start, monday - 2 * DAY);
+        assert!(points[9].estimated && !points[8].estimated);
+    }
+}
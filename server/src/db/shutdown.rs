@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::error::AppResult;
+
+pub const SHUTDOWN_REPORT_FILE: &str = "shutdown_report.json";
+
+/// written into the data dir at the end of a graceful shutdown. it is removed
+/// when the db is opened again, so a start without one means the last run
+/// didnt get to the end of its final sync
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShutdownReport {
+    // unix seconds
+    pub shut_down_at: u64,
+    pub events_ingested: u64,
+    // items still buffered per nsid after the final sync, should be empty
+    pub buffered: BTreeMap<SmolStr, usize>,
+    // jetstream cursor on disk
+    pub cursor: Option<u64>,
+    pub sync_generation: u64,
+    pub phases: ShutdownPhases,
+    // errors we carried on after instead of stopping the shutdown
+    pub errors: Vec<String>,
+}
+
+/// how long every phase of the shutdown took, in seconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShutdownPhases {
+    // waiting for the maintenance task to stop
+    pub cancel: f64,
+    // waiting for ingest to finish what it already read
+    pub drain: f64,
+    pub sync: f64,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty() && self.buffered.is_empty()
+    }
+
+    pub fn write(&self, dir: &Path) -> AppResult<()> {
+        // written next to it first so a crash while writing leaves no report
+        let tmp = dir.join(format!("{SHUTDOWN_REPORT_FILE}.tmp"));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, dir.join(SHUTDOWN_REPORT_FILE))?;
+        Ok(())
+    }
+
+    /// reads the report of the last run and removes it
+    pub fn take(dir: &Path) -> AppResult<Option<Self>> {
+        let path = dir.join(SHUTDOWN_REPORT_FILE);
+        let raw = match std::fs::read(&path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        std::fs::remove_file(&path)?;
+        Ok(Some(serde_json::from_slice(&raw)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report_is_taken_once() {
+        let dir = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-shutdown-report-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let report = ShutdownReport {
+            events_ingested: 10,
+            cursor: Some(1_000_000),
+            ..Default::default()
+        };
+        assert!(report.is_clean());
+        report.write(&dir).unwrap();
+
+        assert_eq!(ShutdownReport::take(&dir).unwrap(), Some(report));
+        assert_eq!(ShutdownReport::take(&dir).unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
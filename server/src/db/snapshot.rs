@@ -0,0 +1,165 @@
+use std::{
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppResult;
+
+pub const SNAPSHOT_MARKER_FILE: &str = "snapshot_marker.json";
+// fjall writes everything to its journals first, so a snapshot of a dir that
+// was still being written to has one newer than the marker
+const JOURNALS_DIR: &str = "journals";
+
+/// written by `Db::quiesce` once everything is on disk, into `_meta` and as
+/// `SNAPSHOT_MARKER_FILE` in the data dir. a filesystem snapshot taken while
+/// writes are held is the data as of `generation`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotMarker {
+    // counts up with every quiesce, across restarts too
+    pub generation: u64,
+    // the sync the data corresponds to, as in `QuiesceState`. this one starts
+    // over on every open
+    pub sync_generation: u64,
+    // jetstream cursor on disk, where a replica of it would resume from
+    pub cursor: Option<u64>,
+    // unix seconds
+    pub quiesced_at: u64,
+}
+
+impl SnapshotMarker {
+    pub fn write(&self, dir: &Path) -> AppResult<()> {
+        // written next to it first so a crash while writing leaves the old one
+        let tmp = dir.join(format!("{SNAPSHOT_MARKER_FILE}.tmp"));
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, dir.join(SNAPSHOT_MARKER_FILE))?;
+        Ok(())
+    }
+
+    /// the marker file and when it was written, None if there is none
+    pub fn read(dir: &Path) -> AppResult<Option<(Self, SystemTime)>> {
+        let path = dir.join(SNAPSHOT_MARKER_FILE);
+        let raw = match std::fs::read(&path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let written = std::fs::metadata(&path)?.modified()?;
+        Ok(Some((serde_json::from_slice(&raw)?, written)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotState {
+    // nothing was written since the last quiesce, the data is as of its marker
+    Consistent,
+    // never quiesced, so there is no point in time the data is known to match
+    NoMarker,
+    // the journal changed after the marker, the dir was copied while writes
+    // went on and might be torn
+    WrittenAfter,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotCheck {
+    pub state: SnapshotState,
+    pub marker: Option<SnapshotMarker>,
+    // unix millis
+    pub marker_written_at: Option<u64>,
+    pub journal_written_at: Option<u64>,
+}
+
+/// whether the data dir at `dir` is a well defined point in time, judged from
+/// the files alone so it can run before the db is opened (opening replays the
+/// journal). mtimes are all we have, on filesystems with coarse ones a write
+/// within the same tick as the marker goes unnoticed
+pub fn check_snapshot_dir(dir: &Path) -> AppResult<SnapshotCheck> {
+    let marker = SnapshotMarker::read(dir)?;
+    let journal = newest_modified(&dir.join(JOURNALS_DIR))?;
+    let state = match (&marker, journal) {
+        (None, _) => SnapshotState::NoMarker,
+        (Some((_, written)), Some(journal)) if journal > *written => SnapshotState::WrittenAfter,
+        (Some(_), _) => SnapshotState::Consistent,
+    };
+    let marker_written_at = marker.as_ref().map(|(_, written)| unix_millis(*written));
+    Ok(SnapshotCheck {
+        state,
+        marker: marker.map(|(marker, _)| marker),
+        marker_written_at,
+        journal_written_at: journal.map(unix_millis),
+    })
+}
+
+fn newest_modified(dir: &Path) -> std::io::Result<Option<SystemTime>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut newest = None;
+    for entry in entries {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let modified = if meta.is_dir() {
+            newest_modified(&entry.path())?
+        } else {
+            Some(meta.modified()?)
+        };
+        newest = newest.max(modified);
+    }
+    Ok(newest)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_journal_written_after_marker() {
+        let dir = std::env::temp_dir().join(format!(
+            "lexicon-tracker-test-snapshot-marker-{}",
+            std::process::id()
+        ));
+        let journals = dir.join(JOURNALS_DIR);
+        std::fs::create_dir_all(&journals).unwrap();
+        std::fs::write(journals.join("0"), "before").unwrap();
+        assert_eq!(
+            check_snapshot_dir(&dir).unwrap().state,
+            SnapshotState::NoMarker
+        );
+
+        std::thread::sleep(Duration::from_millis(50));
+        let marker = SnapshotMarker {
+            generation: 3,
+            sync_generation: 10,
+            cursor: Some(1_000_000),
+            quiesced_at: 1000,
+        };
+        marker.write(&dir).unwrap();
+        let checked = check_snapshot_dir(&dir).unwrap();
+        assert_eq!(checked.state, SnapshotState::Consistent);
+        assert_eq!(checked.marker, Some(marker));
+
+        std::thread::sleep(Duration::from_millis(50));
+        std::fs::write(journals.join("1"), "after").unwrap();
+        assert_eq!(
+            check_snapshot_dir(&dir).unwrap().state,
+            SnapshotState::WrittenAfter
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
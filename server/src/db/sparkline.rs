@@ -0,0 +1,188 @@
+use crate::db::active::HOUR;
+
+pub const SPARKLINE_HOURS: usize = 24;
+
+/// hits per hour over the trailing `SPARKLINE_HOURS` hours, a ring of slots
+/// indexed by hour that moves forward with the newest hit it was given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sparkline {
+    // hour number (unix seconds / HOUR) of the newest slot
+    newest: u64,
+    counts: [u32; SPARKLINE_HOURS],
+}
+
+#[inline(always)]
+fn slot(hour: u64) -> usize {
+    (hour % SPARKLINE_HOURS as u64) as usize
+}
+
+impl Sparkline {
+    pub fn new(now: u64) -> Self {
+        Self {
+            newest: now / HOUR,
+            counts: [0; SPARKLINE_HOURS],
+        }
+    }
+
+    // moves the window forward so it ends with `hour`, clearing the slots of
+    // the hours it passes over
+    fn rotate(&mut self, hour: u64) {
+        if hour <= self.newest {
+            return;
+        }
+        let passed = (hour - self.newest).min(SPARKLINE_HOURS as u64);
+        for hour in hour + 1 - passed..=hour {
+            self.counts[slot(hour)] = 0;
+        }
+        self.newest = hour;
+    }
+
+    #[inline(always)]
+    fn in_window(&self, hour: u64) -> bool {
+        hour <= self.newest && hour + SPARKLINE_HOURS as u64 > self.newest
+    }
+
+    /// counts `hits` at `timestamp`, hits older than the window are dropped
+    pub fn add(&mut self, timestamp: u64, hits: u32) {
+        let hour = timestamp / HOUR;
+        self.rotate(hour);
+        if self.in_window(hour) {
+            let count = &mut self.counts[slot(hour)];
+            *count = count.saturating_add(hits);
+        }
+    }
+
+    /// the hours up to and including the one of `now`, oldest first
+    pub fn points(&self, now: u64) -> [u32; SPARKLINE_HOURS] {
+        let first = (now / HOUR + 1).saturating_sub(SPARKLINE_HOURS as u64);
+        std::array::from_fn(|i| {
+            let hour = first + i as u64;
+            if self.in_window(hour) {
+                self.counts[slot(hour)]
+            } else {
+                0
+            }
+        })
+    }
+}
+
+// a handle's sparkline. handles start out cold, the first request for it
+// makes ingest count queued hits from a cutoff on while everything before
+// the cutoff is counted from what was already queued, see `Db::sparkline`
+#[derive(Debug, Default)]
+pub struct SparklineSlot {
+    state: SparklineState,
+    // newest timestamp queued so far, 0 before the first
+    last_queued: u64,
+}
+
+#[derive(Debug, Default)]
+enum SparklineState {
+    #[default]
+    Cold,
+    Rebuilding {
+        sparkline: Sparkline,
+        cutoff: u64,
+    },
+    Live(Sparkline),
+}
+
+impl SparklineSlot {
+    pub fn observe(&mut self, timestamp: u64) {
+        self.last_queued = self.last_queued.max(timestamp);
+        match &mut self.state {
+            SparklineState::Cold => {}
+            SparklineState::Rebuilding { sparkline, cutoff } => {
+                if timestamp >= *cutoff {
+                    sparkline.add(timestamp, 1);
+                }
+            }
+            SparklineState::Live(sparkline) => sparkline.add(timestamp, 1),
+        }
+    }
+
+    /// None until it was rebuilt
+    pub fn points(&self, now: u64) -> Option<[u32; SPARKLINE_HOURS]> {
+        match &self.state {
+            SparklineState::Live(sparkline) => Some(sparkline.points(now)),
+            _ => None,
+        }
+    }
+
+    /// starts counting queued hits, and returns the cutoff the hits before
+    /// which have to be counted from blocks and the buffer. a rebuild that
+    /// didnt finish is started over
+    pub fn start_rebuild(&mut self, now: u64) -> u64 {
+        // before anything was queued the blocks have everything up to now
+        let cutoff = match self.last_queued {
+            0 => now,
+            last => last + 1,
+        };
+        self.state = SparklineState::Rebuilding {
+            sparkline: Sparkline::new(cutoff),
+            cutoff,
+        };
+        cutoff
+    }
+
+    /// adds the hits counted from before the cutoff and makes it live
+    pub fn finish_rebuild(&mut self, counted: &Sparkline) {
+        let state = std::mem::take(&mut self.state);
+        let SparklineState::Rebuilding { mut sparkline, .. } = state else {
+            self.state = state;
+            return;
+        };
+        let first = (counted.newest + 1).saturating_sub(SPARKLINE_HOURS as u64);
+        for hour in first..=counted.newest {
+            sparkline.add(hour * HOUR, counted.counts[slot(hour)]);
+        }
+        self.state = SparklineState::Live(sparkline);
+    }
+
+    pub fn abort_rebuild(&mut self) {
+        self.state = SparklineState::Cold;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000 / HOUR * HOUR;
+
+    #[test]
+    fn test_sparkline_rotates_on_hour_boundaries() {
+        let mut sparkline = Sparkline::new(NOW);
+        sparkline.add(NOW, 1);
+        sparkline.add(NOW + HOUR - 1, 2);
+        // an hour old, still in the window
+        sparkline.add(NOW - HOUR, 4);
+        let points = sparkline.points(NOW);
+        assert_eq!(points[SPARKLINE_HOURS - 1], 3);
+        assert_eq!(points[SPARKLINE_HOURS - 2], 4);
+
+        // the next hour starts a new slot and the rest moves back
+        sparkline.add(NOW + HOUR, 8);
+        let points = sparkline.points(NOW + HOUR);
+        assert_eq!(&points[SPARKLINE_HOURS - 3..], [4, 3, 8]);
+        assert_eq!(points.iter().sum::<u32>(), 15);
+
+        // older than the window
+        sparkline.add(NOW + HOUR - SPARKLINE_HOURS as u64 * HOUR, 16);
+        assert_eq!(sparkline.points(NOW + HOUR).iter().sum::<u32>(), 15);
+
+        // 23 hours later only the newest hour is left, asking for a later
+        // hour than anything counted shows the gap as zeros
+        let later = NOW + HOUR + 23 * HOUR;
+        assert_eq!(sparkline.points(later)[0], 8);
+        assert_eq!(sparkline.points(later).iter().sum::<u32>(), 8);
+        sparkline.add(later, 1);
+        assert_eq!(sparkline.points(later)[0], 8);
+        sparkline.add(later + HOUR, 1);
+        assert_eq!(sparkline.points(later + HOUR).iter().sum::<u32>(), 2);
+
+        // a jump past the whole window clears it
+        sparkline.add(later + 100 * HOUR, 1);
+        assert_eq!(sparkline.points(later + 100 * HOUR).iter().sum::<u32>(), 1);
+    }
+}
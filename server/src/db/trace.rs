@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rclite::Arc;
+use serde::Serialize;
+
+use crate::{db::handle::BlockKey, utils::CLOCK};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockTrace {
+    pub start: u64,
+    pub end: u64,
+    pub items: usize,
+    pub bytes: usize,
+    pub decode_us: u64,
+}
+
+/// collects per block decode timings of a query, only used when a query
+/// is explicitly debugged so normal queries don't pay for it
+#[derive(Debug, Clone, Default)]
+pub struct QueryTrace {
+    blocks: Arc<Mutex<Vec<BlockTrace>>>,
+}
+
+impl QueryTrace {
+    pub fn block_count(&self) -> usize {
+        self.blocks.lock().len()
+    }
+
+    /// the `n` blocks that took the longest to decode
+    pub fn slowest(&self, n: usize) -> Vec<BlockTrace> {
+        let mut blocks = self.blocks.lock().clone();
+        blocks.sort_unstable_by(|a, b| b.decode_us.cmp(&a.decode_us));
+        blocks.truncate(n);
+        blocks
+    }
+
+    pub(super) fn wrap<I: Iterator>(
+        &self,
+        key: BlockKey,
+        items: usize,
+        bytes: usize,
+        inner: I,
+    ) -> TimedBlock<I> {
+        TimedBlock {
+            inner,
+            elapsed: Duration::ZERO,
+            trace: BlockTrace {
+                start: key.start,
+                end: key.end,
+                items,
+                bytes,
+                decode_us: 0,
+            },
+            sink: self.blocks.clone(),
+        }
+    }
+}
+
+// records how long it took to iterate the block once it's dropped
+pub struct TimedBlock<I> {
+    inner: I,
+    elapsed: Duration,
+    trace: BlockTrace,
+    sink: Arc<Mutex<Vec<BlockTrace>>>,
+}
+
+impl<I: Iterator> Iterator for TimedBlock<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = CLOCK.now();
+        let item = self.inner.next();
+        self.elapsed += start.elapsed();
+        item
+    }
+}
+
+impl<I> Drop for TimedBlock<I> {
+    fn drop(&mut self) {
+        self.trace.decode_us = self.elapsed.as_micros() as u64;
+        self.sink.lock().push(self.trace.clone());
+    }
+}
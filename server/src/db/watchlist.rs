@@ -0,0 +1,127 @@
+use ahash::AHashSet;
+use fjall::Partition;
+use parking_lot::Mutex;
+use serde::Serialize;
+use smol_str::{SmolStr, format_smolstr};
+
+use crate::{
+    error::AppResult,
+    utils::{ArcRefCnt, ArcliteSwap},
+};
+
+const KEY_PREFIX: &str = "watchlist/";
+const MAX_DID_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchResult {
+    Added,
+    AlreadyWatched,
+    Full,
+}
+
+// dids we additionally track per nsid hits for, stored in `_meta` as
+// watchlist/{did} so it survives restarts
+pub struct Watchlist {
+    dids: ArcliteSwap<AHashSet<SmolStr>>,
+    meta: Partition,
+    max: usize,
+    // serializes updates, readers just load the current set
+    write_lock: Mutex<()>,
+}
+
+impl Watchlist {
+    pub fn open(meta: Partition, max: usize, initial: &[SmolStr]) -> AppResult<Self> {
+        let mut dids = AHashSet::new();
+        for res in meta.prefix(KEY_PREFIX) {
+            let (key, _) = res?;
+            dids.insert(SmolStr::new(String::from_utf8_lossy(
+                &key[KEY_PREFIX.len()..],
+            )));
+        }
+        let watchlist = Self {
+            dids: ArcliteSwap::new(ArcRefCnt::new(dids)),
+            meta,
+            max,
+            write_lock: Mutex::new(()),
+        };
+        for did in initial {
+            if !is_valid_did(did) {
+                tracing::warn!("ignoring invalid watchlist did {did}");
+                continue;
+            }
+            if watchlist.add(did)? == WatchResult::Full {
+                tracing::warn!("watchlist is full, ignoring {did}");
+            }
+        }
+        Ok(watchlist)
+    }
+
+    /// the current set, None if nothing is watched so ingest can skip lookups
+    pub fn snapshot(&self) -> Option<ArcRefCnt<AHashSet<SmolStr>>> {
+        let dids = self.dids.load_full();
+        (!dids.is_empty()).then_some(dids)
+    }
+
+    pub fn contains(&self, did: &str) -> bool {
+        self.dids.load().contains(did)
+    }
+
+    pub fn list(&self) -> Vec<SmolStr> {
+        let mut dids = self.dids.load().iter().cloned().collect::<Vec<_>>();
+        dids.sort_unstable();
+        dids
+    }
+
+    pub fn add(&self, did: &str) -> AppResult<WatchResult> {
+        let _lock = self.write_lock.lock();
+        let current = self.dids.load_full();
+        if current.contains(did) {
+            return Ok(WatchResult::AlreadyWatched);
+        }
+        if current.len() >= self.max {
+            return Ok(WatchResult::Full);
+        }
+        self.meta.insert(format!("{KEY_PREFIX}{did}"), "")?;
+        let mut dids = (*current).clone();
+        dids.insert(SmolStr::new(did));
+        self.dids.store(ArcRefCnt::new(dids));
+        Ok(WatchResult::Added)
+    }
+
+    /// stops tracking the did, what was already recorded is kept
+    pub fn remove(&self, did: &str) -> AppResult<bool> {
+        let _lock = self.write_lock.lock();
+        let current = self.dids.load_full();
+        if !current.contains(did) {
+            return Ok(false);
+        }
+        self.meta.remove(format!("{KEY_PREFIX}{did}"))?;
+        let mut dids = (*current).clone();
+        dids.remove(did);
+        self.dids.store(ArcRefCnt::new(dids));
+        Ok(true)
+    }
+}
+
+/// only dids that map to a valid partition name once `:` is replaced
+pub fn is_valid_did(did: &str) -> bool {
+    did.len() <= MAX_DID_LEN
+        && did.strip_prefix("did:").is_some_and(|rest| {
+            !rest.is_empty()
+                && rest
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '.' | '-' | '_'))
+        })
+}
+
+// partition names cant contain `:`, so did:plc:abc + app.bsky.feed.like is
+// stored in `_did#plc#abc$app.bsky.feed.like`
+pub fn did_prefix(did: &str) -> SmolStr {
+    let rest = did.strip_prefix("did:").unwrap_or(did);
+    format_smolstr!("_did#{}$", rest.replace(':', "#"))
+}
+
+pub fn did_partition(did: &str, nsid: &str) -> SmolStr {
+    format_smolstr!("{}{nsid}", did_prefix(did))
+}
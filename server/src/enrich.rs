@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use smol_str::{SmolStr, format_smolstr};
+
+use crate::{
+    config,
+    db::{EventRecord, HitOp, is_valid_nsid},
+    jetstream::JetstreamEvent,
+};
+
+/// derives more records from a jetstream event at ingest, on top of the one
+/// `EventRecord::from_jetstream` makes of it. they go through the same
+/// pipeline, so a derived nsid is counted and queried like any other
+pub trait EventEnricher: Send + Sync {
+    /// the extra records for `event`, None if there are none
+    fn enrich(&self, event: &JetstreamEvent) -> Option<Vec<EventRecord>>;
+}
+
+/// counts records of `collection` whose value at `pointer` matches under
+/// `<collection>.<suffix>` as well, like
+/// `{"collection": "app.bsky.feed.post", "pointer": "/embed/$type", "equals": "app.bsky.embed.images", "suffix": "images"}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrichRule {
+    pub collection: SmolStr,
+    /// a json pointer into the record
+    pub pointer: String,
+    /// the value it has to point at, any value but null does without it
+    #[serde(default)]
+    pub equals: Option<serde_json::Value>,
+    pub suffix: SmolStr,
+}
+
+impl EnrichRule {
+    fn matches(&self, record: &serde_json::Value) -> bool {
+        match (record.pointer(&self.pointer), &self.equals) {
+            (None | Some(serde_json::Value::Null), _) => false,
+            (Some(value), Some(equals)) => value == equals,
+            (Some(_), None) => true,
+        }
+    }
+}
+
+/// the built in enricher, driven by `ENRICH_RULES`. records are only there
+/// for creates (and updates), so derived nsids dont see deletions
+pub struct RuleEnricher {
+    // the rules with the nsid they derive
+    rules: Vec<(EnrichRule, SmolStr)>,
+}
+
+impl RuleEnricher {
+    /// drops rules that wouldnt derive a valid nsid
+    pub fn new(rules: Vec<EnrichRule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .filter_map(|rule| {
+                let nsid = format_smolstr!("{}.{}", rule.collection, rule.suffix);
+                if !is_valid_nsid(&nsid) {
+                    tracing::warn!("ignoring enrich rule for {nsid}, it isnt a valid nsid");
+                    return None;
+                }
+                Some((rule, nsid))
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// reads `ENRICH_RULES` (a json array of `EnrichRule`), None without
+    /// any rules
+    pub fn from_env() -> Option<Self> {
+        let rules = config::env_or("ENRICH_RULES", Vec::<EnrichRule>::new(), |rules| {
+            serde_json::from_str(rules)
+                .inspect_err(|err| tracing::warn!("ignoring ENRICH_RULES: {err}"))
+                .ok()
+        });
+        let enricher = Self::new(rules);
+        (!enricher.rules.is_empty()).then_some(enricher)
+    }
+}
+
+impl EventEnricher for RuleEnricher {
+    fn enrich(&self, event: &JetstreamEvent) -> Option<Vec<EventRecord>> {
+        let JetstreamEvent::Commit {
+            did,
+            time_us,
+            commit,
+            ..
+        } = event
+        else {
+            return None;
+        };
+        let records = self
+            .rules
+            .iter()
+            .filter(|(rule, _)| rule.collection == commit.collection)
+            .filter(|(rule, _)| rule.matches(&commit.record))
+            .map(|(_, nsid)| EventRecord {
+                nsid: nsid.clone(),
+                timestamp: time_us / 1_000_000,
+                op: HitOp::Create,
+                did: Some(SmolStr::new(did)),
+                rkey: Some(SmolStr::new(&commit.rkey)),
+            })
+            .collect::<Vec<_>>();
+        (!records.is_empty()).then_some(records)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // captured from jetstream, anonymized
+    const IMAGE_POST: &str = r#"{"did":"did:plc:11ba875bf90e8ca79ae00365","time_us":1729000001525885,"kind":"commit","commit":{"rev":"3l6kkrikq5xrw","operation":"create","collection":"app.bsky.feed.post","rkey":"3l6kjxycquyzi","record":{"$type":"app.bsky.feed.post","createdAt":"xxxxxxxxxxxxxxxxxxxxxxxx","langs":["xx"],"text":"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx","embed":{"$type":"app.bsky.embed.images","images":[{"alt":"xxxxxxxx","aspectRatio":{"height":1000,"width":750},"image":{"$type":"blob","ref":{"$link":"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"},"mimeType":"xxxxxxxxxx","size":515685}}]}},"cid":"bafyrei34iv2qzosqsmhs275c4bjhorx6sxywzb5pvfbuxkhdox5qvqxen7"}}"#;
+    const TEXT_POST: &str = r#"{"did":"did:plc:1e06965ecaaafec00c00a642","time_us":1729000000724386,"kind":"commit","commit":{"rev":"3l6kkrhsbh4hm","operation":"create","collection":"app.bsky.feed.post","rkey":"3l6kjwdhfoq2a","record":{"$type":"app.bsky.feed.post","createdAt":"xxxxxxxxxxxxxxxxxxxxxxxx","langs":["xx"],"text":"xxxxxxx"},"cid":"bafyreiibag33wbtutiaxzo2au5n32gsdz5bqs7yn4u7ynabguxz7v56snr"}}"#;
+    const DELETE: &str = r#"{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","time_us":1729000000312044,"kind":"commit","commit":{"rev":"3l6kkrhdyfw2e","operation":"delete","collection":"app.bsky.feed.post","rkey":"3l5ubsrbnwo2k"}}"#;
+
+    fn enricher() -> RuleEnricher {
+        let rules = serde_json::from_value(serde_json::json!([
+            { "collection": "app.bsky.feed.post", "pointer": "/embed/$type", "equals": "app.bsky.embed.images", "suffix": "images" },
+            { "collection": "app.bsky.feed.post", "pointer": "/embed", "suffix": "embeds" },
+            { "collection": "app.bsky.feed.post", "pointer": "/langs/0", "suffix": "not-valid" },
+        ]))
+        .unwrap();
+        RuleEnricher::new(rules)
+    }
+
+    #[test]
+    fn test_rules_derive_records() {
+        let enricher = enricher();
+        // the last rule would derive an invalid nsid
+        assert_eq!(enricher.rules.len(), 2);
+
+        let event = JetstreamEvent::parse(IMAGE_POST).unwrap();
+        let records = enricher.enrich(&event).unwrap();
+        let nsids = records.iter().map(|r| r.nsid.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            nsids,
+            ["app.bsky.feed.post.images", "app.bsky.feed.post.embeds"]
+        );
+        let base = EventRecord::from_jetstream(event).unwrap();
+        assert_eq!(records[0].timestamp, base.timestamp);
+        assert_eq!(records[0].op, HitOp::Create);
+        // derived records are told apart from the one they came from
+        assert_ne!(
+            records[0].identity(1729000001525885),
+            base.identity(1729000001525885)
+        );
+
+        let event = JetstreamEvent::parse(TEXT_POST).unwrap();
+        assert!(enricher.enrich(&event).is_none());
+        let event = JetstreamEvent::parse(DELETE).unwrap();
+        assert!(enricher.enrich(&event).is_none());
+    }
+}
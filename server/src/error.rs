@@ -1,11 +1,161 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    body::Body,
+    extract::Request,
+    http::{StatusCode, header::CONTENT_LENGTH},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use serde::Serialize;
+use smol_str::SmolStr;
+
+/// what went wrong, decides the status code an error is answered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // the client asked for something that doesnt make sense
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    // it cant happen right now, like a second sync while one is running
+    Conflict,
+    TooManyRequests,
+    // we cant take it right now, trying again later can work
+    Unavailable,
+    // our fault, everything that isnt explicitly one of the above
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// the `code` of an error body. these are part of the api, a code is never
+/// renamed or reused for something else, see /error_codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidRequest,
+    InvalidNsid,
+    InvalidDid,
+    InvalidLabels,
+    InvalidRange,
+    RangeTooLarge,
+    InvalidLimit,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    NsidNotFound,
+    DidNotWatched,
+    Conflict,
+    SyncRunning,
+    RateLimited,
+    Overloaded,
+    QueryTimeout,
+    StorageDegraded,
+    ShuttingDown,
+    StoreCorrupt,
+    Internal,
+}
+
+impl ErrorCode {
+    pub const ALL: &[Self] = &[
+        Self::InvalidRequest,
+        Self::InvalidNsid,
+        Self::InvalidDid,
+        Self::InvalidLabels,
+        Self::InvalidRange,
+        Self::RangeTooLarge,
+        Self::InvalidLimit,
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::NotFound,
+        Self::NsidNotFound,
+        Self::DidNotWatched,
+        Self::Conflict,
+        Self::SyncRunning,
+        Self::RateLimited,
+        Self::Overloaded,
+        Self::QueryTimeout,
+        Self::StorageDegraded,
+        Self::ShuttingDown,
+        Self::StoreCorrupt,
+        Self::Internal,
+    ];
+
+    // the catalogue, a new code doesnt compile until it has an entry here
+    fn entry(self) -> (ErrorKind, &'static str) {
+        use ErrorKind::*;
+        match self {
+            Self::InvalidRequest => (
+                BadRequest,
+                "a parameter is missing, doesnt parse or doesnt go with the others",
+            ),
+            Self::InvalidNsid => (BadRequest, "the nsid isnt a valid nsid"),
+            Self::InvalidDid => (BadRequest, "the did isnt a valid did"),
+            Self::InvalidLabels => (BadRequest, "labels are too long or too many"),
+            Self::InvalidRange => (BadRequest, "the range ends before it starts"),
+            Self::RangeTooLarge => (
+                BadRequest,
+                "the range spans more than can be queried at once, split it up",
+            ),
+            Self::InvalidLimit => (BadRequest, "the limit is out of the allowed range"),
+            Self::Unauthorized => (Unauthorized, "the admin token is missing or wrong"),
+            Self::Forbidden => (Forbidden, "only admins can ask for this"),
+            Self::NotFound => (NotFound, "there is nothing here"),
+            Self::NsidNotFound => (NotFound, "no events of the nsid were ever seen"),
+            Self::DidNotWatched => (NotFound, "the did isnt on the watchlist"),
+            Self::Conflict => (Conflict, "it cant be done right now"),
+            Self::SyncRunning => (Conflict, "a sync is already running"),
+            Self::RateLimited => (
+                TooManyRequests,
+                "too many requests from this client, wait for retry-after seconds",
+            ),
+            Self::Overloaded => (
+                Unavailable,
+                "too many expensive queries are running, try again shortly",
+            ),
+            Self::QueryTimeout => (Unavailable, "the query took too long and was stopped"),
+            Self::StorageDegraded => (
+                Unavailable,
+                "writes to storage are failing, the server is read only until they recover",
+            ),
+            Self::ShuttingDown => (Unavailable, "the server is shutting down"),
+            Self::StoreCorrupt => (Internal, "stored data couldnt be decoded"),
+            Self::Internal => (Internal, "something went wrong on our side"),
+        }
+    }
+
+    pub fn kind(self) -> ErrorKind {
+        self.entry().0
+    }
+
+    pub fn description(self) -> &'static str {
+        self.entry().1
+    }
+}
 
 #[derive(Debug)]
 pub struct AppError {
     inner: anyhow::Error,
+    code: ErrorCode,
+    detail: Option<serde_json::Value>,
 }
 
 impl Display for AppError {
@@ -14,29 +164,218 @@ impl Display for AppError {
     }
 }
 
+impl AppError {
+    pub fn new(code: ErrorCode, msg: impl Display + Send + Sync + 'static) -> Self {
+        Self {
+            inner: anyhow::Error::msg(msg),
+            code,
+            detail: None,
+        }
+    }
+
+    /// machine readable context for the error body, like the limit that was
+    /// hit, so clients dont have to parse the message
+    pub fn with_detail(mut self, detail: serde_json::Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    pub fn bad_request(msg: impl Display + Send + Sync + 'static) -> Self {
+        Self::new(ErrorCode::InvalidRequest, msg)
+    }
+
+    pub fn forbidden(msg: impl Display + Send + Sync + 'static) -> Self {
+        Self::new(ErrorCode::Forbidden, msg)
+    }
+
+    pub fn not_found(msg: impl Display + Send + Sync + 'static) -> Self {
+        Self::new(ErrorCode::NotFound, msg)
+    }
+
+    pub fn conflict(msg: impl Display + Send + Sync + 'static) -> Self {
+        Self::new(ErrorCode::Conflict, msg)
+    }
+
+    pub fn cancelled() -> Self {
+        Cancelled.into()
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.code.kind()
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// whether the operation was stopped because we are shutting down
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.is::<Cancelled>()
+    }
+}
+
 impl<E> From<E> for AppError
 where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self { inner: err.into() }
+        let inner = err.into();
+        let code = if inner.is::<Cancelled>() {
+            ErrorCode::ShuttingDown
+        } else if inner
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::InvalidData)
+        {
+            // what blocks fail to decode with
+            ErrorCode::StoreCorrupt
+        } else {
+            ErrorCode::Internal
+        };
+        Self {
+            inner,
+            code,
+            detail: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "operation cancelled")
     }
 }
 
-#[derive(Serialize)]
-struct ErrorBody {
+impl std::error::Error for Cancelled {}
+
+/// the body of every error response
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub(crate) struct ErrorBody {
     error: String,
+    code: ErrorCode,
+    /// the `x-request-id` of the request, if it had one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    request_id: Option<SmolStr>,
+    /// more about the error, its fields depend on the code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    detail: Option<serde_json::Value>,
 }
+
+impl AppError {
+    /// what the error response says, without a request id
+    pub(crate) fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.inner.to_string(),
+            code: self.code,
+            request_id: None,
+            detail: self.detail.clone(),
+        }
+    }
+}
+
 impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorBody {
-                error: self.inner.to_string(),
-            }),
-        )
-            .into_response()
+    fn into_response(self) -> Response {
+        let body = self.body();
+        let mut response = (self.kind().status(), Json(body.clone())).into_response();
+        response.extensions_mut().insert(body);
+        response
     }
 }
 
+/// puts the x-request-id of the request into error bodies, so users can
+/// quote it when they report one
+pub async fn with_request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .map(SmolStr::new);
+    let mut response = next.run(request).await;
+    let (Some(id), Some(mut body)) = (id, response.extensions_mut().remove::<ErrorBody>()) else {
+        return response;
+    };
+    body.request_id = Some(id);
+    let Ok(bytes) = serde_json::to_vec(&body) else {
+        return response;
+    };
+    response.headers_mut().remove(CONTENT_LENGTH);
+    *response.body_mut() = Body::from(bytes);
+    response
+}
+
 pub type AppResult<T> = Result<T, AppError>;
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// logs panics with a backtrace and counts them. the default hook only
+/// prints to stderr, which is easy to miss for a panic in a background thread
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        PANICS.fetch_add(1, Ordering::Relaxed);
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        tracing::error!("{info}\n{backtrace}");
+    }));
+}
+
+pub fn panic_count() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_kinds_map_to_status() {
+        let internal = AppError::from(std::io::Error::other("disk on fire"));
+        assert_eq!(internal.kind(), ErrorKind::Internal);
+        assert_eq!(
+            internal.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        let bad = AppError::bad_request("limit must be between 1 and 10");
+        assert_eq!(bad.to_string(), "limit must be between 1 and 10");
+        assert_eq!(bad.into_response().status(), StatusCode::BAD_REQUEST);
+        let missing = AppError::not_found("no such nsid");
+        assert_eq!(missing.into_response().status(), StatusCode::NOT_FOUND);
+        assert_eq!(AppError::cancelled().code(), ErrorCode::ShuttingDown);
+        let corrupt = std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupt block");
+        assert_eq!(AppError::from(corrupt).code(), ErrorCode::StoreCorrupt);
+    }
+
+    #[test]
+    fn test_detail_is_only_sent_when_set() {
+        let body = serde_json::to_value(AppError::bad_request("no").body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "no", "code": "INVALID_REQUEST" })
+        );
+        let err = AppError::new(ErrorCode::InvalidLimit, "limit too big")
+            .with_detail(serde_json::json!({ "max": 10 }));
+        let body = serde_json::to_value(err.body()).unwrap();
+        assert_eq!(body["detail"]["max"], 10);
+    }
+
+    #[test]
+    fn test_error_codes_are_listed_once() {
+        let names = ErrorCode::ALL
+            .iter()
+            .map(|code| serde_json::to_value(code).unwrap())
+            .collect::<Vec<_>>();
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[..i].contains(name), "{name} is listed twice");
+        }
+        // bumped along with every new code, ALL has to list them all
+        assert_eq!(names.len(), 21, "a code is missing from ALL");
+        assert_eq!(ErrorCode::Internal.kind(), ErrorKind::Internal);
+        assert!(
+            ErrorCode::ALL
+                .iter()
+                .all(|code| !code.description().is_empty())
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// the `format=bin` body of /hits, for bulk consumers that dont want to parse
+// (or download) hundreds of thousands of json objects. varints are the
+// ordered-varint encoding the blocks use:
+//
+//   magic    4 bytes, `LTHB`
+//   version  1 byte, currently 1
+//   count    varint, how many hits follow
+//   nsid     varint length, then that many utf-8 bytes
+//   start    varint, the timestamp of the first hit. left out if count is 0
+//   hits     left out if count is 0. the hits oldest first, the same way a
+//            block is encoded by `ItemEncoder`: the count again, the first
+//            hit, then for every other hit the delta of its timestamp delta
+//            (or i64::MIN and the timestamp itself after a jump of over a
+//            day). every hit is a varint length (always 1) and the archived
+//            `HitOp`: 0 created, 1 deleted, 2 deleted in an account purge
+//
+// `decode` reads it back, see the `decode-hits` command
+
+use std::io::{self, Cursor, Read, Write};
+
+use fjall::Slice;
+use smol_str::SmolStr;
+
+use crate::{
+    db::{HitOp, Item, ItemDecoder, ItemEncoder, NsidHit},
+    error::AppResult,
+    utils::{ReadVariableExt, WriteVariableExt},
+};
+
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+const MAGIC: &[u8; 4] = b"LTHB";
+const VERSION: u8 = 1;
+// same as blocks, see `ItemEncoder::reset_over`
+const MAX_DELTA: u64 = 60 * 60 * 24;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// hits of `nsid` as (timestamp, op), oldest first
+pub fn encode(nsid: &str, hits: &[(u64, HitOp)]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(16 + nsid.len() + hits.len() * 3);
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+    out.write_varint(hits.len())?;
+    out.write_varint(nsid.len())?;
+    out.write_all(nsid.as_bytes())?;
+    let Some((start, _)) = hits.first() else {
+        return Ok(out);
+    };
+    out.write_varint(*start)?;
+    // the same bytes a block stores for every op
+    let ops =
+        [HitOp::Create, HitOp::Delete, HitOp::Purge].map(|op| Item::new(0, &NsidHit { op }).data);
+    let mut encoder = ItemEncoder::new(out, hits.len()).reset_over(MAX_DELTA);
+    for (timestamp, op) in hits {
+        encoder.encode_raw(*timestamp, &ops[*op as usize])?;
+    }
+    encoder.finish()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BinHits {
+    pub nsid: SmolStr,
+    pub hits: Vec<(u64, HitOp)>,
+}
+
+pub fn decode(mut bytes: &[u8]) -> AppResult<BinHits> {
+    let mut magic = [0; 4];
+    bytes.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a binary hits body").into());
+    }
+    let mut version = [0];
+    bytes.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        return Err(invalid("unknown binary hits version").into());
+    }
+    let count = bytes.read_varint::<usize>()?;
+    let mut nsid = vec![0; bytes.read_varint::<usize>()?];
+    bytes.read_exact(&mut nsid)?;
+    let nsid = String::from_utf8(nsid).map_err(|_| invalid("nsid isnt utf-8"))?;
+    let mut hits = Vec::with_capacity(count);
+    if count > 0 {
+        let start = bytes.read_varint::<u64>()?;
+        let decoder = ItemDecoder::new(Cursor::new(Slice::from(bytes)), start)?;
+        if decoder.item_count() != count {
+            return Err(invalid("hit counts dont match").into());
+        }
+        for item in decoder {
+            let item = item?;
+            hits.push((item.timestamp, item.deser()?.op));
+        }
+    }
+    if hits.len() != count {
+        return Err(invalid("body ends before the last hit").into());
+    }
+    Ok(BinHits {
+        nsid: SmolStr::new(nsid),
+        hits,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let nsid = "app.bsky.feed.like";
+        let hits = vec![
+            (1000, HitOp::Create),
+            (1000, HitOp::Delete),
+            (1003, HitOp::Create),
+            (1004, HitOp::Purge),
+            // a clock jump resets the delta chain
+            (1004 + MAX_DELTA * 10, HitOp::Create),
+            (1005 + MAX_DELTA * 10, HitOp::Delete),
+        ];
+        let bytes = encode(nsid, &hits).unwrap();
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.nsid, nsid);
+        assert_eq!(decoded.hits, hits);
+
+        let empty = decode(&encode(nsid, &[]).unwrap()).unwrap();
+        assert_eq!(empty.nsid, nsid);
+        assert!(empty.hits.is_empty());
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(b"LTHA\x01").is_err());
+    }
+
+    #[test]
+    fn test_ops_are_one_byte() {
+        for (op, byte) in [(HitOp::Create, 0), (HitOp::Delete, 1), (HitOp::Purge, 2)] {
+            assert_eq!(Item::new(0, &NsidHit { op }).data.as_slice(), [byte]);
+        }
+    }
+
+    #[test]
+    fn test_smaller_than_json() {
+        let hits = (0..10_000)
+            .map(|i| (1_700_000_000 + i / 3, HitOp::Create))
+            .collect::<Vec<_>>();
+        let bytes = encode("app.bsky.feed.like", &hits).unwrap();
+        // `{"timestamp":1700000000,"deleted":false}` is 40 bytes
+        assert!(bytes.len() < hits.len() * 4, "{}", bytes.len());
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+use rclite::Arc;
+use smol_str::{SmolStr, ToSmolStr};
+use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::{
+    config,
+    db::{Db, DbConfig, EventRecord, ShutdownPhases},
+    enrich::{EventEnricher, RuleEnricher},
+    error::{AppError, AppResult},
+    jetstream::EventSource,
+    utils::{CLOCK, Coalescer, DedupWindow, RelativeDateTime, get_time},
+    webhook::{self, WebhookConfig},
+};
+
+pub const DEFAULT_JETSTREAM_URLS: &[&str] = &[
+    "wss://jetstream2.fr.hose.cam/subscribe",
+    "wss://jetstream.fire.hose.cam/subscribe",
+    "wss://jetstream1.us-west.bsky.network/subscribe",
+    "wss://jetstream2.us-west.bsky.network/subscribe",
+];
+
+// events are ingested in batches of up to 500, waiting at most 20ms to fill
+// one so slow periods dont pay the per batch cost for every event
+const INGEST_BATCH: Coalescer = Coalescer {
+    max_items: 500,
+    max_delay: Duration::from_millis(20),
+};
+
+// seconds of event time a redelivered event is recognized within,
+// `DEDUP_WINDOW_SECS`. 0 turns it off
+const DEFAULT_DEDUP_WINDOW_SECS: u64 = 30;
+
+fn dedup_window() -> Option<DedupWindow> {
+    let secs = config::env_or("DEDUP_WINDOW_SECS", DEFAULT_DEDUP_WINDOW_SECS, |secs| {
+        secs.parse().ok()
+    });
+    (secs > 0).then(|| DedupWindow::new(Duration::from_secs(secs)))
+}
+
+fn enricher_from_env() -> Option<Box<dyn EventEnricher>> {
+    RuleEnricher::from_env().map(|enricher| Box::new(enricher) as Box<dyn EventEnricher>)
+}
+
+pub struct InstanceConfig {
+    // None is the default instance, served on the flat routes
+    pub name: Option<SmolStr>,
+    pub db: DbConfig,
+    pub urls: Vec<SmolStr>,
+    // where new nsids are announced, see `webhook`
+    pub webhook: Option<WebhookConfig>,
+    // where alerts are sent, see `webhook::start_alerts`
+    pub alert_webhook: Option<WebhookConfig>,
+    // derives more records from the events, see `enrich`
+    pub enricher: Option<Box<dyn EventEnricher>>,
+}
+
+impl InstanceConfig {
+    /// reads `INSTANCES` (comma separated names) and for every name
+    /// `INSTANCE_<NAME>_PATH` and `INSTANCE_<NAME>_JETSTREAM_URLS`.
+    /// without `INSTANCES` we run a single default instance. all instances
+    /// announce to the same `ONBOARDING_WEBHOOK_URL`
+    pub fn from_env(db: impl Fn() -> DbConfig) -> Vec<Self> {
+        let names = config::env_or("INSTANCES", None, |names| {
+            Some(Some(
+                names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(SmolStr::new)
+                    .collect::<Vec<_>>(),
+            ))
+        });
+        let Some(names) = names else {
+            return vec![Self {
+                name: None,
+                db: db(),
+                urls: DEFAULT_JETSTREAM_URLS
+                    .iter()
+                    .map(|url| url.to_smolstr())
+                    .collect(),
+                webhook: WebhookConfig::from_env(),
+                alert_webhook: WebhookConfig::alerts_from_env(),
+                enricher: enricher_from_env(),
+            }];
+        };
+        names
+            .into_iter()
+            .map(|name| {
+                let key = |key: &str| format!("INSTANCE_{}_{key}", name.to_uppercase());
+                let path = config::env_or(&key("PATH"), format!(".fjall_data_{name}"), |path| {
+                    Some(path.to_owned())
+                });
+                let mut cfg = db().path(path);
+                cfg.cold_path = cfg.cold_path.map(|path| path.join(name.as_str()));
+                let default_urls = DEFAULT_JETSTREAM_URLS
+                    .iter()
+                    .map(|url| url.to_smolstr())
+                    .collect::<Vec<_>>();
+                let urls = config::env_or(&key("JETSTREAM_URLS"), default_urls, |urls| {
+                    Some(urls.split(',').map(|url| url.trim().to_smolstr()).collect())
+                });
+                Self {
+                    name: Some(name),
+                    db: cfg,
+                    urls,
+                    webhook: WebhookConfig::from_env(),
+                    alert_webhook: WebhookConfig::alerts_from_env(),
+                    enricher: enricher_from_env(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// one tracker: a db and the tasks that feed and maintain it
+pub struct Instance {
+    pub name: Option<SmolStr>,
+    pub db: Arc<Db>,
+    consume_events: JoinHandle<AppResult<()>>,
+    ingest_events: std::thread::JoinHandle<()>,
+    counts_flusher: std::thread::JoinHandle<()>,
+    db_task: JoinHandle<()>,
+    notifier: Option<JoinHandle<()>>,
+    alert_notifier: Option<JoinHandle<()>>,
+}
+
+impl Instance {
+    pub fn start(cfg: InstanceConfig, cancel_token: &CancellationToken) -> AppResult<Self> {
+        let span = match cfg.name.as_ref() {
+            Some(name) => tracing::info_span!("instance", name = %name),
+            None => tracing::Span::none(),
+        };
+        let _entered = span.clone().entered();
+
+        let db = Arc::new(Db::new(cfg.db, cancel_token.child_token())?);
+        match db.schema_version() {
+            Ok(version) => tracing::info!("db schema version {version}"),
+            Err(err) => tracing::error!("cant read db schema version: {err}"),
+        }
+
+        let mut jetstream = EventSource::new(cfg.urls, db.upstream())?;
+
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(1000);
+        let mut dedup = dedup_window();
+        let enricher = cfg.enricher;
+        let consume_events = tokio::spawn({
+            let consume_cancel = cancel_token.child_token();
+            let db = db.clone();
+            async move {
+                jetstream.connect().await?;
+                loop {
+                    if !db.is_writable() {
+                        tracing::warn!("storage is degraded, pausing jetstream reader...");
+                        tokio::select! {
+                            _ = db.writable() => tracing::info!("storage recovered, resuming jetstream reader"),
+                            _ = consume_cancel.cancelled() => break Ok(()),
+                        }
+                    }
+                    if db.is_ingest_paused() {
+                        // disconnect so we dont have to keep up with the stream,
+                        // we will resume from the cursor once unpaused
+                        tracing::warn!("ingest paused, disconnecting from jetstream...");
+                        jetstream.disconnect().await;
+                        tokio::select! {
+                            _ = db.ingest_running() => {}
+                            _ = consume_cancel.cancelled() => break Ok(()),
+                        }
+                        jetstream.connect().await?;
+                    }
+                    tokio::select! {
+                        maybe_event = jetstream.read(consume_cancel.child_token()) => match maybe_event {
+                            Ok(event) => {
+                                let time_us = event.time_us();
+                                let derived = enricher
+                                    .as_ref()
+                                    .and_then(|enricher| enricher.enrich(&event))
+                                    .unwrap_or_default();
+                                let records = EventRecord::from_jetstream(event)
+                                    .into_iter()
+                                    .chain(derived);
+                                for record in records {
+                                    // a reconnect without a cursor sends the last few
+                                    // seconds again, they would be counted twice
+                                    let duplicate = dedup
+                                        .as_mut()
+                                        .zip(record.identity(time_us))
+                                        .is_some_and(|(dedup, identity)| {
+                                            dedup.is_duplicate(identity, time_us)
+                                        });
+                                    if duplicate {
+                                        db.observe_duplicate();
+                                        continue;
+                                    }
+                                    event_tx.send((record, time_us)).await?;
+                                }
+                            }
+                            Err(err) => return Err(err),
+                        },
+                        _ = consume_cancel.cancelled() => break Ok(()),
+                    }
+                }
+            }
+            .instrument(span.clone())
+        });
+
+        // before ingest starts, so no new nsid is missed
+        let notifier = cfg
+            .webhook
+            .map(|webhook| webhook::start(db.clone(), webhook, cancel_token.child_token()))
+            .transpose()?;
+        let alert_notifier = cfg
+            .alert_webhook
+            .map(|webhook| webhook::start_alerts(db.clone(), webhook, cancel_token.child_token()))
+            .transpose()?;
+
+        let ingest_events = std::thread::spawn({
+            let db = db.clone();
+            let span = span.clone();
+            move || {
+                let _entered = span.entered();
+                // a panic loses the batch that was being ingested, not ingest
+                loop {
+                    let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        ingest(&db, &mut event_rx);
+                    }));
+                    if res.is_ok() {
+                        break;
+                    }
+                    tracing::error!("ingest panicked, restarting it");
+                }
+            }
+        });
+
+        // ingest only updates counts in memory, this writes them in batches
+        let counts_flusher = std::thread::spawn({
+            let db = db.clone();
+            let span = span.clone();
+            let cancel = cancel_token.child_token();
+            move || {
+                let _entered = span.entered();
+                db.run_counts_flusher(&cancel);
+            }
+        });
+
+        let db_task = tokio::task::spawn(maintain(db.clone()).instrument(span.clone()));
+
+        Ok(Self {
+            name: cfg.name,
+            db,
+            consume_events,
+            ingest_events,
+            counts_flusher,
+            db_task,
+            notifier,
+            alert_notifier,
+        })
+    }
+
+    /// resolves with the error once the jetstream consumer fails
+    pub async fn consumer_failed(&mut self) -> AppError {
+        (&mut self.consume_events)
+            .await
+            .map_err(AppError::from)
+            .and_then(std::convert::identity)
+            .expect_err("consume events cant return ok")
+    }
+
+    /// waits for the tasks to stop and syncs everything, cancel first. a
+    /// report of how that went is written into the data dir
+    pub async fn shutdown(self) {
+        let mut errors = Vec::new();
+        let start = CLOCK.now();
+        if self.ingest_events.join().is_err() {
+            errors.push("ingest events panicked".to_owned());
+        }
+        let drained = CLOCK.now();
+        if let Err(err) = self.db_task.await {
+            errors.push(format!("cant join db task: {err}"));
+        }
+        if let Some(notifier) = self.notifier {
+            if let Err(err) = notifier.await {
+                errors.push(format!("cant join onboarding notifier: {err}"));
+            }
+        }
+        if let Some(notifier) = self.alert_notifier {
+            if let Err(err) = notifier.await {
+                errors.push(format!("cant join alert notifier: {err}"));
+            }
+        }
+        // whatever it didnt get to is written by the sync below
+        self.db.wake_counts_flusher();
+        if self.counts_flusher.join().is_err() {
+            errors.push("counts flusher panicked".to_owned());
+        }
+        let cancelled = CLOCK.now();
+        // a held backup cant keep us from writing out what is buffered
+        if let Err(err) = self.db.unquiesce() {
+            errors.push(format!("cant release quiesce: {err}"));
+        }
+        if let Err(err) = self.db.sync(true) {
+            errors.push(format!("cant sync db: {err}"));
+        }
+        let synced = CLOCK.now();
+        for err in &errors {
+            tracing::error!("{err}");
+        }
+
+        let phases = ShutdownPhases {
+            drain: (drained - start).as_secs_f64(),
+            cancel: (cancelled - drained).as_secs_f64(),
+            sync: (synced - cancelled).as_secs_f64(),
+        };
+        let report = self.db.shutdown_report(phases, errors);
+        match serde_json::to_string(&report) {
+            Ok(json) => tracing::info!(report = %json, "shut down"),
+            Err(err) => tracing::error!("cant serialize shutdown report: {err}"),
+        }
+        if let Err(err) = report.write(&self.db.cfg.path) {
+            tracing::error!("cant write shutdown report: {err}");
+        }
+    }
+}
+
+const DEGRADED_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+// feeds events from the jetstream consumer into the db until it is closed or
+// we shut down
+fn ingest(db: &Db, event_rx: &mut Receiver<(EventRecord, u64)>) {
+    let mut buffer = Vec::new();
+    loop {
+        // events already in flight stay queued until we are unpaused
+        while db.is_ingest_paused() && !db.is_shutting_down() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        // and while storage is degraded, instead of failing every batch. the
+        // reader is paused then too, so they dont pile up
+        let mut warned: Option<Instant> = None;
+        while !db.is_writable() && !db.is_shutting_down() {
+            if warned.is_none_or(|at| at.elapsed() >= DEGRADED_WARN_INTERVAL) {
+                tracing::warn!("storage is degraded, holding back ingest");
+                warned = Some(Instant::now());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let read = INGEST_BATCH.recv(event_rx, &mut buffer, db.eps() as f64);
+        let cursor = buffer.last().map(|(_, time_us)| *time_us);
+        match db.ingest_events(buffer.drain(..).map(|(record, _)| record)) {
+            Ok(_) => {
+                if let Some(cursor) = cursor {
+                    db.observe_cursor(cursor);
+                }
+            }
+            Err(err) => tracing::error!("failed to ingest events: {}", err),
+        }
+        if read == 0 || db.is_shutting_down() {
+            break;
+        }
+    }
+}
+
+// periodic sync, compaction, tiering, recompression and windowed alerts. a
+// panic in one of them is logged by the panic hook and they run again on the
+// next tick
+async fn maintain(db: Arc<Db>) {
+    // the interval adapts to how much is coming in, see `SyncPacer`
+    let sync_sleep = tokio::time::sleep(db.next_sync_interval());
+    tokio::pin!(sync_sleep);
+
+    let compact_period = std::time::Duration::from_secs(60 * 30); // 30 mins
+    let mut compact_interval = tokio::time::interval(compact_period);
+    compact_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut tier_interval = tokio::time::interval(Duration::from_secs(60 * 60)); // 1 hour
+    tier_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut recompress_interval = tokio::time::interval(Duration::from_secs(60 * 60)); // 1 hour
+    recompress_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut alerts_interval = tokio::time::interval(Duration::from_secs(60)); // 1 min
+    alerts_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let sync_db = async || {
+            tokio::task::spawn_blocking({
+                let db = db.clone();
+                let span = tracing::Span::current();
+                move || {
+                    let _entered = span.entered();
+                    if db.is_shutting_down() {
+                        return;
+                    }
+                    // a sync started from the admin api is as good as ours
+                    match db.try_sync(false) {
+                        Some(Ok(_)) => (),
+                        Some(Err(e)) => tracing::error!("failed to sync db: {}", e),
+                        None => tracing::debug!("a sync is already running, skipping"),
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|err| tracing::error!("sync task failed: {err}"));
+        };
+        let probe_db = async || {
+            tokio::task::spawn_blocking({
+                let db = db.clone();
+                let span = tracing::Span::current();
+                move || {
+                    let _entered = span.entered();
+                    if db.is_shutting_down() {
+                        return;
+                    }
+                    let _ = db.probe_storage();
+                }
+            })
+            .await
+            .unwrap_or_else(|err| tracing::error!("storage probe task failed: {err}"));
+        };
+        let compact_db = async || {
+            tokio::task::spawn_blocking({
+                let db = db.clone();
+                let span = tracing::Span::current();
+                move || {
+                    let _entered = span.entered();
+                    if db.is_shutting_down()
+                        || !db.is_writable()
+                        || db.is_ingest_paused()
+                        || db.is_quiesced()
+                    {
+                        return;
+                    }
+                    let end = get_time();
+                    let start = end - compact_period;
+                    let range = start.as_secs()..end.as_secs();
+                    tracing::info!(
+                        {
+                            start = %RelativeDateTime::from_now(start),
+                            end = %RelativeDateTime::from_now(end),
+                        },
+                        "running compaction...",
+                    );
+                    // overlapping blocks first, reads work around them but
+                    // they make them scan more
+                    let compacted = db
+                        .compact_overlaps(db.cfg.max_block_size)
+                        .and_then(|_| db.compact_all(db.cfg.max_block_size, range, false));
+                    match compacted {
+                        Ok(_) => (),
+                        Err(e) if e.is_cancelled() => tracing::info!("compaction cancelled"),
+                        Err(e) => tracing::error!("failed to compact db: {}", e),
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|err| tracing::error!("compaction task failed: {err}"));
+        };
+        let tier_db = async || {
+            tokio::task::spawn_blocking({
+                let db = db.clone();
+                let span = tracing::Span::current();
+                move || {
+                    let _entered = span.entered();
+                    if db.is_shutting_down()
+                        || !db.is_writable()
+                        || db.is_ingest_paused()
+                        || db.is_quiesced()
+                    {
+                        return;
+                    }
+                    match db.tier_cold() {
+                        Ok(_) => (),
+                        Err(e) if e.is_cancelled() => tracing::info!("tiering cancelled"),
+                        Err(e) => {
+                            tracing::error!("failed to move blocks to cold tier: {}", e)
+                        }
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|err| tracing::error!("tiering task failed: {err}"));
+        };
+        let recompress_db = async || {
+            tokio::task::spawn_blocking({
+                let db = db.clone();
+                let span = tracing::Span::current();
+                move || {
+                    let _entered = span.entered();
+                    if db.is_shutting_down()
+                        || !db.is_writable()
+                        || db.is_ingest_paused()
+                        || db.is_quiesced()
+                    {
+                        return;
+                    }
+                    match db.recompress_old() {
+                        Ok(_) => (),
+                        Err(e) if e.is_cancelled() => tracing::info!("recompression cancelled"),
+                        Err(e) => tracing::error!("failed to recompress old blocks: {}", e),
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|err| tracing::error!("recompression task failed: {err}"));
+        };
+        let evaluate_alerts = async || {
+            tokio::task::spawn_blocking({
+                let db = db.clone();
+                let span = tracing::Span::current();
+                move || {
+                    let _entered = span.entered();
+                    if db.is_shutting_down() {
+                        return;
+                    }
+                    if let Err(e) = db.evaluate_alert_windows() {
+                        tracing::error!("failed to evaluate alerts: {}", e);
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|err| tracing::error!("alerts task failed: {err}"));
+        };
+        tokio::select! {
+            _ = &mut sync_sleep => {
+                db.check_free_space();
+                if db.is_writable() {
+                    sync_db().await
+                } else {
+                    probe_db().await
+                }
+                let next = tokio::time::Instant::now() + db.next_sync_interval();
+                sync_sleep.as_mut().reset(next);
+            }
+            _ = compact_interval.tick() => compact_db().await,
+            _ = tier_interval.tick(), if db.has_cold_tier() => tier_db().await,
+            _ = recompress_interval.tick(), if db.cfg.recompress_after.is_some() => {
+                recompress_db().await
+            }
+            _ = alerts_interval.tick() => evaluate_alerts().await,
+            _ = db.shutting_down() => break,
+        }
+    }
+}
@@ -1,40 +1,64 @@
-use std::time::Duration;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
 use smol_str::SmolStr;
 use tokio::net::TcpStream;
 use tokio_util::sync::CancellationToken;
 use tokio_websockets::{ClientBuilder, MaybeTlsStream, Message as WsMessage, WebSocketStream};
 
-use crate::error::AppResult;
+use crate::{
+    db::UpstreamStatus,
+    error::AppResult,
+    replay::{FileReplaySource, ReplayPace},
+};
 
 pub struct JetstreamClient {
     stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     tls_connector: tokio_websockets::Connector,
     urls: Vec<SmolStr>,
+    // time_us of the last event we read
+    last_time_us: Option<u64>,
+    // where to resume from on the next connect
+    cursor: Option<u64>,
+    status: UpstreamStatus,
 }
 
 impl JetstreamClient {
-    pub fn new(urls: impl IntoIterator<Item = impl Into<SmolStr>>) -> AppResult<Self> {
+    pub fn new(
+        urls: impl IntoIterator<Item = impl Into<SmolStr>>,
+        status: UpstreamStatus,
+    ) -> AppResult<Self> {
         Ok(Self {
             stream: None,
             tls_connector: tokio_websockets::Connector::new()?,
             urls: urls.into_iter().map(Into::into).collect(),
+            last_time_us: None,
+            cursor: None,
+            status,
         })
     }
 
     pub async fn connect(&mut self) -> AppResult<()> {
         for uri in &self.urls {
+            let uri = match self.cursor {
+                Some(cursor) => with_cursor(uri, cursor),
+                None => uri.to_string(),
+            };
             let conn_result = ClientBuilder::new()
                 .connector(&self.tls_connector)
-                .uri(uri)?
+                .uri(&uri)?
                 .connect()
                 .await;
             match conn_result {
                 Ok((stream, _)) => {
                     self.stream = Some(stream);
+                    self.cursor = None;
+                    self.status.set_connected(true);
                     tracing::info!("connected to jetstream {}", uri);
                     return Ok(());
                 }
@@ -46,8 +70,49 @@ impl JetstreamClient {
         Err(anyhow!("failed to connect to any jetstream server").into())
     }
 
-    // automatically retries connection, only returning error if it fails many times
+    /// closes the connection, remembering where we left off so the next
+    /// `connect` resumes from the last event we read
+    pub async fn disconnect(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.close().await;
+        }
+        self.status.set_connected(false);
+        self.cursor = self.last_time_us;
+    }
+
+    #[inline(always)]
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // automatically retries connection, only returning error if it fails many times.
+    // frames we cant parse are counted and skipped, see `malformed_frame_count`
     pub async fn read(&mut self, cancel_token: CancellationToken) -> AppResult<JetstreamEvent> {
+        loop {
+            let msg = self.read_frame(cancel_token.clone()).await?;
+            let text = msg.as_text().unwrap_or_default();
+            match JetstreamEvent::parse(text) {
+                Ok(event) => {
+                    self.last_time_us = Some(event.time_us());
+                    return Ok(event);
+                }
+                Err(err) => {
+                    let seen = MALFORMED_FRAMES.fetch_add(1, Ordering::Relaxed) + 1;
+                    // same as unknown kinds, logged now and then
+                    if seen.is_power_of_two() {
+                        tracing::warn!(
+                            { err = %err, seen = seen },
+                            "skipping malformed jetstream frame",
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// the next text message as it was sent, before it is parsed. pings are
+    /// answered and the connection is retried like `read` does
+    pub async fn read_frame(&mut self, cancel_token: CancellationToken) -> AppResult<WsMessage> {
         let mut retry = false;
         loop {
             {
@@ -57,13 +122,13 @@ impl JetstreamClient {
                 tokio::select! {
                     res = stream.next() => match res {
                         Some(Ok(msg)) => {
-                            if let Some(event) = msg
-                                .as_text()
-                                .and_then(|v| serde_json::from_str::<JetstreamEvent>(v).ok())
-                            {
-                                return Ok(event);
+                            if msg.is_text() {
+                                return Ok(msg);
                             } else if msg.is_ping() {
                                 let _ = stream.send(WsMessage::pong(msg.into_payload())).await;
+                            } else if msg.is_close() {
+                                tracing::warn!("jetstream closed the connection");
+                                retry = true;
                             } else {
                                 return Err(anyhow!("unsupported message type").into());
                             }
@@ -79,7 +144,12 @@ impl JetstreamClient {
                     }
                 }
             }
-            // retry until connected
+            // retry until connected, resuming after the last event we read so
+            // nothing sent while we were away is missed
+            if retry {
+                self.status.set_connected(false);
+                self.cursor = self.last_time_us;
+            }
             let mut backoff = Duration::from_secs(1);
             while retry {
                 if backoff.as_secs() > 64 {
@@ -106,7 +176,70 @@ impl JetstreamClient {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// where an instance reads its events from. a `file://` url replays a
+/// fixture written by `capture` instead of connecting to jetstream, with
+/// `?pace=fast` to skip the original timing
+pub enum EventSource {
+    Jetstream(JetstreamClient),
+    Replay(FileReplaySource),
+}
+
+impl EventSource {
+    pub fn new(
+        urls: impl IntoIterator<Item = impl Into<SmolStr>>,
+        status: UpstreamStatus,
+    ) -> AppResult<Self> {
+        let urls = urls.into_iter().map(Into::into).collect::<Vec<SmolStr>>();
+        let Some(file) = urls.first().and_then(|url| url.strip_prefix("file://")) else {
+            return JetstreamClient::new(urls, status).map(Self::Jetstream);
+        };
+        let (path, pace) = match file.split_once('?') {
+            Some((path, "pace=fast")) => (path, ReplayPace::Fast),
+            Some((path, "pace=original")) => (path, ReplayPace::Original),
+            Some((_, query)) => return Err(anyhow!("unknown replay option {query}").into()),
+            None => (file, ReplayPace::Original),
+        };
+        Ok(Self::Replay(FileReplaySource::new(path, pace, status)))
+    }
+
+    pub async fn connect(&mut self) -> AppResult<()> {
+        match self {
+            Self::Jetstream(client) => client.connect().await,
+            Self::Replay(source) => source.connect(),
+        }
+    }
+
+    pub async fn disconnect(&mut self) {
+        match self {
+            Self::Jetstream(client) => client.disconnect().await,
+            Self::Replay(source) => source.disconnect(),
+        }
+    }
+
+    pub async fn read(&mut self, cancel_token: CancellationToken) -> AppResult<JetstreamEvent> {
+        match self {
+            Self::Jetstream(client) => client.read(cancel_token).await,
+            Self::Replay(source) => source.read(cancel_token).await,
+        }
+    }
+}
+
+static UNKNOWN_KINDS: AtomicU64 = AtomicU64::new(0);
+static MALFORMED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// events of kinds we dont know (yet) since we started
+pub fn unknown_kind_count() -> u64 {
+    UNKNOWN_KINDS.load(Ordering::Relaxed)
+}
+
+/// frames that didnt parse as an event since we started, they are skipped
+pub fn malformed_frame_count() -> u64 {
+    MALFORMED_FRAMES.load(Ordering::Relaxed)
+}
+
+// serialized like jetstream sends them, parsed by `kind` (see the
+// Deserialize impl below) so a payload can only ever match one variant
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum JetstreamEvent {
     /// Repository commit event (create/update operations)
@@ -164,6 +297,139 @@ pub enum JetstreamEvent {
         /// Account data
         identity: serde_json::Value,
     },
+
+    /// An event of a kind (or a commit operation) we dont know, kept so
+    /// the cursor still moves past it
+    Unknown {
+        did: String,
+        time_us: u64,
+        /// the kind, `commit/<operation>` for unknown commit operations
+        kind: String,
+    },
+}
+
+impl JetstreamEvent {
+    /// parses a jetstream message, counting (and now and then logging)
+    /// events of unknown kinds
+    pub fn parse(text: &str) -> serde_json::Result<Self> {
+        let event = serde_json::from_str::<Self>(text)?;
+        if let Self::Unknown { kind, .. } = &event {
+            let seen = UNKNOWN_KINDS.fetch_add(1, Ordering::Relaxed) + 1;
+            // 1st, 2nd, 4th, 8th... so a new kind shows up without flooding
+            if seen.is_power_of_two() {
+                tracing::warn!({ kind = %kind, seen = seen }, "unknown jetstream event kind");
+            }
+        }
+        Ok(event)
+    }
+
+    pub fn time_us(&self) -> u64 {
+        match self {
+            JetstreamEvent::Commit { time_us, .. }
+            | JetstreamEvent::Delete { time_us, .. }
+            | JetstreamEvent::Identity { time_us, .. }
+            | JetstreamEvent::Account { time_us, .. }
+            | JetstreamEvent::Unknown { time_us, .. } => *time_us,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for JetstreamEvent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // every field of every kind, `kind` decides which ones have to be
+        // there. fields we dont know are skipped
+        #[derive(Deserialize)]
+        struct RawEvent {
+            did: String,
+            time_us: u64,
+            kind: String,
+            commit: Option<RawCommit>,
+            identity: Option<serde_json::Value>,
+            account: Option<serde_json::Value>,
+        }
+
+        #[derive(Deserialize)]
+        struct RawCommit {
+            rev: String,
+            operation: String,
+            collection: String,
+            rkey: String,
+            cid: Option<String>,
+            record: Option<serde_json::Value>,
+        }
+
+        let RawEvent {
+            did,
+            time_us,
+            kind,
+            commit,
+            identity,
+            account,
+        } = RawEvent::deserialize(deserializer)?;
+        let event = match kind.as_str() {
+            "commit" => {
+                let commit = commit.ok_or_else(|| D::Error::missing_field("commit"))?;
+                match commit.operation.as_str() {
+                    "create" | "update" => match (commit.cid, commit.record) {
+                        (Some(cid), Some(record)) => Self::Commit {
+                            did,
+                            time_us,
+                            kind,
+                            commit: JetstreamEventCommit {
+                                cid,
+                                record,
+                                rev: commit.rev,
+                                operation: commit.operation,
+                                collection: commit.collection,
+                                rkey: commit.rkey,
+                            },
+                        },
+                        // broken, but the cursor still has to move past it.
+                        // counted with the unknown kinds
+                        (cid, _) => Self::Unknown {
+                            did,
+                            time_us,
+                            kind: format!(
+                                "commit/{} without {}",
+                                commit.operation,
+                                if cid.is_none() { "cid" } else { "record" },
+                            ),
+                        },
+                    },
+                    "delete" => Self::Delete {
+                        did,
+                        time_us,
+                        kind,
+                        commit: JetstreamEventDelete {
+                            rev: commit.rev,
+                            operation: commit.operation,
+                            collection: commit.collection,
+                            rkey: commit.rkey,
+                        },
+                    },
+                    operation => Self::Unknown {
+                        did,
+                        time_us,
+                        kind: format!("commit/{operation}"),
+                    },
+                }
+            }
+            "identity" => Self::Identity {
+                did,
+                time_us,
+                kind,
+                identity: identity.ok_or_else(|| D::Error::missing_field("identity"))?,
+            },
+            "account" => Self::Account {
+                did,
+                time_us,
+                kind,
+                identity: account.ok_or_else(|| D::Error::missing_field("account"))?,
+            },
+            _ => Self::Unknown { did, time_us, kind },
+        };
+        Ok(event)
+    }
 }
 
 /// Repository commit operation details
@@ -195,3 +461,76 @@ pub struct JetstreamEventDelete {
     /// Record key that was deleted
     pub rkey: String,
 }
+
+// the urls can already have a query, like `?wantedCollections=...`
+fn with_cursor(uri: &str, cursor: u64) -> String {
+    let sep = if uri.contains('?') { '&' } else { '?' };
+    format!("{uri}{sep}cursor={cursor}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // captured from jetstream, with the account anonymized
+    const COMMIT: &str = r#"{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","time_us":1729000000085498,"kind":"commit","commit":{"rev":"3l6kkrh6rjuxf","operation":"create","collection":"app.bsky.feed.like","rkey":"3l6kkfp7t2gfb","record":{"$type":"app.bsky.feed.like","createdAt":"2024-10-15T13:46:40.012Z","subject":{"cid":"bafyreiaxmq2xvcw3kqlqkl2cdiug2vxm4n6lwpmbkcszdspfmlyqdfm7ay","uri":"at://did:plc:z72i7hdynmk6r22z27h6tvur/app.bsky.feed.post/3l6kkf2yvcs2y"}},"cid":"bafyreiopprj6p2pypqxipuud3t6a6voao5slkfut47476vg2goyxnw42pd"}}"#;
+    const DELETE: &str = r#"{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","time_us":1729000000312044,"kind":"commit","commit":{"rev":"3l6kkrhdyfw2e","operation":"delete","collection":"app.bsky.graph.follow","rkey":"3l5ubsrbnwo2k"}}"#;
+    const IDENTITY: &str = r#"{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","time_us":1729000000512044,"kind":"identity","identity":{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","handle":"someone.bsky.social","seq":2275117561,"time":"2024-10-15T13:46:40.458Z"}}"#;
+    const ACCOUNT: &str = r#"{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","time_us":1729000000612044,"kind":"account","account":{"active":true,"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","seq":2275117562,"time":"2024-10-15T13:46:40.561Z"}}"#;
+
+    #[test]
+    fn test_events_are_parsed_by_kind() {
+        let JetstreamEvent::Commit { commit, .. } = JetstreamEvent::parse(COMMIT).unwrap() else {
+            panic!("not a commit");
+        };
+        assert_eq!(commit.collection, "app.bsky.feed.like");
+        let JetstreamEvent::Delete { commit, .. } = JetstreamEvent::parse(DELETE).unwrap() else {
+            panic!("not a delete");
+        };
+        assert_eq!(commit.rkey, "3l5ubsrbnwo2k");
+        let event = JetstreamEvent::parse(IDENTITY).unwrap();
+        assert!(
+            matches!(event, JetstreamEvent::Identity { .. }),
+            "{event:?}"
+        );
+        // same shape as an identity event, but it is an account event
+        let event = JetstreamEvent::parse(ACCOUNT).unwrap();
+        assert!(matches!(event, JetstreamEvent::Account { .. }), "{event:?}");
+
+        // a commit without its record is broken, not a delete
+        let broken = COMMIT.replace(r#""record":"#, r#""recrd":"#);
+        let JetstreamEvent::Unknown { kind, .. } = JetstreamEvent::parse(&broken).unwrap() else {
+            panic!("not unknown");
+        };
+        assert_eq!(kind, "commit/create without record");
+        // the kind decides, not which fields happen to be there
+        let mislabeled = ACCOUNT.replace(r#""kind":"account""#, r#""kind":"identity""#);
+        assert!(JetstreamEvent::parse(&mislabeled).is_err());
+    }
+
+    #[test]
+    fn test_unknown_kinds_are_counted() {
+        let before = unknown_kind_count();
+        let future = r#"{"did":"did:plc:3f2ba0d5a4c9e8b7f6a1d2c3","time_us":1729000000712044,"kind":"labels","labels":{"seq":1}}"#;
+        let event = JetstreamEvent::parse(future).unwrap();
+        let JetstreamEvent::Unknown { kind, time_us, .. } = &event else {
+            panic!("not unknown: {event:?}");
+        };
+        assert_eq!((kind.as_str(), *time_us), ("labels", 1729000000712044));
+        let archive = DELETE.replace(r#""operation":"delete""#, r#""operation":"archive""#);
+        let JetstreamEvent::Unknown { kind, .. } = JetstreamEvent::parse(&archive).unwrap() else {
+            panic!("not unknown");
+        };
+        assert_eq!(kind, "commit/archive");
+        // other tests can parse unknown kinds at the same time
+        assert!(unknown_kind_count() >= before + 2);
+    }
+
+    #[test]
+    fn test_cursor_is_added_to_the_query() {
+        let base = "wss://jetstream2.us-east.bsky.network/subscribe";
+        assert_eq!(with_cursor(base, 5), format!("{base}?cursor=5"));
+        let filtered = format!("{base}?wantedCollections=app.bsky.feed.post");
+        assert_eq!(with_cursor(&filtered, 5), format!("{filtered}&cursor=5"));
+    }
+}
@@ -1,25 +1,42 @@
 use std::{ops::Deref, time::Duration, u64, usize};
 
+use ahash::AHashMap;
 use itertools::Itertools;
 use rclite::Arc;
-use smol_str::ToSmolStr;
+use smol_str::{SmolStr, ToSmolStr};
 use tokio_util::sync::CancellationToken;
-use tracing::Level;
-use tracing_subscriber::EnvFilter;
 
 use crate::{
-    api::serve,
-    db::{Db, DbConfig, EventRecord},
-    error::AppError,
+    api::{BindAddr, serve},
+    build_info::BuildInfo,
+    db::{CountsMerge, Db, DbConfig, EventRecord, InfoOptions, LegacyDb, Tier, check_snapshot_dir},
+    error::install_panic_hook,
+    instance::{DEFAULT_JETSTREAM_URLS, Instance, InstanceConfig},
     jetstream::JetstreamClient,
-    utils::{CLOCK, RelativeDateTime, get_time},
+    report::{
+        CompactReport, DebugReport, DigestReport, SnapshotReport, StatsReport, TotalsCheck,
+        VerifyReport,
+    },
+    utils::{CLOCK, RelativeDateTime},
 };
 
 mod api;
+mod build_info;
+mod config;
 mod db;
+mod enrich;
 mod error;
+mod hits_bin;
+mod instance;
 mod jetstream;
+mod replay;
+mod report;
+mod tail;
+mod telemetry;
+#[cfg(test)]
+mod tests;
 mod utils;
+mod webhook;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
@@ -27,176 +44,174 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(Level::INFO.into())
-                .from_env_lossy(),
-        )
-        .compact()
-        .init();
+    // `--set KEY=value` overrides any setting, of the server and of every
+    // command. read before anything reads a setting, telemetry included
+    let args = std::env::args().collect_vec();
+    let overrides = args
+        .iter()
+        .tuple_windows()
+        .filter(|(flag, _)| *flag == "--set")
+        .map(|(_, arg)| arg.as_str());
+    let config = config::init(overrides);
+    let _telemetry = telemetry::init();
+    if let Err(err) = config {
+        tracing::error!("{err}");
+        return;
+    }
 
+    // only the report commands (debug, stats, compact, verify, digest,
+    // snapshot-info) and tail look at this
+    let json = std::env::args().any(|arg| arg == "--json");
     match std::env::args().nth(1).as_deref() {
         Some("compact") => {
-            compact();
+            compact(json);
             return;
         }
         Some("migrate") => {
             migrate();
             return;
         }
+        Some("migrate-legacy") => {
+            let mut args = std::env::args().skip(2);
+            let (Some(src), Some(dst)) = (args.next(), args.next()) else {
+                tracing::error!("usage: migrate-legacy <src> <dst>");
+                return;
+            };
+            migrate_legacy(&src, &dst);
+            return;
+        }
         Some("debug") => {
-            debug();
+            debug(json);
+            return;
+        }
+        Some("stats") => {
+            stats(json);
+            return;
+        }
+        Some("verify") => {
+            verify(json);
+            return;
+        }
+        Some("export-counts") => {
+            let Some(path) = std::env::args().nth(2) else {
+                tracing::error!("usage: export-counts <file>");
+                return;
+            };
+            export_counts(&path);
+            return;
+        }
+        Some("import-counts") => {
+            let mut args = std::env::args().skip(2);
+            let Some(path) = args.next() else {
+                tracing::error!("usage: import-counts <file> [--merge overwrite|replace|sum]");
+                return;
+            };
+            let merge = match args.skip_while(|arg| arg != "--merge").nth(1) {
+                Some(merge) => match merge.parse::<CountsMerge>() {
+                    Ok(merge) => merge,
+                    Err(err) => {
+                        tracing::error!("{err}");
+                        return;
+                    }
+                },
+                None => CountsMerge::default(),
+            };
+            import_counts(&path, merge);
+            return;
+        }
+        Some("digest") => {
+            digest(json);
+            return;
+        }
+        Some("decode-hits") => {
+            let Some(path) = std::env::args().nth(2) else {
+                tracing::error!("usage: decode-hits <file>");
+                return;
+            };
+            decode_hits(&path);
             return;
         }
         Some("print") => {
             print_all();
             return;
         }
-        Some(x) => {
+        Some("tier-status") => {
+            tier_status();
+            return;
+        }
+        Some("snapshot-info") => {
+            snapshot_info(json);
+            return;
+        }
+        Some("replica") => {
+            replica(std::env::args().any(|arg| arg == "--force")).await;
+            return;
+        }
+        Some("capture") => {
+            capture().await;
+            return;
+        }
+        Some("tail") => {
+            tail(json).await;
+            return;
+        }
+        Some(x) if x != "--set" => {
             tracing::error!("unknown command: {}", x);
             return;
         }
-        None => {}
+        _ => {}
     }
 
-    let cancel_token = CancellationToken::new();
+    tracing::info!("starting server {}", BuildInfo::get());
+    install_panic_hook();
 
-    let db = Arc::new(
-        Db::new(DbConfig::default(), cancel_token.child_token()).expect("couldnt create db"),
-    );
+    let cancel_token = CancellationToken::new();
 
     rustls::crypto::ring::default_provider()
         .install_default()
         .expect("cant install rustls crypto provider");
 
-    let urls = [
-        "wss://jetstream2.fr.hose.cam/subscribe",
-        "wss://jetstream.fire.hose.cam/subscribe",
-        "wss://jetstream1.us-west.bsky.network/subscribe",
-        "wss://jetstream2.us-west.bsky.network/subscribe",
-    ];
-    let mut jetstream = match JetstreamClient::new(urls) {
-        Ok(client) => client,
+    // before the instances start ingesting, so a typo doesnt cost a startup
+    let bind = match BindAddr::from_env() {
+        Ok(bind) => bind,
         Err(err) => {
-            tracing::error!("can't create jetstream client: {err}");
+            tracing::error!("{err}");
             return;
         }
     };
 
-    let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(1000);
-    let consume_events = tokio::spawn({
-        let consume_cancel = cancel_token.child_token();
-        async move {
-            jetstream.connect().await?;
-            loop {
-                tokio::select! {
-                    maybe_event = jetstream.read(consume_cancel.child_token()) => match maybe_event {
-                        Ok(event) => {
-                            let Some(record) = EventRecord::from_jetstream(event) else {
-                                continue;
-                            };
-                            event_tx.send(record).await?;
-                        }
-                        Err(err) => return Err(err),
-                    },
-                    _ = consume_cancel.cancelled() => break Ok(()),
-                }
-            }
-        }
-    });
-
-    let ingest_events = std::thread::spawn({
-        let db = db.clone();
-        move || {
-            let mut buffer = Vec::new();
-            loop {
-                let read = event_rx.blocking_recv_many(&mut buffer, 500);
-                if let Err(err) = db.ingest_events(buffer.drain(..)) {
-                    tracing::error!("failed to ingest events: {}", err);
-                }
-                if read == 0 || db.is_shutting_down() {
-                    break;
-                }
+    let mut instances = Vec::new();
+    for cfg in InstanceConfig::from_env(config_from_env) {
+        let name = cfg.name.clone();
+        match Instance::start(cfg, &cancel_token) {
+            Ok(instance) => instances.push(instance),
+            Err(err) => {
+                tracing::error!("can't start instance {name:?}: {err}");
+                return;
             }
         }
-    });
-
-    let db_task = tokio::task::spawn({
-        let db = db.clone();
-        async move {
-            let sync_period = Duration::from_secs(10);
-            let mut sync_interval = tokio::time::interval(sync_period);
-            sync_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
-
-            let compact_period = std::time::Duration::from_secs(60 * 30); // 30 mins
-            let mut compact_interval = tokio::time::interval(compact_period);
-            compact_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
-
-            loop {
-                let sync_db = async || {
-                    tokio::task::spawn_blocking({
-                        let db = db.clone();
-                        move || {
-                            if db.is_shutting_down() {
-                                return;
-                            }
-                            match db.sync(false) {
-                                Ok(_) => (),
-                                Err(e) => tracing::error!("failed to sync db: {}", e),
-                            }
-                        }
-                    })
-                    .await
-                    .unwrap();
-                };
-                let compact_db = async || {
-                    tokio::task::spawn_blocking({
-                        let db = db.clone();
-                        move || {
-                            if db.is_shutting_down() {
-                                return;
-                            }
-                            let end = get_time();
-                            let start = end - compact_period;
-                            let range = start.as_secs()..end.as_secs();
-                            tracing::info!(
-                                {
-                                    start = %RelativeDateTime::from_now(start),
-                                    end = %RelativeDateTime::from_now(end),
-                                },
-                                "running compaction...",
-                            );
-                            match db.compact_all(db.cfg.max_block_size, range, false) {
-                                Ok(_) => (),
-                                Err(e) => tracing::error!("failed to compact db: {}", e),
-                            }
-                        }
-                    })
-                    .await
-                    .unwrap();
-                };
-                tokio::select! {
-                    _ = sync_interval.tick() => sync_db().await,
-                    _ = compact_interval.tick() => compact_db().await,
-                    _ = db.shutting_down() => break,
-                }
-            }
-        }
-    });
+    }
+    if instances.is_empty() {
+        tracing::error!("no instances configured");
+        return;
+    }
 
+    let served = instances
+        .iter()
+        .map(|instance| (instance.name.clone(), instance.db.clone()))
+        .collect();
+    let consumers_failed = futures_util::future::select_all(
+        instances
+            .iter_mut()
+            .map(|instance| Box::pin(instance.consumer_failed())),
+    );
     tokio::select! {
-        res = serve(db.clone(), cancel_token.child_token()) => {
+        res = serve(served, &bind, cancel_token.child_token()) => {
             if let Err(e) = res {
                 tracing::error!("serve failed: {}", e);
             }
         }
-        res = consume_events => {
-            let err =
-                res
-                .map_err(AppError::from)
-                .and_then(std::convert::identity)
-                .expect_err("consume events cant return ok");
+        (err, _, _) = consumers_failed => {
             tracing::error!("consume events failed: {}", err);
         },
         _ = tokio::signal::ctrl_c() => {
@@ -207,51 +222,411 @@ async fn main() {
 
     tracing::info!("shutting down...");
     cancel_token.cancel();
-    ingest_events.join().expect("failed to join ingest events");
-    db_task.await.expect("cant join db task");
-    db.sync(true).expect("cant sync db");
+    for instance in instances {
+        instance.shutdown().await;
+    }
+}
+
+// optional settings from the environment
+fn config_from_env() -> DbConfig {
+    let mut cfg = DbConfig::default();
+    let secs = |key: &str, default: Duration| {
+        Duration::from_secs(config::env_or(key, default.as_secs(), |s| s.parse().ok()))
+    };
+    cfg.broadcast_capacity = config::env_or("BROADCAST_CAPACITY", cfg.broadcast_capacity, |s| {
+        s.parse().ok()
+    });
+    cfg.watchlist = config::env_or("WATCHLIST", cfg.watchlist, |dids| {
+        Some(
+            dids.split(',')
+                .map(str::trim)
+                .filter(|did| !did.is_empty())
+                .map(SmolStr::new)
+                .collect(),
+        )
+    });
+    cfg.max_watchlist = config::env_or("MAX_WATCHLIST", cfg.max_watchlist, |s| s.parse().ok());
+    cfg.purge_threshold =
+        config::env_or("PURGE_THRESHOLD", cfg.purge_threshold, |s| s.parse().ok());
+    cfg.purge_window = secs("PURGE_WINDOW_SECS", cfg.purge_window);
+    cfg.purge_tracked_dids = config::env_or("PURGE_TRACKED_DIDS", cfg.purge_tracked_dids, |s| {
+        s.parse().ok()
+    });
+    cfg.longtail_threshold = config::env_or("LONGTAIL_THRESHOLD", cfg.longtail_threshold, |s| {
+        s.parse().ok()
+    });
+    cfg.min_sync_interval = secs("MIN_SYNC_INTERVAL_SECS", cfg.min_sync_interval);
+    cfg.max_sync_interval = secs("MAX_SYNC_INTERVAL_SECS", cfg.max_sync_interval);
+    // 0 would have the flusher spin
+    cfg.counts_flush_interval = Duration::from_secs(config::env_or(
+        "COUNTS_FLUSH_INTERVAL_SECS",
+        cfg.counts_flush_interval.as_secs(),
+        |s| s.parse().ok().filter(|secs| *secs > 0),
+    ));
+    cfg.counts_flush_max_dirty =
+        config::env_or("COUNTS_FLUSH_MAX_DIRTY", cfg.counts_flush_max_dirty, |s| {
+            s.parse().ok()
+        });
+    // privacy mode, 0 would divide by zero
+    cfg.timestamp_granularity = config::env_or(
+        "TIMESTAMP_GRANULARITY_SECS",
+        cfg.timestamp_granularity,
+        |s| s.parse().ok().filter(|secs| *secs > 0),
+    );
+    // a json array of rules, see `AlertRule`
+    cfg.alert_rules = config::env_or("ALERT_RULES", cfg.alert_rules, |rules| {
+        serde_json::from_str(rules)
+            .inspect_err(|err| tracing::warn!("ignoring ALERT_RULES: {err}"))
+            .ok()
+    });
+    // 0 turns the free space check off
+    let min_free_space_mb = config::env_or("MIN_FREE_SPACE_MB", 512, |s| s.parse::<u64>().ok());
+    cfg.min_free_space = min_free_space_mb * 1024 * 1024;
+    // 0 leaves old blocks as they are
+    let recompress_after_days =
+        config::env_or("RECOMPRESS_AFTER_DAYS", 0, |s| s.parse::<u64>().ok());
+    cfg.recompress_after = (recompress_after_days > 0)
+        .then(|| Duration::from_secs(recompress_after_days * 60 * 60 * 24));
+    cfg.recompress_tier = config::env_or("RECOMPRESS_CODEC", cfg.recompress_tier, Tier::from_codec);
+    cfg.recompress_level =
+        config::env_or("RECOMPRESS_LEVEL", cfg.recompress_level, |s| s.parse().ok());
+    let cold_path = config::env_or("COLD_TIER_PATH", None, |path| Some(Some(path.to_owned())));
+    let cold_after_days = config::env_or("COLD_TIER_AFTER_DAYS", 90, |s| s.parse::<u64>().ok());
+    let Some(cold_path) = cold_path else {
+        return cfg;
+    };
+    cfg.cold_tier(
+        cold_path,
+        Duration::from_secs(cold_after_days * 60 * 60 * 24),
+    )
 }
 
 fn print_all() {
-    let db = Db::new(DbConfig::default(), CancellationToken::new()).expect("couldnt create db");
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
     let nsids = db.get_nsids().collect::<Vec<_>>();
     let mut count = 0_usize;
     for nsid in nsids {
         println!("{}:", nsid.deref());
         for hit in db.get_hits(&nsid, .., usize::MAX) {
             let hit = hit.expect("aaa");
-            println!("{} {}", hit.timestamp, hit.deser().unwrap().deleted);
+            println!("{} {}", hit.timestamp, hit.deser().unwrap().op.is_deleted());
             count += 1;
         }
     }
     println!("total hits: {}", count);
 }
 
-fn debug() {
-    let db = Db::new(DbConfig::default(), CancellationToken::new()).expect("couldnt create db");
-    let info = db.info().expect("cant get db info");
-    println!("disk size: {}", info.disk_size);
-    for (nsid, blocks) in info.nsids {
-        print!("{nsid}:");
-        let mut last_size = 0;
-        let mut same_size_count = 0;
-        for item_count in blocks {
-            if item_count == last_size {
-                same_size_count += 1;
-            } else {
-                if same_size_count > 1 {
-                    print!("x{}", same_size_count);
-                }
-                print!(" {item_count}");
-                same_size_count = 0;
+// prints a `format=bin` /hits body as `timestamp op` lines
+fn decode_hits(path: &str) {
+    let bytes = std::fs::read(path).expect("cant read file");
+    let decoded = hits_bin::decode(&bytes).expect("cant decode hits");
+    println!("{}:", decoded.nsid);
+    for (timestamp, op) in &decoded.hits {
+        println!("{timestamp} {op:?}");
+    }
+    println!("total hits: {}", decoded.hits.len());
+}
+
+fn tier_status() {
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    for nsid in db.get_nsids() {
+        let status = db.tier_status(&nsid).expect("cant get tier status");
+        let held = status
+            .hold_until
+            .map(|until| {
+                let until = RelativeDateTime::from_now(Duration::from_secs(until));
+                format!(", held hot until {until}")
+            })
+            .unwrap_or_default();
+        println!(
+            "{}: hot {} blocks ({} bytes){held}",
+            nsid.deref(),
+            status.hot_blocks,
+            status.hot_bytes
+        );
+        for (month, segment) in status.cold {
+            println!(
+                "  {month}: {} blocks, {} items ({} bytes)",
+                segment.blocks, segment.items, segment.bytes
+            );
+        }
+    }
+}
+
+// the files are checked before opening, that replays the journal
+fn snapshot_report(cfg: DbConfig) -> (Db, SnapshotReport) {
+    let files = check_snapshot_dir(&cfg.path).expect("cant check snapshot files");
+    let db = Db::new(cfg, CancellationToken::new()).expect("couldnt create db");
+    let marker = db.snapshot_marker().expect("cant read snapshot marker");
+    let cursor = db.stored_cursor().expect("cant read cursor");
+    (db, SnapshotReport::new(files, marker, cursor))
+}
+
+// which quiesce the data dir (usually a filesystem snapshot of one) is from
+fn snapshot_info(json: bool) {
+    let (_, report) = snapshot_report(config_from_env());
+    report::print(&report, json);
+    if !report.is_consistent() {
+        std::process::exit(1);
+    }
+}
+
+// serves the data dir read only: no jetstream, no syncs. meant for a
+// filesystem snapshot, which it refuses unless it is the point in time of its
+// marker (or --force is given)
+async fn replica(force: bool) {
+    install_panic_hook();
+    let bind = match BindAddr::from_env() {
+        Ok(bind) => bind,
+        Err(err) => {
+            tracing::error!("{err}");
+            return;
+        }
+    };
+    let (db, report) = snapshot_report(config_from_env());
+    if !report.is_consistent() {
+        if !force {
+            tracing::error!(
+                "not serving a snapshot that isnt consistent, --force to serve it anyway:\n{report}"
+            );
+            return;
+        }
+        tracing::warn!("serving a snapshot that isnt consistent:\n{report}");
+    }
+    match &report.marker {
+        Some(marker) => tracing::info!(
+            "serving replica of generation {} (cursor {:?})",
+            marker.generation,
+            marker.cursor
+        ),
+        None => tracing::info!("serving replica without a snapshot marker"),
+    }
+    let cancel_token = CancellationToken::new();
+    tokio::select! {
+        res = serve(vec![(None, Arc::new(db))], &bind, cancel_token.child_token()) => {
+            if let Err(e) = res {
+                tracing::error!("serve failed: {}", e);
+            }
+        }
+        _ = tokio::signal::ctrl_c() => tracing::info!("received ctrl+c!"),
+    }
+    cancel_token.cancel();
+}
+
+// [--prefix <prefix>] [--budget <30s|5m|1h>] of debug and stats, a report
+// cut short by the budget says it is partial
+fn info_options() -> InfoOptions {
+    let mut options = InfoOptions::default();
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => options.prefix = args.next().map(SmolStr::from),
+            "--set" => drop(args.next()),
+            "--budget" => {
+                let budget = args.next().as_deref().and_then(parse_duration);
+                options.budget = Some(budget.expect("expected a duration like 60s, 5m or 1h"));
             }
-            last_size = item_count;
+            _ => {}
         }
-        print!("\n");
     }
+    options
+}
+
+fn debug(json: bool) {
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    let info = db.info_with(info_options()).expect("cant get db info");
+    report::print(&DebugReport::new(info), json);
+}
+
+fn stats(json: bool) {
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    let info = db.info_with(info_options()).expect("cant get db info");
+    let counts = db
+        .get_counts()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("cant get counts");
+    report::print(&StatsReport::new(info, counts), json);
 }
 
-fn compact() {
+fn verify(json: bool) {
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    let checks = db
+        .get_nsids()
+        .map(|nsid| {
+            let check = db.verify(&nsid).expect("cant verify nsid");
+            (nsid.to_smolstr(), check)
+        })
+        .collect_vec();
+    let running = db.reconcile_totals().expect("cant recount totals");
+    let report = VerifyReport::new(checks, TotalsCheck::new(running, db.totals()));
+    report::print(&report, json);
+    if !report.ok {
+        std::process::exit(1);
+    }
+}
+
+// digest [prefix] [--from <timestamp>] [--to <timestamp>]
+fn digest(json: bool) {
+    let (mut prefix, mut from, mut to) = (String::new(), 0, u64::MAX);
+    let mut args = std::env::args().skip(2);
+    let timestamp = |value: Option<String>| {
+        value
+            .and_then(|value| value.parse::<u64>().ok())
+            .expect("expected a timestamp")
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = timestamp(args.next()),
+            "--to" => to = timestamp(args.next()),
+            "--set" => drop(args.next()),
+            "--json" => {}
+            _ => prefix = arg,
+        }
+    }
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    let digest = db
+        .content_digest(&prefix, from..=to)
+        .expect("cant digest hits");
+    report::print(&DigestReport::new(digest), json);
+}
+
+// capture --out <file> [--duration <30s|5m|1h>] [--anonymize]
+// records jetstream (JETSTREAM_URLS, or the defaults) into a replay fixture
+async fn capture() {
+    let (mut out, mut duration, mut anonymize) = (None, Duration::from_secs(60), false);
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => out = args.next(),
+            "--duration" => {
+                duration = args
+                    .next()
+                    .as_deref()
+                    .and_then(parse_duration)
+                    .expect("expected a duration like 60s, 5m or 1h")
+            }
+            "--anonymize" => anonymize = true,
+            "--set" => drop(args.next()),
+            _ => {
+                tracing::error!("unknown capture option: {arg}");
+                return;
+            }
+        }
+    }
+    let Some(out) = out else {
+        tracing::error!("usage: capture --out <file> [--duration <duration>] [--anonymize]");
+        return;
+    };
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("cant install rustls crypto provider");
+    let urls = match std::env::var("JETSTREAM_URLS") {
+        Ok(urls) => urls
+            .split(',')
+            .map(|url| url.trim().to_smolstr())
+            .collect_vec(),
+        Err(_) => DEFAULT_JETSTREAM_URLS
+            .iter()
+            .map(|url| url.to_smolstr())
+            .collect_vec(),
+    };
+    let client = JetstreamClient::new(urls, Default::default()).expect("cant create client");
+    let cancel_token = CancellationToken::new();
+    tokio::spawn({
+        let cancel_token = cancel_token.clone();
+        async move {
+            let _ = tokio::signal::ctrl_c().await;
+            cancel_token.cancel();
+        }
+    });
+    tracing::info!("capturing jetstream for {duration:?} into {out}");
+    let written = replay::capture(
+        client,
+        duration,
+        std::path::Path::new(&out),
+        anonymize,
+        cancel_token,
+    )
+    .await
+    .expect("cant capture jetstream");
+    tracing::info!("captured {written} frames into {out}");
+}
+
+// tail (--nsid <nsid>... | --all) [--server <url>] [--json]
+// prints the updates a running server streams, reconnecting when it drops
+async fn tail(json: bool) {
+    let (mut nsids, mut all) = (Vec::new(), false);
+    let port = config::env_or("PORT", 3713, |s| s.parse::<u16>().ok());
+    let mut server = format!("http://localhost:{port}");
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--nsid" => nsids.extend(args.next().map(SmolStr::from)),
+            "--server" => server = args.next().expect("expected a server url"),
+            "--all" => all = true,
+            "--set" => drop(args.next()),
+            "--json" => {}
+            _ => {
+                tracing::error!("unknown tail option: {arg}");
+                return;
+            }
+        }
+    }
+    if nsids.is_empty() != all {
+        tracing::error!("usage: tail (--nsid <nsid>... | --all) [--server <url>] [--json]");
+        return;
+    }
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("cant install rustls crypto provider");
+    let cancel_token = CancellationToken::new();
+    tokio::spawn({
+        let cancel_token = cancel_token.clone();
+        async move {
+            let _ = tokio::signal::ctrl_c().await;
+            cancel_token.cancel();
+        }
+    });
+    let url = tail::stream_url(&server, &nsids);
+    if let Err(err) = tail::run(url, json, cancel_token).await {
+        tracing::error!("cant tail {server}: {err}");
+    }
+}
+
+// `30s`, `5m` or `1h`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = s.split_at(split);
+    let amount = amount.parse::<u64>().ok()?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit).map(Duration::from_secs)
+}
+
+fn export_counts(path: &str) {
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    let file = std::fs::File::create(path).expect("cant create counts dump");
+    let (exported, _) = db
+        .export_counts(std::io::BufWriter::new(file))
+        .expect("cant export counts");
+    tracing::info!("exported counts of {exported} nsids to {path}");
+}
+
+fn import_counts(path: &str, merge: CountsMerge) {
+    let db = Db::new(config_from_env(), CancellationToken::new()).expect("couldnt create db");
+    let file = std::fs::File::open(path).expect("cant open counts dump");
+    let imported = db
+        .import_counts(std::io::BufReader::new(file), merge)
+        .expect("cant import counts");
+    db.sync(true).expect("cant sync");
+    tracing::info!("imported counts of {imported} nsids from {path} ({merge:?})");
+}
+
+fn compact(json: bool) {
     let db = Db::new(
         DbConfig::default().ks(|c| {
             c.max_journaling_size(u64::MAX)
@@ -264,17 +639,7 @@ fn compact() {
     db.major_compact().expect("cant compact");
     std::thread::sleep(Duration::from_secs(5));
     let compacted_info = db.info().expect("cant get db info");
-    println!(
-        "disk size: {} -> {}",
-        info.disk_size, compacted_info.disk_size
-    );
-    for (nsid, blocks) in info.nsids {
-        println!(
-            "{nsid}: {} -> {}",
-            blocks.len(),
-            compacted_info.nsids[&nsid].len()
-        )
-    }
+    report::print(&CompactReport::new(info, compacted_info), json);
 }
 
 fn migrate() {
@@ -320,18 +685,19 @@ fn migrate() {
         threads.push(std::thread::spawn(move || {
             tracing::info!("{}: migrating...", nsid.deref());
             let mut count = 0_u64;
-            for hits in from
-                .get_hits(&nsid, .., usize::MAX)
-                .chunks(100000)
-                .into_iter()
-            {
+            let Some(snapshot) = from.pin_snapshot(&nsid) else {
+                return count;
+            };
+            for hits in from.export_hits(&snapshot, ..).chunks(100000).into_iter() {
                 to.ingest_events(hits.map(|hit| {
                     count += 1;
                     let hit = hit.expect("cant decode hit");
                     EventRecord {
                         nsid: nsid.to_smolstr(),
                         timestamp: hit.timestamp,
-                        deleted: hit.deser().unwrap().deleted,
+                        op: hit.deser().unwrap().op,
+                        did: None,
+                        rkey: None,
                     }
                 }))
                 .expect("cant record event");
@@ -357,3 +723,90 @@ fn migrate() {
         "migrated {total_count} events in {total_time:?} ({read_per_second:.2} rps, {write_per_second:.2} wps)"
     );
 }
+
+fn migrate_legacy(src: &str, dst: &str) {
+    let from = Arc::new(LegacyDb::open(src).expect("couldnt open legacy db"));
+    let to = Arc::new(
+        Db::new(
+            DbConfig::default().path(dst).ks(|c| {
+                c.max_journaling_size(u64::MAX)
+                    .max_write_buffer_size(u64::MAX)
+                    .compaction_workers(rayon::current_num_threads() * 4)
+                    .flush_workers(rayon::current_num_threads() * 4)
+            }),
+            CancellationToken::new(),
+        )
+        .expect("couldnt create db"),
+    );
+
+    let legacy_counts = from
+        .get_counts()
+        .and_then(|counts| counts.collect::<Result<AHashMap<_, _>, _>>())
+        .expect("cant read legacy counts");
+    let nsids = from.get_nsids().collect::<Vec<_>>();
+    let mut threads = Vec::with_capacity(nsids.len());
+    let start = CLOCK.now();
+    for nsid in nsids {
+        let from = from.clone();
+        let to = to.clone();
+        threads.push(std::thread::spawn(move || {
+            tracing::info!("{}: migrating...", nsid.deref());
+            let (mut count, mut deleted_count) = (0_u128, 0_u128);
+            let events = from.get_events(&nsid).expect("cant read legacy events");
+            for chunk in events.chunks(100000).into_iter() {
+                to.ingest_events(chunk.map(|event| {
+                    let event = event.expect("cant decode legacy event");
+                    if event.op.is_deleted() {
+                        deleted_count += 1;
+                    } else {
+                        count += 1;
+                    }
+                    event
+                }))
+                .expect("cant record event");
+            }
+            tracing::info!(
+                "{}: ingested {} events...",
+                nsid.deref(),
+                count + deleted_count
+            );
+            (nsid.to_smolstr(), count, deleted_count)
+        }));
+    }
+    let mut migrated = Vec::with_capacity(threads.len());
+    for thread in threads {
+        migrated.push(thread.join().expect("thread panicked"));
+    }
+    tracing::info!("starting sync!!!");
+    to.sync(true).expect("cant sync");
+
+    // the legacy counts are authoritative (they also know last_seen), so keep them
+    for (nsid, counts) in &legacy_counts {
+        to.put_count(nsid, counts).expect("cant write counts");
+    }
+
+    let mut mismatches = 0;
+    let (mut total, mut total_deleted) = (0_u128, 0_u128);
+    migrated.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    for (nsid, count, deleted_count) in migrated {
+        total += count;
+        total_deleted += deleted_count;
+        let (expected, expected_deleted) = legacy_counts
+            .get(&nsid)
+            .map_or((0, 0), |counts| (counts.count, counts.deleted_count));
+        let status = if (count, deleted_count) == (expected, expected_deleted) {
+            "ok"
+        } else {
+            mismatches += 1;
+            "MISMATCH"
+        };
+        println!(
+            "{nsid}: {count} created, {deleted_count} deleted (counts say {expected}, {expected_deleted}) {status}"
+        );
+    }
+    println!(
+        "migrated {} events ({total} created, {total_deleted} deleted) in {:?}, {mismatches} mismatched nsids",
+        total + total_deleted,
+        start.elapsed(),
+    );
+}
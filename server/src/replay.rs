@@ -0,0 +1,229 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    db::UpstreamStatus,
+    error::AppResult,
+    jetstream::{JetstreamClient, JetstreamEvent},
+    utils::get_time,
+};
+
+/// one jetstream message as it was received, a line of a fixture
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    pub received_us: u64,
+    // the text as jetstream sent it
+    pub frame: String,
+}
+
+/// the frames of a fixture (zstd compressed json lines), in order
+pub fn read_fixture(
+    path: impl AsRef<Path>,
+) -> AppResult<impl Iterator<Item = AppResult<CapturedFrame>> + Send> {
+    let decoder = zstd::Decoder::new(File::open(path)?)?;
+    Ok(BufReader::new(decoder).lines().map(|line| {
+        let frame: CapturedFrame = serde_json::from_str(&line?)?;
+        AppResult::Ok(frame)
+    }))
+}
+
+/// records jetstream messages for `duration` (or until cancelled) into a
+/// fixture at `out`, returns how many were written. with `anonymize` the
+/// dids are hashed and the strings of records are blanked out, see
+/// `anonymize_frame`
+pub async fn capture(
+    mut client: JetstreamClient,
+    duration: Duration,
+    out: &Path,
+    anonymize: bool,
+    cancel_token: CancellationToken,
+) -> AppResult<usize> {
+    let mut fixture = zstd::Encoder::new(BufWriter::new(File::create(out)?), 19)?;
+    let deadline = Instant::now() + duration;
+    let mut written = 0;
+    client.connect().await?;
+    loop {
+        let msg = tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => break,
+            _ = tokio::time::sleep_until(deadline) => break,
+            msg = client.read_frame(cancel_token.child_token()) => msg?,
+        };
+        let received_us = get_time().as_micros() as u64;
+        let text = msg.as_text().unwrap_or_default();
+        let frame = if anonymize {
+            anonymize_frame(text)?
+        } else {
+            text.to_owned()
+        };
+        serde_json::to_writer(&mut fixture, &CapturedFrame { received_us, frame })?;
+        fixture.write_all(b"\n")?;
+        written += 1;
+    }
+    client.disconnect().await;
+    fixture.finish()?.flush()?;
+    Ok(written)
+}
+
+// makes a frame fit to be checked in without changing its shape or size
+// much: dids are swapped for a did:plc of their hash (so events of the same
+// account still line up), and every string of records and identity or
+// account data is replaced with as many `x`s, except `$type`
+pub fn anonymize_frame(frame: &str) -> AppResult<String> {
+    let mut event = serde_json::from_str::<serde_json::Value>(frame)?;
+    let Some(fields) = event.as_object_mut() else {
+        return Err(anyhow!("jetstream frame isnt an object").into());
+    };
+    if let Some(did) = fields.get_mut("did") {
+        *did = anonymize_did(did.as_str().unwrap_or_default()).into();
+    }
+    if let Some(record) = fields
+        .get_mut("commit")
+        .and_then(|commit| commit.get_mut("record"))
+    {
+        blank_strings(record);
+    }
+    for key in ["identity", "account"] {
+        if let Some(data) = fields.get_mut(key) {
+            blank_strings(data);
+        }
+    }
+    Ok(serde_json::to_string(&event)?)
+}
+
+fn anonymize_did(did: &str) -> String {
+    let hash = xxhash_rust::xxh3::xxh3_128(did.as_bytes());
+    format!("did:plc:{:024x}", hash >> 32)
+}
+
+fn blank_strings(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => *s = "x".repeat(s.len()),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(blank_strings),
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if key != "$type" {
+                    blank_strings(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayPace {
+    // frames are as far apart as they were when captured
+    Original,
+    // as fast as they are read
+    Fast,
+}
+
+/// replays a fixture in place of jetstream. once every frame was read it
+/// stays quiet until cancelled, like a jetstream with nothing left to send
+pub struct FileReplaySource {
+    path: PathBuf,
+    pace: ReplayPace,
+    status: UpstreamStatus,
+    // None until the first connect, and once the fixture ran out
+    frames: Option<Box<dyn Iterator<Item = AppResult<CapturedFrame>> + Send>>,
+    finished: bool,
+    // when the last frame was replayed and when it was received, the next
+    // one is due as much later as it was received
+    last: Option<(Instant, u64)>,
+}
+
+impl FileReplaySource {
+    pub fn new(path: impl Into<PathBuf>, pace: ReplayPace, status: UpstreamStatus) -> Self {
+        Self {
+            path: path.into(),
+            pace,
+            status,
+            frames: None,
+            finished: false,
+            last: None,
+        }
+    }
+
+    pub fn connect(&mut self) -> AppResult<()> {
+        if self.frames.is_none() && !self.finished {
+            self.frames = Some(Box::new(read_fixture(&self.path)?));
+            tracing::info!("replaying jetstream from {}", self.path.display());
+        }
+        self.status.set_connected(true);
+        Ok(())
+    }
+
+    // the position is kept, a reconnect carries on where it left off.
+    // pauses arent replayed
+    pub fn disconnect(&mut self) {
+        self.status.set_connected(false);
+        self.last = None;
+    }
+
+    pub async fn read(&mut self, cancel_token: CancellationToken) -> AppResult<JetstreamEvent> {
+        let Some(frames) = self.frames.as_mut() else {
+            if !self.finished {
+                return Err(anyhow!("not connected, call .connect() first").into());
+            }
+            cancel_token.cancelled().await;
+            return Err(anyhow!("cancelled").into());
+        };
+        // reading a line of a local file doesnt block for long
+        let Some(captured) = frames.next() else {
+            tracing::info!("replay of {} finished", self.path.display());
+            self.frames = None;
+            self.finished = true;
+            cancel_token.cancelled().await;
+            return Err(anyhow!("cancelled").into());
+        };
+        let captured = captured?;
+        if self.pace == ReplayPace::Original {
+            if let Some((at, received_us)) = self.last {
+                let gap = captured.received_us.saturating_sub(received_us);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(at + Duration::from_micros(gap)) => {}
+                    _ = cancel_token.cancelled() => return Err(anyhow!("cancelled").into()),
+                }
+            }
+            self.last = Some((Instant::now(), captured.received_us));
+        }
+        let event = JetstreamEvent::parse(&captured.frame)
+            .map_err(|err| anyhow!("cant parse replayed event: {err}"))?;
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_anonymized_frames_keep_their_shape() {
+        let frame = r#"{"did":"did:plc:eygmaihciaxprqvxpfvl6flk","time_us":1700000000000000,"kind":"commit","commit":{"rev":"3l3qo2vutsw2b","operation":"create","collection":"app.bsky.feed.post","rkey":"3l3qo2vuowo2b","cid":"bafyrei","record":{"$type":"app.bsky.feed.post","text":"hello","langs":["en"]}}}"#;
+        let anonymized = anonymize_frame(frame).unwrap();
+        let event = serde_json::from_str::<serde_json::Value>(&anonymized).unwrap();
+        assert_eq!(
+            event["did"],
+            anonymize_did("did:plc:eygmaihciaxprqvxpfvl6flk")
+        );
+        assert_ne!(event["did"], "did:plc:eygmaihciaxprqvxpfvl6flk");
+        assert_eq!(event["time_us"], 1700000000000000_u64);
+        assert_eq!(event["commit"]["collection"], "app.bsky.feed.post");
+        assert_eq!(
+            event["commit"]["record"],
+            serde_json::json!({ "$type": "app.bsky.feed.post", "text": "xxxxx", "langs": ["xx"] })
+        );
+        // still a jetstream event
+        serde_json::from_str::<JetstreamEvent>(&anonymized).unwrap();
+    }
+}
@@ -0,0 +1,569 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::db::{
+    BlockCheck, ContentDigest, DbInfo, NsidCounts, SnapshotCheck, SnapshotMarker, SnapshotState,
+    Totals,
+};
+
+// output of the cli commands. the text output is rendered from the same
+// structs that are emitted with --json, bump this when their shape changes
+pub const REPORT_SCHEMA_VERSION: u32 = 3;
+
+/// prints the report as json if `json` is set, as text otherwise
+pub fn print(report: &(impl Serialize + Display), json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(report).expect("cant serialize report")
+        );
+    } else {
+        print!("{report}");
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DebugReport {
+    pub schema_version: u32,
+    pub disk_size: u64,
+    // item count of every block, newest first
+    pub nsids: BTreeMap<SmolStr, Vec<usize>>,
+    // only set when --budget ran out, some nsids are missing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+impl DebugReport {
+    pub fn new(info: DbInfo) -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            disk_size: info.disk_size,
+            nsids: info.nsids.into_iter().collect(),
+            partial: info.partial,
+        }
+    }
+}
+
+impl Display for DebugReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "disk size: {}", self.disk_size)?;
+        for (nsid, blocks) in &self.nsids {
+            write!(f, "{nsid}:")?;
+            // runs of same sized blocks are written as ` {size}x{count}`
+            for (count, item_count) in blocks.iter().dedup_with_count() {
+                write!(f, " {item_count}")?;
+                if count > 1 {
+                    write!(f, "x{count}")?;
+                }
+            }
+            writeln!(f)?;
+        }
+        if self.partial {
+            writeln!(f, "partial, the budget ran out before every nsid was read")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NsidStats {
+    pub blocks: usize,
+    pub items: usize,
+    pub count: u128,
+    pub deleted_count: u128,
+    pub purged_count: u128,
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub schema_version: u32,
+    pub disk_size: u64,
+    pub nsids: BTreeMap<SmolStr, NsidStats>,
+    // only set when --budget ran out, some nsids are missing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+impl StatsReport {
+    pub fn new(info: DbInfo, counts: impl IntoIterator<Item = (SmolStr, NsidCounts)>) -> Self {
+        let counts = counts.into_iter().collect::<BTreeMap<_, _>>();
+        let partial = info.partial;
+        let nsids = info
+            .nsids
+            .into_iter()
+            .map(|(nsid, blocks)| {
+                let counts = counts.get(&nsid).cloned().unwrap_or_default();
+                let stats = NsidStats {
+                    blocks: blocks.len(),
+                    items: blocks.iter().sum(),
+                    count: counts.count,
+                    deleted_count: counts.deleted_count,
+                    purged_count: counts.purged_count,
+                    last_seen: counts.last_seen,
+                };
+                (nsid, stats)
+            })
+            .collect();
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            disk_size: info.disk_size,
+            nsids,
+            partial,
+        }
+    }
+}
+
+impl Display for StatsReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "disk size: {}", self.disk_size)?;
+        for (nsid, stats) in &self.nsids {
+            writeln!(
+                f,
+                "{nsid}: {} blocks, {} items, {} created, {} deleted, {} purged, last seen {}",
+                stats.blocks,
+                stats.items,
+                stats.count,
+                stats.deleted_count,
+                stats.purged_count,
+                stats.last_seen
+            )?;
+        }
+        if self.partial {
+            writeln!(f, "partial, the budget ran out before every nsid was read")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactedNsid {
+    pub blocks_before: usize,
+    pub blocks_after: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactReport {
+    pub schema_version: u32,
+    pub disk_size_before: u64,
+    pub disk_size_after: u64,
+    pub nsids: BTreeMap<SmolStr, CompactedNsid>,
+}
+
+impl CompactReport {
+    pub fn new(before: DbInfo, after: DbInfo) -> Self {
+        let nsids = before
+            .nsids
+            .into_iter()
+            .map(|(nsid, blocks)| {
+                let blocks_after = after.nsids.get(&nsid).map_or(0, Vec::len);
+                let compacted = CompactedNsid {
+                    blocks_before: blocks.len(),
+                    blocks_after,
+                };
+                (nsid, compacted)
+            })
+            .collect();
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            disk_size_before: before.disk_size,
+            disk_size_after: after.disk_size,
+            nsids,
+        }
+    }
+}
+
+impl Display for CompactReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "disk size: {} -> {}",
+            self.disk_size_before, self.disk_size_after
+        )?;
+        for (nsid, compacted) in &self.nsids {
+            writeln!(
+                f,
+                "{nsid}: {} -> {}",
+                compacted.blocks_before, compacted.blocks_after
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VerifiedNsid {
+    pub blocks: usize,
+    pub items: usize,
+    pub problems: Vec<String>,
+}
+
+// the running totals against a recount of every nsid's counts. they are
+// replaced with the recount when they differ
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TotalsCheck {
+    pub running: Totals,
+    pub counted: Totals,
+    pub reconciled: bool,
+}
+
+impl TotalsCheck {
+    pub fn new(running: Totals, counted: Totals) -> Self {
+        Self {
+            running,
+            counted,
+            reconciled: running != counted,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub schema_version: u32,
+    pub ok: bool,
+    pub nsids: BTreeMap<SmolStr, VerifiedNsid>,
+    pub totals: TotalsCheck,
+}
+
+impl VerifyReport {
+    pub fn new(
+        checks: impl IntoIterator<Item = (SmolStr, BlockCheck)>,
+        totals: TotalsCheck,
+    ) -> Self {
+        let nsids = checks
+            .into_iter()
+            .map(|(nsid, check)| {
+                let verified = VerifiedNsid {
+                    blocks: check.blocks,
+                    items: check.items,
+                    problems: check.problems,
+                };
+                (nsid, verified)
+            })
+            .collect::<BTreeMap<_, _>>();
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            ok: nsids.values().all(|nsid| nsid.problems.is_empty()),
+            nsids,
+            totals,
+        }
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (nsid, verified) in &self.nsids {
+            writeln!(
+                f,
+                "{nsid}: {} blocks, {} items, {} problems",
+                verified.blocks,
+                verified.items,
+                verified.problems.len()
+            )?;
+            for problem in &verified.problems {
+                writeln!(f, "  {problem}")?;
+            }
+        }
+        let totals = &self.totals;
+        if totals.reconciled {
+            writeln!(
+                f,
+                "totals were off, reconciled: {} created, {} deleted, {} purged (was {}, {}, {})",
+                totals.counted.count,
+                totals.counted.deleted_count,
+                totals.counted.purged_count,
+                totals.running.count,
+                totals.running.deleted_count,
+                totals.running.purged_count
+            )?;
+        }
+        writeln!(f, "{}", if self.ok { "ok" } else { "problems found" })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestReport {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub digest: ContentDigest,
+}
+
+impl DigestReport {
+    pub fn new(digest: ContentDigest) -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            digest,
+        }
+    }
+}
+
+impl Display for DigestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (nsid, digest) in &self.digest.nsids {
+            writeln!(f, "{nsid}: {} ({} hits)", digest.digest, digest.hits)?;
+        }
+        writeln!(f, "digest: {}", self.digest.digest)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotReport {
+    pub schema_version: u32,
+    // checked before the db was opened
+    pub files: SnapshotCheck,
+    // what `_meta` says, should be the same marker as the file
+    pub marker: Option<SnapshotMarker>,
+    pub cursor: Option<u64>,
+}
+
+impl SnapshotReport {
+    pub fn new(files: SnapshotCheck, marker: Option<SnapshotMarker>, cursor: Option<u64>) -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            files,
+            marker,
+            cursor,
+        }
+    }
+
+    /// whether the dir can be served as the point in time of its marker
+    pub fn is_consistent(&self) -> bool {
+        self.files.state == SnapshotState::Consistent && self.files.marker == self.marker
+    }
+}
+
+impl Display for SnapshotReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.marker {
+            Some(marker) => writeln!(
+                f,
+                "generation {} (sync {}), quiesced at {}",
+                marker.generation, marker.sync_generation, marker.quiesced_at
+            )?,
+            None => writeln!(f, "never quiesced")?,
+        }
+        match self.cursor {
+            Some(cursor) => writeln!(f, "cursor: {cursor}")?,
+            None => writeln!(f, "cursor: none")?,
+        }
+        let state = match self.files.state {
+            SnapshotState::Consistent if self.files.marker != self.marker => {
+                "the marker file doesnt match _meta"
+            }
+            SnapshotState::Consistent => "consistent",
+            SnapshotState::NoMarker => "no snapshot marker",
+            SnapshotState::WrittenAfter => "written to after the marker, might be torn",
+        };
+        writeln!(f, "{state}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T>(report: &T, expected: serde_json::Value)
+    where
+        T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug,
+    {
+        let value = serde_json::to_value(report).unwrap();
+        assert_eq!(value, expected);
+        let parsed = serde_json::from_value::<T>(value).unwrap();
+        assert_eq!(&parsed, report);
+    }
+
+    #[test]
+    fn test_debug_report_schema() {
+        let report = DebugReport {
+            schema_version: REPORT_SCHEMA_VERSION,
+            disk_size: 1024,
+            nsids: [(SmolStr::new("app.bsky.feed.like"), vec![3, 3, 2])].into(),
+            partial: false,
+        };
+        round_trip(
+            &report,
+            serde_json::json!({
+                "schema_version": 3,
+                "disk_size": 1024,
+                "nsids": { "app.bsky.feed.like": [3, 3, 2] },
+            }),
+        );
+        assert_eq!(
+            report.to_string(),
+            "disk size: 1024\napp.bsky.feed.like: 3x2 2\n"
+        );
+
+        let partial = DebugReport {
+            partial: true,
+            ..report
+        };
+        round_trip(
+            &partial,
+            serde_json::json!({
+                "schema_version": 3,
+                "disk_size": 1024,
+                "nsids": { "app.bsky.feed.like": [3, 3, 2] },
+                "partial": true,
+            }),
+        );
+        assert!(partial.to_string().ends_with("every nsid was read\n"));
+    }
+
+    #[test]
+    fn test_stats_report_schema() {
+        let report = StatsReport {
+            schema_version: REPORT_SCHEMA_VERSION,
+            disk_size: 1024,
+            nsids: [(
+                SmolStr::new("app.bsky.feed.like"),
+                NsidStats {
+                    blocks: 2,
+                    items: 5,
+                    count: 4,
+                    deleted_count: 1,
+                    purged_count: 0,
+                    last_seen: 1000,
+                },
+            )]
+            .into(),
+            partial: false,
+        };
+        round_trip(
+            &report,
+            serde_json::json!({
+                "schema_version": 3,
+                "disk_size": 1024,
+                "nsids": {
+                    "app.bsky.feed.like": {
+                        "blocks": 2,
+                        "items": 5,
+                        "count": 4,
+                        "deleted_count": 1,
+                        "purged_count": 0,
+                        "last_seen": 1000,
+                    },
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn test_compact_report_schema() {
+        let report = CompactReport {
+            schema_version: REPORT_SCHEMA_VERSION,
+            disk_size_before: 2048,
+            disk_size_after: 1024,
+            nsids: [(
+                SmolStr::new("app.bsky.feed.like"),
+                CompactedNsid {
+                    blocks_before: 10,
+                    blocks_after: 1,
+                },
+            )]
+            .into(),
+        };
+        round_trip(
+            &report,
+            serde_json::json!({
+                "schema_version": 3,
+                "disk_size_before": 2048,
+                "disk_size_after": 1024,
+                "nsids": {
+                    "app.bsky.feed.like": { "blocks_before": 10, "blocks_after": 1 },
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn test_verify_report_schema() {
+        let totals = Totals {
+            count: 2,
+            deleted_count: 1,
+            purged_count: 0,
+        };
+        let report = VerifyReport::new(
+            [(
+                SmolStr::new("app.bsky.feed.like"),
+                BlockCheck {
+                    blocks: 1,
+                    items: 3,
+                    problems: vec!["bad block".to_string()],
+                    overlap: None,
+                },
+            )],
+            TotalsCheck::new(totals, totals),
+        );
+        assert!(!report.ok);
+        round_trip(
+            &report,
+            serde_json::json!({
+                "schema_version": 3,
+                "ok": false,
+                "nsids": {
+                    "app.bsky.feed.like": {
+                        "blocks": 1,
+                        "items": 3,
+                        "problems": ["bad block"],
+                    },
+                },
+                "totals": {
+                    "running": { "count": 2, "deleted_count": 1, "purged_count": 0 },
+                    "counted": { "count": 2, "deleted_count": 1, "purged_count": 0 },
+                    "reconciled": false,
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn test_snapshot_report_schema() {
+        let marker = SnapshotMarker {
+            generation: 2,
+            sync_generation: 7,
+            cursor: Some(1_000_000),
+            quiesced_at: 1000,
+        };
+        let files = SnapshotCheck {
+            state: SnapshotState::Consistent,
+            marker: Some(marker.clone()),
+            marker_written_at: Some(1_000_100),
+            journal_written_at: Some(1_000_050),
+        };
+        let report = SnapshotReport::new(files, Some(marker.clone()), Some(1_000_000));
+        assert!(report.is_consistent());
+        let marker_json = serde_json::json!({
+            "generation": 2,
+            "sync_generation": 7,
+            "cursor": 1_000_000,
+            "quiesced_at": 1000,
+        });
+        round_trip(
+            &report,
+            serde_json::json!({
+                "schema_version": 3,
+                "files": {
+                    "state": "consistent",
+                    "marker": marker_json,
+                    "marker_written_at": 1_000_100,
+                    "journal_written_at": 1_000_050,
+                },
+                "marker": marker_json,
+                "cursor": 1_000_000,
+            }),
+        );
+        // a marker file left from another dir
+        let other = SnapshotReport::new(
+            report.files.clone(),
+            Some(SnapshotMarker {
+                generation: 1,
+                ..marker
+            }),
+            None,
+        );
+        assert!(!other.is_consistent());
+    }
+}
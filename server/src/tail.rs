@@ -0,0 +1,271 @@
+use std::{
+    fmt::Display,
+    io::{IsTerminal, Write},
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use futures_util::{SinkExt, StreamExt};
+use itertools::Itertools;
+use serde::Deserialize;
+use smol_str::SmolStr;
+use tokio::{net::TcpStream, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_websockets::{ClientBuilder, MaybeTlsStream, Message, WebSocketStream};
+
+use crate::error::AppResult;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// the counts of an nsid in a snapshot frame
+#[derive(Debug, Clone, Deserialize)]
+pub struct Counts {
+    pub count: u128,
+    pub deleted_count: u128,
+}
+
+/// how much the counts of an nsid went up since the frame before
+#[derive(Debug, Clone, Deserialize)]
+pub struct Delta {
+    pub count_delta: u128,
+    pub deleted_delta: u128,
+}
+
+/// a /stream_events frame with mode=delta, the parts tail shows
+#[derive(Debug, Clone, Deserialize)]
+pub struct Frame {
+    pub per_second: usize,
+    #[serde(default)]
+    pub snapshot: bool,
+    #[serde(default)]
+    pub events: AHashMap<SmolStr, Counts>,
+    #[serde(default)]
+    pub deltas: AHashMap<SmolStr, Delta>,
+}
+
+/// the /stream_events url of `server` (an http or ws base url) for these
+/// nsids, every nsid if there are none
+pub fn stream_url(server: &str, nsids: &[SmolStr]) -> String {
+    let server = server.trim_end_matches('/');
+    let base = match (
+        server.strip_prefix("http://"),
+        server.strip_prefix("https://"),
+    ) {
+        (Some(rest), _) => format!("ws://{rest}"),
+        (_, Some(rest)) => format!("wss://{rest}"),
+        _ => server.to_owned(),
+    };
+    let mut url = format!("{base}/stream_events?mode=delta");
+    if !nsids.is_empty() {
+        url.push_str("&nsids=");
+        url.push_str(&nsids.iter().join(","));
+    }
+    url
+}
+
+/// a /stream_events connection that reconnects whenever it drops. the
+/// server sends a fresh snapshot on every connect, so nothing is lost but
+/// the deltas of the time in between
+pub struct TailClient {
+    url: String,
+    connector: tokio_websockets::Connector,
+    stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    backoff: Duration,
+}
+
+impl TailClient {
+    pub fn new(url: String) -> AppResult<Self> {
+        Ok(Self {
+            url,
+            connector: tokio_websockets::Connector::new()?,
+            stream: None,
+            backoff: MIN_BACKOFF,
+        })
+    }
+
+    async fn connect(&mut self) -> AppResult<()> {
+        let (stream, _) = ClientBuilder::new()
+            .connector(&self.connector)
+            .uri(&self.url)?
+            .connect()
+            .await?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    // false if we were cancelled while waiting
+    async fn wait(&mut self, cancel_token: &CancellationToken) -> bool {
+        let backoff = self.backoff;
+        self.backoff = (backoff * 2).min(MAX_BACKOFF);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => true,
+            _ = cancel_token.cancelled() => false,
+        }
+    }
+
+    /// the next frame as it was sent and parsed, None once cancelled
+    pub async fn next(&mut self, cancel_token: &CancellationToken) -> Option<(String, Frame)> {
+        loop {
+            let Some(stream) = self.stream.as_mut() else {
+                let connected = tokio::select! {
+                    res = self.connect() => res,
+                    _ = cancel_token.cancelled() => return None,
+                };
+                if let Err(err) = connected {
+                    tracing::warn!("cant connect to {}: {err}", self.url);
+                    if !self.wait(cancel_token).await {
+                        return None;
+                    }
+                }
+                continue;
+            };
+            let msg = tokio::select! {
+                msg = stream.next() => msg,
+                _ = cancel_token.cancelled() => return None,
+            };
+            match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    let text = msg.as_text().unwrap_or_default().to_owned();
+                    match serde_json::from_str::<Frame>(&text) {
+                        Ok(frame) => {
+                            self.backoff = MIN_BACKOFF;
+                            return Some((text, frame));
+                        }
+                        Err(err) => tracing::warn!("cant parse frame: {err}"),
+                    }
+                    continue;
+                }
+                Some(Ok(msg)) if msg.is_ping() => {
+                    let _ = stream.send(Message::pong(msg.into_payload())).await;
+                    continue;
+                }
+                Some(Ok(msg)) if !msg.is_close() => continue,
+                Some(Ok(_)) | None => tracing::warn!("the server closed the stream"),
+                Some(Err(err)) => tracing::warn!("the stream errored: {err}"),
+            }
+            // the server could be restarting, dont hammer it
+            self.stream = None;
+            if !self.wait(cancel_token).await {
+                return None;
+            }
+        }
+    }
+}
+
+// ansi colors, only when printing to a terminal
+struct Style(bool);
+
+impl Style {
+    fn paint(&self, code: &str, text: impl Display) -> String {
+        match self.0 {
+            true => format!("\x1b[{code}m{text}\x1b[0m"),
+            false => text.to_string(),
+        }
+    }
+}
+
+/// the lines tail prints for a frame, a line per nsid and for updates a
+/// summary of the rate. `elapsed` is the time since the frame before
+pub fn render(frame: &Frame, color: bool, elapsed: Duration) -> Vec<String> {
+    let style = Style(color);
+    let mut lines = Vec::new();
+    if frame.snapshot {
+        for (nsid, counts) in frame.events.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            lines.push(format!(
+                "{} {} created, {} deleted",
+                style.paint("1", nsid),
+                counts.count,
+                counts.deleted_count
+            ));
+        }
+        return lines;
+    }
+    if frame.deltas.is_empty() {
+        return lines;
+    }
+    let mut hits = 0;
+    for (nsid, delta) in frame.deltas.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+        hits += delta.count_delta + delta.deleted_delta;
+        let mut line = format!(
+            "{} {}",
+            style.paint("1", nsid),
+            style.paint("32", format_args!("+{}", delta.count_delta))
+        );
+        if delta.deleted_delta > 0 {
+            line.push(' ');
+            line.push_str(&style.paint("31", format_args!("-{}", delta.deleted_delta)));
+        }
+        lines.push(line);
+    }
+    let rate = hits as f64 / elapsed.as_secs_f64().max(0.001);
+    lines.push(style.paint(
+        "2",
+        format_args!(
+            "{rate:.1}/s for these nsids, {}/s overall",
+            frame.per_second
+        ),
+    ));
+    lines
+}
+
+/// prints the frames of `url` to stdout until cancelled, as they were sent
+/// with `json`
+pub async fn run(url: String, json: bool, cancel_token: CancellationToken) -> AppResult<()> {
+    let mut client = TailClient::new(url)?;
+    let color = !json && std::io::stdout().is_terminal();
+    let mut last = Instant::now();
+    while let Some((text, frame)) = client.next(&cancel_token).await {
+        let mut out = std::io::stdout().lock();
+        if json {
+            writeln!(out, "{text}")?;
+            continue;
+        }
+        for line in render(&frame, color, last.elapsed()) {
+            writeln!(out, "{line}")?;
+        }
+        last = Instant::now();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stream_url_and_render() {
+        let nsids = [SmolStr::new("app.bsky.feed.post"), SmolStr::new("app.*")];
+        assert_eq!(
+            stream_url("http://localhost:3713/", &nsids),
+            "ws://localhost:3713/stream_events?mode=delta&nsids=app.bsky.feed.post,app.*"
+        );
+        assert_eq!(
+            stream_url("https://example.com", &[]),
+            "wss://example.com/stream_events?mode=delta"
+        );
+
+        let frame: Frame = serde_json::from_value(serde_json::json!({
+            "per_second": 40,
+            "events": {},
+            "deltas": {
+                "b.nsid": {"count_delta": 3, "deleted_delta": 1, "purged_delta": 0, "last_seen": 5},
+                "a.nsid": {"count_delta": 4, "deleted_delta": 0, "purged_delta": 0, "last_seen": 5},
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            render(&frame, false, Duration::from_secs(2)),
+            [
+                "a.nsid +4",
+                "b.nsid +3 -1",
+                "4.0/s for these nsids, 40/s overall"
+            ]
+        );
+        let colored = render(&frame, true, Duration::from_secs(2));
+        assert_eq!(
+            colored[1],
+            "\x1b[1mb.nsid\x1b[0m \x1b[32m+3\x1b[0m \x1b[31m-1\x1b[0m"
+        );
+    }
+}
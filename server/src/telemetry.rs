@@ -0,0 +1,119 @@
+use tracing::Level;
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// flushes exported spans when dropped, so it is kept until main returns
+#[must_use]
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            // the subscriber is still up but nothing reads logs this late
+            if let Err(err) = provider.shutdown() {
+                eprintln!("failed to flush traces: {err}");
+            }
+        }
+    }
+}
+
+/// logs to stdout, filtered by `RUST_LOG`. with the `otel` feature spans
+/// are also exported over otlp (grpc) to `OTEL_EXPORTER_OTLP_ENDPOINT` if
+/// it is set
+pub fn init() -> TelemetryGuard {
+    let registry = tracing_subscriber::registry()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(Level::INFO.into())
+                .from_env_lossy(),
+        )
+        .with(tracing_subscriber::fmt::layer().compact());
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+
+        let provider = otel::provider();
+        let layer = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("lexicon-tracker"))
+        });
+        registry.with(layer).init();
+        TelemetryGuard { provider }
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        TelemetryGuard {}
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use otel::follow_traceparent;
+
+#[cfg(feature = "otel")]
+mod otel {
+    use axum::http::HeaderMap;
+    use opentelemetry::propagation::Extractor;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider,
+    };
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use crate::config;
+
+    // None if no endpoint is set, exporting is off then
+    pub(super) fn provider() -> Option<SdkTracerProvider> {
+        let endpoint = config::env_or("OTEL_EXPORTER_OTLP_ENDPOINT", None, |endpoint| {
+            Some(Some(endpoint.to_owned()))
+        })?;
+        let service = config::env_or(
+            "OTEL_SERVICE_NAME",
+            "lexicon-tracker".to_owned(),
+            |service| Some(service.to_owned()),
+        );
+        let exporter = match SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(exporter) => exporter,
+            // tracing isnt set up yet
+            Err(err) => {
+                eprintln!("cant export traces, otlp exporter failed: {err}");
+                return None;
+            }
+        };
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(service).build())
+                .build(),
+        )
+    }
+
+    struct Headers<'a>(&'a HeaderMap);
+
+    impl Extractor for Headers<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    /// makes `span` a child of the callers trace if the request has a
+    /// `traceparent` header, so frontend traces link to ours
+    pub fn follow_traceparent(span: &tracing::Span, headers: &HeaderMap) {
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&Headers(headers))
+        });
+        span.set_parent(parent);
+    }
+}
@@ -0,0 +1,2510 @@
+// end to end: jetstream (a local replay of it) through ingest and sync to
+// the http api
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axum::{body::Body, http::Request};
+use fjall::PartitionCreateOptions;
+use futures_util::StreamExt;
+use rclite::Arc;
+use smol_str::SmolStr;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
+
+use crate::{
+    api,
+    db::{Admission, DataSource, Db, DbConfig, EventRecord, HitOp, LabelMap, block_cache},
+    error, hits_bin,
+    instance::{Instance, InstanceConfig},
+    jetstream::{JetstreamClient, JetstreamEvent, malformed_frame_count},
+    replay, tail,
+    webhook::{self, WebhookConfig},
+};
+
+mod support;
+
+const START_US: u64 = 1_700_000_000_000_000;
+const START: u64 = START_US / 1_000_000;
+
+fn commit(second: u64, collection: &str, operation: &str) -> serde_json::Value {
+    let mut commit = serde_json::json!({
+        "rev": "3l3qo2vutsw2b",
+        "operation": operation,
+        "collection": collection,
+        "rkey": format!("3l3qo2vuowo{second}"),
+    });
+    if operation != "delete" {
+        commit["cid"] = "bafyreidc6sydkkbchcyg62v77wbhzvb2mvytlmsychqgwf2xojjtirmzj4".into();
+        commit["record"] = serde_json::json!({ "$type": collection });
+    }
+    serde_json::json!({
+        "did": "did:plc:eygmaihciaxprqvxpfvl6flk",
+        "time_us": START_US + second * 1_000_000,
+        "kind": "commit",
+        "commit": commit,
+    })
+}
+
+async fn get(router: &axum::Router, uri: &str) -> serde_json::Value {
+    let response = router
+        .clone()
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(
+        response.status().is_success(),
+        "{uri}: {}",
+        response.status()
+    );
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_jetstream_to_query() {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let events = vec![
+        commit(0, like, "create"),
+        commit(1, like, "create"),
+        commit(2, like, "create"),
+        commit(3, post, "create"),
+        commit(4, like, "create"),
+        // the server drops us here, we resume from the cursor
+        commit(5, like, "create"),
+        commit(6, post, "create"),
+        commit(7, like, "create"),
+        commit(8, like, "create"),
+        commit(9, like, "delete"),
+    ];
+    let server = support::ReplayServer::start(events, 5, Duration::from_millis(5)).await;
+
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-pipeline-{}",
+        std::process::id()
+    ));
+    let mut db = DbConfig::default().path(&path);
+    // sync often, and write out whatever is buffered every time
+    db.min_sync_interval = Duration::from_millis(50);
+    db.max_sync_interval = Duration::from_millis(100);
+    db.max_last_activity = Duration::ZERO;
+    let cfg = InstanceConfig {
+        name: None,
+        db,
+        urls: vec![server.url.as_str().into()],
+        webhook: None,
+        alert_webhook: None,
+        enricher: None,
+    };
+    let cancel_token = CancellationToken::new();
+    let instance = Instance::start(cfg, &cancel_token).unwrap();
+    let db = instance.db.clone();
+
+    // wait for everything to be synced
+    let synced = async {
+        while db.get_hits(like, .., usize::MAX).count() < 8
+            || db.get_hits(post, .., usize::MAX).count() < 2
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), synced)
+        .await
+        .expect("events werent synced in time");
+
+    assert_eq!(*server.cursors.lock(), [None, Some(START_US + 4_000_000)]);
+
+    let router = api::instance(db.clone());
+    // `to` is the start of the range
+    let uri = format!("/hits?nsid={like}&to={START}&from={}", START + 9);
+    let hits = get(&router, &uri).await;
+    let expected = [0, 1, 2, 4, 5, 7, 8]
+        .into_iter()
+        .map(|second| serde_json::json!({ "timestamp": START + second, "deleted": false }))
+        .chain([serde_json::json!({ "timestamp": START + 9, "deleted": true })])
+        .collect::<Vec<_>>();
+    assert_eq!(hits, serde_json::Value::Array(expected));
+
+    let events = get(&router, "/events").await;
+    assert_eq!(
+        events["events"],
+        serde_json::json!({
+            like: { "count": 7, "deleted_count": 1, "purged_count": 0, "last_seen": START + 9 },
+            post: { "count": 2, "deleted_count": 0, "purged_count": 0, "last_seen": START + 6 },
+        })
+    );
+    assert_eq!(events["totals"]["count"], 9);
+
+    cancel_token.cancel();
+    instance.shutdown().await;
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_malformed_frames_are_skipped() {
+    let like = "app.bsky.feed.like";
+    let mut broken = commit(1, like, "create");
+    broken["time_us"] = "soon".into();
+    let events = vec![
+        commit(0, like, "create"),
+        broken,
+        serde_json::json!([1, 2]),
+        commit(2, like, "create"),
+    ];
+    let server = support::ReplayServer::start(events, usize::MAX, Duration::ZERO).await;
+    let mut client = JetstreamClient::new([server.url.as_str()], Default::default()).unwrap();
+    client.connect().await.unwrap();
+
+    let before = malformed_frame_count();
+    let cancel_token = CancellationToken::new();
+    for second in [0, 2] {
+        let event = client.read(cancel_token.clone()).await.unwrap();
+        assert_eq!(event.time_us(), START_US + second * 1_000_000);
+    }
+    // other tests can skip frames at the same time
+    assert!(malformed_frame_count() >= before + 2);
+}
+
+#[tokio::test]
+async fn test_commits_missing_fields_dont_stop_reading() {
+    let like = "app.bsky.feed.like";
+    let mut no_record = commit(1, like, "create");
+    no_record["commit"]
+        .as_object_mut()
+        .unwrap()
+        .remove("record");
+    let mut no_cid = commit(2, like, "update");
+    no_cid["commit"].as_object_mut().unwrap().remove("cid");
+    let events = vec![no_record, no_cid, commit(3, like, "create")];
+    let server = support::ReplayServer::start(events, usize::MAX, Duration::ZERO).await;
+    let mut client = JetstreamClient::new([server.url.as_str()], Default::default()).unwrap();
+    client.connect().await.unwrap();
+
+    let cancel_token = CancellationToken::new();
+    let mut kinds = Vec::new();
+    for _ in 0..3 {
+        match client.read(cancel_token.clone()).await.unwrap() {
+            JetstreamEvent::Unknown { kind, .. } => kinds.push(kind),
+            event => {
+                assert_eq!(event.time_us(), START_US + 3_000_000);
+                assert!(EventRecord::from_jetstream(event).is_some());
+            }
+        }
+    }
+    assert_eq!(
+        kinds,
+        ["commit/create without record", "commit/update without cid"]
+    );
+}
+
+fn record(nsid: &'static str, second: u64) -> EventRecord {
+    EventRecord {
+        nsid: SmolStr::new_static(nsid),
+        timestamp: START + second,
+        op: HitOp::Create,
+        did: None,
+        rkey: None,
+    }
+}
+
+#[tokio::test]
+async fn test_poisoned_counts_keep_serving() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-poisoned-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..10).map(|second| record(like, second)))
+        .unwrap();
+
+    // counts that dont decode and a key that isnt utf8
+    let counts = db
+        .ks
+        .open_partition("_counts", PartitionCreateOptions::default())
+        .unwrap();
+    counts.insert(post, [0xff; 3]).unwrap();
+    counts.insert([0xff, 0xfe], [0xff; 3]).unwrap();
+
+    // ingest refuses the poisoned nsid instead of panicking, and goes on
+    assert!(db.ingest_events(std::iter::once(record(post, 10))).is_err());
+    db.ingest_events(std::iter::once(record(like, 10))).unwrap();
+    db.sync(true).unwrap();
+
+    let router = api::instance(db.clone());
+    let events = get(&router, "/events").await;
+    assert_eq!(events["partial"], true);
+    assert_eq!(events["events"][like]["count"], 11);
+    assert!(events["events"].get(post).is_none());
+    let uri = format!("/hits?nsid={like}&to={START}&from={}", START + 10);
+    let hits = get(&router, &uri).await;
+    assert_eq!(hits.as_array().unwrap().len(), 11);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// the data of the first `n` events of an sse stream
+async fn sse_frames(
+    router: &axum::Router,
+    uri: &str,
+    last_event_id: Option<&str>,
+    n: usize,
+) -> Vec<serde_json::Value> {
+    SseStream::open(router, uri, last_event_id)
+        .await
+        .next(n)
+        .await
+}
+
+// an sse response that is read from as events are needed
+struct SseStream {
+    body: axum::body::BodyDataStream,
+    raw: String,
+    frames: std::collections::VecDeque<serde_json::Value>,
+}
+
+impl SseStream {
+    async fn open(router: &axum::Router, uri: &str, last_event_id: Option<&str>) -> Self {
+        let mut request = Request::builder().uri(uri);
+        if let Some(id) = last_event_id {
+            request = request.header("last-event-id", id);
+        }
+        let response = router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        Self {
+            body: response.into_body().into_data_stream(),
+            raw: String::new(),
+            frames: Default::default(),
+        }
+    }
+
+    // the data of the next `n` events
+    async fn next(&mut self, n: usize) -> Vec<serde_json::Value> {
+        while self.frames.len() < n {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), self.body.next())
+                .await
+                .expect("no sse event in time")
+                .unwrap()
+                .unwrap();
+            self.raw.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some(end) = self.raw.find("\n\n") {
+                let event = self.raw[..end].to_owned();
+                self.raw.drain(..end + 2);
+                if let Some(data) = event.lines().find_map(|line| line.strip_prefix("data: ")) {
+                    self.frames.push_back(serde_json::from_str(data).unwrap());
+                }
+            }
+        }
+        self.frames.drain(..n).collect()
+    }
+}
+
+#[tokio::test]
+async fn test_stream_deltas() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-stream-deltas-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..3).map(|second| record(like, second)))
+        .unwrap();
+    let router = api::instance(db.clone());
+
+    let uri = format!("/stream_events.sse?nsids={like}&mode=delta");
+    let mut stream = SseStream::open(&router, &uri, None).await;
+    let frames = stream.next(2).await;
+    // the snapshot still has the whole counts
+    assert_eq!(frames[1]["snapshot"], true);
+    assert_eq!(frames[1]["events"][like]["count"], 3);
+
+    db.ingest_events((3..5).map(|second| record(like, second)))
+        .unwrap();
+    let frame = stream.next(1).await.remove(0);
+    assert_eq!(frame["events"], serde_json::json!({}));
+    assert_eq!(
+        frame["deltas"][like],
+        serde_json::json!({
+            "count_delta": 2,
+            "deleted_delta": 0,
+            "purged_delta": 0,
+            "last_seen": START + 4,
+        })
+    );
+
+    db.ingest_events(std::iter::once(EventRecord {
+        op: HitOp::Delete,
+        ..record(like, 5)
+    }))
+    .unwrap();
+    let frame = stream.next(1).await.remove(0);
+    assert_eq!(frame["deltas"][like]["count_delta"], 0);
+    assert_eq!(frame["deltas"][like]["deleted_delta"], 1);
+
+    drop(stream);
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_stream_interval_coalesces_updates() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-stream-interval-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..3).map(|second| record(like, second)))
+        .unwrap();
+    let router = api::routes().with_state(db.clone());
+
+    // clamped up to the 100ms minimum
+    let uri = format!("/stream_events.sse?nsids={like}&interval_ms=1");
+    let mut stream = SseStream::open(&router, &uri, None).await;
+    let frames = stream.next(2).await;
+    assert_eq!(frames[1]["events"][like]["count"], 3);
+
+    // paced by the event rate each of these would be a frame of its own
+    for second in 3..8 {
+        db.ingest_events(std::iter::once(record(like, second)))
+            .unwrap();
+    }
+    let mut frames = 0;
+    loop {
+        let frame = stream.next(1).await.remove(0);
+        frames += 1;
+        if frame["events"][like]["count"] == 8 {
+            break;
+        }
+    }
+    assert!(frames < 5, "{frames} frames");
+
+    drop(stream);
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_sse_snapshot_and_reconnect() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path =
+        std::env::temp_dir().join(format!("lexicon-tracker-test-sse-{}", std::process::id()));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..3).map(|second| record(like, second)))
+        .unwrap();
+    db.ingest_events(std::iter::once(record(post, 3))).unwrap();
+    let router = api::instance(db.clone());
+
+    let uri = format!("/stream_events.sse?nsids={like}");
+    let frames = sse_frames(&router, &uri, None, 2).await;
+    assert!(frames[0]["server"].is_object());
+    assert_eq!(frames[1]["snapshot"], true);
+    assert_eq!(frames[1]["events"][like]["count"], 3);
+    assert!(frames[1]["events"].get(post).is_none());
+
+    // a reconnect skips the hello and starts with a snapshot
+    let frames = sse_frames(&router, &uri, Some("2"), 1).await;
+    assert_eq!(frames[0]["snapshot"], true);
+    assert_eq!(frames[0]["events"][like]["count"], 3);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_health_follows_ingest_and_shutdown() {
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-health-{}",
+        std::process::id()
+    ));
+    let cancel_token = CancellationToken::new();
+    let db = Db::new(DbConfig::default().path(&path), cancel_token.clone()).unwrap();
+    let db = Arc::new(db);
+    let router = api::instance(db.clone());
+
+    // just opened, nothing ingested yet
+    let health = get(&router, "/health").await;
+    assert_eq!(health["last_event_age"], serde_json::Value::Null);
+    assert_eq!(health["jetstream_connected"], false);
+
+    db.ingest_events((0..3).map(|second| record("app.bsky.feed.like", second)))
+        .unwrap();
+    let health = get(&router, "/health").await;
+    assert!(health["last_event_age"].as_u64().unwrap() <= 1);
+    assert_eq!(health["open_partitions"], 1);
+    assert_eq!(health["buffered_items"], 3);
+
+    cancel_token.cancel();
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/health")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status(),
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_rate_limit_per_client() {
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-ratelimit-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let router = api::rate_limited(api::instance(db.clone()), api::RateLimiter::new(2, 1, 16));
+    let request = |uri: &str, ip: &str| {
+        let request = Request::builder()
+            .uri(uri)
+            .header("x-real-ip", ip)
+            .body(Body::empty())
+            .unwrap();
+        router.clone().oneshot(request)
+    };
+
+    for _ in 0..2 {
+        let response = request("/events", "10.0.0.1").await.unwrap();
+        assert!(response.status().is_success());
+    }
+    let response = request("/events", "10.0.0.1").await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+    let retry_after = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+
+    // hits have a budget of their own, other clients arent affected
+    let uri = format!("/hits?nsid=app.bsky.feed.like&to={START}");
+    let response = request(&uri, "10.0.0.1").await.unwrap();
+    assert_ne!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+    let response = request(&uri, "10.0.0.1").await.unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+    let response = request("/events", "10.0.0.2").await.unwrap();
+    assert!(response.status().is_success());
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_hits_collapse_to_minutes() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-resolution-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    // START is 20 seconds into a minute
+    let minute = START / 60 * 60;
+    let mut records = [0, 10, 50, 100, 130]
+        .map(|second| record(like, second))
+        .to_vec();
+    records[4].op = HitOp::Delete;
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+
+    let uri = format!(
+        "/hits?nsid={like}&to={}&from={}&resolution=minute",
+        START - 60,
+        START + 200
+    );
+    let rows = get(&router, &uri).await;
+    assert_eq!(
+        rows,
+        serde_json::json!([[minute, 2, 0], [minute + 60, 1, 0], [minute + 120, 2, 1],])
+    );
+
+    // the limit is in rows, the oldest minute is left out
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("{uri}&limit=2"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-truncated"], "true");
+    // read newest first up to the first hit of the oldest minute
+    assert_eq!(response.headers()["x-scanned-hits"], "4");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let rows: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        rows,
+        serde_json::json!([[minute + 60, 1, 0], [minute + 120, 2, 1]])
+    );
+
+    // any width with step, as objects
+    let hour = START / 3600 * 3600;
+    let uri = format!(
+        "/hits?nsid={like}&to={}&from={}&step=3600",
+        START - 60,
+        START + 200
+    );
+    let steps = get(&router, &uri).await;
+    // START is 800 seconds into an hour
+    assert_eq!(
+        steps,
+        serde_json::json!([{ "bucket": hour, "count": 5, "deleted_count": 1 }])
+    );
+    let steps = get(&router, &uri.replace("step=3600", "step=60")).await;
+    assert_eq!(
+        steps[2],
+        serde_json::json!({ "bucket": minute + 120, "count": 2, "deleted_count": 1 })
+    );
+    for bad in ["step=0", "step=60&resolution=minute", "step=60&format=csv"] {
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/hits?nsid={like}&{bad}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{bad}");
+    }
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_labels_filter_and_stream() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-labels-api-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events([record(like, 0), record(post, 1)].into_iter())
+        .unwrap();
+    db.sync(true).unwrap();
+    let official = LabelMap::from([(SmolStr::new("official"), SmolStr::default())]);
+    db.set_labels(post, official).unwrap();
+    let router = api::instance(db.clone());
+
+    let events = get(&router, "/events?labels=true&label=official").await;
+    assert!(events["events"].get(like).is_none());
+    assert_eq!(events["events"][post]["count"], 1);
+    assert_eq!(events["events"][post]["labels"]["official"], "");
+    // labels only when asked for
+    let events = get(&router, "/events").await;
+    assert!(events["events"][post].get("labels").is_none());
+
+    let nsids = get(&router, "/nsids?label=official").await;
+    assert_eq!(nsids, serde_json::json!([{ "nsid": post }]));
+    let info = get(&router, &format!("/nsid_info?nsid={post}")).await;
+    assert_eq!(info["labels"], serde_json::json!({ "official": "" }));
+
+    // label changes reach streams that asked for labels
+    let changer = db.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = LabelMap::from([(SmolStr::new("status"), SmolStr::new("deprecated"))]);
+        changer.set_labels(like, status).unwrap();
+    });
+    let frames = sse_frames(&router, "/stream_events.sse?labels=true", None, 3).await;
+    assert_eq!(frames[1]["events"][post]["labels"]["official"], "");
+    assert_eq!(frames[2]["labels"][like]["status"], "deprecated");
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_bad_queries_are_coded_client_errors() {
+    use axum::http::StatusCode;
+
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-bad-queries-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events(std::iter::once(record(like, 0))).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone()).layer(axum::middleware::from_fn(error::with_request_id));
+    let error_of = |uri: String| {
+        let router = router.clone();
+        async move {
+            let request = Request::builder()
+                .uri(uri)
+                .header("x-request-id", "req-1")
+                .body(Body::empty())
+                .unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(body["error"].is_string(), "{body}");
+            assert_eq!(body["request_id"], "req-1", "{body}");
+            (status, body["code"].as_str().unwrap().to_owned())
+        }
+    };
+
+    let to = START;
+    let from = START + 10;
+    let post = "app.bsky.feed.post";
+    for (uri, status, code) in [
+        // doesnt parse
+        (
+            format!("/hits?nsid={like}&to={to}&from=abc"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        // ends before it starts
+        (
+            format!("/hits?nsid={like}&to={from}&from={to}"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_RANGE",
+        ),
+        (
+            format!("/hits?nsid=not-an-nsid&to={to}&from={from}"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_NSID",
+        ),
+        (
+            format!("/hits?nsid={like},,{post}&to={to}&from={from}"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_NSID",
+        ),
+        (
+            format!("/hits?nsid={like}&to={to}&from={from}&limit=0"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_LIMIT",
+        ),
+        (
+            format!("/hits?nsid={like}&to=0&from={from}"),
+            StatusCode::BAD_REQUEST,
+            "RANGE_TOO_LARGE",
+        ),
+        (
+            "/events?sort=count&limit=0".to_owned(),
+            StatusCode::BAD_REQUEST,
+            "INVALID_LIMIT",
+        ),
+        (
+            format!("/hits?nsid={like}&to={to}&from={from}&resolution=minute&format=csv"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            format!("/hits?nsid={post}&to={to}&from={from}"),
+            StatusCode::NOT_FOUND,
+            "NSID_NOT_FOUND",
+        ),
+        (
+            format!("/hits?nsid={like}&to={to}&from={from}&allow_large=true"),
+            StatusCode::FORBIDDEN,
+            "FORBIDDEN",
+        ),
+        (
+            format!("/hits?nsid={like}&debug=true"),
+            StatusCode::FORBIDDEN,
+            "FORBIDDEN",
+        ),
+        (
+            format!("/histogram?nsid={like}&interval=0"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            format!("/histogram?nsid={like}&from={from}&to={to}"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_RANGE",
+        ),
+        (
+            format!("/histogram?nsid={like}&from=0&to={from}&interval=1"),
+            StatusCode::BAD_REQUEST,
+            "RANGE_TOO_LARGE",
+        ),
+        (
+            format!("/counts_range?nsid={like}&from={from}&to={to}"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_RANGE",
+        ),
+        (
+            format!("/compare?nsid={like}&a_from={from}&a_to={to}&b_from=0&b_to=1"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_RANGE",
+        ),
+        (
+            format!("/rollup?nsid={like}&from={from}&to={to}"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_RANGE",
+        ),
+        (
+            format!("/rollup?nsid={like}&from=0&to={from}"),
+            StatusCode::BAD_REQUEST,
+            "RANGE_TOO_LARGE",
+        ),
+        (
+            format!("/compare?nsid={like}&a_from=0&a_to=1"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            "/compare?window=7y".to_owned(),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            format!("/hits?nsid={like}&sample=0"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            format!("/hits?nsid={like}&sample=0.5&every=2"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            format!("/hits?nsid={like}&sample=0.5&format=csv"),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            "/search?q=%20".to_owned(),
+            StatusCode::BAD_REQUEST,
+            "INVALID_REQUEST",
+        ),
+        (
+            "/top?limit=0".to_owned(),
+            StatusCode::BAD_REQUEST,
+            "INVALID_LIMIT",
+        ),
+        (
+            "/eps?nsid=not-an-nsid".to_owned(),
+            StatusCode::BAD_REQUEST,
+            "INVALID_NSID",
+        ),
+        (
+            format!("/since?nsid={post}"),
+            StatusCode::NOT_FOUND,
+            "NSID_NOT_FOUND",
+        ),
+        (
+            format!("/nsid_info?nsid={post}"),
+            StatusCode::NOT_FOUND,
+            "NSID_NOT_FOUND",
+        ),
+        (
+            format!("/overview?nsid={post}"),
+            StatusCode::NOT_FOUND,
+            "NSID_NOT_FOUND",
+        ),
+        (
+            "/did_events?did=did:plc:eygmaihciaxprqvxpfvl6flk".to_owned(),
+            StatusCode::NOT_FOUND,
+            "DID_NOT_WATCHED",
+        ),
+    ] {
+        assert_eq!(
+            error_of(uri.clone()).await,
+            (status, code.to_owned()),
+            "{uri}"
+        );
+    }
+
+    // errors about a limit say what it is
+    let request = Request::builder()
+        .uri("/search?q=like&limit=1000")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "INVALID_LIMIT");
+    assert_eq!(body["detail"], serde_json::json!({ "min": 1, "max": 100 }));
+
+    // the nsid is known, so an empty range is an empty 200
+    let hits = get(&router, &format!("/hits?nsid={like}&to={from}&from={from}")).await;
+    assert_eq!(hits, serde_json::json!([]));
+
+    // every code above is in the catalogue, with its status
+    let catalogue = get(&router, "/error_codes").await;
+    let catalogue = catalogue.as_array().unwrap();
+    assert!(catalogue.iter().any(|entry| {
+        entry["code"] == "NSID_NOT_FOUND"
+            && entry["status"] == 404
+            && entry["description"].is_string()
+    }));
+    assert!(
+        catalogue
+            .iter()
+            .all(|entry| entry["code"] != "INTERNAL" || entry["status"] == 500)
+    );
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// the checked in fixture is anonymized jetstream traffic, see `replay`
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/jetstream.jsonl.zst");
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replayed_fixture_is_ingested() {
+    let mut expected = ahash::AHashMap::<SmolStr, usize>::new();
+    for frame in replay::read_fixture(FIXTURE).unwrap() {
+        let event = serde_json::from_str::<JetstreamEvent>(&frame.unwrap().frame).unwrap();
+        if let Some(record) = EventRecord::from_jetstream(event) {
+            *expected.entry(record.nsid).or_default() += 1;
+        }
+    }
+    assert!(expected.len() > 1);
+
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-replay-{}",
+        std::process::id()
+    ));
+    let mut db = DbConfig::default().path(&path);
+    db.min_sync_interval = Duration::from_millis(50);
+    db.max_sync_interval = Duration::from_millis(100);
+    db.max_last_activity = Duration::ZERO;
+    let cfg = InstanceConfig {
+        name: None,
+        db,
+        urls: vec![format!("file://{FIXTURE}?pace=fast").into()],
+        webhook: None,
+        alert_webhook: None,
+        enricher: None,
+    };
+    let cancel_token = CancellationToken::new();
+    let instance = Instance::start(cfg, &cancel_token).unwrap();
+    let db = instance.db.clone();
+
+    let synced = async {
+        while expected
+            .iter()
+            .any(|(nsid, count)| db.get_hits(nsid, .., usize::MAX).count() < *count)
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), synced)
+        .await
+        .expect("fixture wasnt synced in time");
+    for (nsid, count) in &expected {
+        assert_eq!(db.get_hits(nsid, .., usize::MAX).count(), *count, "{nsid}");
+    }
+    let total = expected.values().sum::<usize>() as u128;
+    let totals = db.totals();
+    assert_eq!(
+        totals.count + totals.deleted_count + totals.purged_count,
+        total
+    );
+
+    cancel_token.cancel();
+    instance.shutdown().await;
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// jetstream redelivers the tail of the fixture, like after a reconnect
+// without a cursor, and the counts are still those of the fixture
+#[tokio::test(flavor = "multi_thread")]
+async fn test_redelivered_events_are_dropped() {
+    use std::io::Write;
+
+    let frames = replay::read_fixture(FIXTURE)
+        .unwrap()
+        .collect::<error::AppResult<Vec<_>>>()
+        .unwrap();
+    let redelivered = &frames[frames.len() - 50..];
+    let mut expected = ahash::AHashMap::<SmolStr, u128>::new();
+    for frame in &frames {
+        let event = serde_json::from_str::<JetstreamEvent>(&frame.frame).unwrap();
+        if let Some(record) = EventRecord::from_jetstream(event) {
+            *expected.entry(record.nsid).or_default() += 1;
+        }
+    }
+    let duplicates = redelivered
+        .iter()
+        .filter_map(|frame| serde_json::from_str::<JetstreamEvent>(&frame.frame).ok())
+        .filter_map(EventRecord::from_jetstream)
+        .count() as u64;
+    assert!(duplicates > 0);
+
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-redelivered-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&path).unwrap();
+    let fixture = path.join("overlap.jsonl.zst");
+    let mut out = zstd::Encoder::new(std::fs::File::create(&fixture).unwrap(), 3).unwrap();
+    for frame in frames.iter().chain(redelivered) {
+        serde_json::to_writer(&mut out, frame).unwrap();
+        out.write_all(b"\n").unwrap();
+    }
+    out.finish().unwrap();
+
+    let mut db = DbConfig::default().path(path.join("db"));
+    db.min_sync_interval = Duration::from_millis(50);
+    db.max_sync_interval = Duration::from_millis(100);
+    let cfg = InstanceConfig {
+        name: None,
+        db,
+        urls: vec![format!("file://{}?pace=fast", fixture.display()).into()],
+        webhook: None,
+        alert_webhook: None,
+        enricher: None,
+    };
+    let cancel_token = CancellationToken::new();
+    let instance = Instance::start(cfg, &cancel_token).unwrap();
+    let db = instance.db.clone();
+
+    let total = expected.values().sum::<u128>();
+    let replayed = async {
+        loop {
+            let totals = db.totals();
+            let ingested = totals.count + totals.deleted_count + totals.purged_count;
+            if db.duplicates_dropped() >= duplicates && ingested >= total {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), replayed)
+        .await
+        .expect("fixture wasnt replayed in time");
+    assert_eq!(db.duplicates_dropped(), duplicates);
+    for (nsid, count) in &expected {
+        let counts = db.get_count(nsid).unwrap();
+        assert_eq!(
+            counts.count + counts.deleted_count + counts.purged_count,
+            *count,
+            "{nsid}"
+        );
+    }
+
+    cancel_token.cancel();
+    instance.shutdown().await;
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_top_within_window_and_all_time() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path =
+        std::env::temp_dir().join(format!("lexicon-tracker-test-top-{}", std::process::id()));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let now = crate::utils::get_time().as_secs();
+    let at = |nsid: &'static str, timestamp: u64, op: HitOp| EventRecord {
+        nsid: SmolStr::new_static(nsid),
+        timestamp,
+        op,
+        did: None,
+        rkey: None,
+    };
+    // likes were busy a day ago, posts are busy now
+    let mut records = (0..5)
+        .map(|i| at(like, now - 60 * 60 * 24 + i, HitOp::Create))
+        .collect::<Vec<_>>();
+    records.push(at(like, now - 60, HitOp::Create));
+    records.extend((0..3).map(|i| at(post, now - 30 + i, HitOp::Create)));
+    records.push(at(post, now - 10, HitOp::Delete));
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+
+    let top = get(&router, "/top?window=3600").await;
+    let nsids = top["nsids"].as_array().unwrap();
+    assert_eq!(nsids.len(), 2);
+    assert_eq!(nsids[0]["nsid"], post);
+    assert_eq!(nsids[0]["count"], 3);
+    assert_eq!(nsids[0]["deleted_count"], 1);
+    assert_eq!(nsids[1]["nsid"], like);
+    assert_eq!(nsids[1]["count"], 1);
+
+    let top = get(&router, "/top?window=3600&by=deleted&limit=1").await;
+    assert_eq!(top["nsids"].as_array().unwrap().len(), 1);
+    assert_eq!(top["nsids"][0]["nsid"], post);
+
+    let top = get(&router, "/top").await;
+    assert!(top["window"].is_null());
+    assert_eq!(top["nsids"][0]["nsid"], like);
+    assert_eq!(top["nsids"][0]["count"], 6);
+
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/top?by=likes")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_counts_within_a_range() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-counts-range-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let mut records = (0..10)
+        .map(|second| record(like, second))
+        .collect::<Vec<_>>();
+    records.extend((10..20).map(|second| EventRecord {
+        op: HitOp::Delete,
+        ..record(like, second)
+    }));
+    records.extend((5..8).map(|second| record(post, second)));
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+
+    let uri = format!(
+        "/counts_range?nsid={like},{post},com.example.never&from={}&to={}",
+        START + 5,
+        START + 15
+    );
+    let range = get(&router, &uri).await;
+    assert_eq!(range["from"], START + 5);
+    let nsids = range["nsids"].as_array().unwrap();
+    assert_eq!(nsids.len(), 3);
+    assert_eq!(nsids[0]["nsid"], like);
+    assert_eq!(nsids[0]["count"], 5);
+    assert_eq!(nsids[0]["deleted_count"], 5);
+    assert_eq!(
+        nsids[0]["covered"],
+        serde_json::json!({ "from": START + 5, "to": START + 15 })
+    );
+    // posts stop before the range does
+    assert_eq!(nsids[1]["count"], 3);
+    assert_eq!(
+        nsids[1]["covered"],
+        serde_json::json!({ "from": START + 5, "to": START + 8 })
+    );
+    assert!(nsids[2]["error"].is_string());
+
+    // no history that far back
+    let uri = format!("/counts_range?nsid={post}&from=0&to={START}");
+    let range = get(&router, &uri).await;
+    assert_eq!(range["nsids"][0]["count"], 0);
+    assert!(range["nsids"][0]["covered"].is_null());
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_compare_two_ranges() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-compare-ranges-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    // 4 likes in the first 10 seconds, 6 and 2 deletes in the next 10
+    let mut records = (0..4)
+        .map(|second| record(like, second))
+        .collect::<Vec<_>>();
+    records.extend((10..16).map(|second| record(like, second)));
+    records.extend((16..18).map(|second| EventRecord {
+        op: HitOp::Delete,
+        ..record(like, second)
+    }));
+    // posts only in the second range
+    records.extend((10..13).map(|second| record(post, second)));
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+
+    let uri = format!(
+        "/compare?nsid={like},{post}&a_from={}&a_to={}&b_from={START}&b_to={}",
+        START + 10,
+        START + 20,
+        START + 10
+    );
+    let compared = get(&router, &uri).await;
+    assert_eq!(compared["a"], serde_json::json!([START + 10, START + 20]));
+    let nsids = compared["nsids"].as_array().unwrap();
+    assert_eq!(nsids[0]["nsid"], like);
+    assert_eq!(
+        nsids[0]["a"],
+        serde_json::json!({ "count": 6, "deleted_count": 2 })
+    );
+    assert_eq!(
+        nsids[0]["b"],
+        serde_json::json!({ "count": 4, "deleted_count": 0 })
+    );
+    assert_eq!(
+        nsids[0]["created"],
+        serde_json::json!({ "delta": 2, "percent_change": 50.0 })
+    );
+    // nothing to compare the deletes and posts against
+    assert_eq!(
+        nsids[0]["deleted"],
+        serde_json::json!({ "delta": 2, "percent_change": null })
+    );
+    assert_eq!(nsids[1]["created"]["delta"], 3);
+    assert!(nsids[1]["created"]["percent_change"].is_null());
+
+    // an empty range has no deltas at all
+    let uri = format!(
+        "/compare?nsid={like}&a_from={START}&a_to={START}&b_from={START}&b_to={}",
+        START + 10
+    );
+    let compared = get(&router, &uri).await;
+    assert_eq!(compared["nsids"][0]["a"]["count"], 0);
+    assert!(compared["nsids"][0]["created"]["delta"].is_null());
+
+    // without ranges it still compares windows of every nsid
+    let compared = get(&router, "/compare").await;
+    assert!(compared["windows"].is_object());
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_search_ranks_segment_matches_first() {
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-search-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let mut records = (0..3)
+        .map(|second| record("com.example.superflashes", second))
+        .collect::<Vec<_>>();
+    records.push(record("blue.flashes.feed.post", 3));
+    records.extend((0..2).map(|second| record("blue.flashes.actor.profile", second)));
+    records.push(record("app.bsky.feed.like", 4));
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+
+    let results = get(&router, "/search?q=FLASHES").await;
+    let nsids = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["nsid"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    // busier first within the same kind of match
+    assert_eq!(
+        nsids,
+        [
+            "blue.flashes.actor.profile",
+            "blue.flashes.feed.post",
+            "com.example.superflashes"
+        ]
+    );
+    assert_eq!(results[0]["match"], "segment");
+    assert_eq!(results[0]["count"], 2);
+    assert_eq!(results[2]["match"], "substring");
+
+    let results = get(&router, "/search?q=flashes.blue&limit=1").await;
+    assert_eq!(results.as_array().unwrap().len(), 1);
+    let results = get(&router, "/search?q=nothing").await;
+    assert_eq!(results, serde_json::json!([]));
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_multi_series_matches_histograms() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-multi-series-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    // a day of hits, some of them deletes
+    let mut records = (0..60 * 60 * 24)
+        .step_by(37)
+        .map(|second| record(like, second))
+        .collect::<Vec<_>>();
+    for record in records.iter_mut().step_by(5) {
+        record.op = HitOp::Delete;
+    }
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+
+    let (from, to) = (START - 60 * 60, START + 60 * 60 * 25);
+    let (viewport_from, viewport_to) = (START + 60 * 60 * 3 + 17, START + 60 * 60 * 4);
+    let multi = get(
+        &router,
+        &format!(
+            "/multi_series?nsid={like}&from={from}&to={to}\
+             &viewport_from={viewport_from}&viewport_to={viewport_to}"
+        ),
+    )
+    .await;
+    for (series, from, to) in [
+        (&multi["coarse"], from, to),
+        (&multi["fine"], viewport_from, viewport_to),
+    ] {
+        let interval = series["interval"].as_u64().unwrap();
+        // the bucket sizes are picked per series
+        assert!((to - from) / interval <= 300);
+        let histogram = get(
+            &router,
+            &format!("/histogram?nsid={like}&from={from}&to={to}&interval={interval}"),
+        )
+        .await;
+        assert_eq!(series["buckets"], histogram);
+    }
+    assert!(multi["fine"]["interval"].as_u64() < multi["coarse"]["interval"].as_u64());
+
+    // /stats summarizes the same buckets
+    let query = format!("nsid={like}&from={from}&to={to}&interval=3600&deleted=false");
+    let histogram = get(&router, &format!("/histogram?{query}")).await;
+    let histogram = histogram.as_array().unwrap();
+    let stats = get(&router, &format!("/stats?{query}")).await;
+    let counts = histogram
+        .iter()
+        .map(|bucket| bucket["count"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(stats["buckets"], counts.len());
+    assert_eq!(stats["total"], counts.iter().sum::<u64>());
+    assert_eq!(stats["max"], *counts.iter().max().unwrap());
+    let busiest = counts
+        .iter()
+        .position(|count| stats["max"] == *count)
+        .unwrap();
+    assert_eq!(stats["busiest"], histogram[busiest]["bucket_start"]);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_hits_filtered_by_deleted() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-hits-deleted-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    // one in ten hits is a delete, over a few syncs so there are a few blocks
+    for chunk in (0..1000).collect::<Vec<_>>().chunks(250) {
+        let records = chunk.iter().map(|second| {
+            let mut record = record(like, *second);
+            if second % 10 == 0 {
+                record.op = HitOp::Delete;
+            }
+            record
+        });
+        db.ingest_events(records).unwrap();
+        db.sync(true).unwrap();
+    }
+    let router = api::instance(db.clone());
+
+    let range = format!("nsid={like}&to={START}&from={}", START + 1000);
+    // a page of deletes is full even though most hits are creates
+    let deleted = get(&router, &format!("/hits?{range}&deleted=true&limit=50")).await;
+    let deleted = deleted.as_array().unwrap();
+    assert_eq!(deleted.len(), 50);
+    assert!(deleted.iter().all(|hit| hit["deleted"] == true));
+    // the newest of them, oldest first
+    assert_eq!(deleted[0]["timestamp"], START + 500);
+    assert_eq!(deleted[49]["timestamp"], START + 990);
+    let created = get(&router, &format!("/hits?{range}&deleted=false&limit=5000")).await;
+    assert_eq!(created.as_array().unwrap().len(), 900);
+    // same as without the filter
+    let all = get(&router, &format!("/hits?{range}")).await;
+    assert_eq!(all.as_array().unwrap().len(), 1000);
+
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/hits?{range}&deleted=true&kind=purged"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+
+    let histogram = format!(
+        "/histogram?nsid={like}&from={START}&to={}&interval=1000",
+        START + 999
+    );
+    let deleted = get(&router, &format!("{histogram}&deleted=true")).await;
+    assert_eq!(deleted[0]["count"], 0);
+    assert_eq!(deleted[0]["deleted_count"], 100);
+    let created = get(&router, &format!("{histogram}&deleted=false")).await;
+    assert_eq!(created[0]["count"], 900);
+    assert_eq!(created[0]["deleted_count"], 0);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_hits_of_several_nsids() {
+    let like = "app.bsky.feed.like";
+    let repost = "app.bsky.feed.repost";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-multi-hits-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    // likes every second, reposts every tenth second
+    let records = (0..100)
+        .map(|second| record(like, second))
+        .chain((0..100).step_by(10).map(|second| record(repost, second)));
+    db.ingest_events(records).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let range = format!("to={START}&from={}", START + 99);
+
+    let by_nsid = get(
+        &router,
+        &format!("/hits?nsid={like},{repost},not%20an%20nsid,app.bsky.feed.nope&{range}"),
+    )
+    .await;
+    assert_eq!(by_nsid[like]["hits"].as_array().unwrap().len(), 100);
+    assert_eq!(by_nsid[like]["truncated"], false);
+    assert_eq!(by_nsid[repost]["hits"].as_array().unwrap().len(), 10);
+    assert!(by_nsid["not an nsid"]["error"].is_string());
+    assert!(by_nsid["app.bsky.feed.nope"]["error"].is_string());
+
+    let merged = get(
+        &router,
+        &format!("/hits?nsid={like},{repost},app.bsky.feed.nope&{range}&merge=true&limit=20"),
+    )
+    .await;
+    let hits = merged["hits"].as_array().unwrap();
+    assert_eq!(hits.len(), 20);
+    assert_eq!(merged["truncated"], true);
+    assert!(merged["errors"]["app.bsky.feed.nope"].is_string());
+    // the newest of both, oldest first
+    assert!(
+        hits.windows(2)
+            .all(|pair| pair[0]["timestamp"].as_u64() <= pair[1]["timestamp"].as_u64())
+    );
+    assert_eq!(hits[19]["timestamp"], START + 99);
+    // the tie at 90 goes to reposts, they got fewer in so far
+    let reposts = hits
+        .iter()
+        .filter(|hit| hit["nsid"] == repost)
+        .collect::<Vec<_>>();
+    assert_eq!(reposts.len(), 1);
+    assert_eq!(reposts[0]["timestamp"], START + 90);
+    assert_eq!(hits[0]["timestamp"], START + 81);
+
+    let many = (0..11).map(|i| format!("a.b.c{i}")).collect::<Vec<_>>();
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/hits?nsid={}&{range}", many.join(",")))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_sampled_hits() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-sampled-hits-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let records = (0..1000).map(|second| EventRecord {
+        op: if second % 10 == 0 {
+            HitOp::Delete
+        } else {
+            HitOp::Create
+        },
+        ..record(like, second)
+    });
+    db.ingest_events(records).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let uri = format!("/hits?nsid={like}&to={START}&from={}", START + 999);
+
+    let sampled = get(&router, &format!("{uri}&every=10&seed=3")).await;
+    assert_eq!(sampled["hits"].as_array().unwrap().len(), 100);
+    assert_eq!(sampled["sample_rate"], 0.1);
+    assert_eq!(sampled["seed"], 3);
+    assert_eq!(sampled["scanned"], 1000);
+    assert_eq!(sampled["estimated_total"], 1000);
+    assert_eq!(sampled["truncated"], false);
+
+    // the same seed picks the same hits
+    let sampled = get(&router, &format!("{uri}&sample=0.1&seed=5")).await;
+    assert_eq!(
+        sampled,
+        get(&router, &format!("{uri}&sample=0.1&seed=5")).await
+    );
+    let total = sampled["estimated_total"].as_u64().unwrap();
+    assert!((500..=1500).contains(&total), "{total}");
+    let timestamps = sampled["hits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["timestamp"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert!(timestamps.is_sorted());
+
+    // sampling is over the hits of the asked kind, and the limit counts kept ones
+    let sampled = get(&router, &format!("{uri}&deleted=true&every=2")).await;
+    assert_eq!(sampled["scanned"], 100);
+    assert_eq!(sampled["hits"].as_array().unwrap().len(), 50);
+    assert!(
+        sampled["hits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|hit| hit["deleted"] == true)
+    );
+    let sampled = get(&router, &format!("{uri}&every=10&limit=10")).await;
+    assert_eq!(sampled["hits"].as_array().unwrap().len(), 10);
+    assert_eq!(sampled["truncated"], true);
+    assert_eq!(
+        sampled["hits"][9]["timestamp"].as_u64().unwrap() / 10 * 10,
+        START + 990
+    );
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_hits_in_binary() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-hits-bin-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let records = (0..100).map(|second| EventRecord {
+        op: if second % 7 == 0 {
+            HitOp::Delete
+        } else {
+            HitOp::Create
+        },
+        ..record(like, second)
+    });
+    db.ingest_events(records).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let uri = format!("/hits?nsid={like}&to={START}&from={}&limit=50", START + 99);
+
+    let json = get(&router, &uri).await;
+    let expected = json
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| {
+            let op = if hit["deleted"].as_bool().unwrap() {
+                HitOp::Delete
+            } else {
+                HitOp::Create
+            };
+            (hit["timestamp"].as_u64().unwrap(), op)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(expected.len(), 50);
+
+    let bin = |request: Request<Body>| {
+        let router = router.clone();
+        async move {
+            let response = router.oneshot(request).await.unwrap();
+            assert!(response.status().is_success());
+            assert_eq!(response.headers()["content-type"], hits_bin::CONTENT_TYPE);
+            assert_eq!(response.headers()["x-truncated"], "true");
+            assert_eq!(response.headers()["x-timestamp-granularity"], "1");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            hits_bin::decode(&body).unwrap()
+        }
+    };
+    let by_format = bin(Request::builder()
+        .uri(format!("{uri}&format=bin"))
+        .body(Body::empty())
+        .unwrap())
+    .await;
+    assert_eq!(by_format.nsid, like);
+    assert_eq!(by_format.hits, expected);
+    let by_accept = bin(Request::builder()
+        .uri(&uri)
+        .header("accept", "application/octet-stream, */*;q=0.1")
+        .body(Body::empty())
+        .unwrap())
+    .await;
+    assert_eq!(by_accept, by_format);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_quiet_events_are_not_modified() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-events-etag-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..10).map(|second| record(like, second)))
+        .unwrap();
+    // the rate isnt part of the tag, no need to wait for it to settle
+    let router = api::instance(db.clone());
+    let request = |uri: &str, etag: Option<&str>| {
+        let mut request = Request::builder().uri(uri);
+        if let Some(etag) = etag {
+            request = request.header("if-none-match", etag);
+        }
+        router.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let response = request("/events", None).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+    assert!(etag.starts_with("W/\""), "{etag}");
+    assert!(response.headers().contains_key("last-modified"));
+
+    // nothing came in since
+    for sent in [etag.clone(), format!("W/\"nope\", {etag}"), "*".to_owned()] {
+        let response = request("/events", Some(&sent)).await.unwrap();
+        assert_eq!(response.status(), 304, "{sent}");
+        assert_eq!(response.headers()["etag"], etag.as_str());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+    let response = request("/events", Some("W/\"nope\"")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    // sparklines also depend on synced blocks, so they get a tag of their own
+    let response = request("/events?sparklines=true", Some(&etag))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    db.ingest_events(std::iter::once(record(like, 10))).unwrap();
+    let response = request("/events", Some(&etag)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_ne!(response.headers()["etag"], etag.as_str());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let events: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(events["events"][like]["count"], 11);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rate_of_one_nsid() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-nsid-eps-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..20).map(|second| record(like, second)))
+        .unwrap();
+    let router = api::instance(db.clone());
+
+    let rate = get(&router, &format!("/eps?nsid={like}")).await;
+    assert_eq!(rate["window_secs"], 10);
+    assert_eq!(rate["per_second"], 2.0);
+    let events = get(&router, "/events?rates=true").await;
+    assert_eq!(events["events"][like]["per_second"], 2.0);
+    assert!(
+        get(&router, "/events").await["events"][like]
+            .get("per_second")
+            .is_none()
+    );
+
+    // never seen, and nothing is created for it
+    let rate = get(&router, "/eps?nsid=app.bsky.feed.post").await;
+    assert_eq!(rate["per_second"], 0.0);
+    assert!(!db.has_nsid("app.bsky.feed.post"));
+    let response = router
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/eps?nsid=nope")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_cache_headers_follow_the_source() {
+    const DAY: u64 = 60 * 60 * 24;
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-cache-headers-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..20).map(|second| record(like, second)))
+        .unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let fetch = |uri: String| {
+        let router = router.clone();
+        async move {
+            let request = Request::builder().uri(&uri).body(Body::empty()).unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            assert!(response.status().is_success(), "{uri}");
+            let header = |name: &str| response.headers()[name].to_str().unwrap().to_owned();
+            let source = header("x-data-source");
+            let max_age = header("cache-control")
+                .strip_prefix("max-age=")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+            (source, max_age, response)
+        }
+    };
+
+    // counts are flushed every second
+    let (source, max_age, response) = fetch("/events".to_owned()).await;
+    assert_eq!((source.as_str(), max_age), ("counts", 1));
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let events: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(events["suggested_poll_secs"], 1);
+
+    // without a sync loop a sync can come every MIN_SYNC_INTERVAL_SECS, with
+    // one whats left of its interval. an idle one waits the max of a minute
+    let uri = format!(
+        "/histogram?nsid={like}&from={}&to={}&interval=60",
+        START - 60,
+        START + 60
+    );
+    let (source, max_age, _) = fetch(uri.clone()).await;
+    assert_eq!((source.as_str(), max_age), ("blocks", 2));
+    eventually("the rate to settle", || db.eps() == 0).await;
+    db.next_sync_interval();
+    let (source, max_age, _) = fetch(uri).await;
+    assert_eq!((source.as_str(), max_age), ("blocks", 60));
+    let overview = get(&router, &format!("/overview?nsid={like}")).await;
+    assert_eq!(overview["suggested_poll_secs"], 60);
+
+    // whole settled days come from the rollups, until the next day settles
+    let day = START / DAY * DAY;
+    let uri = format!(
+        "/histogram?nsid={like}&from={day}&to={}&interval={DAY}",
+        day + DAY - 1
+    );
+    let before = db.max_age(DataSource::Rollups);
+    let (source, max_age, response) = fetch(uri).await;
+    let after = db.max_age(DataSource::Rollups);
+    assert_eq!(source, "rollups");
+    assert!((after..=before).contains(&max_age), "{max_age}");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let buckets: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(buckets[0]["count"], 20);
+    // a day that doesnt start on a bucket isnt rolled up as asked
+    let uri = format!(
+        "/histogram?nsid={like}&from={}&to={}&interval={DAY}",
+        day + 1,
+        day + DAY
+    );
+    let (source, _, _) = fetch(uri).await;
+    assert_eq!(source, "blocks");
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// records what the onboarding webhook is sent, failing with a 500 while
+// `failing` is set
+#[derive(Default)]
+struct Hook {
+    received: parking_lot::Mutex<Vec<serde_json::Value>>,
+    attempts: AtomicUsize,
+    failing: AtomicBool,
+}
+
+#[tokio::test]
+async fn test_rollup_days_and_weeks() {
+    const DAY: u64 = 60 * 60 * 24;
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-rollup-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..20).map(|second| record(like, second)))
+        .unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let fetch = |uri: String| {
+        let router = router.clone();
+        async move {
+            let request = Request::builder().uri(&uri).body(Body::empty()).unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            assert!(response.status().is_success(), "{uri}");
+            let header = |name: &str| response.headers()[name].to_str().unwrap().to_owned();
+            let source = header("x-data-source");
+            let cache_control = header("cache-control");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let rollup: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (source, cache_control, rollup)
+        }
+    };
+
+    // the first read rolls the day up on the way, so it is still estimated
+    let day = START / DAY * DAY;
+    let uri = format!("/rollup?nsid={like}&from={day}&to={}", day + DAY - 1);
+    let (source, _, rollup) = fetch(uri.clone()).await;
+    assert_eq!(source, "blocks");
+    assert_eq!(rollup["granularity"], "day");
+    assert_eq!(
+        rollup["points"],
+        serde_json::json!([{
+            "start": day, "count": 20, "deleted_count": 0, "purged_count": 0, "estimated": true
+        }])
+    );
+    let (source, cache_control, rollup) = fetch(uri).await;
+    assert_eq!(source, "rollups");
+    assert_eq!(cache_control, "max-age=604800");
+    assert_eq!(rollup["points"][0]["estimated"], false);
+
+    // 2023-11-13, the monday before START
+    let monday = 1_699_833_600;
+    let uri = format!(
+        "/rollup?nsid={like}&from={day}&to={}&granularity=week",
+        day + DAY - 1
+    );
+    let (_, _, rollup) = fetch(uri).await;
+    assert_eq!(rollup["points"][0]["start"], monday);
+    assert_eq!(rollup["points"][0]["count"], 20);
+
+    let request = Request::builder()
+        .uri("/rollup?nsid=com.example.never")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+async fn serve_hook(hook: std::sync::Arc<Hook>) -> String {
+    use axum::{Json, extract::State, http::StatusCode, routing::post};
+
+    async fn receive(
+        State(hook): State<std::sync::Arc<Hook>>,
+        Json(body): Json<serde_json::Value>,
+    ) -> StatusCode {
+        hook.attempts.fetch_add(1, Ordering::SeqCst);
+        if hook.failing.load(Ordering::SeqCst) {
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        hook.received.lock().push(body);
+        StatusCode::NO_CONTENT
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = axum::Router::new()
+        .route("/hook", post(receive))
+        .with_state(hook);
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{addr}/hook")
+}
+
+async fn eventually(what: &str, cond: impl Fn() -> bool) {
+    let waited = tokio::time::timeout(Duration::from_secs(10), async {
+        while !cond() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    assert!(waited.is_ok(), "timed out waiting for {what}");
+}
+
+#[tokio::test]
+async fn test_new_nsids_are_announced_once() {
+    let like = "app.bsky.feed.like";
+    let repost = "app.bsky.feed.repost";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-onboarding-webhook-{}",
+        std::process::id()
+    ));
+    let hook = std::sync::Arc::new(Hook::default());
+    let cfg = WebhookConfig {
+        backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(50),
+        rescan: Duration::from_millis(50),
+        ..WebhookConfig::new(serve_hook(hook.clone()).await)
+    };
+    let open =
+        || Arc::new(Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap());
+    let received = || hook.received.lock().clone();
+
+    let db = open();
+    let cancel = CancellationToken::new();
+    let notifier = webhook::start(db.clone(), cfg.clone(), cancel.clone()).unwrap();
+    hook.failing.store(true, Ordering::SeqCst);
+    db.ingest_events((0..3).map(|second| record(like, second)))
+        .unwrap();
+    eventually("retries", || hook.attempts.load(Ordering::SeqCst) >= 3).await;
+    assert!(received().is_empty());
+    hook.failing.store(false, Ordering::SeqCst);
+    eventually("the like", || received().len() == 1).await;
+    assert_eq!(
+        received()[0],
+        serde_json::json!({ "nsid": like, "first_seen": START, "initial_count": 3 })
+    );
+
+    // an nsid that is already known isnt new
+    db.ingest_events((3..6).map(|second| record(like, second)))
+        .unwrap();
+    // this one isnt delivered before we restart
+    hook.failing.store(true, Ordering::SeqCst);
+    let attempts = hook.attempts.load(Ordering::SeqCst);
+    db.ingest_events(std::iter::once(record(repost, 10)))
+        .unwrap();
+    eventually("the repost", || {
+        hook.attempts.load(Ordering::SeqCst) > attempts
+    })
+    .await;
+    cancel.cancel();
+    notifier.await.unwrap();
+    db.sync(true).unwrap();
+    drop(db);
+
+    let db = open();
+    hook.failing.store(false, Ordering::SeqCst);
+    let cancel = CancellationToken::new();
+    let notifier = webhook::start(db.clone(), cfg, cancel.clone()).unwrap();
+    eventually("the repost after a restart", || received().len() == 2).await;
+    assert_eq!(received()[1]["nsid"], repost);
+    assert_eq!(received()[1]["initial_count"], 1);
+    db.ingest_events([record(like, 20), record(repost, 20)].into_iter())
+        .unwrap();
+    // a few rescans, nothing else is sent
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(received().len(), 2);
+    assert!(db.onboarding().pending().unwrap().is_empty());
+
+    cancel.cancel();
+    notifier.await.unwrap();
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// a load test, run with `cargo test -- --ignored test_ingest_latency`. ingest
+// keeps going while more queries than the query pool has threads decode every
+// block of a big nsid, its p99 batch latency should stay about what it is
+// without them
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore]
+async fn test_ingest_latency_under_query_load() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-ingest-load-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    for start in (0..1_000_000_u64).step_by(10_000) {
+        db.ingest_events((start..start + 10_000).map(|i| record(like, i / 20)))
+            .unwrap();
+        db.sync(true).unwrap();
+    }
+    let router = api::instance(db.clone());
+
+    // batches like the ingest thread gets them, returns the p99 in millis
+    let ingest_p99 = |db: Arc<Db>, batches: u64| {
+        std::thread::spawn(move || {
+            let mut latencies = (0..batches)
+                .map(|batch| {
+                    let start = std::time::Instant::now();
+                    db.ingest_events((0..500).map(|i| record(post, batch * 500 + i)))
+                        .unwrap();
+                    std::thread::sleep(Duration::from_millis(5));
+                    start.elapsed() - Duration::from_millis(5)
+                })
+                .collect::<Vec<_>>();
+            latencies.sort_unstable();
+            latencies[latencies.len() * 99 / 100].as_secs_f64() * 1000.0
+        })
+    };
+    let quiet = ingest_p99(db.clone(), 200).join().unwrap();
+
+    let cancel = CancellationToken::new();
+    let hammers = (0..32)
+        .map(|_| {
+            let (router, cancel) = (router.clone(), cancel.clone());
+            tokio::spawn(async move {
+                let uri = format!("/histogram?nsid={like}&from={START}&to={}", START + 50_000);
+                let mut queries = 0;
+                while !cancel.is_cancelled() {
+                    // too many at once is a 503, that still counts as load
+                    let _ = router
+                        .clone()
+                        .oneshot(Request::builder().uri(&uri).body(Body::empty()).unwrap())
+                        .await;
+                    queries += 1;
+                }
+                queries
+            })
+        })
+        .collect::<Vec<_>>();
+    let loaded = ingest_p99(db.clone(), 200);
+    let loaded = tokio::task::spawn_blocking(move || loaded.join().unwrap())
+        .await
+        .unwrap();
+    cancel.cancel();
+    let mut queries = 0;
+    for hammer in hammers {
+        queries += hammer.await.unwrap();
+    }
+
+    println!("ingest p99: {quiet:.2}ms quiet, {loaded:.2}ms with {queries} queries");
+    assert!(queries > 0);
+    assert!(
+        loaded < (quiet * 10.0).max(50.0),
+        "ingest p99 went from {quiet:.2}ms to {loaded:.2}ms"
+    );
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// a benchmark, run with `cargo test -- --ignored test_block_cache_keeps`.
+// the dashboard asks for the last hour of a busy nsid over and over while an
+// export reads every block of it a few times, the blocks of that hour should
+// stay cached throughout
+#[test]
+#[ignore]
+fn test_block_cache_keeps_hot_blocks_under_export() {
+    let like = "app.bsky.feed.like";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-block-cache-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    // a week of 5 hits a second
+    let end = 60 * 60 * 24 * 7;
+    for start in (0..end).step_by(60 * 60) {
+        db.ingest_events((start * 5..(start + 60 * 60) * 5).map(|i| record(like, i / 5)))
+            .unwrap();
+        db.sync(true).unwrap();
+    }
+
+    let export = std::thread::spawn({
+        let db = db.clone();
+        move || {
+            let snapshot = db.pin_snapshot(like).unwrap();
+            (0..3)
+                .map(|_| db.export_hits(&snapshot, ..).count())
+                .sum::<usize>()
+        }
+    });
+    let before = block_cache().stats();
+    let started = std::time::Instant::now();
+    let (from, to) = (START + end - 60 * 60, START + end);
+    let mut queries = 0;
+    while !export.is_finished() || queries < 1000 {
+        let page = db.query_hits(like, from..=to, usize::MAX, None, Admission::Admit);
+        assert!(page.hits.count() > 0);
+        db.histogram(like, from, to, 60).unwrap();
+        queries += 1;
+    }
+    let exported = export.join().unwrap();
+    let after = block_cache().stats();
+    let (hits, misses) = (after.hits - before.hits, after.misses - before.misses);
+    let hit_rate = hits as f64 / (hits + misses).max(1) as f64;
+
+    println!(
+        "{queries} queries in {:.2}s while exporting {exported} hits: \
+         {hit_rate:.3} hit rate, {} evicted, {} bypassed",
+        started.elapsed().as_secs_f64(),
+        after.evicted - before.evicted,
+        after.bypassed - before.bypassed,
+    );
+    assert_eq!(exported, end as usize * 5 * 3);
+    assert!(hit_rate > 0.95, "{after:?}");
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_openapi_covers_every_route() {
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-openapi-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let router = api::instance(db.clone());
+
+    let spec = get(&router, "/openapi.json").await;
+    let paths = spec["paths"].as_object().unwrap();
+    // bumped along with every new route, they are only served if documented
+    assert_eq!(paths.len(), 25, "{:?}", paths.keys().collect::<Vec<_>>());
+    for (route, item) in paths {
+        assert!(item["get"].is_object(), "{route}");
+        let request = Request::builder().uri(route).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_ne!(
+            response.status(),
+            404,
+            "{route} is documented but not served"
+        );
+    }
+    assert!(paths.keys().all(|route| !route.starts_with("/admin")));
+
+    let param = |route: &str, name: &str| {
+        paths[route]["get"]["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|param| param["name"] == name)
+            .unwrap()
+            .clone()
+    };
+    for route in ["/hits", "/histogram", "/did_hits"] {
+        for name in ["from", "to"] {
+            let description = param(route, name)["description"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+            assert!(description.contains("epoch seconds"), "{route} {name}");
+        }
+    }
+    assert_eq!(param("/hits", "nsid")["required"], true);
+    assert_eq!(param("/hits", "from")["required"], false);
+    assert!(spec["components"]["schemas"]["ErrorBody"].is_object());
+
+    let docs = Request::builder().uri("/docs").body(Body::empty()).unwrap();
+    assert!(router.oneshot(docs).await.unwrap().status().is_success());
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_tail_follows_the_stream() {
+    let like = "app.bsky.feed.like";
+    let path =
+        std::env::temp_dir().join(format!("lexicon-tracker-test-tail-{}", std::process::id()));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..3).map(|second| record(like, second)))
+        .unwrap();
+
+    // nothing listens yet, so tail has to retry until the server is up
+    let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let url = tail::stream_url(&format!("http://{addr}"), &[SmolStr::new(like)]);
+    let cancel_token = CancellationToken::new();
+    let mut client = tail::TailClient::new(url).unwrap();
+    let server = tokio::spawn({
+        let router = api::instance(db.clone());
+        async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, router).await.unwrap();
+        }
+    });
+
+    let mut next = async || {
+        tokio::time::timeout(Duration::from_secs(10), client.next(&cancel_token))
+            .await
+            .unwrap()
+            .unwrap()
+            .1
+    };
+    let snapshot = loop {
+        let frame = next().await;
+        if frame.snapshot {
+            break frame;
+        }
+    };
+    assert_eq!(snapshot.events[like].count, 3);
+    assert_eq!(
+        tail::render(&snapshot, false, Duration::from_secs(1)),
+        [format!("{like} 3 created, 0 deleted")]
+    );
+
+    db.ingest_events((3..5).map(|second| record(like, second)))
+        .unwrap();
+    let frame = loop {
+        let frame = next().await;
+        if !frame.deltas.is_empty() {
+            break frame;
+        }
+    };
+    assert_eq!(frame.deltas[like].count_delta, 2);
+    assert_eq!(
+        tail::render(&frame, false, Duration::from_secs(1))[0],
+        format!("{like} +2")
+    );
+
+    cancel_token.cancel();
+    assert!(client.next(&cancel_token).await.is_none());
+    server.abort();
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// a benchmark, run with `cargo test -- --ignored test_info_reads`. info of
+// many small blocks over many nsids only reads block headers, it should
+// get through them at a few million blocks a second
+#[test]
+#[ignore]
+fn test_info_reads_block_headers_quickly() {
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-info-bench-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let nsids = (0..200)
+        .map(|i| SmolStr::new(format!("com.example.bench{i}")))
+        .collect::<Vec<_>>();
+    for second in (0..1000).step_by(4) {
+        let events = nsids.iter().flat_map(|nsid| {
+            (second..second + 4).map(|second| EventRecord {
+                nsid: nsid.clone(),
+                ..record("com.example.bench0", second)
+            })
+        });
+        db.ingest_events(events).unwrap();
+        db.sync(true).unwrap();
+    }
+
+    let started = std::time::Instant::now();
+    let info = db.info().unwrap();
+    let elapsed = started.elapsed();
+    let blocks = info.nsids.values().map(Vec::len).sum::<usize>();
+    println!(
+        "info of {blocks} blocks in {:.3}s, {:.0} blocks/s",
+        elapsed.as_secs_f64(),
+        blocks as f64 / elapsed.as_secs_f64()
+    );
+    assert!(!info.partial);
+    assert_eq!(info.nsids.values().flatten().sum::<usize>(), 200 * 1000);
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+// a benchmark, run with `cargo test -- --ignored test_ingest_interleaved`.
+// nsids too long to be inlined are refcounted, ingest clones each distinct
+// one once per batch, so batches where they are interleaved (like jetstream
+// sends them) should ingest about as fast as ones sorted by nsid
+#[test]
+#[ignore]
+fn test_ingest_interleaved_long_nsids() {
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-ingest-interleaved-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let nsids = (0..8)
+        .map(|i| SmolStr::new(format!("fyi.unravel.frontpage.comment{i}")))
+        .collect::<Vec<_>>();
+    assert!(nsids.iter().all(SmolStr::is_heap_allocated));
+    let batches = 2000;
+    let batch = |batch: u64, interleaved: bool| {
+        let nsids = &nsids;
+        (0..500_u64).map(move |i| {
+            let (nsid, second) = if interleaved {
+                (i % 8, i / 8)
+            } else {
+                (i / 63, i % 63)
+            };
+            EventRecord {
+                nsid: nsids[nsid as usize].clone(),
+                ..record("com.example.bench", batch * 63 + second)
+            }
+        })
+    };
+    // the second run goes on after the first, so hits stay in order
+    let ingest = |first: u64, interleaved: bool| {
+        let started = std::time::Instant::now();
+        for i in first..first + batches {
+            db.ingest_events(batch(i, interleaved)).unwrap();
+        }
+        db.sync(true).unwrap();
+        let elapsed = started.elapsed();
+        println!(
+            "{batches} batches, interleaved: {interleaved}, {:.0} events/s",
+            (batches * 500) as f64 / elapsed.as_secs_f64()
+        );
+        elapsed
+    };
+    let sorted = ingest(0, false);
+    let interleaved = ingest(batches, true);
+    assert!(interleaved < sorted * 2, "{interleaved:?} vs {sorted:?}");
+    let total = nsids
+        .iter()
+        .map(|nsid| db.get_count(nsid).unwrap().count)
+        .sum::<u128>();
+    assert_eq!(total, batches as u128 * 500 * 2);
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_sorted_events_pages() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let follow = "app.bsky.graph.follow";
+    let path = std::env::temp_dir().join(format!(
+        "lexicon-tracker-test-sorted-events-{}",
+        std::process::id()
+    ));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    let records = [
+        record(like, 0),
+        record(like, 1),
+        record(post, 2),
+        record(post, 3),
+        record(follow, 4),
+    ];
+    db.ingest_events(records.into_iter()).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let nsids = |events: &serde_json::Value| {
+        events["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["nsid"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    // without paging params the map stays
+    let events = get(&router, "/events").await;
+    assert!(events["events"].is_object());
+    assert!(events.get("total").is_none());
+
+    // like and post tie on count, so by nsid
+    let events = get(&router, "/events?sort=count").await;
+    assert_eq!(nsids(&events), [like, post, follow]);
+    assert_eq!(events["events"][0]["count"], 2);
+    assert_eq!(events["total"], 3);
+    let events = get(&router, "/events?sort=count&order=asc").await;
+    assert_eq!(nsids(&events), [follow, like, post]);
+    let events = get(&router, "/events?sort=last_seen").await;
+    assert_eq!(nsids(&events), [follow, post, like]);
+    let events = get(&router, "/events?sort=nsid&limit=2").await;
+    assert_eq!(nsids(&events), [like, post]);
+    assert_eq!(events["total"], 3);
+
+    // pages dont overlap and the total is of every match
+    let events = get(&router, "/events?limit=1&offset=1").await;
+    assert_eq!(nsids(&events), [post]);
+    let events = get(&router, "/events?offset=5").await;
+    assert_eq!(nsids(&events), Vec::<String>::new());
+    assert_eq!(events["total"], 3);
+    let events = get(&router, "/events?prefix=app.bsky.feed.&sort=count&limit=1").await;
+    assert_eq!(nsids(&events), [like]);
+    assert_eq!(events["total"], 2);
+
+    // filters go before sorting and paging, totals stay of every nsid
+    let events = get(&router, "/events?min_count=2&sort=nsid").await;
+    assert_eq!(nsids(&events), [like, post]);
+    assert_eq!(events["totals"]["count"], 5);
+    let since = START + 2;
+    let events = get(
+        &router,
+        &format!("/events?seen_since={since}&prefix=app.bsky.feed."),
+    )
+    .await;
+    assert_eq!(events["events"].as_object().unwrap().len(), 1);
+    assert!(events["events"].get(post).is_some());
+    let events = get(&router, "/events?min_deleted=1").await;
+    assert!(events["events"].as_object().unwrap().is_empty());
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn test_batch_runs_every_query() {
+    let like = "app.bsky.feed.like";
+    let post = "app.bsky.feed.post";
+    let path =
+        std::env::temp_dir().join(format!("lexicon-tracker-test-batch-{}", std::process::id()));
+    let db = Db::new(DbConfig::default().path(&path), CancellationToken::new()).unwrap();
+    let db = Arc::new(db);
+    db.ingest_events((0..5).map(|second| record(like, second)))
+        .unwrap();
+    db.ingest_events(std::iter::once(record(post, 5))).unwrap();
+    db.sync(true).unwrap();
+    let router = api::instance(db.clone());
+    let batch = |body: serde_json::Value| {
+        let router = router.clone();
+        async move {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/batch")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    let (from, to) = (START + 10, START);
+    let (status, results) = batch(serde_json::json!([
+        { "query": "events", "params": { "nsid": post } },
+        { "query": "hits", "params": { "nsid": like, "from": from, "to": to, "limit": 2 } },
+        { "query": "since" },
+        { "query": "nsid_info", "params": { "nsid": "com.example.never" } },
+        { "query": "eps", "params": { "nsid": 5 } },
+    ]))
+    .await;
+    assert_eq!(status, 200);
+    let results = results.as_array().unwrap();
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0]["status"], 200);
+    assert_eq!(results[0]["body"]["events"][post]["count"], 1);
+    assert!(results[0]["body"]["events"].get(like).is_none());
+    assert_eq!(results[1]["body"]["hits"].as_array().unwrap().len(), 2);
+    assert_eq!(results[1]["body"]["truncated"], true);
+    assert_eq!(results[2]["body"]["since"], START);
+    // a failed query only fails its own entry
+    assert_eq!(results[3]["status"], 404);
+    assert_eq!(results[3]["error"]["code"], "NSID_NOT_FOUND");
+    assert_eq!(results[4]["status"], 400);
+
+    let too_many = vec![serde_json::json!({ "query": "since" }); 21];
+    let (status, body) = batch(serde_json::json!(too_many)).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "INVALID_REQUEST");
+
+    drop(router);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&path);
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use rclite::Arc;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_websockets::{Message, ServerBuilder};
+
+/// a local stand in for jetstream that replays canned events. every
+/// connection gets the events after its `cursor` (all of them without one),
+/// `delay` apart. the first connection is closed after `disconnect_after`
+/// events, later ones send the rest and stay open
+pub struct ReplayServer {
+    pub url: String,
+    // the cursor every connection asked for, in order
+    pub cursors: Arc<Mutex<Vec<Option<u64>>>>,
+    task: JoinHandle<()>,
+}
+
+impl ReplayServer {
+    pub async fn start(
+        events: Vec<serde_json::Value>,
+        disconnect_after: usize,
+        delay: Duration,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/subscribe", listener.local_addr().unwrap());
+        let cursors = Arc::new(Mutex::new(Vec::new()));
+        let events = Arc::new(events);
+        let task = tokio::spawn({
+            let cursors = cursors.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let (request, ws) = ServerBuilder::new().accept(stream).await.unwrap();
+                    let cursor = request
+                        .uri()
+                        .query()
+                        .and_then(|query| query.strip_prefix("cursor="))
+                        .map(|cursor| cursor.parse::<u64>().unwrap());
+                    let first = {
+                        let mut cursors = cursors.lock();
+                        cursors.push(cursor);
+                        cursors.len() == 1
+                    };
+                    let limit = if first { disconnect_after } else { usize::MAX };
+                    tokio::spawn(replay(ws, events.clone(), cursor, limit, first, delay));
+                }
+            }
+        });
+        Self { url, cursors, task }
+    }
+}
+
+impl Drop for ReplayServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn replay<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+    mut ws: tokio_websockets::WebSocketStream<S>,
+    events: Arc<Vec<serde_json::Value>>,
+    cursor: Option<u64>,
+    limit: usize,
+    close: bool,
+    delay: Duration,
+) {
+    let events = events
+        .iter()
+        .filter(|event| cursor.is_none_or(|cursor| event["time_us"].as_u64().unwrap() > cursor))
+        .take(limit);
+    for event in events {
+        tokio::time::sleep(delay).await;
+        if ws.send(Message::text(event.to_string())).await.is_err() {
+            return;
+        }
+    }
+    if close {
+        let _ = ws.close().await;
+        return;
+    }
+    // keep the connection open until the client goes away
+    while let Some(Ok(_)) = ws.next().await {}
+}
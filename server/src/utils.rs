@@ -3,17 +3,95 @@ use std::ops::Deref;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
+use ahash::AHashSet;
 use arc_swap::RefCnt;
 use byteview::ByteView;
 use ordered_varint::Variable;
 use rclite::Arc;
 
+/// bytes an unprivileged process can still write to the filesystem `path` is
+/// on, None if it cant be told
+#[cfg(unix)]
+pub fn free_space(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is nul terminated and stat is only read once statvfs filled it
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // the field types differ between platforms
+    #[allow(clippy::useless_conversion)]
+    let free = u64::from(stat.f_bavail).saturating_mul(u64::from(stat.f_frsize));
+    Some(free)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &std::path::Path) -> Option<u64> {
+    None
+}
+
 pub fn get_time() -> Duration {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
 }
 
+// civil year, month and day of a unix timestamp (seconds)
+// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_date(timestamp: u64) -> (i64, u32, u32) {
+    let z = (timestamp / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month as u32, day as u32)
+}
+
+pub fn year_month(timestamp: u64) -> (i64, u32) {
+    let (year, month, _) = civil_date(timestamp);
+    (year, month)
+}
+
+// a unix timestamp (seconds) as rfc3339 in utc
+pub fn rfc3339(timestamp: u64) -> String {
+    let (year, month, day) = civil_date(timestamp);
+    let secs = timestamp % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// a unix timestamp (seconds) as an http date, `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day) = civil_date(timestamp);
+    let secs = timestamp % 86400;
+    format!(
+        "{}, {day:02} {} {year:04} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a thursday
+        WEEKDAYS[(timestamp / 86400 % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
 pub trait WriteVariableExt: Write {
     fn write_varint(&mut self, value: impl Variable) -> io::Result<usize> {
         value.encode_variable(self)
@@ -173,6 +251,34 @@ impl<const BUCKET_WINDOW: u64> RateTracker<BUCKET_WINDOW> {
         total_events as f64 / self.window_duration.as_secs_f64()
     }
 
+    /// how long until fewer than `limit` events are left in the window, if
+    /// nothing else is observed until then
+    pub fn time_until_below(&self, limit: u64) -> Duration {
+        self.maybe_advance_buckets();
+
+        let mut total: u64 = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum();
+        if total < limit {
+            return Duration::ZERO;
+        }
+        // the bucket after the current one is the oldest, it is cleared when
+        // the next bucket starts, the one after that a bucket later and so on
+        let elapsed = self.elapsed();
+        let current = elapsed / self.bucket_duration_nanos;
+        let len = self.buckets.len() as u64;
+        for i in 1..=len {
+            let bucket_index = ((current + i) % len) as usize;
+            total = total.saturating_sub(self.buckets[bucket_index].load(Ordering::Relaxed));
+            if total < limit {
+                return Duration::from_nanos((current + i) * self.bucket_duration_nanos - elapsed);
+            }
+        }
+        self.window_duration
+    }
+
     fn get_current_bucket_index(&self) -> usize {
         let bucket_number = self.elapsed() / self.bucket_duration_nanos;
         (bucket_number as usize) % self.buckets.len()
@@ -211,12 +317,198 @@ impl<const BUCKET_WINDOW: u64> RateTracker<BUCKET_WINDOW> {
     }
 }
 
+/// reads batches off a channel. after the first item it keeps reading for a
+/// bit so that at low rates the reader isnt woken up for every single item,
+/// see `deadline` for how long
+#[derive(Debug, Clone, Copy)]
+pub struct Coalescer {
+    pub max_items: usize,
+    pub max_delay: Duration,
+}
+
+impl Coalescer {
+    // how often the channel is polled while waiting for more
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// how long to wait for more items when we have `have` at `rate` items
+    /// per second: long enough to fill the batch, but never past
+    /// `max_delay`, and not at all if the next item isnt expected before then
+    pub fn deadline(&self, rate: f64, have: usize) -> Duration {
+        let left = self.max_items.saturating_sub(have);
+        if left == 0 || rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        let gap = Duration::from_secs_f64(1.0 / rate);
+        if gap >= self.max_delay {
+            return Duration::ZERO;
+        }
+        self.max_delay.min(gap.saturating_mul(left as u32))
+    }
+
+    /// blocks until there is something to read, then reads until
+    /// `max_items` or the deadline. returns how many items were read, 0 once
+    /// the channel is closed and empty
+    pub fn recv<T>(
+        &self,
+        rx: &mut tokio::sync::mpsc::Receiver<T>,
+        buf: &mut Vec<T>,
+        rate: f64,
+    ) -> usize {
+        let mut read = rx.blocking_recv_many(buf, self.max_items);
+        if read == 0 {
+            return 0;
+        }
+        let deadline = self.deadline(rate, read);
+        let start = CLOCK.now();
+        while read < self.max_items {
+            match rx.try_recv() {
+                Ok(item) => {
+                    buf.push(item);
+                    read += 1;
+                }
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                    let waited = start.elapsed();
+                    if waited >= deadline {
+                        break;
+                    }
+                    std::thread::sleep(Self::POLL_INTERVAL.min(deadline - waited));
+                }
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => break,
+            }
+        }
+        read
+    }
+}
+
+/// identities of the events seen within the last `window` of event time, to
+/// drop the ones a reconnect delivers again. they are kept in a bucket per
+/// second that is cleared once event time moves past it, like `RateTracker`,
+/// so only about a window of events is held
+pub struct DedupWindow {
+    buckets: Vec<AHashSet<u128>>,
+    // the second of the newest event seen
+    newest: u64,
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration) -> Self {
+        let len = window.as_secs().max(1) as usize;
+        Self {
+            buckets: vec![AHashSet::new(); len],
+            newest: 0,
+        }
+    }
+
+    /// whether `identity` was already seen within the window, remembers it
+    /// if not. events older than the window cant be told apart and pass
+    pub fn is_duplicate(&mut self, identity: u128, time_us: u64) -> bool {
+        let second = time_us / 1_000_000;
+        let len = self.buckets.len() as u64;
+        if second > self.newest {
+            // the buckets of the seconds we moved into held the ones a
+            // window ago
+            let advance = (second - self.newest).min(len);
+            for past in second + 1 - advance..=second {
+                self.buckets[(past % len) as usize].clear();
+            }
+            self.newest = second;
+        } else if second + len <= self.newest {
+            return false;
+        }
+        !self.buckets[(second % len) as usize].insert(identity)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::sync::Arc;
     use std::thread;
 
+    const COALESCER: Coalescer = Coalescer {
+        max_items: 500,
+        max_delay: Duration::from_millis(20),
+    };
+
+    // sends `count` items `gap` apart from another thread, the channel stays
+    // open for a while after so readers dont stop early because it closed
+    fn paced(count: usize, gap: Duration) -> tokio::sync::mpsc::Receiver<usize> {
+        let (tx, rx) = tokio::sync::mpsc::channel(1000);
+        thread::spawn(move || {
+            for item in 0..count {
+                thread::sleep(gap);
+                if tx.blocking_send(item).is_err() {
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(500));
+        });
+        rx
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let mut window = DedupWindow::new(Duration::from_secs(3));
+        let second = |s: u64| 1_700_000_000_000_000 + s * 1_000_000;
+        assert!(!window.is_duplicate(1, second(0)));
+        assert!(window.is_duplicate(1, second(0)));
+        // same identity at another time is another event
+        assert!(!window.is_duplicate(1, second(1)));
+        assert!(!window.is_duplicate(2, second(2)));
+        // redelivered out of order, still within the window
+        assert!(window.is_duplicate(1, second(0)));
+        assert!(window.is_duplicate(2, second(2)));
+        // second 0 fell out of the window
+        assert!(!window.is_duplicate(3, second(3)));
+        assert!(!window.is_duplicate(1, second(0)));
+        assert!(window.is_duplicate(1, second(1)));
+        // a jump clears everything
+        assert!(!window.is_duplicate(2, second(100)));
+        assert!(!window.is_duplicate(3, second(99)));
+        assert!(!window.is_duplicate(1, second(98)));
+        assert!(!window.is_duplicate(2, second(2)));
+    }
+
+    #[test]
+    fn test_coalescer_deadline() {
+        // too sparse to coalesce anything, or nothing known yet
+        assert_eq!(COALESCER.deadline(10.0, 1), Duration::ZERO);
+        assert_eq!(COALESCER.deadline(0.0, 1), Duration::ZERO);
+        // just long enough to fill the batch
+        let deadline = COALESCER.deadline(100_000.0, 400);
+        assert!(deadline > Duration::from_micros(990) && deadline <= Duration::from_millis(1));
+        // but never longer than max_delay
+        assert_eq!(COALESCER.deadline(1000.0, 1), Duration::from_millis(20));
+        assert_eq!(COALESCER.deadline(1000.0, 500), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_coalescer_batches_paced_items() {
+        let mut rx = paced(100, Duration::from_millis(2));
+        let mut buf = Vec::new();
+        let read = COALESCER.recv(&mut rx, &mut buf, 500.0);
+        // about 10 items arrive within the 20ms
+        assert!(read > 1, "read {read}");
+        assert_eq!(read, buf.len());
+        assert_eq!(buf, (0..read).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_coalescer_bounds_latency() {
+        let mut buf = Vec::new();
+        // items that arent coming soon arent waited for
+        let mut rx = paced(2, Duration::from_millis(200));
+        assert_eq!(COALESCER.recv(&mut rx, &mut buf, 5.0), 1);
+
+        // and waiting stops at max_delay even if the rate says more are coming
+        let mut rx = paced(1, Duration::ZERO);
+        let start = CLOCK.now();
+        assert_eq!(COALESCER.recv(&mut rx, &mut buf, 1000.0), 1);
+        let took = start.elapsed();
+        assert!(took >= COALESCER.max_delay, "took {took:?}");
+        assert!(took < Duration::from_millis(200), "took {took:?}");
+    }
+
     #[test]
     fn test_rate_tracker_basic() {
         let tracker = DefaultRateTracker::new(Duration::from_secs(2));
@@ -259,6 +551,40 @@ mod tests {
         let rate = tracker.rate();
         assert_eq!(rate, 40.0); // 40 events in 1 second
     }
+
+    #[test]
+    fn test_rate_tracker_time_until_below() {
+        let tracker = DefaultRateTracker::new(Duration::from_secs(60));
+        assert_eq!(tracker.time_until_below(1), Duration::ZERO);
+        tracker.observe(5);
+        assert_eq!(tracker.time_until_below(6), Duration::ZERO);
+        // everything is in the current bucket, it leaves the window last
+        let wait = tracker.time_until_below(5);
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_year_month() {
+        assert_eq!(year_month(0), (1970, 1));
+        assert_eq!(year_month(951_782_400), (2000, 2)); // 2000-02-29
+        assert_eq!(year_month(951_868_800), (2000, 3)); // 2000-03-01
+        assert_eq!(year_month(1_704_067_199), (2023, 12)); // 2023-12-31 23:59:59
+        assert_eq!(year_month(1_704_067_200), (2024, 1));
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_825_845), "2000-02-29T12:04:05Z");
+        assert_eq!(rfc3339(1_704_067_199), "2023-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(951_825_845), "Tue, 29 Feb 2000 12:04:05 GMT");
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
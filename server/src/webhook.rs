@@ -0,0 +1,209 @@
+use std::{collections::VecDeque, time::Duration};
+
+use ahash::AHashSet;
+use rclite::Arc;
+use smol_str::SmolStr;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config,
+    db::{Db, NewNsid},
+    error::AppResult,
+};
+
+// new nsids are rare, a burst past this waits for the next rescan
+const QUEUE_CAPACITY: usize = 1024;
+// alerts past this are only listed on /alerts
+const ALERT_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    // the first retry waits `backoff`, every next one twice as long up to
+    // `max_backoff`
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // how often pending nsids that didnt fit into the queue are picked up
+    pub rescan: Duration,
+}
+
+impl WebhookConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60 * 10),
+            rescan: Duration::from_secs(60),
+        }
+    }
+
+    /// reads `ONBOARDING_WEBHOOK_URL`, None if it isnt set
+    pub fn from_env() -> Option<Self> {
+        config::env_or("ONBOARDING_WEBHOOK_URL", None, |url| {
+            Some(Some(url.to_owned()))
+        })
+        .map(Self::new)
+    }
+
+    /// reads `ALERT_WEBHOOK_URL`, falling back to `ONBOARDING_WEBHOOK_URL`.
+    /// None if neither is set
+    pub fn alerts_from_env() -> Option<Self> {
+        config::env_or("ALERT_WEBHOOK_URL", None, |url| Some(Some(url.to_owned())))
+            .map(Self::new)
+            .or_else(Self::from_env)
+    }
+}
+
+fn client() -> AppResult<reqwest::Client> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(anyhow::Error::from)?;
+    Ok(client)
+}
+
+// POSTs `body` until it gets a 2xx, false if we were cancelled before it
+// went through. `what` names it in the logs
+async fn post(
+    client: &reqwest::Client,
+    cfg: &WebhookConfig,
+    cancel: &CancellationToken,
+    body: &impl serde::Serialize,
+    what: &str,
+) -> bool {
+    let mut backoff = cfg.backoff;
+    loop {
+        let res = tokio::select! {
+            res = client.post(&cfg.url).json(body).send() => res,
+            _ = cancel.cancelled() => return false,
+        };
+        match res.map(|res| res.status()) {
+            Ok(status) if status.is_success() => return true,
+            Ok(status) => {
+                tracing::warn!("{what} failed with {status}, retrying in {backoff:?}")
+            }
+            Err(err) => tracing::warn!("{what} failed: {err}, retrying in {backoff:?}"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = cancel.cancelled() => return false,
+        }
+        backoff = (backoff * 2).min(cfg.max_backoff);
+    }
+}
+
+/// POSTs `{nsid, first_seen, initial_count}` to the webhook for every nsid
+/// once, the first time an event of it is ingested. a delivery is retried
+/// until it gets a 2xx, across restarts too. starts recording new nsids right
+/// away, so it has to be started before ingest
+pub fn start(
+    db: Arc<Db>,
+    cfg: WebhookConfig,
+    cancel: CancellationToken,
+) -> AppResult<JoinHandle<()>> {
+    let (pending, rx) = db.onboarding().subscribe(QUEUE_CAPACITY)?;
+    let client = client()?;
+    let notifier = Notifier {
+        db,
+        cfg,
+        client,
+        cancel,
+        backlog: VecDeque::new(),
+        queued: AHashSet::new(),
+    };
+    Ok(tokio::spawn(notifier.run(pending, rx)))
+}
+
+struct Notifier {
+    db: Arc<Db>,
+    cfg: WebhookConfig,
+    client: reqwest::Client,
+    cancel: CancellationToken,
+    backlog: VecDeque<NewNsid>,
+    // whats in the backlog, the queue and a rescan can both bring an nsid
+    queued: AHashSet<SmolStr>,
+}
+
+impl Notifier {
+    async fn run(mut self, pending: Vec<NewNsid>, mut rx: tokio::sync::mpsc::Receiver<NewNsid>) {
+        pending.into_iter().for_each(|new| self.push(new));
+        let mut rescan = tokio::time::interval(self.cfg.rescan);
+        rescan.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        rescan.reset();
+        loop {
+            if let Some(new) = self.backlog.pop_front() {
+                let delivered = self.deliver(&new).await;
+                self.queued.remove(&new.nsid);
+                if !delivered {
+                    // cancelled, it stays pending for the next start
+                    break;
+                }
+                continue;
+            }
+            tokio::select! {
+                new = rx.recv() => match new {
+                    Some(new) => self.push(new),
+                    None => break,
+                },
+                _ = rescan.tick() => match self.db.onboarding().pending() {
+                    Ok(pending) => pending.into_iter().for_each(|new| self.push(new)),
+                    Err(err) => tracing::error!("cant read pending onboarding webhooks: {err}"),
+                },
+                _ = self.cancel.cancelled() => break,
+            }
+        }
+    }
+
+    fn push(&mut self, new: NewNsid) {
+        if self.queued.insert(new.nsid.clone()) {
+            self.backlog.push_back(new);
+        }
+    }
+
+    // false if we were cancelled before it went through
+    async fn deliver(&self, new: &NewNsid) -> bool {
+        match self.db.onboarding().is_delivered(&new.nsid) {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(err) => tracing::error!("cant check onboarding state of {}: {err}", new.nsid),
+        }
+        let what = format!("onboarding webhook for {}", new.nsid);
+        if !post(&self.client, &self.cfg, &self.cancel, new, &what).await {
+            return false;
+        }
+        tracing::info!("announced new nsid {}", new.nsid);
+        if let Err(err) = self.db.onboarding().delivered(&new.nsid) {
+            tracing::error!("cant mark {} as announced: {err}", new.nsid);
+        }
+        true
+    }
+}
+
+/// POSTs every alert (see `Alert`) as it fires or resolves, in order. an
+/// alert is stored as fired before it is sent so it isnt sent twice, the
+/// flip side is that one still being retried on shutdown is only listed
+pub fn start_alerts(
+    db: Arc<Db>,
+    cfg: WebhookConfig,
+    cancel: CancellationToken,
+) -> AppResult<JoinHandle<()>> {
+    let mut rx = db.alerts().subscribe(ALERT_QUEUE_CAPACITY);
+    let client = client()?;
+    Ok(tokio::spawn(async move {
+        loop {
+            let alert = tokio::select! {
+                alert = rx.recv() => match alert {
+                    Some(alert) => alert,
+                    None => break,
+                },
+                _ = cancel.cancelled() => break,
+            };
+            let what = format!("alert webhook for {} of {}", alert.rule, alert.nsid);
+            if !post(&client, &cfg, &cancel, &alert, &what).await {
+                break;
+            }
+            tracing::info!("sent alert {} for {}", alert.rule, alert.nsid);
+        }
+    }))
+}